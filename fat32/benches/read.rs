@@ -0,0 +1,172 @@
+//! Benchmarks of the read path: sequential and small reads of one file and
+//! concurrent reads of several, with the cache and read tuning knobs of
+//! `MountOptions`. Each volume is mounted from an in-memory image behind a
+//! `ChaosDevice` that delays each device read, standing in for the cost per
+//! read of a real backend. Run with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate fat32;
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use criterion::{black_box, Criterion};
+use fat32::traits::{BlockDevice, ChaosDevice, FileSystem};
+use fat32::vfat::{format, FormatOptions, MountOptions, Shared, VFat};
+
+const SECTORS: u64 = 32 * 1024;
+const FILES: usize = 4;
+const FILE_SIZE: usize = 1024 * 1024;
+/// The delay of each device read, in nanoseconds.
+const READ_DELAY: u32 = 20_000;
+
+/// An image in memory shared by every volume mounted from it.
+#[derive(Clone)]
+struct Image(Arc<Mutex<Vec<u8>>>);
+
+impl BlockDevice for Image {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_sectors(n, &mut buf[..512])
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = n as usize * 512;
+        buf.copy_from_slice(&self.0.lock().unwrap()[start..start + buf.len()]);
+        Ok(buf.len())
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let start = n as usize * 512;
+        self.0.lock().unwrap()[start..start + 512].copy_from_slice(&buf[..512]);
+        Ok(512)
+    }
+}
+
+/// Returns a volume image holding `FILES` files of `FILE_SIZE` bytes,
+/// `/F0.BIN` and so on.
+fn image() -> Image {
+    let image = Image(Arc::new(Mutex::new(vec![0u8; SECTORS as usize * 512])));
+    let options = FormatOptions {
+        sectors_per_cluster: 8,
+        ..FormatOptions::default()
+    };
+    format(image.clone(), 0, SECTORS, &options).expect("format");
+
+    let vfat = VFat::from_volume(image.clone()).expect("mount");
+    for i in 0..FILES {
+        let data: Vec<u8> = (0..FILE_SIZE).map(|b| (b % 251 + i) as u8).collect();
+        let mut file = vfat.create_file(format!("/F{}.BIN", i)).expect("create");
+        file.write_all(&data).expect("write");
+    }
+    vfat.unmount().expect("unmount");
+    image
+}
+
+/// Mounts `image` with `options`, delaying each device read.
+fn mount(image: &Image, options: &MountOptions) -> Shared<VFat> {
+    let mut device = ChaosDevice::new(image.clone(), 1);
+    device.set_read_delay(Duration::new(0, READ_DELAY));
+    VFat::from_volume_with_options(device, options).expect("mount")
+}
+
+/// Reads the file at `path` to its end in reads of `chunk` bytes.
+fn read_file(vfat: &Shared<VFat>, path: &str, chunk: usize) -> usize {
+    let mut file = vfat.open_file(path).expect("open");
+    let mut buf = vec![0u8; chunk];
+    let mut total = 0;
+    loop {
+        match file.read(&mut buf).expect("read") {
+            0 => return total,
+            read => total += read,
+        }
+    }
+}
+
+/// The tuning knobs compared by each benchmark, by name.
+fn configurations() -> Vec<(&'static str, MountOptions)> {
+    vec![
+        ("default", MountOptions::default()),
+        (
+            "read_ahead=32",
+            MountOptions {
+                read_ahead: 32,
+                ..MountOptions::default()
+            },
+        ),
+        (
+            "max_run_clusters=1",
+            MountOptions {
+                max_run_clusters: 1,
+                ..MountOptions::default()
+            },
+        ),
+        (
+            "cache_capacity=256",
+            MountOptions {
+                cache_capacity: 256,
+                ..MountOptions::default()
+            },
+        ),
+    ]
+}
+
+/// Reads one file with large reads, each on a freshly mounted volume so that
+/// the cache starts empty.
+fn sequential(c: &mut Criterion) {
+    let image = image();
+    for (name, options) in configurations() {
+        let image = image.clone();
+        c.bench_function(&format!("sequential/{}", name), move |b| {
+            b.iter(|| {
+                let vfat = mount(&image, &options);
+                black_box(read_file(&vfat, "/F0.BIN", 256 * 1024))
+            })
+        });
+    }
+}
+
+/// Reads one file 100 bytes at a time.
+fn small_reads(c: &mut Criterion) {
+    let image = image();
+    for (name, options) in configurations() {
+        let image = image.clone();
+        c.bench_function(&format!("small_reads/{}", name), move |b| {
+            b.iter(|| {
+                let vfat = mount(&image, &options);
+                black_box(read_file(&vfat, "/F1.BIN", 100))
+            })
+        });
+    }
+}
+
+/// Reads every file at once, each on its own thread, from one volume.
+fn concurrent(c: &mut Criterion) {
+    let image = image();
+    for (name, options) in configurations() {
+        let image = image.clone();
+        c.bench_function(&format!("concurrent/{}", name), move |b| {
+            b.iter(|| {
+                let vfat = mount(&image, &options);
+                let readers: Vec<_> = (0..FILES)
+                    .map(|i| {
+                        let vfat = vfat.clone();
+                        let path = format!("/F{}.BIN", i);
+                        thread::spawn(move || read_file(&vfat, &path, 64 * 1024))
+                    })
+                    .collect();
+                let total: usize = readers.into_iter().map(|r| r.join().expect("reader")).sum();
+                black_box(total)
+            })
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = sequential, small_reads, concurrent
+}
+criterion_main!(benches);
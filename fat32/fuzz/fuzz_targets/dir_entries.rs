@@ -0,0 +1,74 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat32;
+
+use std::io::{Cursor, Read};
+
+use fat32::traits::{Dir, Entry, FileSystem};
+use fat32::vfat::{self, BiosParameterBlock, FormatOptions, VFat};
+
+/// Sector of the volume in the template image.
+const START: u64 = 64;
+
+/// Number of sectors in the template image.
+const SECTORS: u64 = 8192;
+
+/// Returns a freshly formatted image and the byte offset of its root
+/// directory's first cluster, which is `cluster_size` bytes long.
+fn template() -> (Vec<u8>, usize, usize) {
+    let mut image = Cursor::new(vec![0u8; (START + SECTORS) as usize * 512]);
+    vfat::format(&mut image, START, SECTORS, &FormatOptions::default()).unwrap();
+
+    let mut data = image.into_inner();
+    let mbr = &mut data[..512];
+    mbr[446 + 4] = 0x0C;
+    mbr[446 + 8..446 + 12].copy_from_slice(&[START as u8, 0, 0, 0]);
+    mbr[446 + 12..446 + 16].copy_from_slice(&[SECTORS as u8, (SECTORS >> 8) as u8, 0, 0]);
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    let (root, cluster_size) = {
+        let ebpb = BiosParameterBlock::from(Cursor::new(&mut data[..]), START).unwrap();
+        let sector_size = ebpb.bytes_per_sector as usize;
+        let data_start = START as usize
+            + ebpb.sectors_reserved as usize
+            + ebpb.fats_number as usize * ebpb.sectors_per_fat as usize;
+        let cluster_size = ebpb.sectors_per_cluster as usize * sector_size;
+        let root = data_start * sector_size + (ebpb.root_dir_cluster as usize - 2) * cluster_size;
+        (root, cluster_size)
+    };
+    (data, root, cluster_size)
+}
+
+fn visit(dir: &vfat::Dir, depth: usize) {
+    let entries = match dir.entries() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries {
+        let _ = entry.name();
+        match entry {
+            vfat::Entry::File(mut file) => {
+                let mut buf = [0u8; 512];
+                let _ = file.read(&mut buf);
+            }
+            vfat::Entry::Dir(ref child) if depth < 4 => visit(child, depth + 1),
+            vfat::Entry::Dir(_) => {}
+        }
+    }
+}
+
+// Treats the input as the contents of the root directory of a valid volume.
+fuzz_target!(|data: &[u8]| {
+    let (mut image, root, cluster_size) = template();
+    let len = ::std::cmp::min(data.len(), cluster_size);
+    image[root..root + len].copy_from_slice(&data[..len]);
+
+    if let Ok(vfat) = VFat::from(Cursor::new(image)) {
+        if let Ok(root) = vfat.open_dir("/") {
+            visit(&root, 0);
+        }
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat32;
+
+use std::io::Cursor;
+
+use fat32::MasterBootRecord;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mbr) = MasterBootRecord::from(Cursor::new(data.to_vec())) {
+        let _ = mbr.first_fat32();
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat32;
+
+use std::io::Cursor;
+
+use fat32::traits::{Dir, Entry, FileSystem};
+use fat32::vfat::{BiosParameterBlock, VFat};
+
+// Treats the input as a whole disk image: an MBR followed by a FAT32 volume.
+fuzz_target!(|data: &[u8]| {
+    let _ = BiosParameterBlock::from(Cursor::new(data.to_vec()), 0);
+
+    if let Ok(vfat) = VFat::from(Cursor::new(data.to_vec())) {
+        if let Ok(root) = vfat.open_dir("/") {
+            if let Ok(entries) = root.entries() {
+                for entry in entries {
+                    let _ = entry.name();
+                }
+            }
+        }
+    }
+});
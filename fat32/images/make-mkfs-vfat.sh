@@ -0,0 +1,31 @@
+#!/bin/sh
+# Builds the `mkfs-vfat` golden image fixture. Requires sfdisk, mkfs.vfat, and
+# mtools.
+set -eu
+
+cd "$(dirname "$0")"
+out=mkfs-vfat
+rm -rf "$out"
+mkdir -p "$out/tree/DCIM/100CANON" "$out/tree/Documents/Ünïcödé names"
+
+# The sample tree: 8.3 names, long names, non-ASCII names, and files spanning
+# several clusters.
+printf 'hello, world\n' > "$out/tree/README.TXT"
+printf 'long name\n' > "$out/tree/Documents/A file with a long name.txt"
+printf 'unicode\n' > "$out/tree/Documents/Ünïcödé names/日本語.txt"
+head -c 100000 /dev/urandom > "$out/tree/DCIM/100CANON/IMG_0001.JPG"
+: > "$out/tree/Documents/empty"
+
+export TZ=UTC
+find "$out/tree" -mindepth 1 -exec touch -d '2018-03-14 15:09:26' {} +
+
+# 64 MiB image with one FAT32 (LBA) partition starting at sector 2048.
+truncate -s 64M "$out/image.img"
+echo 'start=2048, type=c' | sfdisk -q "$out/image.img"
+part="$out/image.img@@1048576"
+mkfs.vfat -F 32 -n GOLDEN --offset 2048 "$out/image.img" > /dev/null
+MTOOLS_SKIP_CHECK=1 mcopy -s -m -i "$part" "$out/tree/"* ::/
+
+(cd "$out/tree" && find . -type f | sed 's|^\./||' | sort) | while read -r path; do
+    printf '%s\t2018-03-14 15:09:26\n' "$path"
+done > "$out/timestamps"
@@ -0,0 +1,208 @@
+//! `fat32`: inspect and modify FAT32 disk images.
+//!
+//! Paths inside the image are absolute. For `cp`, paths inside the image are
+//! prefixed with `::`, as in `fat32 cp card.img ::/DCIM photos`.
+
+extern crate fat32;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+
+use fat32::traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata};
+use fat32::vfat::{Dir, Entry, MountOptions, Shared, VFat};
+
+const USAGE: &str = "usage: fat32 <command> <image> [args]
+
+commands:
+    ls <image> [path]        list a directory
+    cat <image> <path>       write a file to stdout
+    stat <image> <path>      show an entry's metadata
+    tree <image> [path]      list a directory tree
+    cp <image> <src> <dst>   copy into or out of the image; prefix paths
+                             inside the image with `::`
+    df <image>               show space usage
+    fsck <image>             check the volume for consistency";
+
+fn usage_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+fn mount(image: &str, writable: bool) -> io::Result<Shared<VFat>> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(image)?;
+    let options = MountOptions {
+        read_only: !writable,
+        ..MountOptions::default()
+    };
+    VFat::from_with_options(file, &options).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", image, e))
+    })
+}
+
+fn entry_size(entry: &Entry) -> u64 {
+    match *entry {
+        Entry::File(ref file) => file.size as u64,
+        Entry::Dir(_) => 0,
+    }
+}
+
+fn flags(entry: &Entry) -> String {
+    let metadata = entry.metadata();
+    let d = if entry.is_dir() { 'd' } else { '-' };
+    let r = if metadata.read_only() { 'r' } else { '-' };
+    let h = if metadata.hidden() { 'h' } else { '-' };
+    format!("{}{}{}", d, r, h)
+}
+
+fn ls(vfat: &Shared<VFat>, path: &str) -> io::Result<()> {
+    for entry in vfat.open_dir(path)?.entries()? {
+        if entry.metadata().volume_id() {
+            continue;
+        }
+        println!(
+            "{} {:>10} {} {}",
+            flags(&entry),
+            entry_size(&entry),
+            entry.metadata().modified(),
+            entry.name()
+        );
+    }
+    Ok(())
+}
+
+fn cat(vfat: &Shared<VFat>, path: &str) -> io::Result<()> {
+    let mut file = vfat.open_file(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(&data)?;
+    stdout.flush()
+}
+
+fn stat(vfat: &Shared<VFat>, path: &str) -> io::Result<()> {
+    let entry = vfat.open(path)?;
+    let metadata = entry.metadata();
+    println!("name:      {}", entry.name());
+    println!("type:      {}", if entry.is_dir() { "directory" } else { "file" });
+    println!("size:      {}", entry_size(&entry));
+    println!("flags:     {}", metadata.attr);
+    println!("created:   {}", metadata.created());
+    println!("modified:  {}", metadata.modified());
+    println!("accessed:  {}", metadata.accessed());
+    if let Entry::File(ref file) = entry {
+        let extents = file.extents()?;
+        let clusters: u32 = extents.iter().map(|e| e.len).sum();
+        println!("clusters:  {} in {} extents", clusters, extents.len());
+    }
+    Ok(())
+}
+
+fn print_tree(dir: &Dir, path: &Path) -> io::Result<()> {
+    for entry in dir.entries()? {
+        let name = entry.name().to_string();
+        if name == "." || name == ".." || entry.metadata().volume_id() {
+            continue;
+        }
+
+        let path = path.join(&name);
+        match entry {
+            Entry::File(_) => println!("{}", path.display()),
+            Entry::Dir(ref dir) => {
+                println!("{}/", path.display());
+                print_tree(dir, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn tree(vfat: &Shared<VFat>, path: &str) -> io::Result<()> {
+    println!("{}", path);
+    print_tree(&vfat.open_dir(path)?, Path::new(path))
+}
+
+fn cp(image: &str, src: &str, dst: &str) -> io::Result<()> {
+    match (src.starts_with("::"), dst.starts_with("::")) {
+        (true, false) => mount(image, false)?.copy_to_host(&src[2..], dst),
+        (false, true) => {
+            let vfat = mount(image, true)?;
+            if fs::metadata(src)?.is_dir() {
+                vfat.copy_dir_from_host(src, &dst[2..])?;
+            } else {
+                vfat.copy_from_host(src, &dst[2..])?;
+            }
+            vfat.borrow_mut().sync()?;
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "exactly one of <src> and <dst> must be an image path starting with `::`",
+        )),
+    }
+}
+
+fn df(vfat: &Shared<VFat>) -> io::Result<()> {
+    let (cluster_size, clusters) = {
+        let vfat = vfat.borrow();
+        (vfat.cluster_size() as u64, vfat.data_clusters() as u64)
+    };
+    let free = vfat.fragmentation_report()?.free_clusters as u64;
+    println!("cluster size: {}", cluster_size);
+    println!("total:        {}", clusters * cluster_size);
+    println!("used:         {}", (clusters - free) * cluster_size);
+    println!("free:         {}", free * cluster_size);
+    Ok(())
+}
+
+fn fsck(vfat: &Shared<VFat>) -> io::Result<bool> {
+    let report = vfat.check()?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "{} files, {} directories, {} problems",
+        report.files,
+        report.dirs,
+        report.problems.len()
+    );
+    Ok(report.is_clean())
+}
+
+/// Runs the command in `args`, returning whether it succeeded.
+fn run(args: &[String]) -> io::Result<bool> {
+    if args.len() < 2 {
+        return Err(usage_error());
+    }
+
+    let (command, image) = (args[0].as_str(), args[1].as_str());
+    let path = args.get(2).map(String::as_str);
+    match (command, args.len()) {
+        ("ls", 2) | ("ls", 3) => ls(&mount(image, false)?, path.unwrap_or("/"))?,
+        ("cat", 3) => cat(&mount(image, false)?, path.unwrap())?,
+        ("stat", 3) => stat(&mount(image, false)?, path.unwrap())?,
+        ("tree", 2) | ("tree", 3) => tree(&mount(image, false)?, path.unwrap_or("/"))?,
+        ("cp", 4) => cp(image, &args[2], &args[3])?,
+        ("df", 2) => df(&mount(image, false)?)?,
+        ("fsck", 2) => return fsck(&mount(image, false)?),
+        _ => return Err(usage_error()),
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("fat32: {}", e);
+            process::exit(2);
+        }
+    }
+}
@@ -1,8 +1,33 @@
 #![feature(decl_macro, conservative_impl_trait)]
-#![allow(safe_packed_borrows)]
 
-#[cfg(not(target_endian = "little"))]
-compile_error!("only little endian platforms supported");
+#[cfg(feature = "crypto")]
+extern crate aes;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "digest")]
+extern crate digest;
+#[cfg(not(any(target_os = "ros", target_arch = "wasm32")))]
+extern crate filetime;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "fuse")]
+extern crate fuser;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+extern crate js_sys;
+#[cfg(feature = "fuse")]
+extern crate libc;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(not(feature = "tracing"))]
+#[macro_use]
+mod trace;
 
 mod mbr;
 #[cfg(test)]
@@ -11,5 +36,7 @@ mod util;
 
 pub mod traits;
 pub mod vfat;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub mod wasm;
 
 pub use mbr::*;
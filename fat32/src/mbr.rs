@@ -1,6 +1,7 @@
-use std::{fmt, io, mem};
+use std::{fmt, io, mem, slice};
 
 use traits::BlockDevice;
+use util::Le32;
 
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
@@ -17,8 +18,70 @@ pub struct PartitionEntry {
     chs_start: CHS,
     part_type: u8,
     chs_end: CHS,
-    relative_sector: u32,
-    total_sectors: u32,
+    relative_sector: Le32,
+    total_sectors: Le32,
+}
+
+impl CHS {
+    /// The largest cylinder a CHS address can store.
+    const MAX_CYLINDER: u16 = 1023;
+
+    /// Returns the CHS address of logical block `lba` on a disk with `heads`
+    /// heads and `sectors_per_track` sectors per track, commonly 255 and 63,
+    /// or `None` if a CHS address can't describe that geometry: `heads` must
+    /// be between 1 and 255 and `sectors_per_track` between 1 and 63. Blocks
+    /// beyond the largest addressable cylinder get the address 1023/254/63,
+    /// which marks them as only reachable by LBA.
+    pub fn from_lba(lba: u64, heads: u32, sectors_per_track: u32) -> Option<CHS> {
+        if heads == 0 || heads > 255 || sectors_per_track == 0 || sectors_per_track > 63 {
+            return None;
+        }
+        let track = lba / sectors_per_track as u64;
+        let cylinder = track / heads as u64;
+        if cylinder > CHS::MAX_CYLINDER as u64 {
+            return Some(CHS::new(CHS::MAX_CYLINDER, 254, 63));
+        }
+        let head = (track % heads as u64) as u8;
+        let sector = (lba % sectors_per_track as u64) as u8 + 1;
+        Some(CHS::new(cylinder as u16, head, sector))
+    }
+
+    /// Returns the CHS address of cylinder `cylinder`, head `head`, and
+    /// 1-based sector `sector`. The cylinder is truncated to 10 bits and the
+    /// sector to 6 bits.
+    pub fn new(cylinder: u16, head: u8, sector: u8) -> CHS {
+        CHS {
+            head,
+            sector: (sector & 0x3F) | ((cylinder >> 2) as u8 & 0xC0),
+            cylinder: cylinder as u8,
+        }
+    }
+
+    /// The cylinder, whose two high bits are stored in the sector byte.
+    pub fn cylinder(&self) -> u16 {
+        self.cylinder as u16 | (self.sector as u16 & 0xC0) << 2
+    }
+
+    pub fn head(&self) -> u8 {
+        self.head
+    }
+
+    /// The 1-based sector within the track.
+    pub fn sector(&self) -> u8 {
+        self.sector & 0x3F
+    }
+
+    /// Returns the logical block at this address on a disk with `heads`
+    /// heads and `sectors_per_track` sectors per track, or `None` if the
+    /// address isn't valid for that geometry.
+    pub fn to_lba(&self, heads: u32, sectors_per_track: u32) -> Option<u64> {
+        let sector = self.sector() as u32;
+        if sector == 0 || sector > sectors_per_track || self.head() as u32 >= heads {
+            return None;
+        }
+        let track = self.cylinder() as u64 * heads as u64 + self.head() as u64;
+        Some(track * sectors_per_track as u64 + sector as u64 - 1)
+    }
 }
 
 /// The master boot record (MBR).
@@ -32,6 +95,29 @@ pub struct MasterBootRecord {
 
 const MBR_SIZE: usize = mem::size_of::<MasterBootRecord>();
 
+/// The size of the bootstrap code region at the start of the MBR, which
+/// includes the disk signature.
+pub const BOOTSTRAP_SIZE: usize = 446;
+
+/// The size of the part of the bootstrap code region that precedes the disk
+/// signature.
+const BOOTSTRAP_CODE_SIZE: usize = 440;
+
+/// The CHS address stored for partitions that are addressed by LBA only.
+const CHS_LBA_ONLY: CHS = CHS {
+    head: 0xFE,
+    sector: 0xFF,
+    cylinder: 0xFF,
+};
+
+/// Builds a new MBR with a partition table, for provisioning a blank device.
+/// Created with `MasterBootRecord::builder()`.
+#[derive(Debug, Clone, Default)]
+pub struct MbrBuilder {
+    /// The start, length, type, and bootable flag of each partition.
+    partitions: Vec<(u64, u64, u8, bool)>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// There was an I/O error while reading the MBR.
@@ -43,6 +129,11 @@ pub enum Error {
 }
 
 impl MasterBootRecord {
+    /// Returns a builder for an MBR with an empty partition table.
+    pub fn builder() -> MbrBuilder {
+        MbrBuilder::default()
+    }
+
     /// Reads and returns the master boot record (MBR) from `device`.
     ///
     /// # Errors
@@ -83,21 +174,202 @@ impl MasterBootRecord {
 
     /// Returns sector of first FAT32 partition on disk
     pub fn first_fat32(&self) -> Result<&PartitionEntry, io::Error> {
-        for partition in self.partition_table.iter() {
-            if partition.part_type == 0xB || partition.part_type == 0xC {
-                return Ok(partition);
-            }
-        }
-        Err(io::Error::new(
+        self.fat32_partitions().next().ok_or(io::Error::new(
             io::ErrorKind::Other,
             "FAT32 partition not found",
         ))
     }
+
+    /// Writes the MBR to sector 0 of `device`.
+    ///
+    /// # Errors
+    ///
+    /// Returns any I/O error that occurs while writing to `device`.
+    pub fn write<T: BlockDevice>(&self, mut device: T) -> io::Result<()> {
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, MBR_SIZE) };
+        device.write_sector(0, bytes)?;
+        Ok(())
+    }
+
+    /// The 4-byte disk signature that operating systems use to identify the
+    /// disk, or 0 if none is set.
+    pub fn disk_signature(&self) -> u32 {
+        let id = &self.disk_id;
+        id[4] as u32 | (id[5] as u32) << 8 | (id[6] as u32) << 16 | (id[7] as u32) << 24
+    }
+
+    /// Sets the disk signature to `signature`. The MBR must be written back
+    /// with `write()` for the change to reach the device.
+    pub fn set_disk_signature(&mut self, signature: u32) {
+        for i in 0..4 {
+            self.disk_id[4 + i] = (signature >> (8 * i)) as u8;
+        }
+    }
+
+    /// Returns a copy of the `BOOTSTRAP_SIZE` bytes of bootstrap code that
+    /// precede the partition table. The last six bytes hold the disk
+    /// signature and two bytes that are usually zero.
+    pub fn bootstrap(&self) -> Vec<u8> {
+        let mut code = Vec::with_capacity(BOOTSTRAP_SIZE);
+        code.extend_from_slice(&self.bootstrap);
+        code.extend_from_slice(&self.disk_id);
+        code
+    }
+
+    /// Installs `code` at the start of the bootstrap code region, zeroing the
+    /// rest of the first 440 bytes. Code of up to 440 bytes leaves the disk
+    /// signature alone; longer code, up to `BOOTSTRAP_SIZE` bytes, overwrites
+    /// it as well. The partition table is never touched. The MBR must be
+    /// written back with `write()` for the change to reach the device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `code` is longer than
+    /// `BOOTSTRAP_SIZE` bytes.
+    pub fn set_bootstrap(&mut self, code: &[u8]) -> io::Result<()> {
+        if code.len() > BOOTSTRAP_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bootstrap code is too long",
+            ));
+        }
+
+        let mut region = self.bootstrap();
+        let len = ::std::cmp::max(code.len(), BOOTSTRAP_CODE_SIZE);
+        for byte in &mut region[..len] {
+            *byte = 0;
+        }
+        region[..code.len()].copy_from_slice(code);
+
+        let split = self.bootstrap.len();
+        self.bootstrap.copy_from_slice(&region[..split]);
+        self.disk_id.copy_from_slice(&region[split..]);
+        Ok(())
+    }
+
+    /// Returns `true` if the MBR is a protective or hybrid MBR of a disk
+    /// partitioned with a GUID partition table: if it holds a partition of
+    /// type 0xEE covering the GPT. A hybrid MBR also lists some of the GPT
+    /// partitions, which can be used as usual.
+    pub fn is_protective(&self) -> bool {
+        self.partition_table.iter().any(|partition| partition.part_type == 0xEE)
+    }
+
+    /// Returns the FAT32 partitions on disk, in partition table order.
+    pub fn fat32_partitions<'a>(&'a self) -> impl Iterator<Item = &'a PartitionEntry> + 'a {
+        self.partition_table
+            .iter()
+            .filter(|partition| partition.part_type == 0xB || partition.part_type == 0xC)
+    }
 }
 
 impl PartitionEntry {
     pub fn sector(&self) -> u64 {
-        self.relative_sector as u64
+        self.relative_sector.get() as u64
+    }
+
+    /// The number of sectors in the partition.
+    pub fn sectors(&self) -> u64 {
+        self.total_sectors.get() as u64
+    }
+
+    /// The partition type, like `0x0C` for FAT32 with LBA addressing.
+    pub fn part_type(&self) -> u8 {
+        self.part_type
+    }
+
+    /// Returns `true` if the partition is marked as bootable.
+    pub fn is_bootable(&self) -> bool {
+        self.boot == 0x80
+    }
+
+    /// The CHS address of the first sector of the partition.
+    pub fn chs_start(&self) -> CHS {
+        self.chs_start
+    }
+
+    /// The CHS address of the last sector of the partition.
+    pub fn chs_end(&self) -> CHS {
+        self.chs_end
+    }
+}
+
+impl MbrBuilder {
+    /// Adds a partition of type `part_type`, like `0x0C` for FAT32 with LBA
+    /// addressing, spanning the `sectors` sectors starting at sector `start`.
+    /// Partitions are stored in the order they are added.
+    pub fn partition(
+        mut self,
+        start: u64,
+        sectors: u64,
+        part_type: u8,
+        bootable: bool,
+    ) -> MbrBuilder {
+        self.partitions.push((start, sectors, part_type, bootable));
+        self
+    }
+
+    /// Returns the MBR holding the partitions added so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if more than four partitions were
+    /// added, or if a partition is empty, starts at sector 0 where the MBR
+    /// is, extends past sector 2^32, or overlaps another partition.
+    pub fn build(self) -> io::Result<MasterBootRecord> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.partitions.len() > 4 {
+            return invalid("an MBR holds at most four partitions");
+        }
+        for (i, &(start, sectors, _, _)) in self.partitions.iter().enumerate() {
+            if start == 0 || sectors == 0 || start.saturating_add(sectors) > 1 << 32 {
+                return invalid("invalid partition bounds");
+            }
+            for &(other_start, other_sectors, _, _) in &self.partitions[..i] {
+                if start < other_start + other_sectors && other_start < start + sectors {
+                    return invalid("partitions overlap");
+                }
+            }
+        }
+
+        let empty = PartitionEntry {
+            boot: 0,
+            chs_start: CHS_LBA_ONLY,
+            part_type: 0,
+            chs_end: CHS_LBA_ONLY,
+            relative_sector: Le32::new(0),
+            total_sectors: Le32::new(0),
+        };
+        let mut partition_table = [empty.clone(), empty.clone(), empty.clone(), empty];
+        for (slot, (start, sectors, part_type, bootable)) in
+            partition_table.iter_mut().zip(self.partitions)
+        {
+            *slot = PartitionEntry {
+                boot: if bootable { 0x80 } else { 0x00 },
+                chs_start: CHS_LBA_ONLY,
+                part_type,
+                chs_end: CHS_LBA_ONLY,
+                relative_sector: Le32::new(start as u32),
+                total_sectors: Le32::new(sectors as u32),
+            };
+        }
+        Ok(MasterBootRecord {
+            bootstrap: [0; 436],
+            disk_id: [0; 10],
+            partition_table,
+            signature: [0x55, 0xAA],
+        })
+    }
+
+    /// Builds the MBR and writes it to sector 0 of `device`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `build()` and `MasterBootRecord::write()`.
+    pub fn write<T: BlockDevice>(self, device: T) -> io::Result<MasterBootRecord> {
+        let mbr = self.build()?;
+        mbr.write(device)?;
+        Ok(mbr)
     }
 }
 
@@ -127,4 +399,95 @@ mod tests {
             _ => assert!(false, "unexpected error"),
         }
     }
+
+    #[test]
+    fn check_chs() {
+        // Sector byte 0xC1: sector 1, cylinder bits 8 and 9 set.
+        let chs = CHS {
+            head: 7,
+            sector: 0xC1,
+            cylinder: 0x02,
+        };
+        assert_eq!((chs.cylinder(), chs.head(), chs.sector()), (0x302, 7, 1));
+        assert_eq!(chs.to_lba(255, 63), Some((0x302 * 255 + 7) * 63));
+
+        for &lba in &[0, 62, 63, 2048, 16_450_559] {
+            let chs = CHS::from_lba(lba, 255, 63).unwrap();
+            assert_eq!(chs.to_lba(255, 63), Some(lba));
+        }
+        let chs = CHS::from_lba(16_450_560, 255, 63).unwrap();
+        assert_eq!((chs.cylinder(), chs.head(), chs.sector()), (1023, 254, 63));
+        assert!(CHS::from_lba(2048, 0, 63).is_none());
+        assert!(CHS::from_lba(2048, 255, 0).is_none());
+        assert!(CHS::from_lba(2048, 256, 63).is_none());
+        assert!(CHS::from_lba(2048, 16, 64).is_none());
+
+        assert_eq!(CHS::new(0, 0, 0).to_lba(255, 63), None);
+        assert_eq!(CHS::new(0, 16, 1).to_lba(16, 63), None);
+    }
+
+    #[test]
+    fn check_bootstrap_and_disk_signature() {
+        let mut mbr = MasterBootRecord::builder().build().unwrap();
+        assert_eq!(mbr.disk_signature(), 0);
+        mbr.set_disk_signature(0xDEADBEEF);
+        assert_eq!(mbr.disk_signature(), 0xDEADBEEF);
+        assert_eq!(&mbr.bootstrap()[440..446], &[0xEF, 0xBE, 0xAD, 0xDE, 0, 0]);
+
+        // Short code is zero padded and keeps the signature.
+        mbr.set_bootstrap(&[0xAA; 440]).unwrap();
+        mbr.set_bootstrap(&[0xEB, 0xFE]).unwrap();
+        let code = mbr.bootstrap();
+        assert_eq!(code.len(), BOOTSTRAP_SIZE);
+        assert_eq!(&code[..2], &[0xEB, 0xFE]);
+        assert!(code[2..440].iter().all(|&b| b == 0));
+        assert_eq!(mbr.disk_signature(), 0xDEADBEEF);
+
+        // Code filling the whole region replaces the signature.
+        mbr.set_bootstrap(&[0x90; BOOTSTRAP_SIZE]).unwrap();
+        assert_eq!(mbr.disk_signature(), 0x90909090);
+        let err = mbr.set_bootstrap(&[0; BOOTSTRAP_SIZE + 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut disk = vec![0u8; 512];
+        mbr.set_disk_signature(0x12345678);
+        mbr.write(Cursor::new(&mut disk[..])).unwrap();
+        assert_eq!(&disk[440..444], &[0x78, 0x56, 0x34, 0x12]);
+        let read = MasterBootRecord::from(Cursor::new(&mut disk[..])).unwrap();
+        assert_eq!(read.disk_signature(), 0x12345678);
+        assert_eq!(read.bootstrap(), mbr.bootstrap());
+    }
+
+    #[test]
+    fn check_mbr_builder() {
+        let mut data = vec![0u8; 512];
+        MasterBootRecord::builder()
+            .partition(2048, 1 << 20, 0x0C, true)
+            .partition(1 << 21, 4096, 0x83, false)
+            .write(Cursor::new(&mut data[..]))
+            .expect("write MBR");
+
+        let mbr = MasterBootRecord::from(Cursor::new(&mut data[..])).expect("read MBR");
+        let partitions: Vec<(u64, u64)> = mbr
+            .fat32_partitions()
+            .map(|p| (p.sector(), p.sectors()))
+            .collect();
+        assert_eq!(partitions, [(2048, 1 << 20)]);
+        assert_eq!(data[446], 0x80);
+        assert_eq!(data[446 + 16], 0x00);
+        assert_eq!(data[446 + 16 + 4], 0x83);
+        assert!(data[446 + 32..510].iter().all(|&b| b == 0 || b == 0xFE || b == 0xFF));
+
+        let invalid = |builder: MbrBuilder| {
+            builder.build().unwrap_err().kind() == io::ErrorKind::InvalidInput
+        };
+        let builder = MasterBootRecord::builder().partition(100, 100, 0x0C, false);
+        assert!(invalid(builder.clone().partition(0, 10, 0x0C, false)));
+        assert!(invalid(builder.clone().partition(1, 0, 0x0C, false)));
+        assert!(invalid(builder.clone().partition(1 << 32, 1, 0x0C, false)));
+        assert!(invalid(builder.clone().partition(199, 10, 0x0C, false)));
+        let five = (0..4).fold(builder.clone(), |b, i| b.partition(200 + i * 10, 10, 0x0C, false));
+        assert!(invalid(five));
+        assert!(builder.partition(200, 10, 0x0C, false).build().is_ok());
+    }
 }
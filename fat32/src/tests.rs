@@ -1,4 +1,6 @@
 extern crate rand;
+#[cfg(feature = "digest")]
+extern crate sha2;
 
 use std::io::prelude::*;
 use std::io::Cursor;
@@ -6,7 +8,8 @@ use std::path::Path;
 
 use mbr::{MasterBootRecord, PartitionEntry, CHS};
 use traits::*;
-use vfat::{BiosParameterBlock, Shared, VFat};
+use vfat::{format, BiosParameterBlock, CancelToken, Cluster, Extent, FormatOptions, Problem};
+use vfat::{CacheBudget, Shared, VFat};
 
 macro check_size($T:ty, $size:expr) {
     assert_eq!(
@@ -66,6 +69,113 @@ macro vfat_from_resource($name:expr) {
     VFat::from(resource!($name)).expect("failed to initialize VFAT from image")
 }
 
+/// Start sector of the partition in images created by `formatted_image()`.
+const IMAGE_PARTITION_START: u64 = 64;
+
+/// Returns an in-memory disk of `sectors` sectors with an MBR holding a single
+/// FAT32 partition formatted with `options`.
+fn formatted_image(sectors: u64, options: &FormatOptions) -> Cursor<Vec<u8>> {
+    fn put_u32(buf: &mut [u8], val: u32) {
+        for i in 0..4 {
+            buf[i] = (val >> (i * 8)) as u8;
+        }
+    }
+
+    let mut data = vec![0u8; sectors as usize * 512];
+    let part_sectors = sectors - IMAGE_PARTITION_START;
+    data[446 + 4] = 0x0C;
+    put_u32(&mut data[446 + 8..], IMAGE_PARTITION_START as u32);
+    put_u32(&mut data[446 + 12..], part_sectors as u32);
+    data[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut image = Cursor::new(data);
+    format(&mut image, IMAGE_PARTITION_START, part_sectors, options).expect("format image");
+    image
+}
+
+/// A formatted in-memory image that can be modified byte-wise to build test
+/// fixtures before it is mounted.
+struct RawImage {
+    data: Vec<u8>,
+    fats: Vec<usize>,
+    data_start: usize,
+    cluster_size: usize,
+}
+
+impl RawImage {
+    fn new(sectors: u64, options: &FormatOptions) -> RawImage {
+        let mut data = formatted_image(sectors, options).into_inner();
+        let (fats, data_start, cluster_size) = {
+            let ebpb = BiosParameterBlock::from(Cursor::new(&mut data[..]), IMAGE_PARTITION_START)
+                .expect("valid EBPB");
+            let bytes_per_sector = ebpb.bytes_per_sector() as usize;
+            let fat_size = ebpb.sectors_per_fat() as usize * bytes_per_sector;
+            let fat_start = (IMAGE_PARTITION_START as usize + ebpb.sectors_reserved() as usize)
+                * bytes_per_sector;
+            let fats: Vec<usize> = (0..ebpb.fats_number() as usize)
+                .map(|i| fat_start + i * fat_size)
+                .collect();
+            let data_start = fat_start + fats.len() * fat_size;
+            (fats, data_start, ebpb.sectors_per_cluster() as usize * bytes_per_sector)
+        };
+
+        RawImage {
+            data,
+            fats,
+            data_start,
+            cluster_size,
+        }
+    }
+
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        for &fat in &self.fats {
+            let offset = fat + cluster as usize * 4;
+            for i in 0..4 {
+                self.data[offset + i] = (value >> (i * 8)) as u8;
+            }
+        }
+    }
+
+    fn cluster_mut(&mut self, cluster: u32) -> &mut [u8] {
+        let start = self.data_start + (cluster as usize - 2) * self.cluster_size;
+        &mut self.data[start..start + self.cluster_size]
+    }
+
+    /// Writes a regular 8.3 directory entry at `index` of the directory whose
+    /// (single) cluster is `dir`.
+    fn set_entry(&mut self, dir: u32, index: usize, name: &[u8; 11], attr: u8, cluster: u32, size: u32) {
+        let entry = &mut self.cluster_mut(dir)[index * 32..(index + 1) * 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attr;
+        entry[20] = (cluster >> 16) as u8;
+        entry[21] = (cluster >> 24) as u8;
+        entry[26] = cluster as u8;
+        entry[27] = (cluster >> 8) as u8;
+        for i in 0..4 {
+            entry[28 + i] = (size >> (i * 8)) as u8;
+        }
+    }
+
+    /// Creates a file named `name` in the root directory entry `index` whose
+    /// data occupies `clusters`, in order. Cluster `n` of the file is filled
+    /// with the byte `n + 1`.
+    fn add_file(&mut self, index: usize, name: &[u8; 11], clusters: &[u32]) {
+        for (n, &cluster) in clusters.iter().enumerate() {
+            let next = clusters.get(n + 1).cloned().unwrap_or(0x0FFF_FFFF);
+            self.set_fat(cluster, next);
+            for byte in self.cluster_mut(cluster).iter_mut() {
+                *byte = n as u8 + 1;
+            }
+        }
+        let size = (clusters.len() * self.cluster_size) as u32;
+        self.set_entry(2, index, name, 0x20, clusters[0], size);
+    }
+
+    fn mount(self) -> Shared<VFat> {
+        VFat::from(Cursor::new(self.data)).expect("mount raw image")
+    }
+}
+
 #[test]
 fn check_mbr_size() {
     check_size!(MasterBootRecord, 512);
@@ -156,6 +266,4326 @@ fn test_vfat_init() {
     vfat_from_resource!("mock4.fat32.img");
 }
 
+#[test]
+fn test_format_layout() {
+    let options = FormatOptions {
+        sectors_per_cluster: 4,
+        ..FormatOptions::default()
+    };
+    let image = formatted_image(8192, &options);
+    let mut data = image.into_inner();
+
+    let ebpb = BiosParameterBlock::from(Cursor::new(&mut data[..]), IMAGE_PARTITION_START)
+        .expect("valid EBPB");
+    assert_eq!(ebpb.bytes_per_sector(), 512);
+    assert_eq!(ebpb.sectors_per_cluster(), 4);
+    assert_eq!(ebpb.fats_number(), 2);
+    assert_eq!(ebpb.root_dir_cluster(), 2);
+
+    let backup = BiosParameterBlock::from(Cursor::new(&mut data[..]), IMAGE_PARTITION_START + 6)
+        .expect("valid backup EBPB");
+    assert_eq!(backup.sectors_per_fat(), ebpb.sectors_per_fat());
+}
+
+#[test]
+fn test_format_rejects_bad_options() {
+    let mut data = vec![0u8; 4096 * 512];
+    let bad_cluster = FormatOptions {
+        sectors_per_cluster: 3,
+        ..FormatOptions::default()
+    };
+    let e = format(Cursor::new(&mut data[..]), 0, 4096, &bad_cluster).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let bad_label = FormatOptions {
+        volume_label: Some("A LABEL TOO LONG".to_string()),
+        ..FormatOptions::default()
+    };
+    let e = format(Cursor::new(&mut data[..]), 0, 4096, &bad_label).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let e = format(Cursor::new(&mut data[..]), 0, 16, &FormatOptions::default()).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    let big_clusters = FormatOptions {
+        sectors_per_cluster: 128,
+        ..FormatOptions::default()
+    };
+    let sectors = u32::max_value() as u64 + 1;
+    let e = format(Cursor::new(&mut data[..]), 0, sectors, &big_clusters).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_format_mounts_empty() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let entries: Vec<_> = vfat.open_dir("/").expect("root").entries().expect("entries").collect();
+    assert!(entries.is_empty());
+
+    let options = FormatOptions {
+        volume_label: Some("boot".to_string()),
+        ..FormatOptions::default()
+    };
+    let vfat = VFat::from(formatted_image(8192, &options)).expect("mount");
+    let entries: Vec<_> = vfat.open_dir("/").expect("root").entries().expect("entries").collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name(), "BOOT");
+    assert!(entries[0].metadata().volume_id());
+}
+
+fn read_all<T: File>(mut file: T) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+        let mut buf = [0u8; 512];
+        match file.read(&mut buf).expect("read file") {
+            0 => return data,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn fragmented_image() -> Shared<VFat> {
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    image.add_file(0, b"A       BIN", &[3, 5, 7]);
+    image.add_file(1, b"B       BIN", &[4]);
+    image.add_file(2, b"C       BIN", &[6, 8]);
+    image.mount()
+}
+
+/// A device backed by a shared image that fails every write after the first
+/// `writes_left`, as if the machine had crashed.
+struct CrashDevice {
+    image: Shared<Vec<u8>>,
+    writes_left: usize,
+}
+
+impl CrashDevice {
+    fn new(image: &Shared<Vec<u8>>, writes_left: usize) -> CrashDevice {
+        CrashDevice {
+            image: image.clone(),
+            writes_left,
+        }
+    }
+}
+
+impl BlockDevice for CrashDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let start = n as usize * 512;
+        let len = ::std::cmp::min(buf.len(), 512);
+        buf[..len].copy_from_slice(&self.image.borrow()[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        if self.writes_left == 0 {
+            return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "crashed"));
+        }
+        self.writes_left -= 1;
+
+        let start = n as usize * 512;
+        let len = ::std::cmp::min(buf.len(), 512);
+        self.image.borrow_mut()[start..start + len].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// A file or directory in a tree synthesized by `TreeGenerator`.
+enum Node {
+    File(Vec<u8>),
+    Dir(Vec<(String, Node)>),
+}
+
+/// Synthesizes images holding random directory trees by writing the on-disk
+/// structures directly, independently of the driver's write path. Geometry,
+/// names (8.3 and long, including non-ASCII), and file sizes are random, and
+/// clusters are handed out in random order so that most chains are
+/// fragmented.
+struct TreeGenerator {
+    rng: rand::StdRng,
+    image: RawImage,
+    free: Vec<u32>,
+    short_names: usize,
+}
+
+impl TreeGenerator {
+    fn new(seed: usize) -> TreeGenerator {
+        use tests::rand::{Rng, SeedableRng};
+
+        let mut rng = rand::StdRng::from_seed(&[seed][..]);
+        let options = FormatOptions {
+            sectors_per_cluster: *rng.choose(&[1, 2, 4, 8]).unwrap(),
+            ..FormatOptions::default()
+        };
+        let image = RawImage::new(rng.gen_range(8192, 20480), &options);
+
+        let data_clusters = (image.data.len() - image.data_start) / image.cluster_size;
+        let mut free: Vec<u32> = (3..data_clusters as u32 + 2).collect();
+        rng.shuffle(&mut free);
+
+        TreeGenerator {
+            rng,
+            image,
+            free,
+            short_names: 0,
+        }
+    }
+
+    /// Generates a random tree, writes it to the image, and returns the tree
+    /// and the mounted image.
+    fn generate(mut self) -> (Vec<(String, Node)>, Shared<VFat>) {
+        let tree = self.tree(0);
+        self.write_dir(2, 0, &tree);
+        (tree, self.image.mount())
+    }
+
+    fn tree(&mut self, depth: usize) -> Vec<(String, Node)> {
+        use tests::rand::Rng;
+
+        let mut children: Vec<(String, Node)> = Vec::new();
+        for _ in 0..self.rng.gen_range(0, 10) {
+            let name = loop {
+                let name = self.name();
+                let upper = name.to_uppercase();
+                if !children.iter().any(|&(ref n, _)| n.to_uppercase() == upper) {
+                    break name;
+                }
+            };
+
+            let node = if depth < 3 && self.rng.gen_weighted_bool(4) {
+                Node::Dir(self.tree(depth + 1))
+            } else {
+                let cluster_size = self.image.cluster_size;
+                let len = match self.rng.gen_range(0, 4) {
+                    0 => 0,
+                    1 => cluster_size * self.rng.gen_range(1, 4),
+                    _ => self.rng.gen_range(1, 3 * cluster_size),
+                };
+                let mut data = vec![0u8; len];
+                self.rng.fill_bytes(&mut data);
+                Node::File(data)
+            };
+            children.push((name, node));
+        }
+        children
+    }
+
+    /// Returns a random 8.3 name or a random long name. Long names always
+    /// contain a lowercase letter, so they are never valid 8.3 names.
+    fn name(&mut self) -> String {
+        use tests::rand::Rng;
+
+        const SHORT: &[char] = &['A', 'Q', 'Z', '0', '7', '_', '-'];
+        const LONG: &[char] = &['a', 'b', 'X', '3', ' ', '.', '-', '+', 'é', 'Ж', '日', '😀'];
+
+        if self.rng.gen() {
+            let base_len = self.rng.gen_range(1, 9);
+            let mut name: String = (0..base_len).map(|_| *self.rng.choose(SHORT).unwrap()).collect();
+            let ext_len = self.rng.gen_range(0, 4);
+            if ext_len > 0 {
+                name.push('.');
+                name.extend((0..ext_len).map(|_| *self.rng.choose(SHORT).unwrap()));
+            }
+            name
+        } else {
+            let len = self.rng.gen_range(0, 40);
+            let mut name = String::from("x");
+            name.extend((0..len).map(|_| *self.rng.choose(LONG).unwrap()));
+            name.push('y');
+            name
+        }
+    }
+
+    fn alloc(&mut self) -> u32 {
+        self.free.pop().expect("generated tree fits in the image")
+    }
+
+    /// Writes `data` to a chain of clusters starting at `first`, allocating
+    /// as many further clusters as needed. At least one cluster is written.
+    fn write_chain(&mut self, first: u32, data: &[u8]) {
+        let cluster_size = self.image.cluster_size;
+        let count = ::std::cmp::max(1, (data.len() + cluster_size - 1) / cluster_size);
+        let mut chain = vec![first];
+        for _ in 1..count {
+            let cluster = self.alloc();
+            chain.push(cluster);
+        }
+
+        for (i, &cluster) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).cloned().unwrap_or(0x0FFF_FFFF);
+            self.image.set_fat(cluster, next);
+
+            let start = ::std::cmp::min(i * cluster_size, data.len());
+            let end = ::std::cmp::min(start + cluster_size, data.len());
+            let buf = self.image.cluster_mut(cluster);
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            buf[..end - start].copy_from_slice(&data[start..end]);
+        }
+    }
+
+    /// Writes the directory with first cluster `cluster` holding `children`,
+    /// and everything below it. `parent` is the first cluster of the parent
+    /// directory; `cluster` 2 is the root directory.
+    fn write_dir(&mut self, cluster: u32, parent: u32, children: &[(String, Node)]) {
+        let mut data = Vec::new();
+        if cluster != 2 {
+            data.extend_from_slice(&raw_entry(b".          ", 0x10, cluster, 0));
+            data.extend_from_slice(&raw_entry(b"..         ", 0x10, parent, 0));
+        }
+
+        for &(ref name, ref node) in children {
+            let (attr, first, size) = match *node {
+                Node::File(ref bytes) if bytes.is_empty() => (0x20, 0, 0),
+                Node::File(ref bytes) => {
+                    let first = self.alloc();
+                    self.write_chain(first, bytes);
+                    (0x20, first, bytes.len() as u32)
+                }
+                Node::Dir(ref grandchildren) => {
+                    let first = self.alloc();
+                    self.write_dir(first, if cluster == 2 { 0 } else { cluster }, grandchildren);
+                    (0x10, first, 0)
+                }
+            };
+            let entries = self.named_entries(name, attr, first, size);
+            data.extend_from_slice(&entries);
+        }
+
+        self.write_chain(cluster, &data);
+    }
+
+    /// Returns the LFN entries, if `name` needs any, and the regular entry
+    /// for an entry named `name`.
+    fn named_entries(&mut self, name: &str, attr: u8, cluster: u32, size: u32) -> Vec<u8> {
+        let is_short = name.chars().all(|c| c.is_ascii() && !c.is_ascii_lowercase());
+        let mut short_name = [b' '; 11];
+        if is_short {
+            let mut parts = name.split('.');
+            let base = parts.next().unwrap().as_bytes();
+            let ext = parts.next().unwrap_or("").as_bytes();
+            short_name[..base.len()].copy_from_slice(base);
+            short_name[8..8 + ext.len()].copy_from_slice(ext);
+            return raw_entry(&short_name, attr, cluster, size).to_vec();
+        }
+
+        self.short_names += 1;
+        short_name[..8].copy_from_slice(format!("GEN{:05}", self.short_names).as_bytes());
+        let checksum = short_name
+            .iter()
+            .fold(0u8, |sum, &c| (sum >> 1 | sum << 7).wrapping_add(c));
+
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() % 13 != 0 {
+            units.push(0);
+        }
+        while units.len() % 13 != 0 {
+            units.push(0xFFFF);
+        }
+
+        let count = units.len() / 13;
+        let mut entries = Vec::new();
+        for seq in (1..count + 1).rev() {
+            let chunk = &units[(seq - 1) * 13..seq * 13];
+            let mut entry = [0u8; 32];
+            entry[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+            entry[11] = 0x0F;
+            entry[13] = checksum;
+            let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+            for (&offset, &unit) in offsets.iter().zip(chunk) {
+                entry[offset] = unit as u8;
+                entry[offset + 1] = (unit >> 8) as u8;
+            }
+            entries.extend_from_slice(&entry);
+        }
+        entries.extend_from_slice(&raw_entry(&short_name, attr, cluster, size));
+        entries
+    }
+}
+
+/// Returns a regular directory entry.
+fn raw_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[20] = (cluster >> 16) as u8;
+    entry[21] = (cluster >> 24) as u8;
+    entry[26] = cluster as u8;
+    entry[27] = (cluster >> 8) as u8;
+    for i in 0..4 {
+        entry[28 + i] = (size >> (i * 8)) as u8;
+    }
+    entry
+}
+
+/// Checks that the directory at `path` holds exactly `children`, in order.
+fn verify_tree(vfat: &Shared<VFat>, path: &Path, children: &[(String, Node)]) {
+    let entries: Vec<::vfat::Entry> = vfat
+        .open_dir(path)
+        .expect("open generated dir")
+        .entries()
+        .expect("read generated dir")
+        .filter(|e| e.name() != "." && e.name() != "..")
+        .collect();
+    let names: Vec<&str> = entries.iter().map(|e| e.name()).collect();
+    let expected: Vec<&str> = children.iter().map(|&(ref name, _)| name.as_str()).collect();
+    assert_eq!(names, expected, "listing of {}", path.display());
+
+    for (entry, &(ref name, ref node)) in entries.iter().zip(children) {
+        let child = path.join(name);
+        match (entry.is_dir(), node) {
+            (false, &Node::File(ref data)) => {
+                let file = vfat.open_file(&child).expect("open generated file");
+                assert!(read_all(file) == *data, "contents of {}", child.display());
+            }
+            (true, &Node::Dir(ref grandchildren)) => verify_tree(vfat, &child, grandchildren),
+            _ => panic!("{} has the wrong type", child.display()),
+        }
+    }
+}
+
+#[test]
+fn test_file_extents() {
+    let vfat = fragmented_image();
+
+    let extents = vfat.open_file("/A.BIN").expect("open").extents().expect("extents");
+    let runs: Vec<_> = extents.iter().map(|e| (e.start.fat_index(), e.len)).collect();
+    assert_eq!(runs, vec![(3, 1), (5, 1), (7, 1)]);
+
+    let extents = vfat.open_file("/B.BIN").expect("open").extents().expect("extents");
+    let runs: Vec<_> = extents.iter().map(|e| (e.start.fat_index(), e.len)).collect();
+    assert_eq!(runs, vec![(4, 1)]);
+}
+
+#[test]
+fn test_fragmentation_report() {
+    let vfat = fragmented_image();
+    let report = vfat.fragmentation_report().expect("report");
+    assert_eq!(report.files, 3);
+    assert_eq!(report.fragmented_files, 2);
+    assert_eq!(report.file_extents, 6);
+    assert_eq!(report.file_clusters, 6);
+    assert_eq!(report.free_extents, 1);
+    assert_eq!(report.free_clusters, report.largest_free_extent);
+
+    vfat.defragment(false).expect("defragment");
+    let report = vfat.fragmentation_report().expect("report");
+    assert_eq!(report.fragmented_files, 0);
+    assert_eq!(report.file_extents, 3);
+}
+
+#[test]
+fn test_du() {
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    let cluster_size = image.cluster_size as u64;
+    image.add_file(0, b"A       BIN", &[3, 5, 7]);
+    image.add_file(1, b"B       BIN", &[4]);
+    image.set_fat(9, 0x0FFF_FFFF);
+    image.set_entry(2, 2, b"SUB        ", 0x10, 9, 0);
+    image.set_fat(10, 0x0FFF_FFFF);
+    image.set_entry(9, 0, b"D       TXT", 0x20, 10, 100);
+    let vfat = image.mount();
+
+    let usage = vfat.du("/SUB").expect("du");
+    assert_eq!(usage.files, 1);
+    assert_eq!(usage.dirs, 1);
+    assert_eq!(usage.bytes, 100);
+    assert_eq!(usage.allocated, 2 * cluster_size);
+
+    let usage = vfat.du("/").expect("du");
+    assert_eq!(usage.files, 3);
+    assert_eq!(usage.dirs, 2);
+    assert_eq!(usage.bytes, 4 * cluster_size + 100);
+    assert_eq!(usage.allocated, 7 * cluster_size);
+
+    let usage = vfat.du("/SUB/D.TXT").expect("du");
+    assert_eq!((usage.files, usage.dirs, usage.bytes), (1, 0, 100));
+    assert_eq!(usage.allocated, cluster_size);
+}
+
+#[test]
+fn test_export_tar() {
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    image.add_file(0, b"A       BIN", &[3, 5]);
+    image.set_fat(9, 0x0FFF_FFFF);
+    image.set_entry(2, 1, b"SUB        ", 0x11, 9, 0);
+    image.set_fat(10, 0x0FFF_FFFF);
+    image.set_entry(9, 0, b"D       TXT", 0x20, 10, 100);
+    image.cluster_mut(10)[..100].copy_from_slice(&[7u8; 100]);
+    let vfat = image.mount();
+
+    let mut tar = Vec::new();
+    vfat.export_tar("/", &mut tar).expect("export");
+    assert_eq!(tar.len() % 512, 0);
+    assert!(tar[tar.len() - 1024..].iter().all(|&b| b == 0));
+
+    fn octal(field: &[u8]) -> u64 {
+        let s = ::std::str::from_utf8(field).unwrap().trim_matches('\0');
+        u64::from_str_radix(s, 8).unwrap()
+    }
+
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    while tar[offset] != 0 {
+        let block = &tar[offset..offset + 512];
+        let name_len = block[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let name = String::from_utf8(block[..name_len].to_vec()).unwrap();
+        let size = octal(&block[124..135]) as usize;
+        let checksum = octal(&block[148..154]);
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 })
+            .sum();
+        assert_eq!(checksum, sum, "checksum of {}", name);
+
+        let data = tar[offset + 512..offset + 512 + size].to_vec();
+        entries.push((name, block[156], octal(&block[100..107]), data));
+        offset += 512 + (size + 511) / 512 * 512;
+    }
+
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].0, "A.BIN");
+    assert_eq!(entries[0].1, b'0');
+    assert_eq!(entries[0].3, read_all(vfat.open_file("/A.BIN").expect("open")));
+    assert_eq!(entries[1].0, "SUB/");
+    assert_eq!(entries[1].1, b'5');
+    assert_eq!(entries[1].2, 0o555);
+    assert_eq!(entries[2].0, "SUB/D.TXT");
+    assert_eq!(entries[2].3, vec![7u8; 100]);
+
+    // The archive may be written to a file on the volume being exported.
+    let out = vfat.create_file("/OUT.TAR").expect("create");
+    vfat.export_tar("/SUB", out).expect("export");
+    let archive = read_all(vfat.open_file("/OUT.TAR").expect("open"));
+    assert_eq!(archive.len(), 4 * 512);
+    assert_eq!(&archive[512..612], &[7u8; 100][..]);
+}
+
+#[test]
+fn test_create_and_write_file() {
+    use std::io::Write;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+
+    let mut file = vfat.create_file("/DATA.BIN").expect("create");
+    file.write_all(&data[..100]).expect("write");
+    file.write_all(&data[100..]).expect("write");
+    assert_eq!(file.size(), data.len() as u64);
+
+    let file = vfat.open_file("/data.bin").expect("open");
+    assert_eq!(file.size(), data.len() as u64);
+    assert_eq!(read_all(file), data);
+
+    let e = vfat.create_file("/DATA.BIN").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+    let e = vfat.create_file("/missing/file").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    let e = vfat.create_file("/DATA.BIN/file").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = vfat.create_file("relative").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_attributes() {
+    use traits::Metadata;
+    use vfat::Attributes;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/NEW.TXT").expect("create");
+    vfat.create_dir("/NEWDIR", false).expect("create");
+
+    let file = vfat.open("/NEW.TXT").expect("open");
+    assert!(file.metadata().archive());
+    assert!(!file.metadata().system() && !file.metadata().volume_id());
+    assert_eq!(file.metadata().attr.to_string(), "-----A");
+
+    let dir = vfat.open("/NEWDIR").expect("open");
+    assert!(!dir.metadata().archive());
+    assert_eq!(dir.metadata().attr.to_string(), "----D-");
+
+    let all = Attributes(0x3F);
+    assert!(all.read_only() && all.hidden() && all.system());
+    assert!(all.volume_id() && all.directory() && all.archive());
+    assert_eq!(all.to_string(), "RHSVDA");
+    assert_eq!(format!("{:?}", Attributes(0x05)), "Attributes(0x05 R-S---)");
+}
+
+#[test]
+fn test_entry_info() {
+    use std::io::Write;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/Info File.txt").and_then(|mut f| f.write_all(b"hello")).expect("write");
+    vfat.create_dir("/SUB", false).expect("create");
+
+    let list = vfat.open_dir("/").expect("open").list().expect("list");
+    let file = list.iter().find(|e| e.name == "Info File.txt").expect("file");
+    assert!(!file.is_dir);
+    assert_eq!(file.size, 5);
+    assert!(file.cluster >= 2);
+    assert!(file.metadata.attr.archive());
+
+    let dir = list.iter().find(|e| e.name == "SUB").expect("dir");
+    assert!(dir.is_dir);
+    assert_eq!(dir.size, 0);
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_hash_file() {
+    use tests::sha2::{Digest, Sha256};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/IMAGE.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.create_file("/EMPTY").expect("create");
+
+    let mut hasher = Sha256::default();
+    assert_eq!(vfat.hash_file("/IMAGE.BIN", &mut hasher).expect("hash"), 10_000);
+    assert_eq!(hasher.result(), Sha256::digest(&contents));
+
+    let mut hasher = Sha256::default();
+    assert_eq!(vfat.hash_file("/EMPTY", &mut hasher).expect("hash"), 0);
+    assert_eq!(hasher.result(), Sha256::digest(&[]));
+    assert!(vfat.hash_file("/", &mut Sha256::default()).is_err());
+}
+
+#[cfg(feature = "digest")]
+#[test]
+fn test_manifest() {
+    use tests::sha2::{Digest, Sha256};
+    use vfat::ManifestProblem;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/BOOT", false).expect("create");
+    vfat.create_file("/BOOT/CONFIG.TXT").expect("create").write_all(b"hello").expect("write");
+    vfat.create_file("/BOOT/kernel image").expect("create").write_all(&[7; 5000]).expect("write");
+    vfat.create_file("/EMPTY").expect("create");
+
+    let records = vfat.manifest("/", Sha256::default()).expect("manifest");
+    let paths: Vec<_> = records.iter().map(|r| r.path.to_str().expect("utf-8")).collect();
+    assert_eq!(paths, ["/BOOT/CONFIG.TXT", "/BOOT/kernel image", "/EMPTY"]);
+    assert_eq!(records[1].size, 5000);
+    assert_eq!(&records[1].hash[..], Sha256::digest(&[7; 5000]).as_slice());
+    assert_eq!(
+        records[0].to_string(),
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 5 /BOOT/CONFIG.TXT"
+    );
+    assert_eq!(vfat.manifest("/EMPTY", Sha256::default()).expect("manifest").len(), 1);
+
+    let manifest: String = records.iter().map(|r| format!("{}\n\n", r)).collect();
+    let problems = vfat.verify_manifest(manifest.as_bytes(), Sha256::default());
+    assert_eq!(problems.expect("verify"), vec![]);
+
+    vfat.open_file("/BOOT/CONFIG.TXT").expect("open").write_all(b"HELLO").expect("write");
+    vfat.create_file("/EMPTY2").expect("create").write_all(b"!").expect("write");
+    vfat.rename("/EMPTY", "/BOOT/EMPTY").expect("rename");
+    vfat.rename("/EMPTY2", "/EMPTY").expect("rename");
+    vfat.rename("/BOOT/kernel image", "/KERNEL").expect("rename");
+    let problems = vfat.verify_manifest(manifest.as_bytes(), Sha256::default());
+    assert_eq!(
+        problems.expect("verify"),
+        vec![
+            ManifestProblem::HashMismatch {
+                path: "/BOOT/CONFIG.TXT".into(),
+            },
+            ManifestProblem::Missing {
+                path: "/BOOT/kernel image".into(),
+            },
+            ManifestProblem::SizeMismatch {
+                path: "/EMPTY".into(),
+                expected: 0,
+                actual: 1,
+            },
+        ]
+    );
+
+    let e = vfat.verify_manifest(&b"00 5 /BOOT\nzz 1 /EMPTY\n"[..], Sha256::default());
+    assert_eq!(e.unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use vfat::{Counters, Metrics};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let counters = Arc::new(Counters::default());
+    vfat.borrow_mut().set_metrics(Some(counters.clone() as Arc<Metrics>));
+
+    let contents = vec![0xA5; 10_000];
+    let mut file = vfat.create_file("/DATA.BIN").expect("create");
+    file.write_all(&contents).expect("write");
+    file.sync().expect("sync");
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+
+    let get = |counter: &::std::sync::atomic::AtomicUsize| counter.load(Ordering::SeqCst);
+    assert_eq!(get(&counters.file_bytes_written), 10_000);
+    assert_eq!(get(&counters.file_bytes_read), 10_000);
+    assert!(get(&counters.sectors_written) >= 10_000 / 512);
+    assert_eq!(get(&counters.device_bytes_written), get(&counters.sectors_written) * 512);
+    assert_eq!(get(&counters.device_bytes_read), get(&counters.sectors_read) * 512);
+    assert!(get(&counters.cache_hits) > 0);
+    assert!(get(&counters.fat_lookups) > 0);
+
+    vfat.borrow_mut().set_metrics(None);
+    let lookups = get(&counters.fat_lookups);
+    read_all(vfat.open_file("/DATA.BIN").expect("open"));
+    assert_eq!(get(&counters.fat_lookups), lookups);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use log::{self, Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static MOUNTED: AtomicBool = AtomicBool::new(false);
+    static WRITTEN_BACK: AtomicBool = AtomicBool::new(false);
+
+    struct Logger;
+
+    impl Log for Logger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Trace
+        }
+
+        fn log(&self, record: &Record) {
+            let message = record.args().to_string();
+            if message.starts_with("mounting volume at sector 64:") {
+                MOUNTED.store(true, Ordering::SeqCst);
+            } else if message.ends_with("directory sectors (sync: false, journal: false)") {
+                WRITTEN_BACK.store(true, Ordering::SeqCst);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    log::set_logger(&Logger).expect("set logger");
+    log::set_max_level(LevelFilter::Trace);
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    assert!(MOUNTED.load(Ordering::SeqCst));
+    vfat.create_dir("/DIR", false).expect("create");
+    vfat.borrow_mut().flush().expect("flush");
+    assert!(WRITTEN_BACK.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_entry_info() {
+    extern crate serde_json;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/A.TXT").expect("create");
+    let mut info = vfat.open("/A.TXT").expect("open").info();
+    info.metadata.modified = ::vfat::Timestamp::from_unix_time(1_500_000_001, 250);
+
+    let json = serde_json::to_value(&info).expect("serialize");
+    assert_eq!(json["name"], "A.TXT");
+    assert_eq!(json["is_dir"], false);
+    assert_eq!(json["size"], 0);
+    assert_eq!(json["metadata"]["attr"]["archive"], true);
+    assert_eq!(json["metadata"]["attr"]["read_only"], false);
+
+    let modified = &json["metadata"]["modified"];
+    assert_eq!(modified["year"], 2017);
+    assert_eq!(modified["month"], 7);
+    assert_eq!(modified["day"], 14);
+    assert_eq!(modified["hour"], 2);
+    assert_eq!(modified["minute"], 40);
+    assert_eq!(modified["second"], 1);
+    assert_eq!(modified["millisecond"], 250);
+}
+
+#[test]
+fn test_file_chunks() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..10 * cluster_size as u32 + 100).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/BIG.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    let chunks: Vec<Vec<u8>> = file
+        .chunks(3 * cluster_size - 1)
+        .map(|chunk| chunk.expect("chunk"))
+        .collect();
+    let lens: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+    assert_eq!(lens, [3 * cluster_size, 3 * cluster_size, 3 * cluster_size, cluster_size + 100]);
+    assert_eq!(chunks.concat(), data);
+    assert!(file.chunks(cluster_size).next().is_none());
+
+    file.seek(SeekFrom::Start(cluster_size as u64 + 7)).unwrap();
+    let rest: Vec<u8> = file.chunks(0).map(|c| c.expect("chunk")).collect::<Vec<_>>().concat();
+    assert_eq!(&rest[..], &data[cluster_size + 7..]);
+
+    let mut empty = vfat.create_file("/EMPTY").expect("create");
+    assert!(empty.chunks(4096).next().is_none());
+}
+
+#[test]
+fn test_file_copy_to() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..5 * cluster_size as u32 + 33).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/BIG.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    let mut copy = Vec::new();
+    assert_eq!(file.copy_to(&mut copy).expect("copy"), data.len() as u64);
+    assert_eq!(copy, data);
+    assert_eq!(file.copy_to(&mut copy).expect("copy"), 0);
+
+    // Copying to a file of the same volume doesn't deadlock.
+    file.seek(SeekFrom::Start(cluster_size as u64 - 3)).unwrap();
+    let mut dest = vfat.create_file("/COPY.BIN").expect("create");
+    file.copy_to(&mut dest).expect("copy");
+    let mut contents = Vec::new();
+    vfat.open_file("/COPY.BIN").and_then(|mut f| f.read_to_end(&mut contents)).expect("read");
+    assert_eq!(&contents[..], &data[cluster_size - 3..]);
+
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    file.seek(SeekFrom::Start(10)).unwrap();
+    let bytes: Vec<u8> = file.bytes().map(|b| b.expect("byte")).collect();
+    assert_eq!(&bytes[..], &data[10..]);
+    assert_eq!(vfat.create_file("/EMPTY").expect("create").bytes().count(), 0);
+}
+
+#[test]
+fn test_file_chain_cache() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..8 * cluster_size as u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/A").and_then(|mut f| f.write_all(&data)).expect("write");
+    vfat.create_file("/B").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/A").expect("open");
+    let mut buf = [0u8; 10];
+    file.seek(SeekFrom::Start(7 * cluster_size as u64)).unwrap();
+    file.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf[..], &data[7 * cluster_size..7 * cluster_size + 10]);
+
+    // Reading again resolves nothing from the FAT.
+    #[cfg(feature = "metrics")]
+    {
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+        use vfat::{Counters, Metrics};
+
+        let counters = Arc::new(Counters::default());
+        vfat.borrow_mut().set_metrics(Some(counters.clone() as Arc<Metrics>));
+        file.seek(SeekFrom::Start(6 * cluster_size as u64 + 1)).unwrap();
+        file.read_exact(&mut buf).expect("read");
+        assert_eq!(counters.fat_lookups.load(Ordering::SeqCst), 0);
+        vfat.borrow_mut().set_metrics(None);
+    }
+
+    // Growing the file past /B's clusters changes its chain.
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(&data).expect("write");
+    let extents = file.extents().expect("extents");
+    assert_eq!(extents.len(), 2);
+    file.seek(SeekFrom::Start(8 * cluster_size as u64 - 5)).unwrap();
+    file.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf[..5], &data[8 * cluster_size - 5..]);
+    assert_eq!(&buf[5..], &data[..5]);
+
+    // So does freeing the file's clusters through another handle.
+    let other = vfat.open_file("/A").expect("open");
+    vfat.borrow_mut().free_chain(other.cluster).expect("free");
+    file.seek(SeekFrom::Start(2 * cluster_size as u64)).unwrap();
+    assert!(file.read_exact(&mut buf).is_err());
+}
+
+#[test]
+fn test_cached_device_read_range() {
+    use vfat::{CachedDevice, Partition};
+
+    let image: Vec<u8> = (0..8 * 512u32).map(|i| (i % 251) as u8).collect();
+    let partition = Partition {
+        start: 2,
+        sector_size: 1024,
+    };
+    let mut device = CachedDevice::new(Cursor::new(image.clone()), partition);
+
+    // Sectors 0 and 1 are physical sectors of 512 bytes, then logical sectors
+    // of 1024 bytes follow.
+    let mut buf = vec![0u8; 2000];
+    assert_eq!(device.read_range(0, 300, &mut buf).expect("read"), 2000);
+    assert_eq!(&buf[..], &image[300..2300]);
+    let mut entry = [0u8; 32];
+    device.read_range(3, 1000, &mut entry).expect("read");
+    assert_eq!(&entry[..], &image[3048..3080]);
+    device.read_range(2, 1024 + 64, &mut entry).expect("read");
+    assert_eq!(&entry[..], &image[2112..2144]);
+
+    // Pending changes to cached sectors are read.
+    device.get_mut(3).expect("get")[1000] = 0xFF;
+    device.read_range(3, 990, &mut entry).expect("read");
+    assert_eq!(entry[10], 0xFF);
+    assert!(device.read_range(5, 0, &mut entry).is_err());
+}
+
+#[test]
+fn test_read_cluster_bytes() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..cluster_size as u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/A").and_then(|mut f| f.write_all(&data)).expect("write");
+    let cluster = vfat.open_file("/A").expect("open").cluster;
+
+    let mut vfat = vfat.borrow_mut();
+    for &(offset, len) in &[(0, 1), (3, 700), (511, 2), (512, 1024), (100, cluster_size)] {
+        let mut buf = vec![0u8; len];
+        let read = vfat.read_cluster_bytes(cluster, offset, &mut buf).expect("read");
+        let end = ::std::cmp::min(offset + len, cluster_size);
+        assert_eq!(read, end - offset);
+        assert_eq!(&buf[..read], &data[offset..end]);
+    }
+    let mut buf = [0u8; 4];
+    assert_eq!(vfat.read_cluster_bytes(cluster, cluster_size, &mut buf).unwrap(), 0);
+    assert!(vfat.read_cluster_bytes(Cluster::from(1), 0, &mut buf).is_err());
+}
+
+#[test]
+fn test_read_chain_up_to() {
+    use std::io::{Seek, SeekFrom};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..3 * cluster_size as u32 + 10).map(|i| (i % 251) as u8).collect();
+    let mut file = vfat.create_file("/DATA.BIN").expect("create");
+    file.write_all(&vec![0xEE; 4 * cluster_size]).expect("write");
+    let mut file = vfat.create_file("/A").expect("create");
+    file.write_all(&data[..cluster_size]).expect("write");
+    // Fragment the chain.
+    vfat.create_file("/B").and_then(|mut f| f.write_all(&[1])).expect("write");
+    file.write_all(&data[cluster_size..]).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+
+    // Leave stale bytes in the slack of the last cluster.
+    let first = vfat.open_file("/A").expect("open").cluster;
+    let last = *vfat.borrow_mut().chain(first).expect("chain").last().unwrap();
+    vfat.borrow_mut().write_cluster(last, 0, &vec![0xEE; cluster_size]).expect("write");
+    let mut file = vfat.open_file("/A").expect("open");
+    file.seek(SeekFrom::Start(3 * cluster_size as u64)).expect("seek");
+    file.write_all(&data[3 * cluster_size..]).expect("write");
+
+    let mut whole = Vec::new();
+    vfat.borrow_mut().read_chain(first, &mut whole).expect("read");
+    assert_eq!(whole.len(), 4 * cluster_size);
+    assert_eq!(whole[data.len()], 0xEE);
+
+    let mut buf = vec![9];
+    let read = vfat.borrow_mut().read_chain_up_to(first, data.len(), &mut buf).expect("read");
+    assert_eq!(read, data.len());
+    assert_eq!(&buf[1..], &data[..]);
+    assert_eq!(buf.capacity(), data.len() + 1);
+
+    for &len in &[0, 5, cluster_size, cluster_size + 1, 2 * cluster_size] {
+        let mut buf = Vec::new();
+        assert_eq!(vfat.borrow_mut().read_chain_up_to(first, len, &mut buf).unwrap(), len);
+        assert_eq!(&buf[..], &data[..len]);
+    }
+    let mut buf = Vec::new();
+    let read = vfat.borrow_mut().read_chain_up_to(first, 1 << 20, &mut buf).expect("read");
+    assert_eq!((read, buf), (whole.len(), whole));
+}
+
+#[test]
+fn test_file_read_to_end() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 253) as u8).collect();
+    vfat.create_file("/BIG.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    let mut contents = Vec::new();
+    assert_eq!(file.read_to_end(&mut contents).expect("read"), data.len());
+    assert_eq!(contents, data);
+    assert_eq!(contents.capacity(), data.len());
+    assert_eq!(file.read_to_end(&mut contents).expect("read"), 0);
+
+    let mut tail = b"head".to_vec();
+    file.seek(SeekFrom::Start(4097)).unwrap();
+    file.read_to_end(&mut tail).expect("read");
+    assert_eq!(&tail[..4], b"head");
+    assert_eq!(&tail[4..], &data[4097..]);
+
+    // Reads that straddle cluster boundaries.
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 777];
+    loop {
+        match file.read(&mut buf).expect("read") {
+            0 => break,
+            n => contents.extend_from_slice(&buf[..n]),
+        }
+    }
+    assert_eq!(contents, data);
+}
+
+#[test]
+fn test_file_try_clone() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::thread;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 241) as u8).collect();
+    vfat.create_file("/CLONE.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/CLONE.BIN").expect("open");
+    file.seek(SeekFrom::Start(100)).unwrap();
+    let mut clone = file.try_clone().expect("clone");
+    let mut buf = [0u8; 10];
+    clone.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf[..], &data[100..110]);
+    file.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf[..], &data[100..110]);
+
+    let entry = vfat.open_dir("/").expect("open").find("clone.bin").expect("find");
+    let workers: Vec<_> = (0..4)
+        .map(|i| {
+            let file = entry.clone().into_file().expect("file");
+            thread::spawn(move || {
+                let mut file = file;
+                let mut contents = Vec::new();
+                file.seek(SeekFrom::Start(i * 1000)).unwrap();
+                file.read_to_end(&mut contents).expect("read");
+                contents
+            })
+        })
+        .collect();
+    for (i, worker) in workers.into_iter().enumerate() {
+        assert_eq!(worker.join().unwrap(), &data[i * 1000..]);
+    }
+}
+
+#[test]
+fn test_open_handles() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let handles = vfat.borrow().handles.clone();
+
+    let file = vfat.create_file("/OPEN.TXT").expect("create");
+    let location = file.location.expect("location");
+    assert_eq!(handles.open_count(location), 1);
+
+    let clone = file.try_clone().expect("clone");
+    let reopened = vfat.open_file("/OPEN.TXT").expect("open");
+    assert_eq!(handles.open_count(location), 3);
+
+    drop(file);
+    drop(clone);
+    assert_eq!(handles.open_count(location), 1);
+    drop(reopened);
+    assert_eq!(handles.open_count(location), 0);
+
+    // Listing a directory doesn't leave its entries open.
+    let dir = vfat.create_dir("/DIR", false).expect("create");
+    let dir_location = dir.location().expect("location");
+    for _ in vfat.open_dir("/").expect("open").entries().expect("entries") {}
+    assert_eq!(handles.open_count(dir_location), 1);
+    drop(dir);
+    assert_eq!(handles.open_count(dir_location), 0);
+}
+
+#[test]
+fn test_file_seek() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/SEEK.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+
+    let mut file = vfat.open_file("/SEEK.BIN").expect("open");
+    assert_eq!(file.seek(SeekFrom::Start(5000)).unwrap(), 5000);
+    let mut buf = [0u8; 16];
+    file.read_exact(&mut buf).expect("read");
+    assert_eq!(&buf[..], &data[5000..5016]);
+
+    assert_eq!(file.seek(SeekFrom::Current(-16)).unwrap(), 5000);
+    assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 9990);
+    assert_eq!(file.read(&mut buf).unwrap(), 10);
+    assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 10_000);
+
+    let e = file.seek(SeekFrom::End(1)).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = file.seek(SeekFrom::Current(-10_001)).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = file.seek(SeekFrom::End(i64::max_value())).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = file.seek(SeekFrom::Current(i64::max_value())).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+
+    file.seek(SeekFrom::Start(2)).unwrap();
+    file.write_all(b"xy").expect("overwrite");
+    let mut expected = data.clone();
+    expected[2..4].copy_from_slice(b"xy");
+    assert_eq!(read_all(vfat.open_file("/SEEK.BIN").unwrap()), expected);
+}
+
+#[test]
+fn test_create_long_names() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let names = [
+        "A very long file name.text",
+        "A very long file name 2.text",
+        "lower.txt",
+        "Привет мир.txt",
+    ];
+    for name in &names {
+        vfat.create_file(Path::new("/").join(name)).expect("create");
+    }
+
+    let mut listed: Vec<String> = vfat
+        .open_dir("/")
+        .expect("root")
+        .entries()
+        .expect("entries")
+        .map(|e| e.name().to_string())
+        .collect();
+    listed.sort();
+    let mut expected: Vec<String> = names.iter().map(|s| s.to_string()).collect();
+    expected.sort();
+    assert_eq!(listed, expected);
+}
+
+#[test]
+fn test_directory_growth() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let dir = vfat.create_dir("/DIR", false).expect("create");
+    let per_cluster = vfat.borrow().cluster_size() / 32;
+
+    // Every name needs two LFN entries besides its regular entry.
+    let count = per_cluster;
+    for i in 0..count {
+        vfat.create_file(format!("/DIR/a long file name {:04}", i)).expect("create");
+    }
+    let chain = vfat.borrow_mut().chain(dir.cluster()).expect("chain");
+    assert_eq!(chain.len(), 4);
+
+    for i in 0..count {
+        vfat.open_file(format!("/DIR/a long file name {:04}", i)).expect("open");
+    }
+    let listed = vfat.open_dir("/DIR").expect("open").entries().expect("entries").count();
+    assert_eq!(listed, count + 2);
+    assert!(vfat.check().expect("check").is_clean());
+
+    // The root directory grows too.
+    for i in 0..per_cluster {
+        vfat.create_file(format!("/F{}", i)).expect("create");
+    }
+    let root = vfat.borrow().root_dir_cluster;
+    assert_eq!(vfat.borrow_mut().chain(root).expect("chain").len(), 2);
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_compact_dir() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/DIR", false).expect("create");
+    vfat.create_dir("/OUT", false).expect("create");
+    let per_cluster = vfat.borrow().cluster_size() / 32;
+    let name = |i| format!("a long file name {:04}", i);
+    for i in 0..per_cluster {
+        let mut file = vfat.create_file(Path::new("/DIR").join(name(i))).expect("create");
+        file.write_all(name(i).as_bytes()).expect("write");
+    }
+
+    // Move out all but the first and last file, leaving deleted entries.
+    for i in 1..per_cluster - 1 {
+        let (from, to) = (Path::new("/DIR").join(name(i)), Path::new("/OUT").join(name(i)));
+        vfat.rename(from, to).expect("rename");
+    }
+
+    let dir = vfat.open_dir("/DIR").expect("open");
+    {
+        let _open = vfat.open_file(Path::new("/DIR").join(name(0))).expect("open");
+        assert_eq!(dir.compact().unwrap_err().kind(), ::std::io::ErrorKind::Other);
+    }
+    let report = dir.compact().expect("compact");
+    assert_eq!(report.removed_entries, (per_cluster - 2) * 3);
+    assert_eq!(report.freed_clusters, 3);
+    assert_eq!(vfat.borrow_mut().chain(dir.cluster()).expect("chain").len(), 1);
+
+    let entries = dir.entries().expect("entries");
+    let names: Vec<String> = entries.map(|e| e.name().to_string()).collect();
+    assert_eq!(names, [".".to_string(), "..".to_string(), name(0), name(per_cluster - 1)]);
+    for &i in &[0, per_cluster - 1] {
+        let file = vfat.open_file(Path::new("/DIR").join(name(i))).expect("open");
+        assert_eq!(read_all(file), name(i).as_bytes());
+    }
+    assert!(vfat.check().expect("check").is_clean());
+
+    // A compact directory is left as it is.
+    assert_eq!(dir.compact().expect("compact"), ::vfat::CompactReport::default());
+}
+
+#[test]
+fn test_raw_entries() {
+    use vfat::raw::{self, DeletedEntry, RawEntry};
+    use vfat::Codepage;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/OUT", false).expect("create");
+    vfat.create_file("/A long file name.txt").expect("create");
+    vfat.create_file("/GONE.TXT").expect("create");
+    vfat.rename("/GONE.TXT", "/OUT/GONE.TXT").expect("rename");
+
+    let mut buf = Vec::new();
+    vfat.borrow_mut().read_chain(Cluster::from(2), &mut buf).expect("read root");
+    let entries: Vec<RawEntry> = raw::entries(&buf[..buf.len() - 1]).collect();
+    assert_eq!(entries.len(), buf.len() / raw::ENTRY_SIZE - 1);
+
+    let mut iter = entries.iter();
+    match (iter.next(), iter.next(), iter.next(), iter.next(), iter.next(), iter.next()) {
+        (
+            Some(&RawEntry::Regular(ref out)),
+            Some(&RawEntry::Lfn(ref second)),
+            Some(&RawEntry::Lfn(ref first)),
+            Some(&RawEntry::Regular(ref file)),
+            Some(&RawEntry::Deleted(DeletedEntry::Regular(ref gone))),
+            Some(&RawEntry::End),
+        ) => {
+            assert_eq!(out.name(Codepage::Cp437), "OUT");
+            assert!(out.is_dir() && out.cluster().fat_index() >= 3);
+            assert_eq!((second.sequence(), second.is_last()), (2, true));
+            assert_eq!((first.sequence(), first.is_last()), (1, false));
+            assert_eq!(first.text() + &second.text(), "A long file name.txt");
+            assert_eq!(first.checksum(), file.checksum());
+            assert_eq!(file.name(Codepage::Cp437), "ALONGF~1.TXT");
+            assert_eq!((file.size(), file.is_dir()), (0, false));
+            assert_eq!(&gone.short_name()[1..], b"ONE    TXT");
+        }
+        other => panic!("unexpected entries {:?}", other),
+    }
+    assert!(iter.all(|entry| match *entry {
+        RawEntry::End => true,
+        _ => false,
+    }));
+
+    let e = raw::parse_entry(&buf[..31]).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_slack() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    vfat.create_dir("/DIR", false).expect("create");
+    vfat.create_file("/EMPTY").expect("create");
+    vfat.create_file("/FULL")
+        .expect("create")
+        .write_all(&vec![1; cluster_size])
+        .expect("write");
+    let mut file = vfat.create_file("/DIR/PART").expect("create");
+    file.write_all(&vec![2; cluster_size + 100]).expect("write");
+
+    // Leave old data behind the end of the file.
+    let extents = file.extents().expect("extents");
+    let extent = extents.last().expect("extent");
+    let last = extent.start.fat_index() + extent.len - 1;
+    let mut old = vec![2; 100];
+    old.resize(cluster_size, 0xEE);
+    vfat.borrow_mut().write_cluster(Cluster::from(last), 0, &old).expect("write");
+
+    let mut buf = vec![9];
+    let file = vfat.open_file("/DIR/PART").expect("open");
+    assert_eq!(file.slack(&mut buf).expect("slack"), cluster_size - 100);
+    assert_eq!(buf.len(), 1 + cluster_size - 100);
+    assert!(buf[1..].iter().all(|&b| b == 0xEE));
+    for path in &["/EMPTY", "/FULL"] {
+        assert_eq!(vfat.open_file(path).expect("open").slack(&mut buf).expect("slack"), 0);
+    }
+
+    let slack: Vec<_> = vfat.slack().expect("slack").map(|s| s.expect("slack")).collect();
+    assert_eq!(slack.len(), 1);
+    assert_eq!(slack[0].path, Path::new("/DIR/PART"));
+    assert_eq!((slack[0].cluster.fat_index(), slack[0].offset), (last, 100));
+    assert_eq!(slack[0].data, &buf[1..]);
+}
+
+#[test]
+fn test_carve_deleted_files() {
+    use vfat::raw::{self, DeletedEntry, RawEntry};
+    use vfat::Confidence;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    vfat.create_dir("/OUT", false).expect("create");
+    let small: Vec<u8> = (0..100).collect();
+    let big: Vec<u8> = (0..cluster_size * 3 - 7).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/SMALL").expect("create").write_all(&small).expect("write");
+    vfat.create_file("/BIG").expect("create").write_all(&big).expect("write");
+
+    // Delete the files, leaving deleted entries and free clusters behind.
+    for name in &["SMALL", "BIG"] {
+        let from = Path::new("/").join(name);
+        vfat.rename(&from, Path::new("/OUT").join(name)).expect("rename");
+        let file = vfat.open_file(Path::new("/OUT").join(name)).expect("open");
+        vfat.borrow_mut().free_chain(file.cluster).expect("free");
+    }
+
+    let mut buf = Vec::new();
+    vfat.borrow_mut().read_chain(Cluster::from(2), &mut buf).expect("read root");
+    let deleted: Vec<_> = raw::entries(&buf)
+        .filter_map(|entry| match entry {
+            RawEntry::Deleted(DeletedEntry::Regular(entry)) => Some(entry),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deleted.len(), 2);
+
+    let carved = vfat.carve(deleted[0].cluster(), deleted[0].size() as u64).expect("carve");
+    assert_eq!((carved.data, carved.confidence), (small, Confidence::High));
+    let carved = vfat.carve(deleted[1].cluster(), deleted[1].size() as u64).expect("carve");
+    assert_eq!((carved.clusters, carved.reused_clusters), (3, 0));
+    assert_eq!((carved.data, carved.confidence), (big.clone(), Confidence::Medium));
+
+    // Reusing clusters of the run lowers the confidence.
+    let (first, size) = (deleted[1].cluster(), big.len() as u64);
+    let eoc = ::vfat::Status::Eoc(0x0FFF_FFFF);
+    let second = Cluster::from(first.fat_index() + 1);
+    vfat.borrow_mut().set_fat_entry(second, eoc).expect("set");
+    let carved = vfat.carve(first, size).expect("carve");
+    assert_eq!((carved.reused_clusters, carved.confidence), (1, Confidence::Low));
+    vfat.borrow_mut().set_fat_entry(first, eoc).expect("set");
+    let carved = vfat.carve(first, size).expect("carve");
+    assert_eq!((carved.reused_clusters, carved.confidence), (2, Confidence::Overwritten));
+    assert_eq!(carved.data, big);
+
+    assert_eq!(
+        vfat.carve(Cluster::from(1), 10).unwrap_err().kind(),
+        ::std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn test_open_by_cluster() {
+    use std::io::{ErrorKind, Read};
+    use traits::{Dir as DirTrait, Entry as EntryTrait};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let dir = vfat.create_dir("/LOST", false).expect("create");
+    vfat.create_file("/LOST/DATA.BIN").expect("create").write_all(&data).expect("write");
+    let file = vfat.open_file("/LOST/DATA.BIN").expect("open");
+
+    let found = vfat.open_cluster_as_dir(dir.cluster()).expect("open dir");
+    let names: Vec<String> = found.entries().expect("entries").map(|e| e.name().into()).collect();
+    assert_eq!(names, [".", "..", "DATA.BIN"]);
+
+    let mut recovered = vfat.open_cluster_chain_as_file(file.cluster, 5000).expect("open file");
+    let mut contents = Vec::new();
+    recovered.read_to_end(&mut contents).expect("read");
+    assert_eq!(contents, data);
+    assert_eq!(recovered.write(b"x").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // A size past the end of the chain shows when reading.
+    let mut recovered = vfat.open_cluster_chain_as_file(file.cluster, 1 << 20).expect("open");
+    assert_eq!(recovered.read_to_end(&mut contents).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let end = Cluster::from(vfat.borrow().data_clusters() + 2);
+    for &cluster in &[Cluster::from(1), end] {
+        let err = vfat.open_cluster_as_dir(cluster).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = vfat.open_cluster_chain_as_file(cluster, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn test_owner_of() {
+    use std::path::PathBuf;
+    use vfat::Status;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    vfat.create_dir("/DIR", false).expect("create");
+    let data = vec![7u8; 2 * cluster_size];
+    vfat.create_file("/DIR/A").expect("create").write_all(&data).expect("write");
+    vfat.create_file("/B").expect("create").write_all(&data).expect("write");
+    let (a, b) = (vfat.open_file("/DIR/A").unwrap(), vfat.open_file("/B").unwrap());
+    let a = vfat.borrow_mut().chain(a.cluster).expect("chain");
+    let b = vfat.borrow_mut().chain(b.cluster).expect("chain");
+    let dir = vfat.open_dir("/DIR").expect("open").cluster();
+
+    assert_eq!(vfat.owner_of(a[1]).expect("owner"), [PathBuf::from("/DIR/A")]);
+    assert_eq!(vfat.owner_of(dir).expect("owner"), [PathBuf::from("/DIR")]);
+    assert_eq!(vfat.owner_of(Cluster::from(2)).expect("owner"), [PathBuf::from("/")]);
+    assert!(vfat.owner_of(Cluster::from(b[1].fat_index() + 1)).expect("owner").is_empty());
+
+    // Cross-link the second cluster of /DIR/A into the chain of /B.
+    vfat.borrow_mut().set_fat_entry(a[0], Status::Data(b[1])).expect("set");
+    let index = vfat.owner_index().expect("index");
+    assert_eq!(index.owners(b[1]), [Path::new("/DIR/A"), Path::new("/B")]);
+    assert_eq!(index.owners(b[0]), [Path::new("/B")]);
+    assert!(index.owners(a[1]).is_empty());
+    assert_eq!(index.cross_linked(), [b[1]]);
+
+    let vfat = vfat.borrow();
+    let sectors = vfat.cluster_sectors(b[1]).expect("sectors");
+    assert_eq!(vfat.sector_cluster(sectors.start), Some(b[1]));
+    assert_eq!(vfat.sector_cluster(sectors.end - 1), Some(b[1]));
+    assert_eq!(vfat.sector_cluster(0), None);
+    let end = vfat.cluster_sectors(Cluster::from(vfat.data_clusters() + 1)).expect("sectors").end;
+    assert_eq!(vfat.sector_cluster(end), None);
+}
+
+#[test]
+fn test_timeline() {
+    use vfat::{EventKind, TimelineFormat, Timestamp};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/A").expect("create");
+    vfat.create_dir("/DIR", false).expect("create");
+    vfat.create_file("/DIR/B,1").expect("create").write_all(b"abc").expect("write");
+
+    let (t2014, t2017, t2020) = (
+        Timestamp::from_unix_time(1_400_000_000, 0),
+        Timestamp::from_unix_time(1_500_000_000, 0),
+        Timestamp::from_unix_time(1_600_000_000, 0),
+    );
+    vfat.set_times("/A", t2014, t2020, t2020).expect("set times");
+    vfat.set_times("/DIR", t2014, t2014, t2014).expect("set times");
+    vfat.set_times("/DIR/B,1", t2017, t2017, t2017).expect("set times");
+
+    let events = vfat.timeline().expect("timeline");
+    assert_eq!(events.len(), 9);
+    assert_eq!(events[0].kind, EventKind::Accessed);
+    assert_eq!(events[0].path, Path::new("/DIR"));
+    assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+
+    let mut csv = Vec::new();
+    vfat.write_timeline(TimelineFormat::Csv, &mut csv).expect("write");
+    let csv = String::from_utf8(csv).expect("utf-8");
+    let expected = "time,event,path,type,size\n\
+                    2014-05-13T00:00:00.00,accessed,/DIR,dir,0\n\
+                    2014-05-13T16:53:20.00,created,/A,file,0\n\
+                    2014-05-13T16:53:20.00,created,/DIR,dir,0\n\
+                    2014-05-13T16:53:20.00,modified,/DIR,dir,0\n\
+                    2017-07-14T00:00:00.00,accessed,\"/DIR/B,1\",file,3\n";
+    assert!(csv.starts_with(expected), "{}", csv);
+    assert_eq!(csv.lines().count(), 10);
+
+    let mut json = Vec::new();
+    vfat.write_timeline(TimelineFormat::Json, &mut json).expect("write");
+    let json = String::from_utf8(json).expect("utf-8");
+    assert!(json.starts_with(
+        "[\n  {\"time\": \"2014-05-13T00:00:00.00\", \"event\": \"accessed\", \
+         \"path\": \"/DIR\", \"is_dir\": true, \"size\": 0},\n"
+    ));
+    assert!(json.ends_with("\"size\": 0}\n]\n"));
+}
+
+#[test]
+fn test_to_json() {
+    use vfat::Timestamp;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/b.txt").expect("create").write_all(b"hello").expect("write");
+    vfat.create_dir("/A", false).expect("create");
+    vfat.create_file("/A/C").expect("create");
+    let t2017 = Timestamp::from_unix_time(1_500_000_000, 0);
+    vfat.set_times("/A/C", t2017, t2017, t2017).expect("set times");
+
+    let json = vfat.to_json("/A/C").expect("to_json");
+    assert_eq!(
+        json,
+        "{\n  \"name\": \"C\",\n  \"is_dir\": false,\n  \"size\": 0,\n  \"cluster\": 0,\n  \
+         \"attributes\": {\"read_only\": false, \"hidden\": false, \"system\": false, \
+         \"volume_id\": false, \"directory\": false, \"archive\": true},\n  \
+         \"created\": \"2017-07-14T02:40:00.00\",\n  \
+         \"modified\": \"2017-07-14T02:40:00.00\",\n  \
+         \"accessed\": \"2017-07-14T00:00:00.00\"\n}\n"
+    );
+
+    let json = vfat.to_json("/").expect("to_json");
+    assert!(json.starts_with("{\n  \"name\": \"/\",\n  \"is_dir\": true,\n"));
+    assert!(json.contains("\"created\": null"));
+    let a = json.find("\"name\": \"A\"").expect("A");
+    let b = json.find("\"name\": \"b.txt\"").expect("b.txt");
+    let c = json.find("\"name\": \"C\"").expect("C");
+    assert!(a < c && c < b);
+    assert!(json.contains("      \"name\": \"C\",\n"));
+    assert!(json.contains("\"size\": 5,"));
+    assert!(!json.contains("\"name\": \".\""));
+    assert!(json.ends_with("  ]\n}\n"));
+}
+
+#[test]
+fn test_name_validation() {
+    use vfat::{MountOptions, NameError};
+
+    let name_error = |e: ::std::io::Error| -> NameError {
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<NameError>())
+            .expect("name error")
+            .clone()
+    };
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    assert_eq!(name_error(vfat.create_file("/A?.TXT").unwrap_err()), NameError::IllegalChar('?'));
+    let e = vfat.create_dir("/DIR.", false).unwrap_err();
+    assert_eq!(name_error(e), NameError::TrailingDotOrSpace);
+    vfat.create_file("/CON").expect("create");
+    assert_eq!(name_error(vfat.rename("/CON", "/a|b").unwrap_err()), NameError::IllegalChar('|'));
+    assert!(vfat.open("/CON").is_ok());
+
+    let options = MountOptions {
+        windows_names: true,
+        ..MountOptions::default()
+    };
+    let image = formatted_image(8192, &FormatOptions::default());
+    let vfat = VFat::from_with_options(image, &options).expect("mount");
+    assert_eq!(name_error(vfat.create_file("/nul.txt").unwrap_err()), NameError::DeviceName);
+    vfat.create_file("/A.TXT").expect("create");
+    assert_eq!(name_error(vfat.rename("/A.TXT", "/LPT1").unwrap_err()), NameError::DeviceName);
+    vfat.rename("/A.TXT", "/CONFIG").expect("rename");
+}
+
+#[test]
+fn test_entries_sorted() {
+    use vfat::SortKey;
+
+    let options = FormatOptions {
+        volume_label: Some("LABEL".to_string()),
+        ..FormatOptions::default()
+    };
+    let vfat = VFat::from(formatted_image(8192, &options)).expect("mount");
+    vfat.create_dir("/DIR", false).expect("create");
+    let create = |name: &str, size: usize, secs: u64| {
+        let mut file = vfat.create_file(name).expect("create");
+        file.write_all(&vec![0; size]).expect("write");
+        let time = ::vfat::Timestamp::from_unix_time(secs, 0);
+        let location = file.location.unwrap();
+        vfat.borrow_mut()
+            .update_dir_entry(location, |entry| entry.set_times(time, time, time))
+            .expect("update");
+    };
+    create("/DIR/beta.txt", 300, 1_500_000_200);
+    create("/DIR/ALPHA.TXT", 100, 1_500_000_400);
+    create("/DIR/gamma", 200, 1_500_000_000);
+    vfat.create_dir("/DIR/SUB", false).expect("create");
+
+    let dir = vfat.open_dir("/DIR").expect("open");
+    let names = |key| -> Vec<String> {
+        dir.entries_sorted(key)
+            .expect("sort")
+            .into_iter()
+            .map(|info| info.name)
+            .collect()
+    };
+    assert_eq!(names(SortKey::Name), ["ALPHA.TXT", "beta.txt", "gamma", "SUB"]);
+    assert_eq!(names(SortKey::Size), ["SUB", "ALPHA.TXT", "gamma", "beta.txt"]);
+    assert_eq!(names(SortKey::DirsFirst), ["SUB", "ALPHA.TXT", "beta.txt", "gamma"]);
+    let modified = names(SortKey::Modified);
+    assert_eq!(&modified[..3], ["gamma", "beta.txt", "ALPHA.TXT"]);
+
+    let root = vfat.open_dir("/").expect("open");
+    assert_eq!(root.entries_sorted(SortKey::Name).expect("sort").len(), 1);
+}
+
+#[test]
+fn test_entries_with_buffer() {
+    let vfat = fragmented_image();
+    vfat.create_dir("/DIR", false).expect("create");
+    vfat.create_file("/DIR/A Long File Name.txt").expect("create");
+
+    let mut buf = Vec::with_capacity(64 * 1024);
+    let ptr = buf.as_ptr();
+    let mut names = Vec::new();
+    for path in &["/", "/DIR"] {
+        let dir = vfat.open_dir(path).expect("open");
+        let mut entries = dir.entries_with_buffer(buf).expect("entries");
+        names.extend(entries.by_ref().map(|entry| entry.name().to_string()));
+        buf = entries.into_buffer();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+    assert_eq!(
+        names,
+        ["A.BIN", "B.BIN", "C.BIN", "DIR", ".", "..", "A Long File Name.txt"]
+    );
+}
+
+#[test]
+fn test_dir_len() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let root = vfat.open_dir("/").expect("open");
+    assert_eq!(root.len().unwrap(), 0);
+    assert!(root.is_empty().unwrap());
+
+    let dir = vfat.create_dir("/DIR", false).expect("create");
+    assert!(dir.is_empty().unwrap());
+    vfat.create_file("/DIR/A Long File Name.txt").expect("create");
+    vfat.create_file("/DIR/B.TXT").expect("create");
+    vfat.create_dir("/DIR/SUB", false).expect("create");
+    assert_eq!(dir.len().unwrap(), 3);
+    assert!(!dir.is_empty().unwrap());
+    assert_eq!(root.len().unwrap(), 1);
+
+    let options = FormatOptions {
+        volume_label: Some("LABEL".to_string()),
+        ..FormatOptions::default()
+    };
+    let vfat = VFat::from(formatted_image(8192, &options)).expect("mount");
+    assert!(vfat.open_dir("/").unwrap().is_empty().unwrap());
+}
+
+#[test]
+fn test_create_dir() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let e = vfat.create_dir("/a/b", false).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+
+    vfat.create_dir("/a/b/c", true).expect("create dirs");
+    vfat.create_file("/a/b/c/file").expect("create file");
+    let e = vfat.create_dir("/a/b", false).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+
+    let names: Vec<String> = vfat
+        .open_dir("/a/b/c")
+        .expect("open")
+        .entries()
+        .expect("entries")
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, vec![".", "..", "file"]);
+
+    let hash = hash_dir_recursive_from(vfat.clone(), "/");
+    assert!(hash.contains("/a/b/c"));
+}
+
+#[test]
+fn test_copy_from_host() {
+    use std::fs;
+    use std::io::Write;
+
+    let host = ::std::env::temp_dir().join(format!("fat32-copy-in-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&host);
+    fs::create_dir_all(host.join("nested dir")).expect("host dirs");
+    fs::File::create(host.join("readme.md"))
+        .and_then(|mut f| f.write_all(b"hello from the host"))
+        .expect("host file");
+    fs::File::create(host.join("nested dir").join("BIG.BIN"))
+        .and_then(|mut f| f.write_all(&vec![0xAB; 100_000]))
+        .expect("host file");
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.copy_dir_from_host(&host, "/boot").expect("copy in");
+    fs::remove_dir_all(&host).expect("clean up");
+
+    let file = vfat.open_file("/boot/readme.md").expect("open");
+    assert!(file.metadata().modified().year() >= 2018);
+    assert_eq!(read_all(file), b"hello from the host".to_vec());
+    let file = vfat.open_file("/boot/nested dir/BIG.BIN").expect("open");
+    assert_eq!(read_all(file), vec![0xAB; 100_000]);
+}
+
+#[test]
+fn test_sync_from_host() {
+    use std::fs;
+    use std::io::Write;
+    use vfat::SyncReport;
+
+    let host = ::std::env::temp_dir().join(format!("fat32-sync-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&host);
+    fs::create_dir_all(host.join("overlays")).expect("host dirs");
+    let write = |path: &str, contents: &[u8], mtime: i64| {
+        let path = host.join(path);
+        fs::File::create(&path).and_then(|mut f| f.write_all(contents)).expect("host file");
+        let mtime = ::filetime::FileTime::from_unix_time(mtime, 0);
+        ::filetime::set_file_times(&path, mtime, mtime).expect("set times");
+    };
+    write("kernel.img", &[1; 3000], 1_500_000_000);
+    write("overlays/uart.dtbo", b"uart", 1_500_000_000);
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let report = vfat.sync_from_host(&host, "/boot").expect("sync");
+    assert_eq!(report.copied_dirs, 1);
+    let report = vfat.sync_from_host(&host, "/boot").expect("sync");
+    assert_eq!(
+        report,
+        SyncReport {
+            copied_files: 0,
+            copied_dirs: 0,
+            unchanged_files: 2,
+        }
+    );
+
+    write("kernel.img", &[2; 2000], 1_500_000_100);
+    write("config.txt", b"arm_64bit=1", 1_500_000_000);
+    let report = vfat.sync_from_host(&host, "/boot").expect("sync");
+    fs::remove_dir_all(&host).expect("clean up");
+    assert_eq!((report.copied_files, report.unchanged_files), (2, 1));
+    assert_eq!(read_all(vfat.open_file("/boot/kernel.img").expect("open")), vec![2; 2000]);
+    assert_eq!(read_all(vfat.open_file("/boot/config.txt").expect("open")), b"arm_64bit=1");
+    assert!(vfat.open("/boot/kernel.img.sync-tmp").is_err());
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_copy_to_host() {
+    use std::fs;
+    use std::io::{Read, Write};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/DCIM/100CANON", true).expect("create dirs");
+    vfat.create_file("/DCIM/100CANON/IMG_0001.JPG")
+        .and_then(|mut f| f.write_all(&vec![0x5A; 70_000]))
+        .expect("write");
+    vfat.create_file("/DCIM/notes for later.txt")
+        .and_then(|mut f| f.write_all(b"notes"))
+        .expect("write");
+
+    let host = ::std::env::temp_dir().join(format!("fat32-copy-out-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&host);
+    vfat.copy_to_host("/DCIM", &host).expect("copy out");
+
+    let mut data = Vec::new();
+    fs::File::open(host.join("100CANON").join("IMG_0001.JPG"))
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .expect("read host file");
+    assert_eq!(data, vec![0x5A; 70_000]);
+
+    let notes = host.join("notes for later.txt");
+    let modified = vfat.open_file("/DCIM/notes for later.txt").expect("open").metadata().modified();
+    let host_modified = fs::metadata(&notes)
+        .and_then(|m| m.modified())
+        .expect("host mtime")
+        .duration_since(::std::time::UNIX_EPOCH)
+        .expect("after epoch")
+        .as_secs();
+    assert_eq!(host_modified, modified.unix_seconds());
+
+    let e = vfat.copy_to_host("/DCIM/notes for later.txt", &notes).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+    fs::remove_dir_all(&host).expect("clean up");
+}
+
+#[test]
+fn test_copy_to_host_rejects_unsafe_names() {
+    use std::fs;
+
+    let host = ::std::env::temp_dir().join(format!("fat32-unsafe-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&host);
+    let out = host.join("a").join("b").join("out");
+
+    // A short name that climbs out of the target directory.
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    image.add_file(0, b"../ESC  TXT", &[3]);
+    let vfat = image.mount();
+    let e = vfat.copy_to_host("/", &out).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    assert!(!host.join("a").join("b").join("ESC.TXT").exists());
+
+    // A long name that is an absolute path, which would replace the target
+    // directory entirely if joined to it.
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    VFat::from(CrashDevice::new(&image, usize::max_value()))
+        .expect("mount")
+        .create_file("/xescape-long.txt")
+        .expect("create");
+    let mut data = image.borrow().clone();
+    let utf16: Vec<u8> = "xesca".bytes().flat_map(|b| vec![b, 0]).collect();
+    let at = data.windows(utf16.len()).position(|w| w == &utf16[..]).expect("long name");
+    data[at] = b'/';
+    let vfat = VFat::from(Cursor::new(data)).expect("mount");
+    let root = vfat.open_dir("/").expect("open");
+    assert!(root.entries().expect("entries").any(|e| e.name() == "/escape-long.txt"));
+    drop(root);
+    let e = vfat.copy_to_host("/", &out).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    assert!(!Path::new("/escape-long.txt").exists());
+    fs::remove_dir_all(&host).expect("clean up");
+}
+
+#[test]
+fn test_open_dot_components() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/A/B", true).expect("create");
+    vfat.create_file("/A/F.TXT").and_then(|mut f| f.write_all(b"f")).expect("write");
+
+    assert_eq!(vfat.open("/A/B/..").expect("open").name(), "A");
+    assert_eq!(vfat.open("/A/./B/../..").expect("open").name(), "/");
+    assert_eq!(vfat.open("/..").expect("open").name(), "/");
+    assert_eq!(read_all(vfat.open_file("/A/B/../F.TXT").expect("open")), b"f");
+    let e = vfat.open("/A/F.TXT/..").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    let e = vfat.open("/MISSING/..").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_root_dir_source() {
+    use vfat::RootDirSource;
+
+    // A FAT16 layout has its root directory in a region after the FATs.
+    let mut image = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let boot = IMAGE_PARTITION_START as usize * 512;
+    image[boot + 17..boot + 19].copy_from_slice(&[0x00, 0x02]);
+    image[boot + 22..boot + 24].copy_from_slice(&[32, 0]);
+    let ebpb = BiosParameterBlock::from(Cursor::new(&mut image[..]), IMAGE_PARTITION_START)
+        .expect("valid EBPB");
+    let fat_start = IMAGE_PARTITION_START + ebpb.sectors_reserved() as u64;
+    let region = RootDirSource::Region {
+        start: fat_start + ebpb.fats_number() as u64 * 32,
+        sectors: 32,
+    };
+    assert_eq!(RootDirSource::from_bpb(&ebpb, IMAGE_PARTITION_START), region);
+
+    // A root directory read from a region lists the same entries as from its
+    // chain.
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/SUB", false).expect("create");
+    vfat.create_file("/A long name.txt").expect("create");
+    let names = |vfat: &Shared<VFat>| -> Vec<String> {
+        let root = vfat.open_dir("/").expect("open");
+        root.entries().expect("entries").map(|e| e.name().to_string()).collect()
+    };
+    let listed = names(&vfat);
+    assert_eq!(listed.len(), 2);
+    {
+        let mut vfat = vfat.borrow_mut();
+        let root = vfat.root_dir_cluster;
+        assert_eq!(vfat.root_dir, RootDirSource::Chain(root));
+        let sectors = vfat.cluster_sectors(root).expect("root sectors");
+        vfat.root_dir = RootDirSource::Region {
+            start: sectors.start,
+            sectors: sectors.end - sectors.start,
+        };
+        // The root's cluster chain is no longer read, so breaking it is
+        // harmless.
+        vfat.set_fat_entry(root, ::vfat::Status::Free).expect("free root");
+    }
+    assert_eq!(names(&vfat), listed);
+    assert!(vfat.open("/SUB").expect("open").as_dir().is_some());
+}
+
+#[test]
+fn test_verify_against_host() {
+    use std::fs;
+    use std::io::Write;
+    use vfat::Change;
+
+    let host = ::std::env::temp_dir().join(format!("fat32-verify-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&host);
+    fs::create_dir_all(host.join("overlays")).expect("host dirs");
+    let write = |path: &str, contents: &[u8]| {
+        fs::File::create(host.join(path)).and_then(|mut f| f.write_all(contents)).expect("write");
+    };
+    write("kernel.img", &[1; 3000]);
+    write("config.txt", b"arm_64bit=1");
+    write("overlays/uart.dtbo", b"uart");
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.copy_dir_from_host(&host, "/boot").expect("copy");
+    assert_eq!(vfat.verify_against_host("/boot", &host).expect("verify"), vec![]);
+
+    write("kernel.img", &[2; 3000]);
+    write("config.txt", b"arm_64bit=0\n");
+    write("cmdline.txt", b"console=serial0");
+    fs::remove_file(host.join("overlays/uart.dtbo")).expect("remove");
+    vfat.create_dir("/boot/dir/sub", true).expect("create");
+    write("dir", b"");
+
+    let changes = vfat.verify_against_host("/boot", &host).expect("verify");
+    fs::remove_dir_all(&host).expect("clean up");
+    assert_eq!(
+        changes,
+        vec![
+            Change::Added {
+                path: "/boot/cmdline.txt".into(),
+                is_dir: false,
+            },
+            Change::Changed {
+                path: "/boot/config.txt".into(),
+                size: Some((11, 12)),
+                modified: None,
+                contents: false,
+            },
+            Change::TypeChanged {
+                path: "/boot/dir".into(),
+            },
+            Change::Changed {
+                path: "/boot/kernel.img".into(),
+                size: None,
+                modified: None,
+                contents: true,
+            },
+            Change::Removed {
+                path: "/boot/overlays/uart.dtbo".into(),
+                is_dir: false,
+            },
+        ]
+    );
+    assert!(vfat.verify_against_host("/boot", "/nonexistent-fat32-host").is_err());
+}
+
+#[test]
+fn test_journal_create() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(!vfat.has_journal());
+    vfat.create_journal(16 * 1024).expect("create journal");
+    assert!(vfat.has_journal());
+    let e = vfat.create_journal(16 * 1024).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+
+    let journal = vfat.open("/JOURNAL.SYS").expect("open");
+    assert!(journal.metadata().hidden() && journal.metadata().system());
+    assert!(journal.metadata().read_only());
+    drop(vfat);
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+    assert!(vfat.has_journal());
+    assert!(vfat.check().expect("check").is_clean());
+
+    let options = FormatOptions {
+        sectors_per_cluster: 1,
+        ..FormatOptions::default()
+    };
+    let vfat = VFat::from(formatted_image(8192, &options)).expect("mount");
+    let e = vfat.create_journal(1024).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    assert!(vfat.open("/JOURNAL.SYS").is_err());
+}
+
+#[test]
+fn test_journal_crafted() {
+    use vfat::journal::fnv1a;
+
+    fn put_u32(buf: &mut [u8], val: u32) {
+        for i in 0..4 {
+            buf[i] = (val >> (i * 8)) as u8;
+        }
+    }
+
+    // A journal file too small to hold a transaction is ignored, rather than
+    // failing the first flush.
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        let mut journal = vec![0u8; 512];
+        journal[..8].copy_from_slice(b"FATJRNL1");
+        vfat.create_file("/JOURNAL.SYS").and_then(|mut f| f.write_all(&journal)).expect("write");
+    }
+    {
+        let mut data = image.borrow_mut();
+        let entry = data.windows(11).position(|w| w == b"JOURNAL SYS").expect("entry");
+        data[entry + 11] |= 0x04;
+    }
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(vfat.open("/JOURNAL.SYS").expect("open").metadata().system());
+    assert!(!vfat.has_journal());
+    vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(b"a")).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    drop(vfat);
+
+    // A committed transaction whose target lies outside of the volume, here
+    // the MBR, is discarded instead of replayed.
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    VFat::from(CrashDevice::new(&image, usize::max_value()))
+        .expect("mount")
+        .create_journal(16 * 1024)
+        .expect("create journal");
+    let mbr = image.borrow()[..512].to_vec();
+    {
+        let mut data = image.borrow_mut();
+        let header = (0..data.len() / 512)
+            .map(|sector| sector * 512)
+            .find(|&offset| &data[offset..offset + 8] == b"FATJRNL1")
+            .expect("journal header");
+        for byte in data[header + 512..header + 1536].iter_mut() {
+            *byte = 0xAB;
+        }
+        put_u32(&mut data[header + 512..], 0);
+        put_u32(&mut data[header + 516..], 0);
+        let hash = fnv1a(vec![&data[header + 512..header + 1024], &data[header + 1024..][..512]]);
+        put_u32(&mut data[header + 8..], 1);
+        put_u32(&mut data[header + 12..], 1);
+        put_u32(&mut data[header + 24..], hash);
+    }
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(vfat.has_journal());
+    assert_eq!(&image.borrow()[..512], &mbr[..]);
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_journal_crash_recovery() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_journal(64 * 1024).expect("create journal");
+        vfat.create_file("/OLD.TXT").and_then(|mut f| f.write_all(b"old")).expect("write");
+        vfat.borrow_mut().flush().expect("flush");
+    }
+    let base = image.borrow().clone();
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 199) as u8).collect();
+
+    // Crash after every possible number of writes, then check that the
+    // remounted volume has either all or none of the changes. The first
+    // writes mark the volume dirty as the directory is created; the rest are
+    // those of the flush.
+    let (mut before, mut after) = (0, 0);
+    for writes in 0.. {
+        let image = Shared::new(base.clone());
+        let vfat = VFat::from(CrashDevice::new(&image, writes)).expect("mount");
+        let done = vfat
+            .create_dir("/DIR", false)
+            .and_then(|_| vfat.create_file("/DIR/NEW.TXT"))
+            .and_then(|mut f| f.write_all(&data))
+            .and_then(|_| vfat.borrow_mut().flush())
+            .is_ok();
+        drop(vfat);
+
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+        let report = vfat.check().expect("check");
+        assert!(report.is_clean(), "{} writes: {:?}", writes, report.problems);
+        assert_eq!(read_all(vfat.open_file("/OLD.TXT").expect("old")), b"old");
+        match vfat.open_file("/DIR/NEW.TXT") {
+            Ok(file) => {
+                assert_eq!(read_all(file), data);
+                after += 1;
+            }
+            Err(_) => {
+                assert!(vfat.open("/DIR").is_err(), "{} writes", writes);
+                before += 1;
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+    assert!(before > 0 && after > 1);
+}
+
+#[test]
+fn test_faulty_device() {
+    use traits::{FaultMode, FaultyDevice};
+
+    let mut device = FaultyDevice::new(Cursor::new(vec![0u8; 512 * 4]), 2);
+    device.set_torn_bytes(100);
+    for n in 0..2 {
+        device.write_sector(n, &[1; 512]).expect("write");
+    }
+    assert!(!device.has_faulted());
+    assert!(device.write_sector(2, &[2; 512]).is_err());
+    assert!(device.write_sector(3, &[2; 512]).is_err() && device.sync().is_err());
+    assert!(device.has_faulted());
+    assert_eq!(device.written(), 2);
+    let mut buf = [0u8; 512];
+    device.read_sector(2, &mut buf).expect("read");
+    assert!(buf[..100].iter().all(|&b| b == 2) && buf[100..].iter().all(|&b| b == 0));
+    device.read_sector(3, &mut buf).expect("read");
+    assert!(buf.iter().all(|&b| b == 0));
+
+    let mut device = FaultyDevice::new(Cursor::new(vec![0u8; 512 * 4]), 0);
+    device.set_mode(FaultMode::Drop);
+    assert_eq!(device.write_sector(0, &[1; 512]).expect("write"), 512);
+    device.sync().expect("sync");
+    assert!(device.into_inner().into_inner().iter().all(|&b| b == 0));
+}
+
+/// Returns the number of sector writes `op` makes to a copy of `base`,
+/// counted by failing after more and more writes until `op` succeeds.
+fn writes_made<F>(base: &[u8], op: F) -> u64
+where
+    F: Fn(&Shared<VFat>) -> ::std::io::Result<()>,
+{
+    use traits::FaultyDevice;
+
+    for limit in 0.. {
+        let image = Shared::new(base.to_vec());
+        let device = FaultyDevice::new(CrashDevice::new(&image, usize::max_value()), limit);
+        let vfat = VFat::from(device).expect("mount");
+        if op(&vfat).is_ok() {
+            return limit;
+        }
+    }
+    unreachable!()
+}
+
+#[test]
+fn test_flush_order_with_lost_writes() {
+    use traits::{FaultMode, FaultyDevice};
+    use vfat::Problem;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_file("/OLD.TXT").and_then(|mut f| f.write_all(b"old")).expect("write");
+    }
+    let base = image.borrow().clone();
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 199) as u8).collect();
+    let op = |vfat: &Shared<VFat>| {
+        vfat.create_dir("/DIR", false)?;
+        vfat.create_file("/DIR/NEW.TXT")?.write_all(&data)?;
+        vfat.borrow_mut().sync()
+    };
+    let total = writes_made(&base, &op);
+
+    // The device silently drops every write after the limit, as one losing
+    // power would. Whatever was lost, the volume has at worst lost clusters
+    // and never an entry referring to clusters that weren't written.
+    let mut lost = 0;
+    for limit in 0..total + 1 {
+        let image = Shared::new(base.clone());
+        let mut device = FaultyDevice::new(CrashDevice::new(&image, usize::max_value()), limit);
+        device.set_mode(FaultMode::Drop);
+        op(&VFat::from(device).expect("mount")).expect("op");
+
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+        for problem in vfat.check().expect("check").problems {
+            match problem {
+                Problem::LostClusters { .. } => lost += 1,
+                problem => panic!("{} writes: {:?}", limit, problem),
+            }
+        }
+        assert_eq!(read_all(vfat.open_file("/OLD.TXT").expect("old")), b"old");
+        if let Ok(file) = vfat.open_file("/DIR/NEW.TXT") {
+            assert_eq!(read_all(file), data, "{} writes", limit);
+        }
+    }
+    assert!(lost > 0);
+}
+
+#[test]
+fn test_journal_torn_writes() {
+    use traits::FaultyDevice;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_journal(64 * 1024).expect("create journal");
+        vfat.create_file("/OLD.TXT").and_then(|mut f| f.write_all(b"old")).expect("write");
+    }
+    let base = image.borrow().clone();
+    // A rename is written in two steps, each atomic with a journal, but in a
+    // transaction, the whole operation is committed to the journal at once.
+    let op = |vfat: &Shared<VFat>| {
+        vfat.transaction(|tx| {
+            tx.create_dir("/DIR", false)?;
+            tx.write("/DIR/NEW.TXT", &[7; 3000])?;
+            tx.rename("/OLD.TXT", "/DIR/OLD.TXT")
+        })
+    };
+    let total = writes_made(&base, &op);
+
+    // Whichever sector write is torn, and wherever in the sector, the
+    // remounted volume is consistent and has either all or none of the
+    // changes.
+    let (mut before, mut after) = (0, 0);
+    for limit in 0..total {
+        for &torn in &[1, 16, 300, 511] {
+            let image = Shared::new(base.clone());
+            let device = CrashDevice::new(&image, usize::max_value());
+            let mut device = FaultyDevice::new(device, limit);
+            device.set_torn_bytes(torn);
+            assert!(op(&VFat::from(device).expect("mount")).is_err());
+
+            let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+            let report = vfat.check().expect("check");
+            assert!(report.is_clean(), "{} writes, {} bytes: {:?}", limit, torn, report.problems);
+            match vfat.open_file("/DIR/NEW.TXT") {
+                Ok(file) => {
+                    assert_eq!(read_all(file), vec![7; 3000]);
+                    assert!(vfat.open("/OLD.TXT").is_err());
+                    assert_eq!(read_all(vfat.open_file("/DIR/OLD.TXT").expect("old")), b"old");
+                    after += 1;
+                }
+                Err(_) => {
+                    assert!(vfat.open("/DIR").is_err(), "{} writes, {} bytes", limit, torn);
+                    assert_eq!(read_all(vfat.open_file("/OLD.TXT").expect("old")), b"old");
+                    before += 1;
+                }
+            }
+        }
+    }
+    assert!(before > 0 && after > 0);
+}
+
+/// A device backed by an in-memory image that records every write and sync
+/// made to it, with a sync recorded as `None`, and every discard.
+struct RecordingDevice {
+    image: Cursor<Vec<u8>>,
+    log: Shared<Vec<Option<u64>>>,
+    discarded: Shared<Vec<(u64, u64)>>,
+}
+
+impl RecordingDevice {
+    fn new(image: Cursor<Vec<u8>>) -> RecordingDevice {
+        RecordingDevice {
+            image,
+            log: Shared::new(Vec::new()),
+            discarded: Shared::new(Vec::new()),
+        }
+    }
+}
+
+/// Returns the first sector of the FAT and the first data sector of the
+/// volume in `image`.
+fn image_layout(image: &mut Cursor<Vec<u8>>) -> (u64, u64) {
+    let device = Cursor::new(&mut image.get_mut()[..]);
+    let ebpb = BiosParameterBlock::from(device, IMAGE_PARTITION_START).expect("valid EBPB");
+    let fat_start = IMAGE_PARTITION_START + ebpb.sectors_reserved() as u64;
+    (fat_start, fat_start + ebpb.fats_number() as u64 * ebpb.sectors_per_fat() as u64)
+}
+
+impl BlockDevice for RecordingDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.image.read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        self.log.borrow_mut().push(Some(n));
+        self.image.write_sector(n, buf)
+    }
+
+    fn sync(&mut self) -> ::std::io::Result<()> {
+        self.log.borrow_mut().push(None);
+        Ok(())
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> ::std::io::Result<()> {
+        self.discarded.borrow_mut().push((n, count));
+        Ok(())
+    }
+}
+
+/// A device backed by an in-memory image that records the first sector and
+/// length in bytes of every multi-sector read made with `read_sectors()`.
+struct RunRecordingDevice {
+    image: Cursor<Vec<u8>>,
+    runs: Shared<Vec<(u64, usize)>>,
+}
+
+impl BlockDevice for RunRecordingDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.image.read_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.runs.borrow_mut().push((n, buf.len()));
+        self.image.read_sectors(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        self.image.write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_coalesced_reads() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let data: Vec<u8> = (0..40 * cluster_size as u32).map(|i| (i % 251) as u8).collect();
+    vfat.create_file("/BIG.BIN").and_then(|mut f| f.write_all(&data)).expect("write");
+    // Interleave two files so that their chains are fragmented.
+    vfat.create_file("/A").expect("create");
+    vfat.create_file("/B").expect("create");
+    for _ in 0..3 {
+        for name in &["/A", "/B"] {
+            let mut file = vfat.open_file(name).expect("open");
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&vec![name.as_bytes()[1]; 2 * cluster_size]).expect("write");
+        }
+    }
+    vfat.borrow_mut().flush().expect("flush");
+
+    let runs = Shared::new(Vec::new());
+    let device = RunRecordingDevice {
+        image: Cursor::new(image.borrow().clone()),
+        runs: runs.clone(),
+    };
+    let vfat = VFat::from(device).expect("mount");
+    let mut file = vfat.open_file("/BIG.BIN").expect("open");
+    runs.borrow_mut().clear();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).expect("read");
+    assert_eq!(contents, data);
+    assert_eq!(runs.borrow().len(), 1);
+    assert_eq!(runs.borrow()[0].1, data.len());
+
+    // Sectors read once stay cached.
+    file.seek(SeekFrom::Start(cluster_size as u64 / 2)).unwrap();
+    runs.borrow_mut().clear();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).expect("read");
+    assert_eq!(&contents[..], &data[cluster_size / 2..]);
+    assert!(runs.borrow().is_empty());
+
+    runs.borrow_mut().clear();
+    let mut contents = Vec::new();
+    vfat.open_file("/A").and_then(|mut f| f.read_to_end(&mut contents)).expect("read");
+    assert_eq!(contents, vec![b'A'; 6 * cluster_size]);
+    assert_eq!(runs.borrow().len(), 3);
+    assert!(runs.borrow().iter().all(|&(_, len)| len == 2 * cluster_size));
+}
+
+#[test]
+fn test_sync_order() {
+    let mut image = formatted_image(8192, &FormatOptions::default());
+    let (fat_start, data_start) = image_layout(&mut image);
+    let device = RecordingDevice::new(image);
+    let log = device.log.clone();
+    let vfat = VFat::from(device).expect("mount");
+    let mut file = vfat.create_file("/F.BIN").expect("create");
+    let fats = [Some(fat_start), Some((fat_start + data_start) / 2)];
+    assert_eq!(&log.borrow()[..], fats);
+    file.write_all(&[7; 3000]).expect("write");
+    log.borrow_mut().clear();
+    file.sync().expect("sync");
+
+    // The volume was marked dirty by the first change. Its mark is synced,
+    // then the contents, the FAT, and the directory entry are written, each
+    // group synced before the next is written.
+    let writes = log.borrow().clone();
+    let groups: Vec<&[Option<u64>]> = writes.split(|write| write.is_none()).collect();
+    assert_eq!(groups.len(), 5);
+    assert!(groups[0].is_empty());
+    assert!(groups[4].is_empty());
+    let is_fat = |&s: &Option<u64>| s.unwrap() >= fat_start && s.unwrap() < data_start;
+    assert!(groups[1].len() >= 6);
+    assert!(!groups[1].iter().any(&is_fat));
+    assert!(!groups[2].is_empty());
+    assert!(groups[2].iter().all(&is_fat));
+    assert_eq!(groups[3].len(), 1);
+    assert!(groups[3][0].unwrap() >= data_start);
+    assert!(groups[1].iter().all(|&s| s != groups[3][0]));
+
+    // Nothing is left to write.
+    log.borrow_mut().clear();
+    file.sync().expect("sync");
+    assert!(log.borrow().is_empty());
+}
+
+#[test]
+fn test_discard_freed_clusters() {
+    let mut image = formatted_image(8192, &FormatOptions::default());
+    let (_, data_start) = image_layout(&mut image);
+    let device = RecordingDevice::new(image);
+    let discarded = device.discarded.clone();
+    let vfat = VFat::from(device).expect("mount");
+
+    let mut file = vfat.create_file("/OLD.BIN").expect("create");
+    file.write_all(&[1; 9000]).expect("write");
+    let extents = file.extents().expect("extents");
+    drop(file);
+    vfat.create_file("/NEW.BIN").and_then(|mut f| f.write_all(&[2; 10])).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert!(discarded.borrow().is_empty());
+
+    // The three clusters of the replaced file are discarded as one run.
+    vfat.replace("/NEW.BIN", "/OLD.BIN").expect("replace");
+    assert_eq!(extents.len(), 1);
+    let first = data_start + (extents[0].start.fat_index() as u64 - 2) * 8;
+    assert_eq!(*discarded.borrow(), [(first, 24)]);
+
+    discarded.borrow_mut().clear();
+    vfat.borrow_mut().flush().expect("flush");
+    assert!(discarded.borrow().is_empty());
+    assert_eq!(read_all(vfat.open_file("/OLD.BIN").expect("open")), vec![2; 10]);
+}
+
+#[test]
+fn test_free_bitmap() {
+    let vfat = fragmented_image();
+    vfat.create_file("/X.BIN").and_then(|mut f| f.write_all(&[1; 5000])).expect("write");
+    vfat.replace("/B.BIN", "/C.BIN").expect("replace");
+
+    // Filling the volume wraps around to the clusters freed by `replace()`.
+    let cluster_size = vfat.borrow().cluster_size();
+    let mut file = vfat.create_file("/FILL.BIN").expect("create");
+    loop {
+        if let Err(e) = file.write(&vec![2; cluster_size]) {
+            assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+            break;
+        }
+    }
+    let extents = file.extents().expect("extents");
+    assert_eq!(extents.last().map(|e| (e.start, e.len)), Some((Cluster::from(8), 1)));
+    assert_eq!(extents[extents.len() - 2].start, Cluster::from(6));
+
+    let mut vfat = vfat.borrow_mut();
+    let free = vfat.free_map().expect("free map");
+    assert!(free.iter().all(|&free| !free));
+    let from_fat: Vec<bool> = (0..free.len() as u32)
+        .map(|i| vfat.fat_entry(Cluster::from(i)).unwrap().status() == ::vfat::Status::Free)
+        .collect();
+    assert_eq!(free, from_fat);
+}
+
+#[test]
+fn test_alloc_policy() {
+    use vfat::AllocPolicy;
+
+    let extents = |vfat: &Shared<VFat>, path: &str, clusters: usize| {
+        let mut file = vfat.create_file(path).expect("create");
+        let cluster_size = vfat.borrow().cluster_size();
+        file.write_all(&vec![1; clusters * cluster_size]).expect("write");
+        let extents = file.extents().expect("extents");
+        extents.iter().map(|e| (e.start.fat_index(), e.len)).collect::<Vec<_>>()
+    };
+
+    // Clusters 6 and 8 are freed, leaving 6 and everything from 8 on free.
+    let vfat = fragmented_image();
+    vfat.replace("/B.BIN", "/C.BIN").expect("replace");
+    assert_eq!(vfat.borrow().alloc_policy(), AllocPolicy::NextFit);
+
+    vfat.borrow_mut().set_alloc_policy(AllocPolicy::Contiguous);
+    assert_eq!(extents(&vfat, "/VIDEO.BIN", 3), [(8, 3)]);
+    vfat.borrow_mut().set_alloc_policy(AllocPolicy::FirstFit);
+    assert_eq!(extents(&vfat, "/SMALL.BIN", 3), [(6, 1), (11, 2)]);
+    vfat.borrow_mut().set_alloc_policy(AllocPolicy::NextFit);
+    assert_eq!(extents(&vfat, "/NEXT.BIN", 1), [(13, 1)]);
+
+    // Next-fit allocation starts from the FSInfo hint.
+    let mut image = formatted_image(8192, &FormatOptions::default());
+    let hint = (IMAGE_PARTITION_START as usize + 1) * 512 + 492;
+    image.get_mut()[hint..hint + 4].copy_from_slice(&[100, 0, 0, 0]);
+    let vfat = VFat::from(image).expect("mount");
+    assert_eq!(extents(&vfat, "/A.BIN", 2), [(100, 2)]);
+}
+
+#[test]
+fn test_preallocate() {
+    let vfat = fragmented_image();
+    let cluster_size = vfat.borrow().cluster_size();
+    let runs = |file: &::vfat::File| -> Vec<(u32, u32)> {
+        let extents = file.extents().expect("extents");
+        extents.iter().map(|e| (e.start.fat_index(), e.len)).collect()
+    };
+
+    // The gap at cluster 9 is too short, so the whole run goes after it.
+    let mut file = vfat.create_file("/VIDEO.BIN").expect("create");
+    vfat.create_file("/GAP.BIN").and_then(|mut f| f.write_all(&[0; 1])).expect("write");
+    vfat.create_file("/X.BIN").and_then(|mut f| f.write_all(&[0; 1])).expect("write");
+    vfat.replace("/X.BIN", "/GAP.BIN").expect("replace");
+    file.preallocate(4 * cluster_size as u64).expect("preallocate");
+    assert_eq!(runs(&file), [(11, 4)]);
+    assert_eq!(file.size, 0);
+    assert!(!vfat.check().expect("check").is_clean());
+
+    // Writes fill the reserved clusters, and growing the reservation extends
+    // the run in place.
+    file.write_all(&vec![7; 3 * cluster_size]).expect("write");
+    file.preallocate(6 * cluster_size as u64 - 1).expect("preallocate");
+    file.preallocate(cluster_size as u64).expect("preallocate");
+    assert_eq!(runs(&file), [(11, 6)]);
+    file.write_all(&vec![8; 3 * cluster_size]).expect("write");
+    assert_eq!(runs(&file), [(11, 6)]);
+    assert!(vfat.check().expect("check").is_clean());
+
+    let mut other = vfat.create_file("/OTHER.BIN").expect("create");
+    other.write_all(&[1; 10]).expect("write");
+    other.preallocate(3 * cluster_size as u64).expect("preallocate");
+    other.release_preallocated().expect("release");
+    assert_eq!(runs(&other).iter().map(|r| r.1).sum::<u32>(), 1);
+    let mut empty = vfat.create_file("/EMPTY.BIN").expect("create");
+    empty.preallocate(1).expect("preallocate");
+    empty.release_preallocated().expect("release");
+    assert!(runs(&empty).is_empty());
+    assert!(vfat.check().expect("check").is_clean());
+
+    let e = empty.preallocate(1 << 31).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    let e = empty.preallocate(1 << 32).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_read_only_mount() {
+    use vfat::MountOptions;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(b"hello")).expect("write");
+        vfat.borrow_mut().flush().expect("flush");
+    }
+
+    // Any write to the device would fail with a different error.
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let vfat = VFat::from_with_options(CrashDevice::new(&image, 0), &options).expect("mount");
+    assert!(vfat.borrow().is_read_only());
+    assert_eq!(read_all(vfat.open_file("/A.TXT").expect("open")), b"hello");
+
+    let denied = |result: ::std::io::Result<()>| {
+        assert_eq!(result.unwrap_err().kind(), ::std::io::ErrorKind::PermissionDenied);
+    };
+    let mut file = vfat.open_file("/A.TXT").expect("open");
+    denied(file.write_all(b"x"));
+    drop(file);
+    denied(vfat.create_file("/B.TXT").map(|_| ()));
+    denied(vfat.create_dir("/D", false).map(|_| ()));
+    denied(vfat.rename("/A.TXT", "/C.TXT"));
+    denied(vfat.create_journal(1 << 16));
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(read_all(vfat.open_file("/A.TXT").expect("open")), b"hello");
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_volume_flags() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let flags = |image: &Shared<Vec<u8>>| {
+        let vfat = VFat::from(CrashDevice::new(image, usize::max_value())).expect("mount");
+        let vfat = vfat.borrow();
+        (vfat.was_dirty(), vfat.has_hard_error())
+    };
+    assert_eq!(flags(&image), (false, false));
+
+    // A flush that is cut short leaves the volume marked dirty.
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, 4)).expect("mount");
+        vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(b"a")).expect("write");
+        assert!(vfat.borrow_mut().flush().is_err());
+    }
+    assert_eq!(flags(&image), (true, false));
+
+    // Unmounting marks it clean again.
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(vfat.borrow().was_dirty());
+    vfat.create_file("/B.TXT").and_then(|mut f| f.write_all(b"b")).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    vfat.borrow_mut().set_hard_error(true).expect("set");
+    assert!(vfat.borrow().has_hard_error());
+    vfat.unmount().expect("unmount");
+    assert_eq!(flags(&image), (false, true));
+
+    // The first change marks the volume dirty, and a flush leaves it so, as
+    // the volume may be removed before it is unmounted.
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert_eq!(read_all(vfat.open_file("/B.TXT").expect("open")), b"b");
+    assert_eq!(flags(&image).0, false);
+    vfat.create_file("/C.TXT").expect("create");
+    assert_eq!(flags(&image).0, true);
+    vfat.borrow_mut().flush().expect("flush");
+    let unplugged = Shared::new(image.borrow().clone());
+    assert_eq!(flags(&unplugged), (true, true));
+
+    // Dropping the volume marks it clean as unmounting does.
+    drop(vfat);
+    assert_eq!(flags(&image), (false, true));
+}
+
+#[test]
+fn test_unmount() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let fsinfo = |image: &Shared<Vec<u8>>| {
+        let base = (IMAGE_PARTITION_START as usize + 1) * 512;
+        let data = image.borrow();
+        let get = |offset| (0..4).fold(0u32, |val, i| val | (data[offset + i] as u32) << (i * 8));
+        (get(base + 488), get(base + 492))
+    };
+    let (initial_free, _) = fsinfo(&image);
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let mut file = vfat.create_file("/A.TXT").expect("create");
+    file.write_all(&vec![7u8; cluster_size * 3]).expect("write");
+    let err = vfat.clone().unmount().unwrap_err();
+    assert_eq!(err.kind(), ::std::io::ErrorKind::Other);
+    drop(file);
+    vfat.unmount().expect("unmount");
+
+    let (free, next_free) = fsinfo(&image);
+    assert_eq!(free, initial_free - 3);
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+    assert!(!vfat.borrow().was_dirty());
+    assert!(next_free >= 2 && next_free < vfat.borrow().data_clusters() + 2);
+    assert_eq!(read_all(vfat.open_file("/A.TXT").expect("open")).len(), cluster_size * 3);
+
+    // Dropping the last handle writes back what wasn't flushed.
+    vfat.create_file("/B.TXT").and_then(|mut f| f.write_all(b"b")).expect("write");
+    drop(vfat);
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+    assert!(!vfat.borrow().was_dirty());
+    assert_eq!(read_all(vfat.open_file("/B.TXT").expect("open")), b"b");
+    assert_eq!(fsinfo(&image).0, initial_free - 4);
+}
+
+/// A clock that is always at the same time.
+#[derive(Debug)]
+struct FixedClock(::vfat::Timestamp);
+
+impl ::vfat::Clock for FixedClock {
+    fn now(&self) -> ::vfat::Timestamp {
+        self.0
+    }
+}
+
+#[test]
+fn test_clock() {
+    use std::sync::Arc;
+    use vfat::{MountOptions, Timestamp};
+
+    let (first, second) = (
+        Timestamp::from_unix_seconds(1_000_000_000),
+        Timestamp::from_unix_seconds(1_200_000_000),
+    );
+    let options = MountOptions {
+        clock: Some(Arc::new(FixedClock(first))),
+        ..MountOptions::default()
+    };
+    let vfat = VFat::from_with_options(formatted_image(8192, &FormatOptions::default()), &options)
+        .expect("mount");
+    assert_eq!(vfat.borrow().now(), first);
+    let mut file = vfat.create_file("/A.TXT").expect("create");
+    let dir = vfat.create_dir("/D", false).expect("create");
+    assert_eq!((file.metadata.created, file.metadata.modified), (first, first));
+    assert_eq!(dir.metadata().created, first);
+
+    vfat.borrow_mut().set_clock(Arc::new(FixedClock(second)));
+    file.write_all(b"later").expect("write");
+    assert_eq!((file.metadata.created, file.metadata.modified), (first, second));
+    let reopened = vfat.open_file("/A.TXT").expect("open");
+    assert_eq!(reopened.metadata.modified, second);
+}
+
+#[test]
+fn test_update_accessed() {
+    use std::sync::Arc;
+    use vfat::{MountOptions, Timestamp};
+
+    let (first, second) = (
+        Timestamp::from_unix_seconds(1_000_000_000),
+        Timestamp::from_unix_seconds(1_000_100_000),
+    );
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let mount = |update_accessed| {
+        let options = MountOptions {
+            clock: Some(Arc::new(FixedClock(second))),
+            update_accessed,
+            ..MountOptions::default()
+        };
+        let device = CrashDevice::new(&image, usize::max_value());
+        VFat::from_with_options(device, &options).expect("mount")
+    };
+    let accessed = |vfat: &Shared<VFat>| {
+        vfat.open_file("/A.TXT").expect("open").metadata.accessed.date
+    };
+
+    {
+        let vfat = mount(false);
+        vfat.borrow_mut().set_clock(Arc::new(FixedClock(first)));
+        vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(b"a")).expect("write");
+        vfat.borrow_mut().set_clock(Arc::new(FixedClock(second)));
+        assert_eq!(read_all(vfat.open_file("/A.TXT").expect("open")), b"a");
+        vfat.borrow_mut().flush().expect("flush");
+        assert_eq!(accessed(&vfat), first.date);
+    }
+
+    let vfat = mount(true);
+    let mut file = vfat.open_file("/A.TXT").expect("open");
+    file.read(&mut [0; 1]).expect("read");
+    assert_eq!(file.metadata.accessed.date, second.date);
+    // The date is only written by the next flush.
+    assert_eq!(accessed(&vfat), first.date);
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(accessed(&vfat), second.date);
+    assert_eq!(file.metadata.accessed.time, Default::default());
+    drop(file);
+    drop(vfat);
+    assert_eq!(accessed(&mount(false)), second.date);
+}
+
+#[test]
+fn test_statvfs() {
+    use vfat::MountOptions;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    let stat = vfat.borrow_mut().statvfs().expect("statvfs");
+    let (cluster_size, data_clusters) = {
+        let vfat = vfat.borrow();
+        (vfat.cluster_size(), vfat.data_clusters())
+    };
+    assert_eq!(stat.block_size as usize, cluster_size);
+    assert_eq!(stat.blocks, data_clusters as u64);
+    assert_eq!(stat.free_blocks, stat.blocks - 1);
+    assert_eq!(stat.available_blocks, stat.free_blocks);
+    assert_eq!(stat.max_name_len, 255);
+    assert!(!stat.read_only && !stat.dirty);
+
+    let mut file = vfat.create_file("/A.TXT").expect("create");
+    file.write_all(&vec![0; cluster_size * 3]).expect("write");
+    assert_eq!(vfat.borrow_mut().statvfs().expect("statvfs").free_blocks, stat.blocks - 4);
+    drop(file);
+    vfat.unmount().expect("unmount");
+
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let device = CrashDevice::new(&image, usize::max_value());
+    let vfat = VFat::from_with_options(device, &options).expect("mount");
+    let stat = vfat.borrow_mut().statvfs().expect("statvfs");
+    assert_eq!((stat.free_blocks, stat.available_blocks), (stat.blocks - 4, 0));
+    assert!(stat.read_only);
+}
+
+#[test]
+fn test_cached_du() {
+    use std::io::SeekFrom;
+    use vfat::MountOptions;
+
+    let options = MountOptions {
+        cache_dir_usage: true,
+        ..MountOptions::default()
+    };
+    let image = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let vfat = VFat::from_with_options(Cursor::new(image), &options).expect("mount");
+    vfat.create_dir("/SUB/DEEP", true).expect("create");
+    vfat.create_dir("/OTHER", false).expect("create");
+    vfat.create_file("/SUB/DEEP/A.TXT").expect("create").write_all(&[1; 700]).expect("write");
+    vfat.create_file("/OTHER/B.TXT").expect("create").write_all(&[2; 10]).expect("write");
+
+    let walked = |vfat: &Shared<VFat>, path: &str| {
+        vfat.borrow_mut().set_dir_usage_cache(false);
+        let usage = vfat.du(path).expect("du");
+        vfat.borrow_mut().set_dir_usage_cache(true);
+        usage
+    };
+    let usage = vfat.du("/").expect("du");
+    assert_eq!((usage.files, usage.dirs, usage.bytes), (2, 4, 710));
+    assert_eq!(usage, walked(&vfat, "/"));
+    vfat.du("/").expect("du");
+    assert_eq!(vfat.borrow().dir_usage.as_ref().map(|cache| cache.len()), Some(4));
+    assert_eq!(vfat.du("/").expect("du"), usage);
+    assert_eq!(vfat.du("/SUB").expect("du"), walked(&vfat, "/SUB"));
+
+    // Growing a file drops the usage of its directory and its ancestors only.
+    vfat.du("/").expect("du");
+    let mut file = vfat.open_file("/SUB/DEEP/A.TXT").expect("open");
+    file.seek(SeekFrom::End(0)).expect("seek");
+    file.write_all(&[3; 2000]).expect("write");
+    drop(file);
+    assert_eq!(vfat.borrow().dir_usage.as_ref().map(|cache| cache.len()), Some(1));
+    assert_eq!(vfat.du("/").expect("du").bytes, 2710);
+    assert_eq!(vfat.du("/").expect("du"), walked(&vfat, "/"));
+
+    vfat.du("/").expect("du");
+    vfat.rename("/SUB/DEEP/A.TXT", "/OTHER/A.TXT").expect("rename");
+    assert_eq!(vfat.du("/OTHER").expect("du").bytes, 2710);
+    assert_eq!(vfat.du("/SUB").expect("du"), walked(&vfat, "/SUB"));
+    assert_eq!(vfat.du("/").expect("du"), walked(&vfat, "/"));
+}
+
+#[test]
+fn test_watch() {
+    use std::path::PathBuf;
+    use vfat::WatchEvent;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/SUB", false).expect("create");
+    vfat.create_file("/SUB/B.TXT").expect("create");
+    let root = vfat.watch("/").expect("watch");
+    let sub = vfat.watch("/SUB").expect("watch");
+    let file = vfat.watch("/A.TXT");
+    assert_eq!(file.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+    let path = |path: &str| PathBuf::from(path);
+    vfat.create_file("/A.TXT").expect("create").write_all(b"hello").expect("write");
+    let file = vfat.watch("/A.TXT").expect("watch");
+    vfat.create_file("/C.TXT").expect("create").write_all(b"hello").expect("write");
+    vfat.open_file("/A.TXT").expect("open").write_all(b"j").expect("write");
+    vfat.rename("/A.TXT", "/D.TXT").expect("rename");
+    vfat.rename("/D.TXT", "/SUB/D.TXT").expect("rename");
+    vfat.replace("/C.TXT", "/SUB/B.TXT").expect("replace");
+    vfat.create_dir("/SUB/DEEP", false).expect("create");
+    vfat.create_file("/SUB/DEEP/E.TXT").expect("create");
+
+    assert_eq!(
+        root.try_iter().collect::<Vec<_>>(),
+        vec![
+            WatchEvent::Created(path("/A.TXT")),
+            WatchEvent::Modified(path("/A.TXT")),
+            WatchEvent::Created(path("/C.TXT")),
+            WatchEvent::Modified(path("/C.TXT")),
+            WatchEvent::Modified(path("/A.TXT")),
+            WatchEvent::Renamed {
+                from: path("/A.TXT"),
+                to: path("/D.TXT"),
+            },
+            WatchEvent::Removed(path("/D.TXT")),
+            WatchEvent::Removed(path("/C.TXT")),
+        ]
+    );
+    assert_eq!(
+        sub.try_iter().collect::<Vec<_>>(),
+        vec![
+            WatchEvent::Created(path("/SUB/D.TXT")),
+            WatchEvent::Modified(path("/SUB/B.TXT")),
+            WatchEvent::Created(path("/SUB/DEEP")),
+        ]
+    );
+    assert_eq!(
+        file.try_iter().collect::<Vec<_>>(),
+        vec![
+            WatchEvent::Modified(path("/A.TXT")),
+            WatchEvent::Renamed {
+                from: path("/A.TXT"),
+                to: path("/D.TXT"),
+            },
+        ]
+    );
+
+    // A watcher is removed by the first change it would be sent after its
+    // receiver was dropped.
+    drop(sub);
+    vfat.create_file("/SUB/F.TXT").expect("create");
+    assert_eq!(vfat.borrow().watchers.len(), 2);
+    assert!(root.try_recv().is_err());
+}
+
+#[test]
+fn test_transaction() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    vfat.transaction(|tx| tx.write("/OLD.TXT", b"old")).expect("transaction");
+    let free = vfat.borrow_mut().statvfs().expect("statvfs").free_blocks;
+
+    // Nothing reaches the device before the transaction is committed.
+    let before = image.borrow().clone();
+    vfat.transaction(|tx| {
+        tx.create_dir("/SUB/DEEP", true)?;
+        tx.write("/SUB/DEEP/A.TXT", &[1; 3000])?;
+        tx.write("/OLD.TXT", b"new")?;
+        tx.rename("/OLD.TXT", "/SUB/B.TXT")?;
+        assert!(vfat.transaction(|_| Ok(())).is_err());
+        assert!(*image.borrow() == before);
+        Ok(())
+    })
+    .expect("transaction");
+    assert!(*image.borrow() != before);
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert_eq!(read_all(vfat.open_file("/SUB/DEEP/A.TXT").expect("open")), vec![1; 3000]);
+    assert_eq!(read_all(vfat.open_file("/SUB/B.TXT").expect("open")), b"new".to_vec());
+    assert!(vfat.open("/OLD.TXT").is_err());
+    assert!(vfat.check().expect("check").is_clean());
+    let stat = vfat.borrow_mut().statvfs().expect("statvfs");
+    let clusters = (3000 + stat.block_size as u64 - 1) / stat.block_size as u64;
+    let free = free - 2 - clusters;
+    assert_eq!(stat.free_blocks, free);
+
+    // A transaction that fails leaves the volume as it was.
+    let before = image.borrow().clone();
+    let result: ::std::io::Result<()> = vfat.transaction(|tx| {
+        tx.create_dir("/NEW", false)?;
+        tx.write("/NEW/C.TXT", &[2; 5000])?;
+        tx.rename("/SUB/B.TXT", "/NEW/B.TXT")?;
+        tx.volume().open_file("/MISSING.TXT").map(|_| ())
+    });
+    assert!(result.is_err());
+    assert!(vfat.open("/NEW").is_err());
+    assert!(vfat.open("/SUB/B.TXT").is_ok());
+    assert_eq!(vfat.borrow_mut().statvfs().expect("statvfs").free_blocks, free);
+    vfat.create_file("/D.TXT").expect("create").write_all(&[3; 700]).expect("write");
+    assert!(vfat.check().expect("check").is_clean());
+    vfat.unmount().expect("unmount");
+    assert!(*image.borrow() != before);
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(vfat.check().expect("check").is_clean());
+    assert!(vfat.open("/NEW").is_err());
+}
+
+#[test]
+fn test_transaction_panic_and_threads() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::mpsc;
+    use std::thread;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+
+    // A panic inside a transaction discards its changes, and later flushes
+    // write again.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        vfat.transaction(|tx| -> ::std::io::Result<()> {
+            tx.write("/LOST.TXT", b"lost")?;
+            panic!("inside transaction");
+        })
+    }));
+    assert!(result.is_err());
+    vfat.create_file("/KEPT.TXT").and_then(|mut f| f.write_all(b"kept")).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    {
+        let remounted = VFat::from(Cursor::new(image.borrow().clone())).expect("remount");
+        assert!(remounted.open("/LOST.TXT").is_err());
+        assert_eq!(read_all(remounted.open_file("/KEPT.TXT").expect("open")), b"kept");
+    }
+
+    // Another thread can neither flush nor change the volume while a
+    // transaction is in progress, so a discarded transaction takes none of
+    // their changes with it.
+    let (start, started) = mpsc::channel();
+    let (done, wait) = mpsc::channel();
+    let other = {
+        let vfat = vfat.clone();
+        thread::spawn(move || {
+            started.recv().expect("started");
+            let flushed = vfat.borrow_mut().flush().map_err(|e| e.kind());
+            let created = vfat.create_file("/OTHER.TXT").map(|_| ()).map_err(|e| e.kind());
+            let read = read_all(vfat.open_file("/KEPT.TXT").expect("open"));
+            done.send(()).expect("done");
+            (flushed, created, read)
+        })
+    };
+    let result = vfat.transaction(|tx| {
+        tx.write("/TX.TXT", b"tx")?;
+        start.send(()).expect("start");
+        wait.recv().expect("wait");
+        Err::<(), _>(::std::io::Error::new(::std::io::ErrorKind::Other, "abort"))
+    });
+    assert!(result.is_err());
+    let (flushed, created, read) = other.join().expect("join");
+    assert_eq!(flushed, Err(::std::io::ErrorKind::WouldBlock));
+    assert_eq!(created, Err(::std::io::ErrorKind::WouldBlock));
+    assert_eq!(read, b"kept");
+    assert!(vfat.open("/TX.TXT").is_err() && vfat.open("/OTHER.TXT").is_err());
+    vfat.create_file("/OTHER.TXT").expect("create after transaction");
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_compare_and_repair_fats() {
+    use vfat::FatMismatch;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let (cluster, fats) = {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        let mut file = vfat.create_file("/A.TXT").expect("create");
+        file.write_all(&[1; 2048]).expect("write");
+        vfat.borrow_mut().flush().expect("flush");
+        let vfat = vfat.borrow();
+        (file.cluster, vec![vfat.fat_sectors(0), vfat.fat_sectors(1)])
+    };
+    assert_eq!(fats.len(), 2);
+
+    // Clear the entry of the file's first cluster in the first FAT only, as a
+    // write interrupted between the copies would.
+    let offset = fats[0].start as usize * 512 + cluster.fat_index() as usize * 4;
+    let value = image.borrow()[offset..offset + 4].to_vec();
+    for byte in &mut image.borrow_mut()[offset..offset + 4] {
+        *byte = 0;
+    }
+    let next = (0..4).fold(0u32, |val, i| val | (value[i] as u32) << (i * 8));
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    let mismatches = vfat.borrow_mut().compare_fats().expect("compare");
+    assert_eq!(
+        mismatches,
+        vec![FatMismatch {
+            cluster,
+            values: vec![0, next],
+        }]
+    );
+    assert!(!vfat.check().expect("check").is_clean());
+
+    assert!(vfat.borrow_mut().repair_fats(2).is_err());
+    assert_eq!(vfat.borrow_mut().repair_fats(1).expect("repair"), 1);
+    assert!(vfat.borrow_mut().compare_fats().expect("compare").is_empty());
+    assert!(vfat.check().expect("check").is_clean());
+    assert_eq!(read_all(vfat.open_file("/A.TXT").expect("open")), vec![1; 2048]);
+    vfat.borrow_mut().flush().expect("flush");
+    drop(vfat);
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    assert!(vfat.borrow_mut().compare_fats().expect("compare").is_empty());
+    assert_eq!(vfat.borrow_mut().repair_fats(0).expect("repair"), 0);
+}
+
+/// A device backed by a shared image whose sectors listed in `bad` can't be
+/// read.
+struct BadSectorDevice {
+    image: Shared<Vec<u8>>,
+    bad: Shared<Vec<u64>>,
+}
+
+impl BlockDevice for BadSectorDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        if self.bad.borrow().contains(&n) {
+            return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "bad sector"));
+        }
+        CrashDevice::new(&self.image, 0).read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        CrashDevice::new(&self.image, 1).write_sector(n, buf)
+    }
+}
+
+/// A device backed by a shared image whose operations each fail `failures`
+/// times before succeeding.
+struct FlakyDevice {
+    image: Shared<Vec<u8>>,
+    failures: u32,
+    failed: u32,
+}
+
+impl FlakyDevice {
+    fn fail(&mut self) -> ::std::io::Result<()> {
+        if self.failed < self.failures {
+            self.failed += 1;
+            return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "flaky"));
+        }
+        self.failed = 0;
+        Ok(())
+    }
+}
+
+impl BlockDevice for FlakyDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.fail()?;
+        CrashDevice::new(&self.image, 0).read_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        self.fail()?;
+        CrashDevice::new(&self.image, 0).read_sectors(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> ::std::io::Result<usize> {
+        self.fail()?;
+        CrashDevice::new(&self.image, 1).write_sector(n, buf)
+    }
+}
+
+#[test]
+fn test_retry_device() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let flaky = |failures| FlakyDevice {
+        image: image.clone(),
+        failures,
+        failed: 0,
+    };
+
+    assert!(VFat::from(RetryDevice::new(flaky(3), 2)).is_err());
+
+    let contents = vec![0x5A; 5000];
+    let vfat = VFat::from(RetryDevice::new(flaky(2), 2)).expect("mount");
+    vfat.create_file("/DATA.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+
+    let vfat = VFat::from(RetryDevice::new(flaky(1), 1)).expect("remount");
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+
+    let mut device = RetryDevice::new(flaky(1), 1);
+    let mut buf = [0u8; 512];
+    device.read_sector(0, &mut buf).expect("read");
+    assert_eq!(device.retried(), 1);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_walk() {
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    for i in 0..4 {
+        for j in 0..3 {
+            let dir = format!("/D{}/E{}", i, j);
+            vfat.create_dir(&dir, true).expect("create");
+            let file = format!("{}/F.TXT", dir);
+            vfat.create_file(&file).expect("create").write_all(&[i; 100]).expect("write");
+        }
+    }
+
+    let mut walked = Vec::new();
+    vfat.open_dir("/")
+        .expect("root")
+        .walk(Path::new("/"), &mut |path, _| Ok(walked.push(path.to_path_buf())))
+        .expect("walk");
+    let visited = Mutex::new(Vec::new());
+    vfat.par_walk("/", |path, _| {
+        let mut visited = visited.lock().unwrap();
+        let parent = path.parent().expect("parent");
+        if parent != Path::new("/") {
+            assert!(visited.iter().any(|p: &PathBuf| p == parent), "{:?}", path);
+        }
+        visited.push(path.to_path_buf());
+        Ok(())
+    })
+    .expect("par_walk");
+    let mut visited = visited.into_inner().unwrap();
+    visited.sort();
+    walked.sort();
+    assert_eq!(visited, walked);
+    assert_eq!(visited.len(), 4 + 12 + 12);
+
+    let result = vfat.par_walk("/", |path, _| match path.file_name() {
+        Some(name) if name == "E1" => Err(io::Error::new(io::ErrorKind::Other, "stop")),
+        _ => Ok(()),
+    });
+    assert_eq!(result.unwrap_err().to_string(), "stop");
+}
+
+#[test]
+fn test_read_tuning() {
+    use vfat::MountOptions;
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let contents: Vec<Vec<u8>> = (0..3u32)
+        .map(|f| (0..9000 + f * 1000).map(|i| (i % 241 + f) as u8).collect())
+        .collect();
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_dir("/SUB", false).expect("create");
+        for (f, data) in contents.iter().enumerate() {
+            let path = format!("/SUB/F{}.BIN", f);
+            vfat.create_file(&path).and_then(|mut file| file.write_all(data)).expect("write");
+        }
+    }
+    let base = image.borrow().clone();
+
+    // However the cache and reads are tuned, files read back the same, and
+    // changes that don't fit in a small cache are still all written.
+    for &(capacity, read_ahead, max_run) in &[(0, 0, 0), (4, 0, 1), (8, 16, 2), (1, 64, 0)] {
+        let options = MountOptions {
+            cache_capacity: capacity,
+            read_ahead,
+            max_run_clusters: max_run,
+            ..MountOptions::default()
+        };
+        let image = Shared::new(base.clone());
+        let device = CrashDevice::new(&image, usize::max_value());
+        let vfat = VFat::from_with_options(device, &options).expect("mount");
+        for (f, data) in contents.iter().enumerate() {
+            let mut file = vfat.open_file(format!("/SUB/F{}.BIN", f)).expect("open");
+            let mut small = [0u8; 100];
+            file.read_exact(&mut small).expect("read");
+            assert_eq!(&small[..], &data[..100]);
+            let mut rest = Vec::new();
+            file.read_to_end(&mut rest).expect("read");
+            assert_eq!(&rest[..], &data[100..], "{:?}", options);
+        }
+        vfat.create_file("/NEW.BIN").and_then(|mut f| f.write_all(&[1; 5000])).expect("write");
+        assert!(vfat.check().expect("check").is_clean(), "{:?}", options);
+        vfat.unmount().expect("unmount");
+
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        assert!(vfat.check().expect("check").is_clean(), "{:?}", options);
+        assert_eq!(read_all(vfat.open_file("/NEW.BIN").expect("open")), vec![1; 5000]);
+    }
+
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    vfat.borrow_mut().set_cache_capacity(3);
+    vfat.borrow_mut().set_read_ahead(4);
+    vfat.borrow_mut().set_max_run_clusters(1);
+    for (f, data) in contents.iter().enumerate() {
+        let file = vfat.open_file(format!("/SUB/F{}.BIN", f)).expect("open");
+        assert_eq!(&read_all(file), data);
+    }
+}
+
+#[test]
+fn test_entry_id() {
+    use std::collections::HashSet;
+    use vfat::{Entry, EntryId};
+
+    let options = FormatOptions {
+        volume_serial: 0x1234_5678,
+        ..FormatOptions::default()
+    };
+    let vfat = VFat::from(formatted_image(8192, &options)).expect("mount");
+    assert_eq!(vfat.borrow().volume_serial(), 0x1234_5678);
+    vfat.create_dir("/SUB", false).expect("create");
+    vfat.create_file("/SUB/A.TXT").expect("create");
+    vfat.create_file("/SUB/B.TXT").expect("create");
+
+    let entries = |vfat: &Shared<VFat>| -> Vec<Entry> {
+        let dir = vfat.open_dir("/SUB").expect("open");
+        dir.entries().expect("entries").filter(|e| !e.name().starts_with('.')).collect()
+    };
+    let first = entries(&vfat);
+    assert_eq!(first.len(), 2);
+    assert!(first[0] != first[1]);
+    assert!(first.iter().all(|e| e.id().volume_serial == 0x1234_5678));
+
+    // Ids survive writes and separate iterations, so entries can be deduped.
+    vfat.open_file("/SUB/A.TXT").expect("open").write_all(&[1; 3000]).expect("write");
+    let second = entries(&vfat);
+    assert_eq!(first, second);
+    let a = vfat.open("/SUB/A.TXT").expect("open");
+    assert_eq!(a.id(), first[0].id());
+    let seen: HashSet<Entry> = first.into_iter().chain(second).collect();
+    assert_eq!(seen.len(), 2);
+    assert!(seen.contains(&a));
+
+    // Moving an entry gives it a new identity.
+    let ids: HashSet<EntryId> = seen.iter().map(|e| e.id()).collect();
+    drop((a, seen));
+    vfat.rename("/SUB/A.TXT", "/A.TXT").expect("rename");
+    assert!(!ids.contains(&vfat.open("/A.TXT").expect("open").id()));
+
+    // The root directory has an id of its own.
+    let root = vfat.open("/").expect("open").id();
+    assert_eq!((root.parent, root.offset), (0, 0));
+    assert!(!ids.contains(&root));
+}
+
+#[test]
+fn test_free_clusters() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    for name in &["/A.BIN", "/B.BIN", "/C.BIN"] {
+        let mut file = vfat.create_file(name).expect("create");
+        file.write_all(&vec![1; cluster_size * 3]).expect("write");
+    }
+    // Replacing B.BIN frees its clusters, between those of A.BIN and C.BIN.
+    vfat.replace("/C.BIN", "/B.BIN").expect("replace");
+    let a = vfat.open_file("/A.BIN").expect("open").cluster;
+
+    let free: Vec<Cluster> = vfat.borrow_mut().free_clusters().expect("free").collect();
+    let count = vfat.borrow_mut().free_cluster_count().expect("count") as usize;
+    assert_eq!(free.len(), count);
+    assert!(free.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(!free.contains(&a));
+
+    // Runs cover the same clusters, and are as long as possible: the clusters
+    // freed by replacing B.BIN are a run of their own.
+    let runs: Vec<Extent> = vfat.borrow_mut().free_clusters().expect("free").runs().collect();
+    let expanded: Vec<Cluster> = runs
+        .iter()
+        .flat_map(|run| (0..run.len).map(move |i| Cluster::from(run.start.fat_index() + i)))
+        .collect();
+    assert_eq!(expanded, free);
+    assert!(runs.windows(2).all(|pair| {
+        pair[0].start.fat_index() + pair[0].len < pair[1].start.fat_index()
+    }));
+    assert!(runs.contains(&Extent {
+        start: Cluster::from(a.fat_index() + 3),
+        len: 3,
+    }));
+
+    // The iterator is a snapshot, and doesn't hold the volume borrowed.
+    let mut clusters = vfat.borrow_mut().free_clusters().expect("free");
+    assert_eq!(clusters.next(), Some(free[0]));
+    vfat.create_file("/D.BIN").expect("create").write_all(&[1]).expect("write");
+    let d = vfat.open_file("/D.BIN").expect("open").cluster;
+    assert_eq!(clusters.count(), count - 1);
+    let free: Vec<Cluster> = vfat.borrow_mut().free_clusters().expect("free").collect();
+    assert_eq!(free.len(), count - 1);
+    assert!(!free.contains(&d));
+}
+
+#[test]
+fn test_usage_map() {
+    use vfat::{ClusterUsage, Status};
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let cluster_size = vfat.borrow().cluster_size();
+    let mut file = vfat.create_file("/A.BIN").expect("create");
+    file.write_all(&vec![1; cluster_size * 5]).expect("write");
+    drop(file);
+    let a = vfat.open_file("/A.BIN").expect("open").cluster;
+    let bad = Cluster::from(a.fat_index() + 10);
+    vfat.borrow_mut().set_fat_entry(bad, Status::Bad).expect("mark bad");
+
+    let map = vfat.borrow_mut().usage_map().expect("usage map");
+    let data_clusters = vfat.borrow().data_clusters();
+    assert_eq!(map.len(), data_clusters);
+    let free = vfat.borrow_mut().free_cluster_count().expect("count");
+    assert_eq!(map.count(ClusterUsage::Free), free);
+    assert_eq!(map.count(ClusterUsage::Bad), 1);
+    assert_eq!(map.count(ClusterUsage::Used), data_clusters - free - 1);
+    assert_eq!(map.get(a), Some(ClusterUsage::Used));
+    assert_eq!(map.get(Cluster::from(a.fat_index() + 5)), Some(ClusterUsage::Free));
+    assert_eq!(map.get(bad), Some(ClusterUsage::Bad));
+    assert_eq!(map.get(Cluster::from(1)), None);
+    assert_eq!(map.get(Cluster::from(data_clusters + 2)), None);
+    assert!(map.runs().windows(2).all(|pair| pair[0].usage != pair[1].usage));
+
+    // One block per cluster matches the map exactly, and fewer blocks keep
+    // the bad cluster visible.
+    let blocks = map.blocks(data_clusters as usize);
+    assert!((0..data_clusters).all(|i| Some(blocks[i as usize]) == map.get(Cluster::from(i + 2))));
+    let blocks = map.blocks(10);
+    assert_eq!(blocks.len(), 10);
+    assert_eq!(blocks.iter().filter(|&&b| b == ClusterUsage::Bad).count(), 1);
+    assert_eq!(blocks[9], ClusterUsage::Free);
+    assert_eq!(map.blocks(data_clusters as usize * 2).len(), data_clusters as usize * 2);
+}
+
+#[test]
+fn test_chaos_device() {
+    use std::time::Duration;
+    use traits::ChaosDevice;
+
+    let image = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let reads = |seed| {
+        let mut device = ChaosDevice::new(Cursor::new(image.clone()), seed);
+        device.set_read_error_rate(0.25);
+        (0..200)
+            .map(|n| device.read_sector(n, &mut [0u8; 512]).is_ok())
+            .collect::<Vec<_>>()
+    };
+    let failed = reads(7).iter().filter(|&&ok| !ok).count();
+    assert!(failed > 20 && failed < 80);
+    assert_eq!(reads(7), reads(7));
+    assert!(reads(7) != reads(8));
+
+    // Retries make an unreliable device usable.
+    let mut device = ChaosDevice::new(Cursor::new(image), 42);
+    device.set_read_error_rate(0.2);
+    device.set_write_error_rate(0.1);
+    device.set_write_delay(Duration::new(0, 1000));
+    let vfat = VFat::from(RetryDevice::new(device, 20)).expect("mount");
+    vfat.create_file("/DATA.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+    assert!(vfat.check().expect("check").is_clean());
+
+    let mut device = ChaosDevice::new(Cursor::new(vec![0u8; 512]), 1);
+    device.set_sync_delay(Duration::new(0, 1000));
+    device.write_sector(0, &[1; 512]).expect("write");
+    device.sync().expect("sync");
+    assert_eq!((device.injected(), device.delayed()), (0, Duration::new(0, 1000)));
+}
+
+#[test]
+fn test_device_layers() {
+    let image = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 253) as u8).collect();
+
+    let mut blob = vec![0xEE; 16 * 512];
+    blob.extend_from_slice(&image);
+    let vfat = VFat::from(OffsetDevice::new(Cursor::new(blob.clone()), 16)).expect("mount");
+    vfat.create_file("/DATA.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+    let mut device = OffsetDevice::with_len(Cursor::new(blob), 16, 8192);
+    assert!(device.read_sector(8191, &mut [0u8; 512]).is_ok());
+    assert!(device.read_sector(8192, &mut [0u8; 512]).is_err());
+
+    let parts = vec![
+        (Cursor::new(image[..3000 * 512].to_vec()), 3000),
+        (Cursor::new(Vec::new()), 0),
+        (Cursor::new(image[3000 * 512..].to_vec()), 5192),
+    ];
+    let device = ConcatDevice::new(parts).expect("concat");
+    assert_eq!(device.sectors(), 8192);
+    let vfat = VFat::from(device).expect("mount");
+    vfat.create_file("/DATA.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert!(vfat.check().expect("check").is_clean());
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+
+    // Stripes of 4 sectors over 3 devices.
+    let mut devices = vec![Vec::new(), Vec::new(), Vec::new()];
+    for (i, stripe) in image.chunks(4 * 512).enumerate() {
+        devices[i % 3].extend_from_slice(stripe);
+    }
+    let devices: Vec<_> = devices.into_iter().map(Cursor::new).collect();
+    let mut device = StripeDevice::new(devices, 4).expect("stripe");
+    let mut sector = [0u8; 512];
+    for &n in &[0, 3, 4, 11, 12, 8191] {
+        device.read_sector(n, &mut sector).expect("read");
+        assert_eq!(&sector[..], &image[n as usize * 512..][..512]);
+    }
+    let vfat = VFat::from(device).expect("mount");
+    vfat.create_file("/DATA.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(read_all(vfat.open_file("/DATA.BIN").expect("open")), contents);
+
+    assert!(StripeDevice::new(Vec::<Cursor<Vec<u8>>>::new(), 4).is_err());
+    assert!(StripeDevice::new(vec![Cursor::new(Vec::new())], 0).is_err());
+}
+
+#[test]
+fn test_wrappers_forward_read_sectors() {
+    use std::time::Duration;
+
+    // Every byte of sector `n` is `n`.
+    let blob: Vec<u8> = (0..64u8).flat_map(|n| vec![n; 512]).collect();
+    let recording = |runs: &Shared<Vec<(u64, usize)>>| RunRecordingDevice {
+        image: Cursor::new(blob.clone()),
+        runs: runs.clone(),
+    };
+    let first_bytes = |buf: &[u8]| buf.chunks(512).map(|s| s[0]).collect::<Vec<u8>>();
+    let mut buf = vec![0u8; 8 * 512];
+
+    let runs = Shared::new(Vec::new());
+    let mut device = OffsetDevice::with_len(recording(&runs), 4, 16);
+    assert_eq!(device.read_sectors(2, &mut buf).expect("read"), buf.len());
+    assert_eq!(first_bytes(&buf), (6..14).collect::<Vec<u8>>());
+    assert!(device.read_sectors(10, &mut buf).is_err());
+    assert_eq!(*runs.borrow(), vec![(6, 8 * 512)]);
+
+    let runs = Shared::new(Vec::new());
+    let mut device = RetryDevice::new(recording(&runs), 3);
+    device.read_sectors(1, &mut buf).expect("read");
+    assert_eq!(first_bytes(&buf), (1..9).collect::<Vec<u8>>());
+    let mut device = ThrottledDevice::new(device, u64::max_value());
+    device.set_burst(u64::max_value());
+    device.read_sectors(3, &mut buf).expect("read");
+    assert_eq!(device.throttled(), Duration::from_secs(0));
+    assert_eq!(*runs.borrow(), vec![(1, 8 * 512), (3, 8 * 512)]);
+
+    let runs = Shared::new(Vec::new());
+    let disk = Shared::new(recording(&runs));
+    let mut device = PartitionDevice::new(&disk, 32, 10);
+    device.read_sectors(2, &mut buf).expect("read");
+    assert_eq!(first_bytes(&buf), (34..42).collect::<Vec<u8>>());
+    assert!(device.read_sectors(3, &mut buf).is_err());
+    assert_eq!(*runs.borrow(), vec![(34, 8 * 512)]);
+
+    // Runs are split where a part or a stripe ends.
+    let (runs, other) = (Shared::new(Vec::new()), Shared::new(Vec::new()));
+    let parts = vec![(recording(&runs), 16), (recording(&other), 16)];
+    let mut device = ConcatDevice::new(parts).expect("concat");
+    device.read_sectors(13, &mut buf).expect("read");
+    assert_eq!(first_bytes(&buf), vec![13, 14, 15, 0, 1, 2, 3, 4]);
+    assert_eq!(*runs.borrow(), vec![(13, 3 * 512)]);
+    assert_eq!(*other.borrow(), vec![(0, 5 * 512)]);
+
+    let (runs, other) = (Shared::new(Vec::new()), Shared::new(Vec::new()));
+    let devices = vec![recording(&runs), recording(&other)];
+    let mut device = StripeDevice::new(devices, 4).expect("stripe");
+    device.read_sectors(2, &mut buf).expect("read");
+    assert_eq!(first_bytes(&buf), vec![2, 3, 0, 1, 2, 3, 4, 5]);
+    assert_eq!(*runs.borrow(), vec![(2, 2 * 512), (4, 2 * 512)]);
+    assert_eq!(*other.borrow(), vec![(0, 4 * 512)]);
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn test_encrypted_device() {
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    let plain: Vec<u8> = (0..512).map(|i| (i * 7 + 3) as u8).collect();
+    let key: Vec<u8> = (1..65).collect();
+    let expected = [
+        ("a60f7df22a30057b0373767b06e1109f", "ee531700df24bd78b41a81bf9e904b23"),
+        ("dec3cf4d763ba810bc04e08a462a8f01", "1c410a748469580aaf8cbe7af6d1b8f0"),
+    ];
+    for (key, &(first, last)) in [&key[..32], &key[..]].iter().zip(expected.iter()) {
+        let mut device = EncryptedDevice::new(Cursor::new(vec![0u8; 8 * 512]), key).expect("key");
+        device.write_sector(5, &plain).expect("write");
+        let mut sector = [0u8; 512];
+        device.read_sector(5, &mut sector).expect("read");
+        assert_eq!(&sector[..], &plain[..]);
+
+        let mut small = [0u8; 100];
+        assert_eq!(device.read_sector(5, &mut small).expect("read"), 100);
+        assert_eq!(&small[..], &plain[..100]);
+        assert!(device.write_sector(5, &small).is_err());
+
+        device.write_sector(6, &plain).expect("write");
+        let mut sectors = vec![0u8; 3 * 512];
+        assert_eq!(device.read_sectors(4, &mut sectors).expect("read"), sectors.len());
+        assert_eq!(&sectors[512..], &[&plain[..], &plain[..]].concat()[..]);
+
+        let debug = format!("{:?}", device);
+        assert!(debug.contains("keys: \"<AES-") && !debug.contains("Aes"));
+
+        let raw = device.into_inner().into_inner();
+        assert_eq!(hex(&raw[5 * 512..][..16]), first);
+        assert_eq!(hex(&raw[6 * 512 - 16..][..16]), last);
+    }
+    assert!(EncryptedDevice::new(Cursor::new(Vec::new()), &key[..16]).is_err());
+
+    let image = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let mut device = EncryptedDevice::new(Cursor::new(vec![0u8; image.len()]), &key).expect("key");
+    for (n, sector) in image.chunks(512).enumerate() {
+        device.write_sector(n as u64, sector).expect("write");
+    }
+    let vfat = VFat::from(device).expect("mount");
+    vfat.create_file("/SECRET.TXT")
+        .and_then(|mut f| f.write_all(b"attack at dawn"))
+        .expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    assert_eq!(read_all(vfat.open_file("/SECRET.TXT").expect("open")), b"attack at dawn");
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_compressed_device() {
+    use flate2::{Compression, GzBuilder};
+    use vfat::MountOptions;
+
+    /// Compresses `data` as `bgzip` does, in members of 65280 bytes.
+    fn bgzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(65280).chain(Some(&[][..])) {
+            let mut member = GzBuilder::new()
+                .extra(vec![b'B', b'C', 2, 0, 0, 0])
+                .write(Vec::new(), Compression::default());
+            member.write_all(chunk).expect("compress");
+            let mut member = member.finish().expect("compress");
+            let size = member.len() - 1;
+            member[16] = size as u8;
+            member[17] = (size >> 8) as u8;
+            out.extend_from_slice(&member);
+        }
+        out
+    }
+
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i / 7) as u8).collect();
+    let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+    vfat.create_file("/ARCHIVE.BIN").and_then(|mut f| f.write_all(&contents)).expect("write");
+    vfat.borrow_mut().flush().expect("flush");
+    let image = image.borrow().clone();
+
+    let mut device = CompressedDevice::new(Cursor::new(bgzip(&image))).expect("index");
+    assert_eq!(device.size(), image.len() as u64);
+    let mut sector = [0u8; 512];
+    for &n in &[0, 127, 128, 8191, 300, 129] {
+        device.read_sector(n, &mut sector).expect("read");
+        assert_eq!(&sector[..], &image[n as usize * 512..][..512]);
+    }
+    assert!(device.read_sector(8192, &mut sector).is_err());
+    assert!(device.write_sector(0, &sector).is_err());
+
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let vfat = VFat::from_with_options(device, &options).expect("mount");
+    assert_eq!(read_all(vfat.open_file("/ARCHIVE.BIN").expect("open")), contents);
+
+    let mut gzip = GzBuilder::new().write(Vec::new(), Compression::default());
+    gzip.write_all(&image).expect("compress");
+    let gzip = gzip.finish().expect("compress");
+    let e = CompressedDevice::new(Cursor::new(gzip)).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+
+    // A block whose recorded size can't hold its own header and trailer.
+    let mut compressed = bgzip(&image);
+    for &bsize in &[0u16, 2, 24] {
+        compressed[16] = bsize as u8;
+        compressed[17] = (bsize >> 8) as u8;
+        let e = CompressedDevice::new(Cursor::new(&compressed[..])).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn test_throttled_device() {
+    use std::time::{Duration, Instant};
+
+    let mut device = ThrottledDevice::new(Cursor::new(vec![0u8; 64 * 512]), 64 * 1024);
+    let mut buf = [0u8; 512];
+    let start = Instant::now();
+    for n in 0..32 {
+        device.read_sector(n, &mut buf).expect("read");
+    }
+    // The first sector fits in the burst; the other 31 take 242ms at 64 KiB/s,
+    // part of which the reads themselves take.
+    assert!(start.elapsed() >= Duration::from_millis(240));
+    assert!(device.throttled() >= Duration::from_millis(200));
+
+    device.set_rate(1 << 30);
+    device.set_burst(64 * 512);
+    ::std::thread::sleep(Duration::from_millis(10));
+    let throttled = device.throttled();
+    for n in 0..64 {
+        device.write_sector(n, &buf).expect("write");
+    }
+    assert_eq!(device.throttled(), throttled);
+
+    let device = ThrottledDevice::with_sector_rate(Cursor::new(vec![0u8; 512]), 100);
+    assert_eq!(device.rate(), 51_200);
+}
+
+#[test]
+fn test_scan_surface() {
+    use vfat::{BadCluster, Status};
+
+    let mut image = formatted_image(8192, &FormatOptions::default());
+    let (_, data_start) = image_layout(&mut image);
+    let image = Shared::new(image.into_inner());
+    let bad = Shared::new(Vec::new());
+    let mount = || {
+        VFat::from(BadSectorDevice {
+            image: image.clone(),
+            bad: bad.clone(),
+        }).expect("mount")
+    };
+
+    let vfat = mount();
+    let cluster_size = vfat.borrow().cluster_size();
+    let contents: Vec<u8> = (0..3 * cluster_size).map(|i| (i / 512) as u8).collect();
+    let mut file = vfat.create_file("/DATA.BIN").expect("create");
+    file.write_all(&contents).expect("write");
+    let middle = Cluster::from(file.extents().expect("extents")[0].start.fat_index() + 1);
+    drop(file);
+    vfat.borrow_mut().flush().expect("flush");
+
+    let sector = |cluster: u32, offset: u64| data_start + (cluster as u64 - 2) * 8 + offset;
+    bad.borrow_mut().extend(&[sector(middle.fat_index(), 1), sector(100, 7)]);
+    let report = vfat.scan_surface(false).expect("scan");
+    assert_eq!(report.clusters, vfat.borrow().data_clusters() as usize);
+    let unrepaired = vec![
+        BadCluster {
+            cluster: middle,
+            owner: Some("/DATA.BIN".into()),
+            relocated_to: None,
+        },
+        BadCluster {
+            cluster: Cluster::from(100),
+            owner: None,
+            relocated_to: None,
+        },
+    ];
+    assert_eq!(report.bad, unrepaired);
+
+    let report = vfat.scan_surface(true).expect("scan");
+    let new = report.bad[0].relocated_to.expect("relocated");
+    assert_eq!(report.bad[1], unrepaired[1]);
+    for &cluster in &[middle, Cluster::from(100)] {
+        assert_eq!(vfat.borrow_mut().fat_entry(cluster).unwrap().status(), Status::Bad);
+    }
+
+    // The readable sectors are moved, the unreadable one is zeroed, and the
+    // bad clusters are neither scanned nor allocated again.
+    let vfat = mount();
+    let file = vfat.open_file("/DATA.BIN").expect("open");
+    let mut expected = contents.clone();
+    for byte in &mut expected[cluster_size + 512..cluster_size + 1024] {
+        *byte = 0;
+    }
+    assert!(file.extents().unwrap().iter().any(|e| e.start == new));
+    assert_eq!(read_all(file), expected);
+    assert!(vfat.check().expect("check").is_clean());
+    let report = vfat.scan_surface(true).expect("scan");
+    assert!(report.bad.is_empty());
+    assert_eq!(report.clusters, vfat.borrow().data_clusters() as usize - 2);
+}
+
+#[test]
+fn test_rename() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let data: Vec<u8> = (0..6000u32).map(|i| (i % 101) as u8).collect();
+    vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(&data)).expect("write");
+    vfat.create_file("/B.TXT").expect("create");
+
+    vfat.rename("/A.TXT", "/A much longer name.txt").expect("rename");
+    assert!(vfat.open("/A.TXT").is_err());
+    assert_eq!(read_all(vfat.open_file("/a much longer NAME.txt").expect("open")), data);
+
+    let e = vfat.rename("/A much longer name.txt", "/B.TXT").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+    let e = vfat.rename("/MISSING", "/C.TXT").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+
+    // Only the spelling of the name changes.
+    vfat.rename("/B.TXT", "/b.txt").expect("rename");
+    let names: Vec<String> = vfat.open_dir("/").expect("open").entries().expect("entries")
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, ["A much longer name.txt", "b.txt"]);
+
+    // Directories move with their contents, and `..` follows them.
+    vfat.create_dir("/X/SUB", true).expect("create");
+    vfat.create_file("/X/SUB/F.TXT").expect("create");
+    vfat.create_dir("/Y", false).expect("create");
+    let e = vfat.rename("/X", "/x/SUB/X").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    vfat.rename("/X/SUB", "/Y/MOVED").expect("rename");
+    assert!(vfat.open("/Y/MOVED/F.TXT").is_ok());
+    let moved = vfat.open_dir("/Y/MOVED").expect("open");
+    let dotdot = moved.entries().unwrap().find(|e| e.name() == "..").expect("..");
+    assert_eq!(dotdot.into_dir().unwrap().cluster(), vfat.open_dir("/Y").unwrap().cluster());
+    drop(moved);
+
+    let open = vfat.open_file("/b.txt").expect("open");
+    let e = vfat.rename("/b.txt", "/c.txt").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    drop(open);
+
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_replace() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/Document.txt").and_then(|mut f| f.write_all(&[1; 9000])).expect("write");
+    vfat.create_file("/DOC.TMP").and_then(|mut f| f.write_all(&[2; 100])).expect("write");
+
+    vfat.replace("/DOC.TMP", "/Document.txt").expect("replace");
+    assert!(vfat.open("/DOC.TMP").is_err());
+    assert_eq!(read_all(vfat.open_file("/Document.txt").expect("open")), vec![2; 100]);
+    assert!(vfat.check().expect("check").is_clean());
+
+    vfat.create_dir("/D", false).expect("create");
+    vfat.create_dir("/E", false).expect("create");
+    let e = vfat.replace("/Document.txt", "/D").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    vfat.create_file("/E/F").expect("create");
+    let e = vfat.replace("/D", "/E").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    vfat.replace("/E", "/D").expect("replace");
+    assert!(vfat.open("/D/F").is_ok());
+    assert!(vfat.check().expect("check").is_clean());
+}
+
+#[test]
+fn test_replace_crash_safety() {
+    let image = Shared::new(formatted_image(8192, &FormatOptions::default()).into_inner());
+    {
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("mount");
+        vfat.create_file("/DOC.TXT").and_then(|mut f| f.write_all(&[1; 5000])).expect("write");
+        vfat.borrow_mut().flush().expect("flush");
+    }
+    let base = image.borrow().clone();
+
+    // Wherever a safe save is interrupted, the document holds either its old
+    // or its new contents.
+    let (mut old, mut new) = (0, 0);
+    for writes in 0.. {
+        let image = Shared::new(base.clone());
+        let vfat = VFat::from(CrashDevice::new(&image, writes)).expect("mount");
+        let done = vfat
+            .create_file("/DOC.TMP")
+            .and_then(|mut f| f.write_all(&[2; 7000]))
+            .and_then(|_| vfat.replace("/DOC.TMP", "/DOC.TXT"))
+            .is_ok();
+        drop(vfat);
+
+        let vfat = VFat::from(CrashDevice::new(&image, usize::max_value())).expect("remount");
+        let contents = read_all(vfat.open_file("/DOC.TXT").expect("document"));
+        if contents == vec![1; 5000] {
+            old += 1;
+        } else {
+            assert_eq!(contents, vec![2; 7000], "{} writes", writes);
+            new += 1;
+        }
+
+        if done {
+            assert!(vfat.check().expect("check").is_clean());
+            break;
+        }
+    }
+    assert!(old > 0 && new > 0);
+}
+
+#[test]
+fn test_malformed_entries() {
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    image.set_entry(2, 0, b"           ", 0x20, 0, 0);
+    {
+        // An LFN entry with sequence number 0 followed by a regular entry.
+        let entry = &mut image.cluster_mut(2)[32..64];
+        entry[0] = 0x40;
+        entry[11] = 0x0F;
+    }
+    image.set_entry(2, 2, b"A       BIN", 0x20, 0, 0);
+
+    let names: Vec<String> = image
+        .mount()
+        .open_dir("/")
+        .expect("root")
+        .entries()
+        .expect("entries")
+        .map(|e| e.name().to_string())
+        .collect();
+    assert_eq!(names, vec!["", "A.BIN"]);
+}
+
+#[test]
+fn test_mount_partitions_of_shared_disk() {
+    use std::io::Write;
+
+    let mut data = vec![0u8; 16384 * 512];
+    MasterBootRecord::builder()
+        .partition(64, 8128, 0x0C, false)
+        .partition(8192, 8192, 0x0C, false)
+        .write(Cursor::new(&mut data[..]))
+        .expect("write MBR");
+    for &(start, sectors) in &[(64, 8128), (8192, 8192)] {
+        let device = Cursor::new(&mut data[..]);
+        format(device, start, sectors, &FormatOptions::default()).expect("format");
+    }
+
+    let disk = Shared::new(Cursor::new(data));
+    let views: Vec<PartitionDevice<Cursor<Vec<u8>>>> = {
+        let mbr = MasterBootRecord::from(&mut *disk.borrow_mut()).expect("mbr");
+        mbr.fat32_partitions()
+            .map(|p| PartitionDevice::new(&disk, p.sector(), p.sectors()))
+            .collect()
+    };
+    assert_eq!(views.len(), 2);
+    let volumes: Vec<Shared<VFat>> = views
+        .into_iter()
+        .map(|view| VFat::from_volume(view).expect("mount"))
+        .collect();
+
+    for (i, vfat) in volumes.iter().enumerate() {
+        let mut file = vfat.create_file(format!("/VOLUME{}.TXT", i)).expect("create");
+        file.write_all(format!("volume {}", i).as_bytes()).expect("write");
+        drop(file);
+        vfat.borrow_mut().flush().expect("flush");
+    }
+    for (i, vfat) in volumes.iter().enumerate() {
+        assert!(vfat.open(format!("/VOLUME{}.TXT", 1 - i)).is_err());
+        assert!(vfat.check().expect("check").is_clean());
+    }
+
+    // The first partition is also the one mounted from the whole disk.
+    let data = disk.borrow().get_ref().clone();
+    let vfat = VFat::from(Cursor::new(data)).expect("mount");
+    assert_eq!(read_all(vfat.open_file("/VOLUME0.TXT").expect("open")), b"volume 0");
+
+    let mut view = PartitionDevice::new(&disk, 8192, 8192);
+    let mut buf = [0u8; 512];
+    let e = view.read_sector(8192, &mut buf).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_partitions_share_cache_budget() {
+    use vfat::MountOptions;
+
+    let mut data = vec![0u8; 16384 * 512];
+    MasterBootRecord::builder()
+        .partition(64, 8128, 0x0C, false)
+        .partition(8192, 8192, 0x0C, false)
+        .write(Cursor::new(&mut data[..]))
+        .expect("write MBR");
+    for &(start, sectors) in &[(64, 8128), (8192, 8192)] {
+        let device = Cursor::new(&mut data[..]);
+        format(device, start, sectors, &FormatOptions::default()).expect("format");
+    }
+
+    let disk = Shared::new(Cursor::new(data));
+    let budget = CacheBudget::new(16);
+    let options = MountOptions {
+        cache_budget: Some(budget.clone()),
+        ..MountOptions::default()
+    };
+    let volumes: Vec<Shared<VFat>> = [(64, 8128), (8192, 8192)]
+        .iter()
+        .map(|&(start, sectors)| PartitionDevice::new(&disk, start, sectors))
+        .map(|view| VFat::from_volume_with_options(view, &options).expect("mount"))
+        .collect();
+    assert!(budget.used() > 0 && budget.used() <= 16);
+
+    let contents: Vec<Vec<u8>> = (0..2u8).map(|i| vec![i + 1; 64 * 512]).collect();
+    for (vfat, data) in volumes.iter().zip(&contents) {
+        let mut file = vfat.create_file("/BIG.BIN").expect("create");
+        file.write_all(data).expect("write");
+        drop(file);
+        vfat.borrow_mut().flush().expect("flush");
+    }
+    for _ in 0..2 {
+        for (vfat, data) in volumes.iter().zip(&contents) {
+            assert_eq!(&read_all(vfat.open_file("/BIG.BIN").expect("open")), data);
+            assert!(budget.used() <= 16 + volumes.len(), "{} sectors", budget.used());
+        }
+    }
+
+    // A volume that is unmounted gives its sectors back.
+    let used = budget.used();
+    let mut volumes = volumes;
+    volumes.pop().expect("volume").unmount().expect("unmount");
+    assert!(budget.used() < used);
+    volumes[0].borrow_mut().set_cache_budget(None);
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn test_mount_gpt_disk() {
+    let mut data = vec![0u8; 8192 * 512];
+    MasterBootRecord::builder()
+        .partition(1, 8191, 0xEE, false)
+        .write(Cursor::new(&mut data[..]))
+        .expect("write MBR");
+    match VFat::from(Cursor::new(data)) {
+        Err(::vfat::Error::GptDisk) => {}
+        other => panic!("expected a GPT disk error, mounted: {:?}", other.is_ok()),
+    }
+
+    // A hybrid MBR lists a FAT32 partition besides the protective one.
+    let mut data = vec![0u8; 8192 * 512];
+    MasterBootRecord::builder()
+        .partition(1, 63, 0xEE, false)
+        .partition(64, 8128, 0x0C, false)
+        .write(Cursor::new(&mut data[..]))
+        .expect("write MBR");
+    format(Cursor::new(&mut data[..]), 64, 8128, &FormatOptions::default()).expect("format");
+    VFat::from(Cursor::new(data)).expect("mount");
+}
+
+#[test]
+fn test_mount_rejects_bad_geometry() {
+    let mut data = formatted_image(8192, &FormatOptions::default()).into_inner();
+    data[IMAGE_PARTITION_START as usize * 512 + 13] = 0;
+    match VFat::from(Cursor::new(data)) {
+        Err(::vfat::Error::Io(ref e)) => assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData),
+        other => panic!("mounted a volume with zero sectors per cluster: {:?}", other.is_ok()),
+    }
+
+    // A FAT16 layout has a fixed-size root directory region, whose entries
+    // would otherwise be read as data clusters.
+    let mut data = formatted_image(8192, &FormatOptions::default()).into_inner();
+    data[IMAGE_PARTITION_START as usize * 512 + 17] = 0x00;
+    data[IMAGE_PARTITION_START as usize * 512 + 18] = 0x02;
+    match VFat::from(Cursor::new(data)) {
+        Err(::vfat::Error::Io(ref e)) => assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidData),
+        other => panic!("mounted a volume with a root directory region: {:?}", other.is_ok()),
+    }
+}
+
+#[test]
+fn test_mount_quick_check() {
+    use vfat::{MountCheck, MountOptions, MountProblem};
+
+    let quick = MountOptions {
+        check: MountCheck::Quick,
+        ..MountOptions::default()
+    };
+    let warn = MountOptions {
+        check: MountCheck::QuickWarn,
+        ..MountOptions::default()
+    };
+    let image = formatted_image(8192, &FormatOptions::default());
+    let vfat = VFat::from_with_options(image, &quick).expect("mount");
+    assert!(vfat.borrow().mount_problems().is_empty());
+
+    let mut data = formatted_image(8192, &FormatOptions::default()).into_inner();
+    let base = IMAGE_PARTITION_START as usize * 512;
+    let fat = base + (data[base + 14] as usize | (data[base + 15] as usize) << 8) * 512;
+    data[fat] = 0x00;
+    data[fat + 8..fat + 12].copy_from_slice(&[0; 4]);
+    data[base + 512 + 492..base + 512 + 496].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0x0F]);
+    let expected = vec![
+        MountProblem::MediaEntry(0x0FFF_FF00),
+        MountProblem::RootDir {
+            cluster: 2,
+            entry: 0,
+        },
+        MountProblem::FsInfoNextFree(0x0FFF_FFFF),
+    ];
+
+    match VFat::from_with_options(Cursor::new(data.clone()), &quick) {
+        Err(::vfat::Error::Corrupt(problems)) => assert_eq!(problems, expected),
+        other => panic!("mounted a corrupt volume: {:?}", other.is_ok()),
+    }
+    let vfat = VFat::from_with_options(Cursor::new(data.clone()), &warn).expect("mount");
+    assert_eq!(vfat.borrow().mount_problems(), &expected[..]);
+    let vfat = VFat::from(Cursor::new(data)).expect("mount");
+    assert!(vfat.borrow().mount_problems().is_empty());
+}
+
+#[test]
+fn test_generated_images() {
+    // Fixed seeds keep the test deterministic; set FAT32_TEST_SEED to also
+    // try another one when exploring locally.
+    let extra = ::std::env::var("FAT32_TEST_SEED").ok().map(|seed| {
+        seed.parse::<usize>().expect("FAT32_TEST_SEED must be a number")
+    });
+    for seed in (0..16).chain(extra) {
+        println!("generating image with seed {}", seed);
+        let (tree, vfat) = TreeGenerator::new(seed).generate();
+        verify_tree(&vfat, Path::new("/"), &tree);
+
+        let report = vfat.check().expect("check generated image");
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
+
+#[test]
+fn test_creation_time_resolution() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::Timestamp;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_file("/file").expect("create file");
+
+    // 2018-03-14 15:09:27.43
+    let time = UNIX_EPOCH + Duration::from_millis(1_521_040_167_430);
+    let timestamp = Timestamp::from_system_time(time);
+    vfat.set_times("/file", timestamp, timestamp, timestamp).expect("set times");
+
+    let metadata = vfat.open("/file").expect("open").metadata().clone();
+    assert_eq!(metadata.created(), timestamp);
+    assert_eq!(metadata.created().to_system_time(), time);
+    assert_eq!(metadata.modified().second(), 26);
+    assert_eq!(metadata.modified().millisecond(), 0);
+}
+
+#[test]
+fn test_set_times() {
+    use vfat::Timestamp;
+
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    vfat.create_dir("/backup", false).expect("create dir");
+    vfat.create_file("/backup/file").expect("create file");
+
+    // 2009-02-13 23:31:30 and 2018-03-14 15:09:26
+    let created = Timestamp::from_unix_seconds(1_234_567_890);
+    let modified = Timestamp::from_unix_seconds(1_521_040_166);
+    for path in &["/backup", "/backup/file"] {
+        vfat.set_times(path, created, modified, modified).expect("set times");
+        let entry = vfat.open(path).expect("open");
+        assert_eq!(entry.metadata().created(), created);
+        assert_eq!(entry.metadata().modified(), modified);
+        assert_eq!(entry.metadata().accessed().day(), 14);
+        assert_eq!(entry.metadata().accessed().hour(), 0);
+    }
+
+    let e = vfat.set_times("/", created, modified, modified).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = vfat.set_times("/missing", created, modified, modified).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_system_time_conversions() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use vfat::Timestamp;
+
+    // 2018-03-14 15:09:26
+    let time = UNIX_EPOCH + Duration::from_secs(1_521_040_166);
+    let timestamp = Timestamp::from_system_time(time);
+    assert_eq!((timestamp.year(), timestamp.month(), timestamp.day()), (2018, 3, 14));
+    assert_eq!((timestamp.hour(), timestamp.minute(), timestamp.second()), (15, 9, 26));
+    assert_eq!(timestamp.to_system_time(), time);
+
+    let odd = Timestamp::from_system_time(time + Duration::from_millis(1_505));
+    assert_eq!(odd.second(), 27);
+    assert_eq!(odd.millisecond(), 500);
+    assert_eq!(odd.to_system_time(), time + Duration::from_millis(1_500));
+    let early = Timestamp::from_system_time(UNIX_EPOCH - Duration::from_secs(1));
+    assert_eq!(early.to_system_time(), UNIX_EPOCH + Duration::from_secs(315_532_800));
+}
+
+#[test]
+fn test_utc_offset() {
+    use std::fs;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use vfat::{MountOptions, Timestamp};
+
+    // 2018-03-14 15:09:26 UTC is 17:09:26 two hours east of UTC.
+    let time = UNIX_EPOCH + Duration::from_secs(1_521_040_166);
+    let local = Timestamp::from_system_time_at(time, 2 * 3600);
+    assert_eq!((local.day(), local.hour(), local.minute()), (14, 17, 9));
+    assert_eq!(local.to_system_time_at(2 * 3600), time);
+    assert_eq!(local.to_system_time(), time + Duration::from_secs(2 * 3600));
+    let west = Timestamp::from_system_time_at(time, -16 * 3600);
+    assert_eq!((west.day(), west.hour()), (13, 23));
+
+    #[cfg(feature = "chrono")]
+    {
+        let datetime = local.to_datetime(2 * 3600).expect("datetime");
+        assert_eq!(datetime.timestamp(), 1_521_040_166);
+        assert_eq!(Timestamp::from_datetime(&datetime, -16 * 3600), west);
+        assert!(local.to_datetime(86_400).is_none());
+    }
+
+    let options = MountOptions {
+        utc_offset: -5 * 3600,
+        ..MountOptions::default()
+    };
+    let vfat = VFat::from_with_options(formatted_image(8192, &FormatOptions::default()), &options)
+        .expect("mount");
+    let now = vfat.borrow().now().to_system_time_at(-5 * 3600);
+    let skew = match now.duration_since(SystemTime::now()) {
+        Ok(ahead) => ahead,
+        Err(behind) => behind.duration(),
+    };
+    assert!(skew < Duration::from_secs(10));
+
+    vfat.create_file("/A.TXT").and_then(|mut f| f.write_all(b"a")).expect("write");
+    vfat.set_times("/A.TXT", local, local, local).expect("set times");
+    let host = ::std::env::temp_dir().join(format!("fat32-utc-offset-{}", ::std::process::id()));
+    let _ = fs::remove_file(&host);
+    vfat.copy_to_host("/A.TXT", &host).expect("copy out");
+    let host_modified = fs::metadata(&host).and_then(|m| m.modified()).expect("host mtime");
+    fs::remove_file(&host).expect("clean up");
+    assert_eq!(host_modified, time + Duration::from_secs(7 * 3600));
+}
+
+#[test]
+fn test_timestamp_display() {
+    use vfat::{Attributes, Metadata, Timestamp};
+
+    // 2009-03-05 07:04:03.250
+    let timestamp = Timestamp::from_unix_time(1_236_236_643, 250);
+    assert_eq!(timestamp.to_string(), "2009-03-05T07:04:03");
+    assert_eq!(format!("{:.0}", timestamp), "2009-03-05T07:04:03");
+    assert_eq!(format!("{:.2}", timestamp), "2009-03-05T07:04:03.25");
+    assert_eq!(format!("{:.9}", timestamp), "2009-03-05T07:04:03.250");
+
+    let metadata = Metadata {
+        attr: Attributes(Attributes::READ_ONLY),
+        created: timestamp,
+        ..Metadata::default()
+    };
+    assert_eq!(metadata.to_string(), "R----- 2009-03-05T07:04:03");
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn test_chrono_conversions() {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use vfat::Timestamp;
+
+    // 2018-03-14 15:09:26
+    let timestamp = Timestamp::from_unix_seconds(1_521_040_166);
+    let datetime = NaiveDate::from_ymd(2018, 3, 14).and_hms(15, 9, 26);
+    assert_eq!(NaiveDateTime::from(timestamp), datetime);
+    assert_eq!(Timestamp::from(datetime), timestamp);
+    let later = datetime + ::chrono::Duration::milliseconds(1_250);
+    assert_eq!(NaiveDateTime::from(Timestamp::from(later)), later);
+
+    let unset = Timestamp::default();
+    assert_eq!(NaiveDateTime::from(unset), NaiveDate::from_ymd(1980, 1, 1).and_hms(0, 0, 0));
+    assert_eq!(Timestamp::from(NaiveDate::from_ymd(1970, 6, 1).and_hms(0, 0, 0)).year(), 1980);
+    let late = Timestamp::from(NaiveDate::from_ymd(2200, 1, 1).and_hms(0, 0, 0));
+    assert_eq!((late.year(), late.month(), late.day()), (2107, 12, 31));
+    assert_eq!((late.hour(), late.minute(), late.second()), (23, 59, 58));
+}
+
+#[test]
+fn test_copy() {
+    let vfat = VFat::from(formatted_image(8192, &FormatOptions::default())).expect("mount");
+    let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 253) as u8).collect();
+    vfat.create_dir("/SRC/SUB", true).expect("create");
+    vfat.create_file("/SRC/Long File Name.txt")
+        .and_then(|mut f| f.write_all(&contents))
+        .expect("write");
+    vfat.create_file("/SRC/SUB/EMPTY").expect("create");
+    let modified = ::vfat::Timestamp::from_unix_time(1_500_000_000, 0);
+    let location = vfat.open("/SRC/Long File Name.txt").unwrap().location().unwrap();
+    vfat.borrow_mut()
+        .update_dir_entry(location, |entry| {
+            entry.set_times(modified, modified, modified);
+            entry.set_read_only(true);
+        })
+        .expect("update");
+
+    vfat.copy("/SRC", "/DST").expect("copy");
+    let copy = vfat.open_file("/DST/Long File Name.txt").expect("open");
+    assert!(copy.metadata().read_only());
+    assert_eq!(copy.metadata().modified, modified);
+    assert_eq!(read_all(copy), contents);
+    assert!(vfat.open_file("/DST/SUB/EMPTY").is_ok());
+    assert_eq!(vfat.du("/DST").expect("du"), vfat.du("/SRC").expect("du"));
+    assert!(vfat.check().expect("check").is_clean());
+
+    let e = vfat.copy("/SRC", "/SRC/SUB/SRC").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    let e = vfat.copy("/SRC/SUB/EMPTY", "/DST/SUB/EMPTY").unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn test_diff() {
+    use std::io::SeekFrom;
+    use vfat::Change;
+
+    let old = fragmented_image();
+    let new = fragmented_image();
+    assert_eq!(old.diff(&new).expect("diff"), vec![]);
+
+    let mut a = new.open_file("/A.BIN").expect("open");
+    a.write_all(&[0xFF]).expect("write");
+    old.create_file("/OLD.TXT").expect("create");
+    let mut c = new.open_file("/C.BIN").expect("open");
+    c.seek(SeekFrom::End(0)).expect("seek");
+    c.write_all(&[1, 2, 3]).expect("write");
+    new.create_dir("/D", false).expect("create");
+    new.create_file("/D/E.TXT").expect("create");
+
+    let changes = old.diff(&new).expect("diff");
+    assert_eq!(changes.len(), 4, "{:?}", changes);
+    match changes[0] {
+        Change::Changed {
+            ref path,
+            size: None,
+            modified: Some(_),
+            contents: true,
+        } if path == Path::new("/A.BIN") => {}
+        ref change => panic!("unexpected change {:?}", change),
+    }
+    match changes[1] {
+        Change::Changed {
+            ref path,
+            size: Some((old, new)),
+            contents: false,
+            ..
+        } if path == Path::new("/C.BIN") && new == old + 3 => {}
+        ref change => panic!("unexpected change {:?}", change),
+    }
+    assert_eq!(
+        changes[2],
+        Change::Added {
+            path: "/D".into(),
+            is_dir: true,
+        }
+    );
+    assert_eq!(
+        changes[3],
+        Change::Removed {
+            path: "/OLD.TXT".into(),
+            is_dir: false,
+        }
+    );
+    assert_eq!(changes[3].to_string(), "- /OLD.TXT");
+}
+
+#[test]
+fn test_check_clean() {
+    let report = fragmented_image().check().expect("check");
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!((report.files, report.dirs), (3, 1));
+}
+
+#[test]
+fn test_check_problems() {
+    let mut image = RawImage::new(8192, &FormatOptions::default());
+    image.add_file(0, b"A       BIN", &[3, 4]);
+    image.add_file(1, b"B       BIN", &[5, 4]);
+    image.add_file(2, b"C       BIN", &[6]);
+    image.set_entry(2, 2, b"C       BIN", 0x20, 6, 5000);
+    image.add_file(3, b"D       BIN", &[7, 8]);
+    image.set_fat(8, 0);
+    image.set_fat(9, 0x0FFF_FFFF);
+    image.set_fat(10, 0x0FFF_FFF7);
+
+    let report = image.mount().check().expect("check");
+    assert_eq!(report.files, 4);
+    assert_eq!(
+        report.problems,
+        vec![
+            Problem::CrossLinked {
+                path: "/B.BIN".into(),
+                cluster: Cluster::from(4),
+            },
+            Problem::SizeMismatch {
+                path: "/C.BIN".into(),
+                size: 5000,
+                clusters: 1,
+            },
+            Problem::BrokenChain { path: "/D.BIN".into() },
+            Problem::LostClusters { clusters: 3 },
+        ]
+    );
+}
+
+#[test]
+fn test_cancel() {
+    let vfat = fragmented_image();
+    let cancel = CancelToken::new();
+    assert!(vfat.check_with_cancel(&cancel).expect("check").is_clean());
+
+    // Cancelling from within a walk stops it before the next entry.
+    let mut visited = 0;
+    let root = vfat.open_dir("/").expect("root");
+    let e = root
+        .walk_with_cancel(Path::new("/"), &cancel, &mut |_, _| {
+            visited += 1;
+            cancel.cancel();
+            Ok(())
+        })
+        .unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    assert_eq!(visited, 1);
+
+    assert!(cancel.is_cancelled());
+    let e = vfat.check_with_cancel(&cancel).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+
+    let start = vfat.open_file("/A.BIN").expect("open").cluster;
+    let mut buf = Vec::new();
+    let e = vfat.borrow_mut().read_chain_with_cancel(start, &mut buf, &cancel).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+
+    let mut data = vec![0u8; 4096 * 512];
+    let options = FormatOptions {
+        cancel: Some(cancel),
+        ..FormatOptions::default()
+    };
+    let e = format(Cursor::new(&mut data[..]), 0, 4096, &options).unwrap_err();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::Other);
+    assert!(data.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_defragment_dry_run() {
+    let vfat = fragmented_image();
+    let before = read_all(vfat.open_file("/A.BIN").expect("open"));
+
+    let report = vfat.defragment(true).expect("dry run");
+    assert_eq!(report.fragmented_files, 2);
+    assert_eq!(report.moved_files, 2);
+    assert_eq!(report.moved_clusters, 5);
+    assert_eq!(report.skipped_files, 0);
+
+    let file = vfat.open_file("/A.BIN").expect("open");
+    assert_eq!(vfat.borrow_mut().chain(file.cluster).unwrap().len(), 3);
+    assert_eq!(file.cluster.fat_index(), 3);
+    assert_eq!(read_all(file), before);
+}
+
+#[test]
+fn test_defragment() {
+    let vfat = fragmented_image();
+    let before_a = read_all(vfat.open_file("/A.BIN").expect("open"));
+    let before_c = read_all(vfat.open_file("/C.BIN").expect("open"));
+
+    let report = vfat.defragment(false).expect("defragment");
+    assert_eq!(report.moved_files, 2);
+
+    for name in &["/A.BIN", "/C.BIN"] {
+        let file = vfat.open_file(name).expect("open");
+        let chain = vfat.borrow_mut().chain(file.cluster).expect("chain");
+        assert!(chain.windows(2).all(|p| p[1].fat_index() == p[0].fat_index() + 1));
+    }
+
+    assert_eq!(read_all(vfat.open_file("/A.BIN").expect("open")), before_a);
+    assert_eq!(read_all(vfat.open_file("/C.BIN").expect("open")), before_c);
+    assert_eq!(vfat.defragment(true).expect("dry run").fragmented_files, 0);
+}
+
+#[test]
+fn test_defragment_skips_open_files() {
+    let vfat = fragmented_image();
+    let open = vfat.open_file("/A.BIN").expect("open");
+
+    let report = vfat.defragment(false).expect("defragment");
+    assert_eq!(report.moved_files, 1);
+    assert_eq!(report.skipped_files, 1);
+    assert!(Extent::from_chain(&vfat.borrow_mut().chain(open.cluster).expect("chain")).len() > 1);
+    drop(open);
+
+    assert_eq!(vfat.defragment(false).expect("defragment").moved_files, 1);
+}
+
 fn hash_entry<T: Entry>(hash: &mut String, entry: &T) -> ::std::fmt::Result {
     use std::fmt::Write;
 
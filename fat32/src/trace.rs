@@ -0,0 +1,27 @@
+//! Stand-ins for the logging macros of the `log` crate, used when the
+//! `tracing` feature is disabled. Their arguments are type checked but never
+//! evaluated, so logging costs nothing when the feature is off.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
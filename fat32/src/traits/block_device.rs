@@ -29,18 +29,41 @@ pub trait BlockDevice: Send {
     fn read_all_sector(&mut self, n: u64, vec: &mut Vec<u8>) -> io::Result<usize> {
         let sector_size = self.sector_size() as usize;
 
+        // The sector is read into zeroed bytes: handing uninitialized memory
+        // to `read_sector()` as a `&mut [u8]` is undefined behavior.
         let start = vec.len();
-        let available = vec.capacity() - start;
-        if available < sector_size {
-            vec.reserve(sector_size - available);
+        vec.resize(start + sector_size, 0);
+        match self.read_sector(n, &mut vec[start..]) {
+            Ok(read) => {
+                vec.truncate(start + read);
+                Ok(read)
+            }
+            Err(e) => {
+                vec.truncate(start);
+                Err(e)
+            }
         }
+    }
 
-        unsafe {
-            vec.set_len(start + sector_size);
-        }
-        let read = self.read_sector(n, &mut vec[start..])?;
-        unsafe {
-            vec.set_len(start + read);
+    /// Reads the `buf.len() / self.sector_size()` consecutive sectors starting
+    /// at sector `n` into `buf`. The number of bytes read is returned.
+    ///
+    /// The default reads one sector at a time with `read_sector()`. Devices
+    /// for which a large read costs about as much as a small one, like files
+    /// and images in memory, should read the whole run at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if seeking or reading from `self` fails, in which
+    /// case the contents of `buf` are unspecified.
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.sector_size() as usize;
+        let mut read = 0;
+        for (i, chunk) in buf.chunks_mut(sector_size).enumerate() {
+            if chunk.len() < sector_size {
+                break;
+            }
+            read += self.read_sector(n + i as u64, chunk)?;
         }
         Ok(read)
     }
@@ -56,6 +79,30 @@ pub trait BlockDevice: Send {
     /// error of `UnexpectedEof` if the length of `buf` is less than
     /// `self.sector_size()`.
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Blocks until every sector written so far has reached stable storage.
+    /// Devices without a volatile write cache needn't do anything; this is
+    /// the default.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device fails to persist its writes.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Hints that the `count` sectors starting at sector `n` no longer hold
+    /// data that is needed, so that the device may release the space backing
+    /// them, as with a TRIM command or by punching a hole in an image file.
+    /// Reading a discarded sector may return its old contents or anything
+    /// else. The default does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device fails to discard the sectors.
+    fn discard(&mut self, _n: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
@@ -63,9 +110,21 @@ impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
         (*self).read_sector(n, buf)
     }
 
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sectors(n, buf)
+    }
+
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
         (*self).write_sector(n, buf)
     }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (*self).sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        (*self).discard(n, count)
+    }
 }
 
 macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
@@ -80,6 +139,14 @@ macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
             Ok(to_read)
         }
 
+        fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let sector_size = self.sector_size();
+            let to_read = buf.len() - buf.len() % sector_size as usize;
+            self.seek(io::SeekFrom::Start(n * sector_size))?;
+            self.read_exact(&mut buf[..to_read])?;
+            Ok(to_read)
+        }
+
         fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
             let sector_size = self.sector_size();
             let to_write = ::std::cmp::min(sector_size as usize, buf.len());
@@ -93,5 +160,37 @@ macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
 impl_for_read_write_seek!(<'a> ::std::io::Cursor<&'a mut [u8]>);
 impl_for_read_write_seek!(::std::io::Cursor<Vec<u8>>);
 impl_for_read_write_seek!(::std::io::Cursor<Box<[u8]>>);
-#[cfg(test)]
-impl_for_read_write_seek!(::std::fs::File);
+
+#[cfg(not(any(target_os = "ros", target_arch = "wasm32")))]
+impl BlockDevice for ::std::fs::File {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Read, Seek};
+        let sector_size = self.sector_size();
+        let to_read = ::std::cmp::min(sector_size as usize, buf.len());
+        self.seek(io::SeekFrom::Start(n * sector_size))?;
+        self.read_exact(&mut buf[..to_read])?;
+        Ok(to_read)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Read, Seek};
+        let sector_size = self.sector_size();
+        let to_read = buf.len() - buf.len() % sector_size as usize;
+        self.seek(io::SeekFrom::Start(n * sector_size))?;
+        self.read_exact(&mut buf[..to_read])?;
+        Ok(to_read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        use std::io::{Seek, Write};
+        let sector_size = self.sector_size();
+        let to_write = ::std::cmp::min(sector_size as usize, buf.len());
+        self.seek(io::SeekFrom::Start(n * sector_size))?;
+        self.write_all(&buf[..to_write])?;
+        Ok(to_write)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use traits::BlockDevice;
+
+/// A `BlockDevice` that injects errors and delays into the operations on the
+/// device it wraps, to exercise retry logic, timeouts, and the behavior of a
+/// file system on slow or unreliable media.
+///
+/// Each read and write fails with an error of `Other`, before reaching the
+/// wrapped device, with a configured probability, and each read, write, and
+/// sync first sleeps for a configured delay. Errors are drawn from a
+/// pseudorandom generator seeded when the device is created, so that the same
+/// seed and sequence of operations always fail the same way. By default, no
+/// errors are injected and operations aren't delayed.
+#[derive(Debug)]
+pub struct ChaosDevice<T> {
+    device: T,
+    /// The state of the xorshift64* generator; never 0.
+    state: u64,
+    read_error_rate: f64,
+    write_error_rate: f64,
+    read_delay: Duration,
+    write_delay: Duration,
+    sync_delay: Duration,
+    injected: u64,
+    delayed: Duration,
+}
+
+/// Panics unless `rate` is a probability.
+fn check_rate(rate: f64) {
+    assert!(rate >= 0.0 && rate <= 1.0, "error rate must be between 0 and 1");
+}
+
+impl<T: BlockDevice> ChaosDevice<T> {
+    /// Wraps `device`, drawing errors from a generator seeded with `seed`.
+    pub fn new(device: T, seed: u64) -> ChaosDevice<T> {
+        ChaosDevice {
+            device,
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+            read_error_rate: 0.0,
+            write_error_rate: 0.0,
+            read_delay: Duration::from_secs(0),
+            write_delay: Duration::from_secs(0),
+            sync_delay: Duration::from_secs(0),
+            injected: 0,
+            delayed: Duration::from_secs(0),
+        }
+    }
+
+    /// Makes each sector read fail with probability `rate`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` isn't between 0 and 1.
+    pub fn set_read_error_rate(&mut self, rate: f64) {
+        check_rate(rate);
+        self.read_error_rate = rate;
+    }
+
+    /// Makes each sector write fail with probability `rate`. A failed write
+    /// leaves the sector untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` isn't between 0 and 1.
+    pub fn set_write_error_rate(&mut self, rate: f64) {
+        check_rate(rate);
+        self.write_error_rate = rate;
+    }
+
+    /// Delays each read, of one sector or of a run of sectors, by `delay`.
+    pub fn set_read_delay(&mut self, delay: Duration) {
+        self.read_delay = delay;
+    }
+
+    /// Delays each sector write by `delay`.
+    pub fn set_write_delay(&mut self, delay: Duration) {
+        self.write_delay = delay;
+    }
+
+    /// Delays each sync by `delay`.
+    pub fn set_sync_delay(&mut self, delay: Duration) {
+        self.sync_delay = delay;
+    }
+
+    /// The number of errors injected so far.
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    /// The total time operations were delayed so far.
+    pub fn delayed(&self) -> Duration {
+        self.delayed
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Returns the next number of the generator, uniformly distributed in
+    /// [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+        bits as f64 / (1u64 << 53) as f64
+    }
+
+    /// Sleeps for `delay`, then fails with probability `rate`.
+    fn disturb(&mut self, delay: Duration, rate: f64, op: &str, n: u64) -> io::Result<()> {
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+            self.delayed += delay;
+        }
+        if rate > 0.0 && self.next_f64() < rate {
+            trace!("injecting {} error at sector {}", op, n);
+            self.injected += 1;
+            return Err(io::Error::new(io::ErrorKind::Other, "injected error"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for ChaosDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (delay, rate) = (self.read_delay, self.read_error_rate);
+        self.disturb(delay, rate, "read", n)?;
+        self.device.read_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (delay, rate) = (self.read_delay, self.read_error_rate);
+        self.disturb(delay, rate, "read", n)?;
+        self.device.read_sectors(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let (delay, rate) = (self.write_delay, self.write_error_rate);
+        self.disturb(delay, rate, "write", n)?;
+        self.device.write_sector(n, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let delay = self.sync_delay;
+        self.disturb(delay, 0.0, "sync", 0)?;
+        self.device.sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        self.device.discard(n, count)
+    }
+}
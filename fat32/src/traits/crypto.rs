@@ -0,0 +1,186 @@
+use std::fmt;
+use std::io;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::{Aes128, Aes256, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+
+use traits::BlockDevice;
+
+/// The AES keys of an `EncryptedDevice`: the key encrypting the data and the
+/// key encrypting the tweaks.
+enum Keys {
+    Aes128(Aes128, Aes128),
+    Aes256(Aes256, Aes256),
+}
+
+/// A `BlockDevice` that encrypts the sectors written to the device it wraps,
+/// and decrypts those read from it, with AES in XTS mode, as used by disk
+/// encryption tools like dm-crypt and VeraCrypt.
+///
+/// Each sector is a data unit of its own, whose tweak is its sector number
+/// in the wrapped device, counted from 0 and stored in little-endian order.
+/// This is dm-crypt's `aes-xts-plain64` with the device's sector size. When
+/// the encrypted area starts past the beginning of an image, wrap an
+/// `OffsetDevice` so that sector numbers count from the start of the area.
+pub struct EncryptedDevice<T> {
+    device: T,
+    keys: Keys,
+    buf: Vec<u8>,
+}
+
+impl<T: BlockDevice> EncryptedDevice<T> {
+    /// Wraps `device`, encrypting with the XTS key `key`: two AES-128 keys
+    /// making up a 32-byte key, or two AES-256 keys making up a 64-byte key,
+    /// the data key first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `key` isn't 32 or 64 bytes long,
+    /// or if the sector size of `device` isn't a multiple of the 16-byte AES
+    /// block.
+    pub fn new(device: T, key: &[u8]) -> io::Result<EncryptedDevice<T>> {
+        let keys = match key.len() {
+            32 => Keys::Aes128(
+                Aes128::new(GenericArray::from_slice(&key[..16])),
+                Aes128::new(GenericArray::from_slice(&key[16..])),
+            ),
+            64 => Keys::Aes256(
+                Aes256::new(GenericArray::from_slice(&key[..32])),
+                Aes256::new(GenericArray::from_slice(&key[32..])),
+            ),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "XTS keys are 32 or 64 bytes long",
+                ))
+            }
+        };
+        if device.sector_size() % 16 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sector size is not a multiple of the AES block size",
+            ));
+        }
+
+        let buf = vec![0u8; device.sector_size() as usize];
+        Ok(EncryptedDevice { device, keys, buf })
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Encrypts or decrypts `data`, the contents of sector `n`, in place.
+    fn apply(&self, n: u64, data: &mut [u8], encrypt: bool) {
+        match self.keys {
+            Keys::Aes128(ref key, ref tweak_key) => xts(key, tweak_key, n, data, encrypt),
+            Keys::Aes256(ref key, ref tweak_key) => xts(key, tweak_key, n, data, encrypt),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for EncryptedDevice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys = match self.keys {
+            Keys::Aes128(..) => "<AES-128 keys>",
+            Keys::Aes256(..) => "<AES-256 keys>",
+        };
+        f.debug_struct("EncryptedDevice")
+            .field("device", &self.device)
+            .field("keys", &keys)
+            .finish()
+    }
+}
+
+/// Encrypts or decrypts `data`, the data unit numbered `n`, in place with
+/// XTS, using `key` for the data and `tweak_key` for the tweak. `data` is a
+/// whole number of blocks.
+fn xts<C>(key: &C, tweak_key: &C, n: u64, data: &mut [u8], encrypt: bool)
+where
+    C: BlockEncrypt + BlockDecrypt,
+{
+    let mut tweak = GenericArray::clone_from_slice(&[0u8; 16]);
+    for i in 0..8 {
+        tweak[i] = (n >> (i * 8)) as u8;
+    }
+    tweak_key.encrypt_block(&mut tweak);
+
+    for chunk in data.chunks_mut(16) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        for i in 0..16 {
+            block[i] ^= tweak[i];
+        }
+        if encrypt {
+            key.encrypt_block(&mut block);
+        } else {
+            key.decrypt_block(&mut block);
+        }
+        for i in 0..16 {
+            chunk[i] = block[i] ^ tweak[i];
+        }
+
+        // Multiply the tweak by the primitive element of GF(2^128), with the
+        // tweak as a little-endian number.
+        let carry = tweak[15] >> 7;
+        for i in (1..16).rev() {
+            tweak[i] = tweak[i] << 1 | tweak[i - 1] >> 7;
+        }
+        tweak[0] = tweak[0] << 1 ^ carry * 0x87;
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for EncryptedDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Whole blocks must be decrypted, so the sector is read whole.
+        let mut sector = ::std::mem::replace(&mut self.buf, Vec::new());
+        let result = self.device.read_sector(n, &mut sector).map(|read| {
+            self.apply(n, &mut sector[..read], false);
+            let len = ::std::cmp::min(read, buf.len());
+            buf[..len].copy_from_slice(&sector[..len]);
+            len
+        });
+        self.buf = sector;
+        result
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Only whole sectors are read, so they're decrypted in place.
+        let read = self.device.read_sectors(n, buf)?;
+        let sector_size = self.buf.len();
+        for (i, sector) in buf[..read].chunks_mut(sector_size).enumerate() {
+            self.apply(n + i as u64, sector, false);
+        }
+        Ok(read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let mut sector = ::std::mem::replace(&mut self.buf, Vec::new());
+        if buf.len() < sector.len() {
+            self.buf = sector;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "buffer is smaller than a sector",
+            ));
+        }
+
+        let len = sector.len();
+        sector.copy_from_slice(&buf[..len]);
+        self.apply(n, &mut sector, true);
+        let result = self.device.write_sector(n, &sector);
+        self.buf = sector;
+        result
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.device.sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        self.device.discard(n, count)
+    }
+}
@@ -0,0 +1,141 @@
+use std::cmp::min;
+use std::io;
+
+use traits::BlockDevice;
+
+/// What a `FaultyDevice` does with the writes made after its write limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultMode {
+    /// Writes, syncs, and discards fail with an error of `Other`, as on a
+    /// device that was unplugged.
+    Fail,
+    /// Writes, syncs, and discards report success but are ignored, as on a
+    /// device that lost power with writes still in its cache.
+    Drop,
+}
+
+/// A `BlockDevice` that stops honoring writes after a given number of sector
+/// writes, to test how a file system copes with a crash or power loss at
+/// every point of an operation.
+///
+/// The first `limit` sector writes are passed to the wrapped device. The
+/// write that goes over the limit is torn if a number of torn bytes is set:
+/// only that many bytes at the start of the sector are written and the rest
+/// of the sector keeps its old contents. From then on, the device is faulted
+/// and every write is handled as set by its `FaultMode`. Reads are always
+/// passed through.
+#[derive(Debug)]
+pub struct FaultyDevice<T> {
+    device: T,
+    limit: u64,
+    mode: FaultMode,
+    torn_bytes: usize,
+    written: u64,
+    faulted: bool,
+}
+
+impl<T: BlockDevice> FaultyDevice<T> {
+    /// Wraps `device`, honoring the first `limit` sector writes only. Later
+    /// writes fail, as with `FaultMode::Fail`.
+    pub fn new(device: T, limit: u64) -> FaultyDevice<T> {
+        FaultyDevice {
+            device,
+            limit,
+            mode: FaultMode::Fail,
+            torn_bytes: 0,
+            written: 0,
+            faulted: false,
+        }
+    }
+
+    /// Sets what is done with the writes made after the limit.
+    pub fn set_mode(&mut self, mode: FaultMode) {
+        self.mode = mode;
+    }
+
+    /// Tears the write that goes over the limit: its first `bytes` bytes are
+    /// written, the rest of the sector isn't. 0, the default, leaves the
+    /// sector untouched.
+    pub fn set_torn_bytes(&mut self, bytes: usize) {
+        self.torn_bytes = bytes;
+    }
+
+    /// The number of sector writes honored so far, not counting a torn one.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Returns `true` once a write went over the limit.
+    pub fn has_faulted(&self) -> bool {
+        self.faulted
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Returns the result of an operation made after the device faulted.
+    fn fault<R>(&self, result: R) -> io::Result<R> {
+        match self.mode {
+            FaultMode::Fail => Err(io::Error::new(io::ErrorKind::Other, "device faulted")),
+            FaultMode::Drop => Ok(result),
+        }
+    }
+
+    /// Writes the first `torn_bytes` bytes of `buf` over sector `n`.
+    fn tear(&mut self, n: u64, buf: &[u8]) -> io::Result<()> {
+        let mut sector = vec![0u8; self.device.sector_size() as usize];
+        self.device.read_sector(n, &mut sector)?;
+        let torn = min(self.torn_bytes, min(buf.len(), sector.len()));
+        sector[..torn].copy_from_slice(&buf[..torn]);
+        self.device.write_sector(n, &sector)?;
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for FaultyDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.device.read_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.device.read_sectors(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        if !self.faulted && self.written == self.limit {
+            trace!("faulting at write {} to sector {}", self.written, n);
+            self.faulted = true;
+            if self.torn_bytes > 0 {
+                self.tear(n, buf)?;
+            }
+        }
+        if self.faulted {
+            let len = min(buf.len(), self.device.sector_size() as usize);
+            return self.fault(len);
+        }
+
+        let written = self.device.write_sector(n, buf)?;
+        self.written += 1;
+        Ok(written)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.faulted {
+            return self.fault(());
+        }
+        self.device.sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        if self.faulted {
+            return self.fault(());
+        }
+        self.device.discard(n, count)
+    }
+}
@@ -123,7 +123,8 @@ pub trait FileSystem: Sized {
     ///
     /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
     ///
-    /// If any component but the last in `path` does not refer to an existing
+    /// If any component but the last in `path` does not exist, an error kind
+    /// of `NotFound` is returned. If one refers to an entry that is not a
     /// directory, an error kind of `InvalidInput` is returned.
     ///
     /// If an entry at `path` already exists, an error kind of `AlreadyExists`
@@ -142,9 +143,10 @@ pub trait FileSystem: Sized {
     ///
     /// If `path` is not absolute, an error kind of `InvalidInput` is returned.
     ///
-    /// If any component but the last in `path` does not refer to an existing
-    /// directory, or `parents` is `false` and there is no entry at that
-    /// component, an error kind of `InvalidInput` is returned.
+    /// If `parents` is `false` and any component but the last in `path` does
+    /// not exist, an error kind of `NotFound` is returned. If one refers to
+    /// an entry that is not a directory, an error kind of `InvalidInput` is
+    /// returned.
     ///
     /// If an entry at `path` already exists, an error kind of `AlreadyExists`
     /// is returned.
@@ -0,0 +1,194 @@
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use flate2::read::GzDecoder;
+
+use traits::BlockDevice;
+
+/// The size of the sectors of a `CompressedDevice`.
+const SECTOR_SIZE: u64 = 512;
+
+/// One gzip member of a BGZF file.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    /// Offset of the member in the compressed file.
+    offset: u64,
+    /// Size of the member in the compressed file.
+    size: usize,
+    /// Offset of the member's contents in the image.
+    start: u64,
+}
+
+/// A read-only `BlockDevice` exposing a disk image compressed in the BGZF
+/// format, as written by `bgzip`, so that an archived card image can be
+/// mounted without decompressing it first.
+///
+/// A BGZF file is a series of gzip members of at most 64 KiB each, whose
+/// sizes are recorded in their headers, so that any of them can be found and
+/// decompressed on its own: plain `gzip` tools read it as an ordinary gzip
+/// file. The members are indexed when the device is created, reading only
+/// their headers and trailers. A sector is read by decompressing the members
+/// holding it; the last member decompressed is kept, since the sectors of a
+/// cluster are usually read one after the other.
+///
+/// Writing to the device fails with an error of `PermissionDenied`, so a
+/// volume on it is usually mounted with `MountOptions::read_only` set.
+#[derive(Debug)]
+pub struct CompressedDevice<R> {
+    source: R,
+    blocks: Vec<Block>,
+    len: u64,
+    /// The index and contents of the last member decompressed.
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek + Send> CompressedDevice<R> {
+    /// Indexes the BGZF file `source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if `source` isn't a BGZF file, like
+    /// a gzip file written by `gzip` rather than `bgzip`, and any error that
+    /// occurs while reading it.
+    pub fn new(mut source: R) -> io::Result<CompressedDevice<R>> {
+        let end = source.seek(SeekFrom::End(0))?;
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        let mut len = 0;
+        while offset < end {
+            source.seek(SeekFrom::Start(offset))?;
+            let size = member_size(&mut source)?;
+            if offset + size as u64 > end {
+                return Err(invalid("truncated BGZF block"));
+            }
+
+            // The trailer ends with the size of the member's contents.
+            source.seek(SeekFrom::Start(offset + size as u64 - 4))?;
+            let mut isize = [0u8; 4];
+            source.read_exact(&mut isize)?;
+            let contents = (0..4).fold(0u64, |val, i| val | (isize[i] as u64) << (i * 8));
+            if contents > 0 {
+                blocks.push(Block {
+                    offset,
+                    size,
+                    start: len,
+                });
+                len += contents;
+            }
+            offset += size as u64;
+        }
+
+        Ok(CompressedDevice {
+            source,
+            blocks,
+            len,
+            cached: None,
+        })
+    }
+
+    /// The size of the decompressed image in bytes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the compressed file.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Returns the contents of member `index`, decompressing it unless it
+    /// was the last one decompressed.
+    fn block(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.cached.as_ref().map(|&(i, _)| i) != Some(index) {
+            let block = self.blocks[index];
+            let mut compressed = vec![0u8; block.size];
+            self.source.seek(SeekFrom::Start(block.offset))?;
+            self.source.read_exact(&mut compressed)?;
+
+            let mut contents = Vec::new();
+            GzDecoder::new(&compressed[..]).read_to_end(&mut contents)?;
+            let expected = self.blocks.get(index + 1).map_or(self.len, |next| next.start);
+            if contents.len() as u64 != expected - block.start {
+                return Err(invalid("BGZF block size does not match its contents"));
+            }
+            self.cached = Some((index, contents));
+        }
+        Ok(&self.cached.as_ref().expect("cached block").1)
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the header of the gzip member at the position of `source` and
+/// returns the size of the member recorded in its BGZF extra field, which is
+/// checked to be large enough to hold the header and the trailer.
+fn member_size<R: Read>(source: &mut R) -> io::Result<usize> {
+    let mut header = [0u8; 12];
+    source.read_exact(&mut header)?;
+    // The magic number, the deflate method, and the FEXTRA flag.
+    if header[..3] != [0x1F, 0x8B, 0x08] || header[3] & 0x04 == 0 {
+        return Err(invalid("not a BGZF block"));
+    }
+
+    let xlen = header[10] as usize | (header[11] as usize) << 8;
+    let mut extra = vec![0u8; xlen];
+    source.read_exact(&mut extra)?;
+    let mut fields = &extra[..];
+    while fields.len() >= 4 {
+        let len = fields[2] as usize | (fields[3] as usize) << 8;
+        if fields[..2] == [b'B', b'C'] && len == 2 && fields.len() >= 6 {
+            // The member holds at least its header and its 8-byte trailer.
+            let size = (fields[4] as usize | (fields[5] as usize) << 8) + 1;
+            if size < header.len() + xlen + 8 {
+                return Err(invalid("bad BGZF block size"));
+            }
+            return Ok(size);
+        }
+        fields = &fields[min(4 + len, fields.len())..];
+    }
+    Err(invalid("not a BGZF block"))
+}
+
+impl<R: Read + Seek + Send> BlockDevice for CompressedDevice<R> {
+    fn sector_size(&self) -> u64 {
+        SECTOR_SIZE
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = n.saturating_mul(SECTOR_SIZE);
+        if start.saturating_add(SECTOR_SIZE) > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sector beyond end of image",
+            ));
+        }
+
+        // Members don't hold a whole number of sectors, so a sector may be
+        // split across two of them.
+        let len = min(SECTOR_SIZE as usize, buf.len());
+        let mut index = match self.blocks.binary_search_by_key(&start, |block| block.start) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        let mut read = 0;
+        while read < len {
+            let block_start = self.blocks[index].start;
+            let offset = (start + read as u64 - block_start) as usize;
+            let contents = self.block(index)?;
+            let count = min(len - read, contents.len() - offset);
+            buf[read..read + count].copy_from_slice(&contents[offset..offset + count]);
+            read += count;
+            index += 1;
+        }
+        Ok(len)
+    }
+
+    fn write_sector(&mut self, _n: u64, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "compressed images are read-only",
+        ))
+    }
+}
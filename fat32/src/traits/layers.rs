@@ -0,0 +1,347 @@
+use std::cmp::min;
+use std::io;
+
+use traits::BlockDevice;
+
+fn past_end() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "sector beyond end of device")
+}
+
+/// Checks that `devices` isn't empty and that its devices share a sector
+/// size, which is returned.
+fn common_sector_size<'a, T, I>(devices: I) -> io::Result<u64>
+where
+    T: BlockDevice + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    let mut sizes = devices.into_iter().map(|device| device.sector_size());
+    let size = sizes.next().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no devices to combine",
+    ))?;
+    if sizes.any(|other| other != size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "devices have different sector sizes",
+        ));
+    }
+    Ok(size)
+}
+
+/// A `BlockDevice` that exposes the sectors of the device it wraps starting
+/// at a given sector, as when a volume is stored inside a larger blob, like
+/// an image with a header or a firmware file.
+///
+/// Sector `n` of the device is sector `offset + n` of the wrapped device. The
+/// device extends to the end of the wrapped one unless a length is given.
+/// Unlike `PartitionDevice`, it owns the device it wraps.
+#[derive(Debug)]
+pub struct OffsetDevice<T> {
+    device: T,
+    offset: u64,
+    sectors: Option<u64>,
+}
+
+impl<T: BlockDevice> OffsetDevice<T> {
+    /// Exposes the sectors of `device` from sector `offset` on.
+    pub fn new(device: T, offset: u64) -> OffsetDevice<T> {
+        OffsetDevice {
+            device,
+            offset,
+            sectors: None,
+        }
+    }
+
+    /// Exposes the `sectors` sectors of `device` starting at sector `offset`.
+    pub fn with_len(device: T, offset: u64, sectors: u64) -> OffsetDevice<T> {
+        OffsetDevice {
+            device,
+            offset,
+            sectors: Some(sectors),
+        }
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Returns the sector of the wrapped device holding sector `n`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `UnexpectedEof` if `n` is past the given length.
+    fn inner_sector(&self, n: u64) -> io::Result<u64> {
+        match self.sectors {
+            Some(sectors) if n >= sectors => Err(past_end()),
+            _ => self.offset.checked_add(n).ok_or_else(past_end),
+        }
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for OffsetDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector = self.inner_sector(n)?;
+        self.device.read_sector(sector, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len() as u64 / self.device.sector_size();
+        if count > 0 {
+            self.inner_sector(n.saturating_add(count - 1))?;
+        }
+        let sector = self.inner_sector(n)?;
+        self.device.read_sectors(sector, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector = self.inner_sector(n)?;
+        self.device.write_sector(sector, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.device.sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let sector = self.inner_sector(n)?;
+        let count = self.sectors.map_or(count, |sectors| min(count, sectors - n));
+        self.device.discard(sector, count)
+    }
+}
+
+/// A `BlockDevice` that joins several devices end to end, as when an image is
+/// split across several files.
+///
+/// The first sectors of the device are those of the first part, followed by
+/// those of the second part, and so on. Each part is given its length in
+/// sectors, since a `BlockDevice` doesn't know its own.
+#[derive(Debug)]
+pub struct ConcatDevice<T> {
+    parts: Vec<T>,
+    /// The sector of the device where each part starts, followed by the
+    /// total number of sectors.
+    starts: Vec<u64>,
+    sector_size: u64,
+}
+
+impl<T: BlockDevice> ConcatDevice<T> {
+    /// Joins `parts`, each given with its length in sectors, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `parts` is empty or if its
+    /// devices have different sector sizes.
+    pub fn new(parts: Vec<(T, u64)>) -> io::Result<ConcatDevice<T>> {
+        let sector_size = common_sector_size(parts.iter().map(|&(ref part, _)| part))?;
+        let mut starts = vec![0];
+        let mut devices = Vec::with_capacity(parts.len());
+        for (part, sectors) in parts {
+            let end = starts[starts.len() - 1] + sectors;
+            starts.push(end);
+            devices.push(part);
+        }
+        Ok(ConcatDevice {
+            parts: devices,
+            starts,
+            sector_size,
+        })
+    }
+
+    /// The total number of sectors of the parts.
+    pub fn sectors(&self) -> u64 {
+        self.starts[self.parts.len()]
+    }
+
+    /// Returns the parts, without their lengths.
+    pub fn into_inner(self) -> Vec<T> {
+        self.parts
+    }
+
+    /// Returns the index of the part holding sector `n` and the sector of
+    /// that part it is.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `UnexpectedEof` if `n` is past the last part.
+    fn locate(&self, n: u64) -> io::Result<(usize, u64)> {
+        if n >= self.sectors() {
+            return Err(past_end());
+        }
+        // Parts of zero sectors share their start with the next part; the
+        // last part starting at or before `n` is the one holding it.
+        let part = match self.starts.binary_search(&n) {
+            Ok(mut i) => {
+                while self.starts[i + 1] == n {
+                    i += 1;
+                }
+                i
+            }
+            Err(i) => i - 1,
+        };
+        Ok((part, n - self.starts[part]))
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for ConcatDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (part, sector) = self.locate(n)?;
+        self.parts[part].read_sector(sector, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Runs crossing into the next part are split where it starts.
+        let sector_size = self.sector_size as usize;
+        let len = buf.len() - buf.len() % sector_size;
+        let mut read = 0;
+        while read < len {
+            let n = n + (read / sector_size) as u64;
+            let (part, sector) = self.locate(n)?;
+            let left = ((len - read) / sector_size) as u64;
+            let run = min(left, self.starts[part + 1] - n) as usize * sector_size;
+            let got = self.parts[part].read_sectors(sector, &mut buf[read..][..run])?;
+            read += got;
+            if got < run {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let (part, sector) = self.locate(n)?;
+        self.parts[part].write_sector(sector, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for part in &mut self.parts {
+            part.sync()?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let end = min(n.saturating_add(count), self.sectors());
+        let mut n = n;
+        while n < end {
+            let (part, sector) = self.locate(n)?;
+            let run = min(end, self.starts[part + 1]) - n;
+            self.parts[part].discard(sector, run)?;
+            n += run;
+        }
+        Ok(())
+    }
+}
+
+/// A `BlockDevice` that spreads its sectors over several devices in stripes,
+/// as RAID 0 does, as when an image was dumped in interleaved pieces.
+///
+/// The device is cut into stripes of a fixed number of sectors, which are
+/// stored on the devices in turn: the first stripe on the first device, the
+/// second on the second device, and so on, starting over with the first
+/// device after the last.
+#[derive(Debug)]
+pub struct StripeDevice<T> {
+    devices: Vec<T>,
+    stripe: u64,
+    sector_size: u64,
+}
+
+impl<T: BlockDevice> StripeDevice<T> {
+    /// Stripes over `devices` in stripes of `stripe_sectors` sectors.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `devices` is empty, if its
+    /// devices have different sector sizes, or if `stripe_sectors` is 0.
+    pub fn new(devices: Vec<T>, stripe_sectors: u64) -> io::Result<StripeDevice<T>> {
+        let sector_size = common_sector_size(&devices)?;
+        if stripe_sectors == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stripes must hold at least one sector",
+            ));
+        }
+        Ok(StripeDevice {
+            devices,
+            stripe: stripe_sectors,
+            sector_size,
+        })
+    }
+
+    /// Returns the devices.
+    pub fn into_inner(self) -> Vec<T> {
+        self.devices
+    }
+
+    /// Returns the index of the device holding sector `n` and the sector of
+    /// that device it is.
+    fn locate(&self, n: u64) -> (usize, u64) {
+        let count = self.devices.len() as u64;
+        let stripe = n / self.stripe;
+        let device = (stripe % count) as usize;
+        (device, stripe / count * self.stripe + n % self.stripe)
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for StripeDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (device, sector) = self.locate(n);
+        self.devices[device].read_sector(sector, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Runs are split at the end of each stripe.
+        let sector_size = self.sector_size as usize;
+        let len = buf.len() - buf.len() % sector_size;
+        let mut read = 0;
+        while read < len {
+            let n = n + (read / sector_size) as u64;
+            let (device, sector) = self.locate(n);
+            let left = ((len - read) / sector_size) as u64;
+            let run = min(left, self.stripe - n % self.stripe) as usize * sector_size;
+            let got = self.devices[device].read_sectors(sector, &mut buf[read..][..run])?;
+            read += got;
+            if got < run {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let (device, sector) = self.locate(n);
+        self.devices[device].write_sector(sector, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for device in &mut self.devices {
+            device.sync()?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let end = n.saturating_add(count);
+        let mut n = n;
+        while n < end {
+            let (device, sector) = self.locate(n);
+            let run = min(end - n, self.stripe - n % self.stripe);
+            self.devices[device].discard(sector, run)?;
+            n += run;
+        }
+        Ok(())
+    }
+}
@@ -34,9 +34,15 @@ pub trait Metadata: Sized {
     /// Whether the entry should be "hidden" from directory traversals.
     fn hidden(&self) -> bool;
 
+    /// Whether the entry is a system file.
+    fn system(&self) -> bool;
+
     /// Whether the entry is volume id
     fn volume_id(&self) -> bool;
 
+    /// Whether the entry has been modified since it was last backed up.
+    fn archive(&self) -> bool;
+
     /// The timestamp when the entry was created.
     fn created(&self) -> Self::Timestamp;
 
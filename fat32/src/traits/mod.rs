@@ -1,9 +1,29 @@
 mod block_device;
+mod chaos;
+#[cfg(feature = "crypto")]
+mod crypto;
 mod dummy;
+mod faulty;
 mod fs;
+#[cfg(feature = "gzip")]
+mod gzip;
+mod layers;
 mod metadata;
+mod partition;
+mod retry;
+mod throttle;
 
 pub use self::block_device::BlockDevice;
+pub use self::chaos::ChaosDevice;
+#[cfg(feature = "crypto")]
+pub use self::crypto::EncryptedDevice;
 pub use self::dummy::Dummy;
+pub use self::faulty::{FaultMode, FaultyDevice};
 pub use self::fs::{Dir, Entry, File, FileSystem};
+#[cfg(feature = "gzip")]
+pub use self::gzip::CompressedDevice;
+pub use self::layers::{ConcatDevice, OffsetDevice, StripeDevice};
 pub use self::metadata::{Metadata, Timestamp};
+pub use self::partition::PartitionDevice;
+pub use self::retry::RetryDevice;
+pub use self::throttle::ThrottledDevice;
@@ -0,0 +1,93 @@
+use std::io;
+
+use traits::BlockDevice;
+use vfat::Shared;
+
+/// A `BlockDevice` that exposes one partition of a shared disk, so that
+/// several volumes on the same disk can be mounted at once.
+///
+/// Sector `n` of the view is sector `start + n` of the disk. Views of the
+/// same `Shared` disk take turns accessing it, one sector at a time; each
+/// volume mounted from a view caches its own sectors, which never overlap
+/// with another partition's, and the volumes bound the memory their caches
+/// take together by sharing a `CacheBudget` through `MountOptions`. A view
+/// is usually mounted with `VFat::from_volume_with_options()`, since its
+/// first sector is the volume's boot sector rather than an MBR.
+#[derive(Debug)]
+pub struct PartitionDevice<T> {
+    disk: Shared<T>,
+    start: u64,
+    sectors: u64,
+}
+
+impl<T: BlockDevice> PartitionDevice<T> {
+    /// Creates a view of the `sectors` sectors of `disk` starting at sector
+    /// `start`.
+    pub fn new(disk: &Shared<T>, start: u64, sectors: u64) -> PartitionDevice<T> {
+        PartitionDevice {
+            disk: disk.clone(),
+            start,
+            sectors,
+        }
+    }
+
+    /// The sector of the disk where the view begins.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The number of sectors in the view.
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Returns the sector of the disk holding sector `n` of the view.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `UnexpectedEof` if `n` is past the end of the view.
+    fn disk_sector(&self, n: u64) -> io::Result<u64> {
+        if n >= self.sectors {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sector beyond end of partition",
+            ));
+        }
+        Ok(self.start + n)
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for PartitionDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.disk.borrow().sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector = self.disk_sector(n)?;
+        self.disk.borrow_mut().read_sector(sector, buf)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len() as u64 / self.sector_size();
+        if count > 0 {
+            self.disk_sector(n.saturating_add(count - 1))?;
+        }
+        let sector = self.disk_sector(n)?;
+        self.disk.borrow_mut().read_sectors(sector, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector = self.disk_sector(n)?;
+        self.disk.borrow_mut().write_sector(sector, buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.disk.borrow_mut().sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        let sector = self.disk_sector(n)?;
+        let count = ::std::cmp::min(count, self.sectors - n);
+        self.disk.borrow_mut().discard(sector, count)
+    }
+}
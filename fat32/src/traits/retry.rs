@@ -0,0 +1,108 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use traits::BlockDevice;
+
+/// A `BlockDevice` that retries failed operations on the device it wraps, for
+/// devices that fail sporadically, like SD cards accessed over SPI.
+///
+/// An operation that fails is retried up to a configured number of times,
+/// waiting an exponentially growing delay between attempts: the backoff
+/// before the first retry, twice the backoff before the second, and so on.
+/// The backoff is zero by default, in which case an operation is retried
+/// immediately and the thread never sleeps. Errors of `InvalidInput`,
+/// `PermissionDenied`, and `UnexpectedEof` are never retried since another
+/// attempt can't succeed.
+#[derive(Debug)]
+pub struct RetryDevice<T> {
+    device: T,
+    retries: u32,
+    backoff: Duration,
+    retried: u64,
+}
+
+impl<T: BlockDevice> RetryDevice<T> {
+    /// Wraps `device`, retrying each failed operation up to `retries` times.
+    pub fn new(device: T, retries: u32) -> RetryDevice<T> {
+        RetryDevice {
+            device,
+            retries,
+            backoff: Duration::from_secs(0),
+            retried: 0,
+        }
+    }
+
+    /// Sets the delay before the first retry of an operation.
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff = backoff;
+    }
+
+    /// The total number of retries made so far, whether or not they
+    /// succeeded.
+    pub fn retried(&self) -> u64 {
+        self.retried
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Calls `op` until it succeeds, fails with an error that can't be
+    /// retried, or has been retried `self.retries` times.
+    fn retry<R, F>(&mut self, mut op: F) -> io::Result<R>
+    where
+        F: FnMut(&mut T) -> io::Result<R>,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match op(&mut self.device) {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+
+            match error.kind() {
+                io::ErrorKind::InvalidInput
+                | io::ErrorKind::PermissionDenied
+                | io::ErrorKind::UnexpectedEof => return Err(error),
+                _ if attempt == self.retries => return Err(error),
+                _ => {}
+            }
+
+            if delay > Duration::from_secs(0) {
+                thread::sleep(delay);
+                delay = delay * 2;
+            }
+            attempt += 1;
+            self.retried += 1;
+        }
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for RetryDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry(|device| device.read_sector(n, buf))
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.retry(|device| device.read_sectors(n, buf))
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|device| device.write_sector(n, buf))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.retry(|device| device.sync())
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        self.retry(|device| device.discard(n, count))
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use traits::BlockDevice;
+
+/// A `BlockDevice` that limits the rate at which data is read from and
+/// written to the device it wraps, so that a background job, like a scrub or
+/// a copy, doesn't starve other users of a slow bus such as an SD card's.
+///
+/// The limit is enforced with a token bucket: every byte transferred uses up
+/// a token, tokens are refilled at the configured rate, and an operation that
+/// runs out of tokens sleeps until the tokens it used have been refilled. Up
+/// to the burst size, set to one sector by default, may be transferred
+/// without waiting after the device has been idle. `sync()` and `discard()`
+/// transfer no data and are never delayed.
+#[derive(Debug)]
+pub struct ThrottledDevice<T> {
+    device: T,
+    bytes_per_second: u64,
+    burst: u64,
+    /// Bytes that may be transferred without waiting; negative while a
+    /// transfer that used more is being paid for.
+    tokens: f64,
+    refilled: Instant,
+    throttled: Duration,
+}
+
+impl<T: BlockDevice> ThrottledDevice<T> {
+    /// Wraps `device`, transferring at most `bytes_per_second` bytes per
+    /// second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is 0.
+    pub fn new(device: T, bytes_per_second: u64) -> ThrottledDevice<T> {
+        assert!(bytes_per_second > 0, "rate must be positive");
+        let burst = device.sector_size();
+        ThrottledDevice {
+            device,
+            bytes_per_second,
+            burst,
+            tokens: burst as f64,
+            refilled: Instant::now(),
+            throttled: Duration::from_secs(0),
+        }
+    }
+
+    /// Wraps `device`, transferring at most `sectors_per_second` sectors per
+    /// second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `sectors_per_second` is 0.
+    pub fn with_sector_rate(device: T, sectors_per_second: u64) -> ThrottledDevice<T> {
+        let bytes_per_second = sectors_per_second.saturating_mul(device.sector_size());
+        ThrottledDevice::new(device, bytes_per_second)
+    }
+
+    /// Changes the limit to `bytes_per_second` bytes per second, as when a
+    /// background job is allowed to speed up while the bus is otherwise idle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is 0.
+    pub fn set_rate(&mut self, bytes_per_second: u64) {
+        assert!(bytes_per_second > 0, "rate must be positive");
+        self.refill();
+        self.bytes_per_second = bytes_per_second;
+    }
+
+    /// The limit in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Sets the number of bytes that may be transferred at once, without
+    /// waiting, after the device has been idle.
+    pub fn set_burst(&mut self, bytes: u64) {
+        self.refill();
+        self.burst = bytes;
+        self.tokens = self.tokens.min(bytes as f64);
+    }
+
+    /// The total time spent waiting so far.
+    pub fn throttled(&self) -> Duration {
+        self.throttled
+    }
+
+    /// Returns the wrapped device.
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Adds the tokens earned since the last refill, up to the burst size.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + seconds * self.bytes_per_second as f64).min(self.burst as f64);
+        self.refilled = now;
+    }
+
+    /// Uses up `bytes` tokens, sleeping until they have been refilled if
+    /// there weren't enough.
+    fn throttle(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            let nanos = -self.tokens / self.bytes_per_second as f64 * 1e9;
+            let wait = Duration::new((nanos / 1e9) as u64, (nanos % 1e9) as u32);
+            thread::sleep(wait);
+            self.throttled += wait;
+        }
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for ThrottledDevice<T> {
+    fn sector_size(&self) -> u64 {
+        self.device.sector_size()
+    }
+
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.device.read_sector(n, buf)?;
+        self.throttle(read);
+        Ok(read)
+    }
+
+    fn read_sectors(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.device.read_sectors(n, buf)?;
+        self.throttle(read);
+        Ok(read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let written = self.device.write_sector(n, buf)?;
+        self.throttle(written);
+        Ok(written)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.device.sync()
+    }
+
+    fn discard(&mut self, n: u64, count: u64) -> io::Result<()> {
+        self.device.discard(n, count)
+    }
+}
@@ -1,112 +1,262 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
 use std::mem::{align_of, forget, size_of};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 pub trait VecExt {
-    /// Casts a `Vec<T>` into a `Vec<U>`.
+    /// Casts a `Vec<T>` into a `Vec<U>`, reinterpreting its bytes.
     ///
-    /// # Safety
-    ///
-    /// The caller must ensure the following safety properties:
+    /// # Errors
     ///
-    ///   * The vector `self` contains valid elements of type `U`. In
-    ///     particular, note that `drop` will never be called for `T`s in `self`
-    ///     and instead will be called for the `U`'s in `self`.
-    ///   * The size and alignment of `T` and `U` are identical.
+    /// Returns an error of `InvalidData`, dropping `self`, if `T` and `U`
+    /// have different alignments, or if the length or capacity of `self` in
+    /// bytes isn't a multiple of the size of `U`.
     ///
-    /// # Panics
+    /// # Safety
     ///
-    /// Panics if the size or alignment of `T` and `U` differ.
-    unsafe fn cast<U>(self) -> Vec<U>;
+    /// The caller must ensure that the vector `self` contains valid elements
+    /// of type `U`. In particular, note that `drop` will never be called for
+    /// `T`s in `self` and instead will be called for the `U`'s in `self`.
+    unsafe fn cast<U>(self) -> io::Result<Vec<U>>;
 }
 
 pub trait SliceExt {
-    /// Casts an `&[T]` into an `&[U]`.
-    ///
-    /// # Safety
+    /// Casts an `&[T]` into an `&[U]`, reinterpreting its bytes.
     ///
-    /// The caller must ensure the following safety properties:
+    /// # Errors
     ///
-    ///   * The slice `self` contains valid elements of type `U`.
-    ///   * The size of `T` and `U` are identical.
-    ///   * The alignment of `T` is an integer multiple of the alignment of `U`.
+    /// Returns an error of `InvalidData` if `self` isn't aligned for `U` or
+    /// if its length in bytes isn't a multiple of the size of `U`.
     ///
-    /// # Panics
+    /// # Safety
     ///
-    /// Panics if the size of `T` and `U` differ or if the alignment of `T` is
-    /// not an integer multiple of `U`.
-    unsafe fn cast<'a, U>(&'a self) -> &'a [U];
+    /// The caller must ensure that the slice `self` contains valid elements
+    /// of type `U`.
+    unsafe fn cast<'a, U>(&'a self) -> io::Result<&'a [U]>;
 
-    /// Casts an `&mut [T]` into an `&mut [U]`.
-    ///
-    /// # Safety
+    /// Casts an `&mut [T]` into an `&mut [U]`, reinterpreting its bytes.
     ///
-    /// The caller must ensure the following safety properties:
+    /// # Errors
     ///
-    ///   * The slice `self` contains valid elements of type `U`.
-    ///   * The size of `T` and `U` are identical.
-    ///   * The alignment of `T` is an integer multiple of the alignment of `U`.
+    /// Returns an error of `InvalidData` if `self` isn't aligned for `U` or
+    /// if its length in bytes isn't a multiple of the size of `U`.
     ///
-    /// # Panics
+    /// # Safety
     ///
-    /// Panics if the size of `T` and `U` differ or if the alignment of `T` is
-    /// not an integer multiple of `U`.
-    unsafe fn cast_mut<'a, U>(&'a mut self) -> &'a mut [U];
+    /// The caller must ensure that the slice `self` contains valid elements
+    /// of type `U`, and that any `U` written through the returned slice is a
+    /// valid `T`.
+    unsafe fn cast_mut<'a, U>(&'a mut self) -> io::Result<&'a mut [U]>;
 }
 
-fn calc_new_len_cap<T, U>(vec: &Vec<T>) -> (usize, usize) {
-    if size_of::<T>() > size_of::<U>() {
-        assert!(size_of::<T>() % size_of::<U>() == 0);
-        let factor = size_of::<T>() / size_of::<U>();
-        (vec.len() * factor, vec.capacity() * factor)
-    } else if size_of::<U>() > size_of::<T>() {
-        assert!(size_of::<U>() % size_of::<T>() == 0);
-        let factor = size_of::<U>() / size_of::<T>();
-        (vec.len() / factor, vec.capacity() / factor)
-    } else {
-        (vec.len(), vec.capacity())
+/// Returns the number of `U`s held in `len` `T`s.
+///
+/// # Errors
+///
+/// Returns an error of `InvalidData` if `len` `T`s don't hold a whole number
+/// of `U`s.
+fn cast_len<T, U>(len: usize) -> io::Result<usize> {
+    let bytes = len * size_of::<T>();
+    if size_of::<U>() == 0 || bytes % size_of::<U>() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "buffer size isn't a multiple of the element size",
+        ));
     }
+    Ok(bytes / size_of::<U>())
+}
+
+/// Checks that `ptr` is aligned for a `U`.
+fn check_align<U>(ptr: usize) -> io::Result<()> {
+    if ptr % align_of::<U>() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "buffer isn't aligned for the element type",
+        ));
+    }
+    Ok(())
 }
 
 impl<T> VecExt for Vec<T> {
-    unsafe fn cast<U>(mut self) -> Vec<U> {
-        assert!(align_of::<T>() == align_of::<U>());
+    unsafe fn cast<U>(mut self) -> io::Result<Vec<U>> {
+        // The allocation is freed with the alignment of `U`.
+        if align_of::<T>() != align_of::<U>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "element types have different alignments",
+            ));
+        }
 
-        let (new_len, new_cap) = calc_new_len_cap::<T, U>(&self);
+        let new_len = cast_len::<T, U>(self.len())?;
+        let new_cap = cast_len::<T, U>(self.capacity())?;
         let new_ptr = self.as_mut_ptr() as *mut U;
         forget(self);
 
-        Vec::from_raw_parts(new_ptr, new_len, new_cap)
+        Ok(Vec::from_raw_parts(new_ptr, new_len, new_cap))
     }
 }
 
-fn calc_new_len<T, U>(slice: &[T]) -> usize {
-    if size_of::<T>() > size_of::<U>() {
-        assert!(size_of::<T>() % size_of::<U>() == 0);
-        let factor = size_of::<T>() / size_of::<U>();
-        slice.len() * factor
-    } else if size_of::<U>() > size_of::<T>() {
-        assert!(size_of::<U>() % size_of::<T>() == 0);
-        let factor = size_of::<U>() / size_of::<T>();
-        slice.len() / factor
-    } else {
-        slice.len()
+impl<T> SliceExt for [T] {
+    unsafe fn cast<'a, U>(&'a self) -> io::Result<&'a [U]> {
+        check_align::<U>(self.as_ptr() as usize)?;
+        let new_len = cast_len::<T, U>(self.len())?;
+        Ok(from_raw_parts(self.as_ptr() as *const U, new_len))
+    }
+
+    unsafe fn cast_mut<'a, U>(&'a mut self) -> io::Result<&'a mut [U]> {
+        check_align::<U>(self.as_ptr() as usize)?;
+        let new_len = cast_len::<T, U>(self.len())?;
+        Ok(from_raw_parts_mut(self.as_mut_ptr() as *mut U, new_len))
     }
 }
 
-impl<T> SliceExt for [T] {
-    unsafe fn cast<'a, U>(&'a self) -> &'a [U] {
-        assert!(align_of::<T>() % align_of::<U>() == 0);
+/// Defines a little-endian integer type, `$name`, storing a `$int` as the
+/// `$size` bytes of an on-disk structure.
+macro_rules! le_int {
+    ($name:ident, $int:ty, $size:expr) => {
+        /// An integer stored in little-endian byte order, as in every on-disk
+        /// structure of FAT and of the MBR. Its value is correct on hosts of
+        /// either endianness, and since it is aligned to a single byte, it can
+        /// be a field of a packed struct and borrowed from one.
+        #[repr(C)]
+        #[derive(Default, Copy, Clone, PartialEq, Eq, Hash)]
+        pub struct $name([u8; $size]);
+
+        impl $name {
+            pub fn new(value: $int) -> $name {
+                let mut le = $name([0; $size]);
+                le.set(value);
+                le
+            }
+
+            pub fn get(&self) -> $int {
+                self.0.iter().rev().fold(0, |value, &byte| value << 8 | byte as $int)
+            }
 
-        let new_len = calc_new_len::<T, U>(self);
-        let new_ptr = self.as_ptr() as *const U;
-        from_raw_parts(new_ptr, new_len)
+            pub fn set(&mut self, value: $int) {
+                for (i, byte) in self.0.iter_mut().enumerate() {
+                    *byte = (value >> (8 * i)) as u8;
+                }
+            }
+
+            /// Returns the integer stored at byte `offset` of `buf`.
+            ///
+            /// # Panics
+            ///
+            /// Panics if `buf` ends before the integer does.
+            #[allow(dead_code)]
+            pub fn read(buf: &[u8], offset: usize) -> $int {
+                let mut le = $name([0; $size]);
+                le.0.copy_from_slice(&buf[offset..offset + $size]);
+                le.get()
+            }
+
+            /// Stores `value` at byte `offset` of `buf`.
+            ///
+            /// # Panics
+            ///
+            /// Panics if `buf` ends before the integer does.
+            #[allow(dead_code)]
+            pub fn write(buf: &mut [u8], offset: usize, value: $int) {
+                buf[offset..offset + $size].copy_from_slice(&$name::new(value).0);
+            }
+        }
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &$name) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $name {
+            fn cmp(&self, other: &$name) -> Ordering {
+                self.get().cmp(&other.get())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+    };
+}
+
+le_int!(Le16, u16, 2);
+le_int!(Le32, u32, 4);
+le_int!(Le64, u64, 8);
+
+/// Returns `s` as a JSON string literal, quoted and escaped.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_cast() {
+        let les: Vec<Le32> = unsafe { vec![0u8; 8].cast() }.unwrap();
+        assert_eq!(les.len(), 2);
+        assert!(unsafe { vec![0u8; 8].cast::<u32>() }.is_err());
+        let mut odd = Vec::with_capacity(10);
+        odd.extend_from_slice(&[0u8; 8]);
+        assert!(unsafe { odd.cast::<Le32>() }.is_err());
+
+        let bytes = [1u8, 0, 0, 0, 2, 0, 0, 0, 3];
+        let les: &[Le32] = unsafe { bytes[..8].cast() }.unwrap();
+        assert_eq!((les[0].get(), les[1].get()), (1, 2));
+        assert!(unsafe { bytes.cast::<Le32>() }.is_err());
+
+        let words = [0u32; 2];
+        let bytes: &[u8] = unsafe { words.cast() }.unwrap();
+        assert_eq!(bytes.len(), 8);
+        assert!(unsafe { bytes[1..5].cast::<u32>() }.is_err());
     }
 
-    unsafe fn cast_mut<'a, U>(&'a mut self) -> &'a mut [U] {
-        assert!(align_of::<T>() % align_of::<U>() == 0);
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a b"), "\"a b\"");
+        assert_eq!(json_string("\"\\/\n\u{1}é"), "\"\\\"\\\\/\\n\\u0001é\"");
+    }
 
-        let new_len = calc_new_len::<T, U>(self);
-        let new_ptr = self.as_mut_ptr() as *mut U;
-        from_raw_parts_mut(new_ptr, new_len)
+    #[test]
+    fn test_le_int() {
+        let mut le = Le32::new(0x1234_5678);
+        assert_eq!(le.0, [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(le.get(), 0x1234_5678);
+        le.set(0xFFFF_0001);
+        assert_eq!(le.0, [0x01, 0x00, 0xFF, 0xFF]);
+        assert_eq!(Le16::new(0xBEEF).0, [0xEF, 0xBE]);
+        assert_eq!(format!("{:?}", Le16::new(512)), "512");
+
+        // Ordered by value, not by the first byte stored.
+        assert!(Le16::new(0x0100) > Le16::new(0x00FF));
+        assert_eq!(size_of::<Le32>(), 4);
+        assert_eq!(align_of::<Le32>(), 1);
+
+        let mut buf = [0u8; 12];
+        Le64::write(&mut buf, 1, 0x0102_0304_0506_0708);
+        Le16::write(&mut buf, 10, 0xBEEF);
+        assert_eq!(&buf[..4], [0, 0x08, 0x07, 0x06]);
+        assert_eq!(Le32::read(&buf, 5), 0x0102_0304);
+        assert_eq!(Le64::read(&buf, 1), 0x0102_0304_0506_0708);
+        assert_eq!(Le16::read(&buf, 10), 0xBEEF);
     }
 }
@@ -0,0 +1,26 @@
+/// How a volume chooses a free cluster when a file or directory grows.
+///
+/// The policy of a volume is set with `VFat::set_alloc_policy()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Takes the lowest-numbered free cluster, keeping data packed at the
+    /// start of the volume at the cost of fragmenting files that grow into
+    /// holes left by deleted ones.
+    FirstFit,
+    /// Takes the first free cluster after the most recently allocated one,
+    /// starting from the next-free hint in the FSInfo sector when the volume
+    /// is mounted. This is the default, and what most FAT drivers do.
+    NextFit,
+    /// Extends a chain with the cluster right after its last cluster when
+    /// that cluster is free and otherwise starts a new run at the beginning
+    /// of the longest run of free clusters. Large files written sequentially,
+    /// like video recordings, stay contiguous for as long as the free space
+    /// allows.
+    Contiguous,
+}
+
+impl Default for AllocPolicy {
+    fn default() -> AllocPolicy {
+        AllocPolicy::NextFit
+    }
+}
@@ -0,0 +1,180 @@
+use std::cmp::min;
+
+use vfat::{Cluster, Extent};
+
+/// A bitmap of the free clusters of a volume, indexed by cluster number, so
+/// that finding a free cluster doesn't require reading the FAT.
+#[derive(Debug, Clone)]
+pub(crate) struct FreeBitmap {
+    /// One bit per cluster, set if the cluster is free.
+    words: Vec<u64>,
+    /// One past the largest cluster number in the bitmap.
+    len: u32,
+}
+
+impl FreeBitmap {
+    /// Creates a bitmap from `free`, which is indexed by cluster number and
+    /// is `true` for every free cluster.
+    pub fn new(free: &[bool]) -> FreeBitmap {
+        let mut bitmap = FreeBitmap {
+            words: vec![0; (free.len() + 63) / 64],
+            len: free.len() as u32,
+        };
+        for (index, &is_free) in free.iter().enumerate() {
+            bitmap.set(index as u32, is_free);
+        }
+        bitmap
+    }
+
+    /// Returns `true` if `cluster` is free.
+    pub fn is_free(&self, cluster: u32) -> bool {
+        cluster < self.len && self.words[(cluster / 64) as usize] & 1 << (cluster % 64) != 0
+    }
+
+    /// Returns the number of free clusters.
+    pub fn count(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// Marks `cluster` as free or in use. Clusters outside of the bitmap are
+    /// ignored.
+    pub fn set(&mut self, cluster: u32, free: bool) {
+        if cluster >= self.len {
+            return;
+        }
+
+        let word = &mut self.words[(cluster / 64) as usize];
+        if free {
+            *word |= 1 << (cluster % 64);
+        } else {
+            *word &= !(1 << (cluster % 64));
+        }
+    }
+
+    /// Returns the first free cluster at or after `start`, wrapping around to
+    /// the beginning of the bitmap, or `None` if no cluster is free.
+    pub fn find_free(&self, start: u32) -> Option<u32> {
+        self.find_free_in(start, self.len)
+            .or_else(|| self.find_free_in(0, start))
+    }
+
+    /// Returns the first cluster and the length of the longest run of free
+    /// clusters, the lowest-numbered one among runs of equal length, or
+    /// `None` if no cluster is free.
+    pub fn longest_free_run(&self) -> Option<(u32, u32)> {
+        let mut longest: Option<(u32, u32)> = None;
+        let mut cluster = 0;
+        while let Some(start) = self.find_free_in(cluster, self.len) {
+            let end = self.run_end(start);
+            if longest.map_or(true, |(_, len)| end - start > len) {
+                longest = Some((start, end - start));
+            }
+            cluster = end;
+        }
+        longest
+    }
+
+    /// Returns the first cluster of the lowest-numbered run of at least `len`
+    /// free clusters that starts at or after `start`, if any.
+    pub fn find_free_run(&self, start: u32, len: u32) -> Option<u32> {
+        let mut cluster = start;
+        while let Some(run_start) = self.find_free_in(cluster, self.len) {
+            let end = self.run_end(run_start);
+            if end - run_start >= len {
+                return Some(run_start);
+            }
+            cluster = end;
+        }
+        None
+    }
+
+    /// Returns an iterator over the free clusters in the bitmap, in order.
+    pub fn iter(&self) -> FreeClusters {
+        FreeClusters {
+            bitmap: self.clone(),
+            next: 0,
+        }
+    }
+
+    /// Returns the first cluster at or after `start` that is in use, or the
+    /// end of the bitmap if there is none.
+    fn run_end(&self, start: u32) -> u32 {
+        let mut cluster = start;
+        while cluster < self.len {
+            let used = !self.words[(cluster / 64) as usize] >> (cluster % 64);
+            if used == 0 {
+                cluster = (cluster / 64 + 1) * 64;
+                continue;
+            }
+            return min(cluster + used.trailing_zeros(), self.len);
+        }
+        self.len
+    }
+
+    /// Returns the first free cluster in `start..end`, skipping 64 clusters
+    /// at a time where none are free.
+    fn find_free_in(&self, start: u32, end: u32) -> Option<u32> {
+        let mut cluster = start;
+        while cluster < end {
+            let word = self.words[(cluster / 64) as usize] >> (cluster % 64);
+            if word == 0 {
+                cluster = (cluster / 64 + 1) * 64;
+                continue;
+            }
+
+            let found = cluster + word.trailing_zeros();
+            return if found < end { Some(found) } else { None };
+        }
+        None
+    }
+}
+
+/// An iterator over the free clusters of a volume in cluster order, returned
+/// by `VFat::free_clusters()`. It iterates over a snapshot of the free
+/// clusters taken when it was created, so clusters allocated or freed since
+/// aren't reflected.
+#[derive(Debug, Clone)]
+pub struct FreeClusters {
+    bitmap: FreeBitmap,
+    next: u32,
+}
+
+/// An iterator over the runs of contiguous free clusters of a volume in
+/// cluster order, returned by `FreeClusters::runs()`.
+#[derive(Debug, Clone)]
+pub struct FreeRuns {
+    clusters: FreeClusters,
+}
+
+impl FreeClusters {
+    /// Returns an iterator over the remaining free clusters grouped into runs
+    /// of contiguous clusters, each as long as possible.
+    pub fn runs(self) -> FreeRuns {
+        FreeRuns { clusters: self }
+    }
+}
+
+impl Iterator for FreeClusters {
+    type Item = Cluster;
+
+    fn next(&mut self) -> Option<Cluster> {
+        let found = self.bitmap.find_free_in(self.next, self.bitmap.len)?;
+        self.next = found + 1;
+        Some(Cluster::from(found))
+    }
+}
+
+impl Iterator for FreeRuns {
+    type Item = Extent;
+
+    fn next(&mut self) -> Option<Extent> {
+        let bitmap = &self.clusters.bitmap;
+        let start = bitmap.find_free_in(self.clusters.next, bitmap.len)?;
+        let end = bitmap.run_end(start);
+        self.clusters.next = end;
+        Some(Extent {
+            start: Cluster::from(start),
+            len: end - start,
+        })
+    }
+}
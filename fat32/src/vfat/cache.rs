@@ -1,13 +1,36 @@
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
 use std::{fmt, io};
 
 use traits::BlockDevice;
+use vfat::journal::fnv1a;
+use vfat::Shared;
+#[cfg(feature = "metrics")]
+use vfat::Metrics;
 
 #[derive(Debug)]
 struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
+    /// Whether the sector holds file system metadata, the FAT or directory
+    /// entries, that a journal must log before writing it back.
+    metadata: bool,
+    /// The checksum of `data` when checksums are verified, or `None` if the
+    /// sector may have been modified since it was last computed.
+    checksum: Option<u32>,
+}
+
+impl CacheEntry {
+    fn new(data: Vec<u8>) -> CacheEntry {
+        CacheEntry {
+            data,
+            dirty: false,
+            metadata: false,
+            checksum: None,
+        }
+    }
 }
 
 pub struct Partition {
@@ -17,10 +40,81 @@ pub struct Partition {
     pub sector_size: u64,
 }
 
+/// A limit on the number of sectors cached by several volumes together, such
+/// as the volumes of the partitions of one disk. Clones share the same limit.
+///
+/// A volume that needs room for a sector while the budget is spent drops its
+/// own clean sectors read longest ago; the sectors of the other volumes are
+/// left alone. As with `VFat::set_cache_capacity()`, sectors with changes
+/// that weren't flushed are always kept, so the budget may be exceeded while
+/// that many sectors are dirty, and by one more sector for each volume that
+/// has no clean sector of its own to drop.
+#[derive(Debug, Clone)]
+pub struct CacheBudget(Shared<Budget>);
+
+#[derive(Debug)]
+struct Budget {
+    capacity: usize,
+    used: usize,
+}
+
+impl CacheBudget {
+    /// Creates a budget of `capacity` sectors, or of any number of sectors if
+    /// `capacity` is 0, in which case the budget only counts them.
+    pub fn new(capacity: usize) -> CacheBudget {
+        CacheBudget(Shared::new(Budget { capacity, used: 0 }))
+    }
+
+    /// The most sectors cached by the volumes sharing the budget.
+    pub fn capacity(&self) -> usize {
+        self.0.borrow().capacity
+    }
+
+    /// The number of sectors presently cached by the volumes sharing the
+    /// budget.
+    pub fn used(&self) -> usize {
+        self.0.borrow().used
+    }
+
+    /// Returns `true` if `extra` more sectors don't fit in the budget.
+    fn is_full(&self, extra: usize) -> bool {
+        let budget = self.0.borrow();
+        budget.capacity != 0 && budget.used + extra > budget.capacity
+    }
+
+    fn take(&self, sectors: usize) {
+        self.0.borrow_mut().used += sectors;
+    }
+
+    fn release(&self, sectors: usize) {
+        // Called from `Drop`, so it mustn't panic while unwinding.
+        if let Some(mut budget) = self.0.try_borrow_mut() {
+            budget.used -= sectors;
+        }
+    }
+}
+
 pub struct CachedDevice {
     device: Box<BlockDevice>,
     cache: HashMap<u64, CacheEntry>,
     partition: Partition,
+    read_only: bool,
+    verify: bool,
+    /// The most sectors kept in the cache, or 0 for no limit.
+    capacity: usize,
+    /// Cached sectors in the order they were read, oldest first, for
+    /// eviction when the cache has a capacity. May hold sectors that were
+    /// since dropped.
+    order: VecDeque<u64>,
+    /// The budget shared with other devices that cached sectors count
+    /// against, if any.
+    budget: Option<CacheBudget>,
+    /// The number of sectors read ahead of a sector that isn't cached.
+    read_ahead: usize,
+    /// The sector past the last one that may be read ahead.
+    read_ahead_end: u64,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl CachedDevice {
@@ -53,7 +147,218 @@ impl CachedDevice {
             device: Box::new(device),
             cache: HashMap::new(),
             partition,
+            read_only: false,
+            verify: false,
+            capacity: 0,
+            order: VecDeque::new(),
+            budget: None,
+            read_ahead: 0,
+            read_ahead_end: 0,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Keeps at most `capacity` sectors in the cache, or any number if
+    /// `capacity` is 0, the default. When the cache is full, the clean sectors
+    /// read longest ago are dropped to make room; dirty sectors are kept
+    /// until they are written back, so the cache may exceed `capacity` while
+    /// more than `capacity` sectors are dirty.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.reset_order();
+    }
+
+    /// Counts cached sectors against `budget` from now on, as well as against
+    /// the device's own capacity, or against the capacity alone if `budget`
+    /// is `None`.
+    pub fn set_budget(&mut self, budget: Option<CacheBudget>) {
+        if let Some(ref old) = self.budget {
+            old.release(self.cache.len());
+        }
+        if let Some(ref new) = budget {
+            new.take(self.cache.len());
+        }
+        self.budget = budget;
+        self.reset_order();
+    }
+
+    /// Returns `true` if the cache is bounded by a capacity or a budget.
+    fn is_bounded(&self) -> bool {
+        self.capacity != 0 || self.budget.is_some()
+    }
+
+    /// Rebuilds the eviction order after the bounds changed, and drops
+    /// sectors until the cache is within them.
+    fn reset_order(&mut self) {
+        self.order = if !self.is_bounded() {
+            VecDeque::new()
+        } else {
+            let mut sectors: Vec<u64> = self.cache.keys().cloned().collect();
+            sectors.sort();
+            sectors.into_iter().collect()
+        };
+        self.make_room(0);
+    }
+
+    /// Reads and caches the `sectors` sectors that follow a sector of the
+    /// partition that isn't cached along with it, in a single device read, so
+    /// that small sequential reads don't each reach the device. Sectors at or
+    /// past `end` aren't read ahead. 0 sectors, the default, disables reading
+    /// ahead.
+    pub fn set_read_ahead(&mut self, sectors: usize, end: u64) {
+        self.read_ahead = sectors;
+        self.read_ahead_end = end;
+    }
+
+    /// Drops the clean sectors read longest ago until `extra` more sectors fit
+    /// in the cache or only dirty sectors are left.
+    fn make_room(&mut self, extra: usize) {
+        if !self.is_bounded() {
+            return;
+        }
+        let mut dirty = 0;
+        while self.is_full(extra) && dirty < self.order.len() {
+            let sector = self.order.pop_front().expect("sector in order");
+            match self.cache.get(&sector).map(|entry| entry.dirty) {
+                Some(true) => {
+                    self.order.push_back(sector);
+                    dirty += 1;
+                }
+                Some(false) => {
+                    trace!("evicting sector {}", sector);
+                    self.remove(sector);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Drops the clean sectors read longest ago until the cache is within its
+    /// capacity and budget again, as is done once dirty sectors were written
+    /// back, so that sectors cached while they were dirty don't hold on to
+    /// a budget other volumes share.
+    pub fn shrink(&mut self) {
+        self.make_room(0);
+    }
+
+    /// Returns `true` if `extra` more sectors don't fit in the cache's
+    /// capacity or its budget.
+    fn is_full(&self, extra: usize) -> bool {
+        (self.capacity != 0 && self.cache.len() + extra > self.capacity)
+            || self.budget.as_ref().map_or(false, |budget| budget.is_full(extra))
+    }
+
+    /// Caches `data` as the contents of sector `sector`, which isn't cached,
+    /// making room for it first.
+    fn insert(&mut self, sector: u64, data: Vec<u8>) {
+        if self.is_bounded() {
+            self.make_room(1);
+            self.order.push_back(sector);
+        }
+        if let Some(ref budget) = self.budget {
+            budget.take(1);
         }
+        self.cache.insert(sector, CacheEntry::new(data));
+    }
+
+    /// Drops sector `sector` from the cache if it is cached.
+    fn remove(&mut self, sector: u64) {
+        if self.cache.remove(&sector).is_some() {
+            if let Some(ref budget) = self.budget {
+                budget.release(1);
+            }
+        }
+    }
+
+    /// Reports reads, writes, and cache accesses to `metrics` from now on, or
+    /// to nothing if `metrics` is `None`.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Calls `f` with the metrics being reported to, if any.
+    #[cfg(feature = "metrics")]
+    pub fn record<F: FnOnce(&Metrics)>(&self, f: F) {
+        record(&self.metrics, f)
+    }
+
+    /// Makes every method that would modify a sector fail with an error of
+    /// `PermissionDenied` if `read_only` is `true`.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Returns `true` if sectors can't be modified.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Checksums sectors as they enter the cache and verifies the checksum
+    /// each time a cached sector is reused or written back if `verify` is
+    /// `true`, so that a cached sector corrupted in memory is caught instead
+    /// of being returned or written to the disk. A sector returned by
+    /// `get_mut()` is checksummed again the next time it is read.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+        if !verify {
+            for entry in self.cache.values_mut() {
+                entry.checksum = None;
+            }
+        }
+    }
+
+    /// Verifies the checksum of the cached sector `sector`, if it has one, or
+    /// computes it if it doesn't. Does nothing unless checksums are verified
+    /// or if the sector isn't cached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the sector doesn't match its
+    /// checksum. A clean sector is dropped from the cache, so that it is read
+    /// from the disk again the next time it is accessed; the changes to a
+    /// dirty one are lost, and it stays cached and fails every check.
+    fn verify_cached(&mut self, sector: u64) -> io::Result<()> {
+        if !self.verify {
+            return Ok(());
+        }
+        let corrupt = match self.cache.get_mut(&sector) {
+            Some(entry) => {
+                let checksum = fnv1a(Some(&entry.data[..]));
+                match entry.checksum {
+                    Some(expected) if expected != checksum => Some(entry.dirty),
+                    _ => {
+                        entry.checksum = Some(checksum);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        match corrupt {
+            None => Ok(()),
+            Some(dirty) => {
+                warn!("cached sector {} doesn't match its checksum", sector);
+                if !dirty {
+                    self.remove(sector);
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("cached sector {} is corrupt", sector),
+                ))
+            }
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "volume is mounted read-only",
+            ));
+        }
+        Ok(())
     }
 
     /// Maps a user's request for a sector `virt` to the physical sector and
@@ -72,21 +377,53 @@ impl CachedDevice {
         }
     }
 
+    /// Reads sector `sector` from the disk, bypassing the cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk.
+    pub fn read_uncached(&mut self, sector: u64) -> io::Result<Vec<u8>> {
+        let (ph_sector, num_sectors) = self.virtual_to_physical(sector);
+        let mut buf = Vec::new();
+        for sec in ph_sector..ph_sector + num_sectors {
+            self.device.read_all_sector(sec, &mut buf)?;
+        }
+        #[cfg(feature = "metrics")]
+        self.record(|m| m.sector_read(sector, buf.len()));
+        Ok(buf)
+    }
+
     fn update_cache(&mut self, sector: u64) -> io::Result<()> {
-        if !self.cache.contains_key(&sector) {
+        if self.cache.contains_key(&sector) {
+            #[cfg(feature = "metrics")]
+            self.record(|m| m.cache_hit(sector));
+            self.verify_cached(sector)?;
+        } else {
             // not cached yet
-            let (ph_sector, num_sectors) = self.virtual_to_physical(sector);
-            let mut buf = Vec::new();
-            for sec in ph_sector..ph_sector + num_sectors {
-                self.device.read_all_sector(sec, &mut buf)?;
+            trace!("caching sector {}", sector);
+            let ahead = if sector < self.partition.start {
+                0
+            } else {
+                min(self.read_ahead as u64, self.read_ahead_end.saturating_sub(sector + 1))
+            };
+            if ahead > 0 {
+                // The run caches the sector as well, unless making room for
+                // the sectors after it dropped it again.
+                let size = self.partition.sector_size as usize;
+                let mut run = vec![0u8; (ahead as usize + 1) * size];
+                self.get_run(sector, &mut run)?;
+                if !self.cache.contains_key(&sector) {
+                    run.truncate(size);
+                    self.insert(sector, run);
+                }
+                return self.verify_cached(sector);
             }
-            self.cache.insert(
-                sector,
-                CacheEntry {
-                    data: buf,
-                    dirty: true,
-                },
-            );
+
+            #[cfg(feature = "metrics")]
+            self.record(|m| m.cache_miss(sector));
+            let buf = self.read_uncached(sector)?;
+            self.insert(sector, buf);
+            self.verify_cached(sector)?;
         }
         Ok(())
     }
@@ -100,12 +437,15 @@ impl CachedDevice {
     ///
     /// # Errors
     ///
-    /// Returns an error if there is an error reading the sector from the disk.
+    /// Returns an error if there is an error reading the sector from the disk,
+    /// and an error of `PermissionDenied` if the device is read-only.
     pub fn get_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.check_writable()?;
         self.update_cache(sector)?;
 
         if let Some(entry) = self.cache.get_mut(&sector) {
             entry.dirty = true;
+            entry.checksum = None;
             return Ok(&mut entry.data[..]);
         } else {
             return Err(io::Error::new(
@@ -133,6 +473,302 @@ impl CachedDevice {
             ));
         }
     }
+
+    /// Reads the `buf.len()` bytes of the consecutive sectors starting at
+    /// sector `sector` into `buf`, like `get()` for each sector, and returns
+    /// the number of bytes read. Cached sectors are copied from the cache;
+    /// each run of sectors that aren't is read from the disk with a single
+    /// call to the device's `read_sectors()` and then cached. Only whole
+    /// sectors are read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sectors from the
+    /// disk. Sectors of runs read before the error stay cached.
+    pub fn get_run(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let physical_size = self.device.sector_size();
+        if sector < self.partition.start && physical_size != self.partition.sector_size {
+            // Sectors before the partition are physical sectors, so the run
+            // isn't made of sectors of one size.
+            let (mut read, mut n) = (0, sector);
+            loop {
+                let size = if n < self.partition.start {
+                    physical_size
+                } else {
+                    self.partition.sector_size
+                } as usize;
+                if buf.len() - read < size {
+                    return Ok(read);
+                }
+                buf[read..read + size].copy_from_slice(self.get(n)?);
+                read += size;
+                n += 1;
+            }
+        }
+
+        let size = self.partition.sector_size as usize;
+        let count = buf.len() / size;
+        let mut i = 0;
+        while i < count {
+            if self.cache.contains_key(&(sector + i as u64)) {
+                #[cfg(feature = "metrics")]
+                record(&self.metrics, |m| m.cache_hit(sector + i as u64));
+                self.verify_cached(sector + i as u64)?;
+                let entry = &self.cache[&(sector + i as u64)];
+                buf[i * size..(i + 1) * size].copy_from_slice(&entry.data);
+                i += 1;
+                continue;
+            }
+
+            let mut end = i + 1;
+            while end < count && !self.cache.contains_key(&(sector + end as u64)) {
+                end += 1;
+            }
+            trace!("caching sectors {} to {}", sector + i as u64, sector + end as u64 - 1);
+            let (physical, _) = self.virtual_to_physical(sector + i as u64);
+            let run = &mut buf[i * size..end * size];
+            if self.device.read_sectors(physical, run)? < run.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "can't read sectors from disk",
+                ));
+            }
+            for (j, data) in run.chunks(size).enumerate() {
+                let n = sector + (i + j) as u64;
+                #[cfg(feature = "metrics")]
+                record(&self.metrics, |m| {
+                    m.cache_miss(n);
+                    m.sector_read(n, size);
+                });
+                self.insert(n, data.to_vec());
+                self.verify_cached(n)?;
+            }
+            i = end;
+        }
+        Ok(count * size)
+    }
+
+    /// Reads `buf.len()` bytes into `buf` starting at byte `offset` of sector
+    /// `sector`, continuing into the sectors that follow as needed, and
+    /// returns the number of bytes read. `offset` may lie past the end of
+    /// `sector`. Only the bytes asked for are copied out of the cache, so a
+    /// 32-byte directory entry is read without copying its whole sector;
+    /// whole sectors in the middle of the range are read as by `get_run()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading a sector from the disk.
+    pub fn read_range(&mut self, sector: u64, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let uniform = self.device.sector_size() == self.partition.sector_size;
+        let (mut sector, mut offset) = (sector, offset);
+        let mut read = 0;
+        while read < buf.len() {
+            let size = self.sector_len(sector);
+            if offset >= size {
+                offset -= size;
+                sector += 1;
+            } else if offset == 0
+                && buf.len() - read >= size
+                && (uniform || sector >= self.partition.start)
+            {
+                let whole = (buf.len() - read) / size * size;
+                read += self.get_run(sector, &mut buf[read..read + whole])?;
+                sector += (whole / size) as u64;
+            } else {
+                let n = min(size - offset, buf.len() - read);
+                buf[read..read + n].copy_from_slice(&self.get(sector)?[offset..offset + n]);
+                read += n;
+                sector += 1;
+                offset = 0;
+            }
+        }
+        Ok(read)
+    }
+
+    /// The size in bytes of sector `sector`: a physical sector before the
+    /// partition and a logical one from its start on.
+    fn sector_len(&self, sector: u64) -> usize {
+        if sector < self.partition.start {
+            self.device.sector_size() as usize
+        } else {
+            self.partition.sector_size as usize
+        }
+    }
+
+    /// Like `get_mut()`, but additionally marks the sector as holding file
+    /// system metadata until it is next written back.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `get_mut()`.
+    pub fn get_mut_metadata(&mut self, sector: u64) -> io::Result<&mut [u8]> {
+        self.get_mut(sector)?;
+        let entry = self.cache.get_mut(&sector).expect("cached sector");
+        entry.metadata = true;
+        Ok(&mut entry.data[..])
+    }
+
+    /// Returns the dirty cached sectors in ascending order, each paired with
+    /// whether it was marked as metadata with `get_mut_metadata()`.
+    pub fn dirty_sectors(&self) -> Vec<(u64, bool)> {
+        let mut dirty: Vec<(u64, bool)> = self
+            .cache
+            .iter()
+            .filter(|&(_, entry)| entry.dirty)
+            .map(|(&sector, entry)| (sector, entry.metadata))
+            .collect();
+        dirty.sort();
+        dirty
+    }
+
+    /// Writes the cached sector `sector` back to the disk if it is dirty and
+    /// marks it clean.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing the sector to the disk,
+    /// in which case the sector remains dirty, and an error of `InvalidData`
+    /// if checksums are verified and the sector doesn't match its checksum,
+    /// in which case nothing is written.
+    pub fn write_back(&mut self, sector: u64) -> io::Result<()> {
+        self.verify_cached(sector)?;
+        let physical = self.virtual_to_physical(sector);
+        if let Some(entry) = self.cache.get_mut(&sector) {
+            if entry.dirty {
+                trace!("writing back sector {}", sector);
+                write_physical(&mut self.device, physical, &entry.data)?;
+                #[cfg(feature = "metrics")]
+                record(&self.metrics, |m| m.sector_written(sector, entry.data.len()));
+                entry.dirty = false;
+                entry.metadata = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every dirty cached sector, undoing the changes made to the cache
+    /// since the sectors were last written back. Returns the number of
+    /// sectors dropped.
+    pub fn drop_dirty(&mut self) -> usize {
+        let before = self.cache.len();
+        self.cache.retain(|_, entry| !entry.dirty);
+        let dropped = before - self.cache.len();
+        if let Some(ref budget) = self.budget {
+            budget.release(dropped);
+        }
+        dropped
+    }
+
+    /// Writes `data`, which must be one sector long, to sector `sector` on the
+    /// disk immediately, bypassing the cache. A cached copy of the sector is
+    /// updated to match but keeps its dirty state.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing the sector to the disk,
+    /// and an error of `PermissionDenied` if the device is read-only.
+    pub fn write_through(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        let physical = self.virtual_to_physical(sector);
+        write_physical(&mut self.device, physical, data)?;
+        #[cfg(feature = "metrics")]
+        self.record(|m| m.sector_written(sector, data.len()));
+        if let Some(entry) = self.cache.get_mut(&sector) {
+            entry.data.copy_from_slice(data);
+            if self.verify {
+                entry.checksum = Some(fnv1a(Some(data)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `update` to sector `sector` as stored on the disk and writes
+    /// the result back immediately, bypassing the cache. A cached copy of the
+    /// sector has `update` applied as well but keeps its dirty state, so that
+    /// changes to the sector that haven't been written back yet stay pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading or writing the sector,
+    /// an error of `PermissionDenied` if the device is read-only, and the
+    /// errors of checksum verification for a cached copy of the sector.
+    pub fn update_on_disk<F: Fn(&mut [u8])>(&mut self, sector: u64, update: F) -> io::Result<()> {
+        self.check_writable()?;
+        self.verify_cached(sector)?;
+        let mut data = self.read_uncached(sector)?;
+        update(&mut data);
+        let physical = self.virtual_to_physical(sector);
+        write_physical(&mut self.device, physical, &data)?;
+        #[cfg(feature = "metrics")]
+        self.record(|m| m.sector_written(sector, data.len()));
+        if let Some(entry) = self.cache.get_mut(&sector) {
+            update(&mut entry.data);
+            if self.verify {
+                entry.checksum = Some(fnv1a(Some(&entry.data[..])));
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks until every sector written back so far has reached stable
+    /// storage. Cached sectors that are still dirty aren't written.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of the device's `sync()`.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.device.sync()
+    }
+
+    /// Discards the `count` sectors starting at sector `sector` on the disk
+    /// with the device's `discard()`. Cached copies of the sectors are kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of the device's `discard()`.
+    pub fn discard(&mut self, sector: u64, count: u64) -> io::Result<()> {
+        self.check_writable()?;
+        let (physical, factor) = self.virtual_to_physical(sector);
+        self.device.discard(physical, count * factor)
+    }
+
+    /// Writes every dirty cached sector back to the disk, in ascending sector
+    /// order, and marks it clean.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing a sector to the disk.
+    /// Sectors that were not written remain dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (sector, _) in self.dirty_sectors() {
+            self.write_back(sector)?;
+        }
+        self.shrink();
+        Ok(())
+    }
+}
+
+/// Writes the logical sector `data` to the `num_sectors` physical sectors of
+/// `device` starting at `ph_sector`.
+fn write_physical(
+    device: &mut Box<BlockDevice>,
+    (ph_sector, num_sectors): (u64, u64),
+    data: &[u8],
+) -> io::Result<()> {
+    let physical_size = data.len() / num_sectors as usize;
+    for (i, chunk) in data.chunks(physical_size).enumerate() {
+        device.write_sector(ph_sector + i as u64, chunk)?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with `metrics`, if any. Takes the field rather than the device so
+/// that it can be called while a cached sector is borrowed.
+#[cfg(feature = "metrics")]
+fn record<F: FnOnce(&Metrics)>(metrics: &Option<Arc<Metrics>>, f: F) {
+    if let Some(ref metrics) = *metrics {
+        f(&**metrics);
+    }
 }
 
 // FIXME: Implement `BlockDevice` for `CacheDevice`. The `read_sector` and
@@ -151,6 +787,11 @@ impl BlockDevice for CachedDevice {
         sec[..len].copy_from_slice(&buf[..len]);
         Ok(len)
     }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.device.sync()
+    }
 }
 
 impl fmt::Debug for CachedDevice {
@@ -161,3 +802,79 @@ impl fmt::Debug for CachedDevice {
             .finish()
     }
 }
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.release(self.cache.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn cached_device(verify: bool) -> CachedDevice {
+        let image: Vec<u8> = (0..4 * 512u32).map(|i| i as u8).collect();
+        let partition = Partition {
+            start: 0,
+            sector_size: 512,
+        };
+        let mut device = CachedDevice::new(Cursor::new(image), partition);
+        device.set_verify(verify);
+        device
+    }
+
+    #[test]
+    fn test_verify_cached_sectors() {
+        let mut device = cached_device(true);
+        device.get(1).expect("get");
+        device.cache.get_mut(&1).expect("cached").data[7] ^= 0x40;
+        let err = device.get(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // The corrupted clean sector was dropped and is read again.
+        assert_eq!(device.get(1).expect("get")[7], 7);
+
+        // Changes made through `get_mut()` aren't corruption.
+        device.get_mut(2).expect("get")[0] = 0xAA;
+        assert_eq!(device.get(2).expect("get")[0], 0xAA);
+        device.cache.get_mut(&2).expect("cached").data[1] = 0;
+        assert!(device.write_back(2).is_err());
+        assert_eq!(device.read_uncached(2).expect("read")[0], 0);
+
+        let mut device = cached_device(false);
+        device.get(1).expect("get");
+        device.cache.get_mut(&1).expect("cached").data[7] ^= 0x40;
+        assert_eq!(device.get(1).expect("get")[7], 7 ^ 0x40);
+    }
+
+    #[test]
+    fn test_capacity_and_read_ahead() {
+        let mut device = cached_device(false);
+        device.set_capacity(2);
+        device.get_mut(0).expect("get")[0] = 0xAA;
+        device.get(1).expect("get");
+        device.get(2).expect("get");
+        device.get(3).expect("get");
+        // The dirty sector is kept, the clean ones read longest ago aren't.
+        let mut cached: Vec<u64> = device.cache.keys().cloned().collect();
+        cached.sort();
+        assert_eq!(cached, vec![0, 3]);
+        device.flush().expect("flush");
+        device.get(1).expect("get");
+        assert_eq!(device.cache.len(), 2);
+        assert!(!device.cache.contains_key(&0));
+
+        let mut device = cached_device(false);
+        device.set_read_ahead(2, 3);
+        assert_eq!(device.get(0).expect("get")[1], 1);
+        let mut cached: Vec<u64> = device.cache.keys().cloned().collect();
+        cached.sort();
+        assert_eq!(cached, vec![0, 1, 2]);
+        device.set_capacity(1);
+        assert_eq!(device.get(3).expect("get")[0], 0);
+        assert_eq!(device.cache.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+}
@@ -0,0 +1,37 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A token for cancelling a long-running operation, like a consistency check
+/// of a large volume, from another thread.
+///
+/// Clones of a token share its state: cancelling any clone cancels them all.
+/// Operations that accept a token check it regularly and fail with an error
+/// of `Other` once it is cancelled, leaving the volume consistent.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels every operation checking this token or one of its clones.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns an error of `Other` if the token has been cancelled.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Other, "operation cancelled"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::min;
+use std::io;
+
+use vfat::{Cluster, Shared, Status, VFat};
+
+/// How likely the contents recovered by `carve()` are those of the deleted
+/// file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    /// The file fit in its first cluster, which is still free: its contents
+    /// are most likely intact.
+    High,
+    /// Every cluster of the assumed run is still free, but the file may not
+    /// have been stored contiguously.
+    Medium,
+    /// Clusters after the first are allocated to other files or lie past the
+    /// end of the volume: the contents are partly overwritten or missing.
+    Low,
+    /// The first cluster is allocated to another file: the contents were
+    /// most likely overwritten.
+    Overwritten,
+}
+
+/// The contents of a deleted file reconstructed by `carve()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Carved {
+    /// The bytes recovered, at most the size of the file. Shorter only if the
+    /// assumed run of clusters extends past the end of the volume.
+    pub data: Vec<u8>,
+    /// Number of clusters read.
+    pub clusters: u32,
+    /// Number of clusters read that are now allocated to other files.
+    pub reused_clusters: u32,
+    pub confidence: Confidence,
+}
+
+impl Shared<VFat> {
+    /// Reconstructs the contents of a deleted file of `size` bytes whose
+    /// first cluster was `start`, as found in its deleted directory entry.
+    ///
+    /// Deleting a file frees its cluster chain in the FAT, so the clusters
+    /// after the first are guessed: the file is assumed to have been stored
+    /// in consecutive clusters, as files written to a volume with little
+    /// fragmentation are. The clusters are read whether or not they have
+    /// been reused since, and the returned `Confidence` tells how much the
+    /// result can be trusted. Some systems clear the high 16 bits of the
+    /// first cluster when deleting an entry, so `start` may need adjusting.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `start` isn't a data cluster of
+    /// the volume, and any error that occurs while reading the FAT or the
+    /// clusters.
+    pub fn carve(&self, start: Cluster, size: u64) -> io::Result<Carved> {
+        let mut vfat = self.borrow_mut();
+        let end = vfat.data_clusters() as u64 + 2;
+        if start.fat_index() < 2 || start.fat_index() as u64 >= end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "start cluster is not a data cluster",
+            ));
+        }
+
+        let cluster_size = vfat.cluster_size();
+        let count = (size + cluster_size as u64 - 1) / cluster_size as u64;
+        let available = min(count, end - start.fat_index() as u64);
+        let mut carved = Carved {
+            data: Vec::with_capacity(min(size, available * cluster_size as u64) as usize),
+            clusters: 0,
+            reused_clusters: 0,
+            confidence: Confidence::High,
+        };
+        let mut buf = vec![0u8; cluster_size];
+        for i in 0..available {
+            let cluster = Cluster::from(start.fat_index() + i as u32);
+            if vfat.fat_entry(cluster)?.status() != Status::Free {
+                carved.reused_clusters += 1;
+                if i == 0 {
+                    carved.confidence = Confidence::Overwritten;
+                }
+            }
+            vfat.read_cluster(cluster, 0, &mut buf)?;
+            let len = min(cluster_size as u64, size - carved.data.len() as u64) as usize;
+            carved.data.extend_from_slice(&buf[..len]);
+            carved.clusters += 1;
+        }
+
+        if carved.confidence != Confidence::Overwritten {
+            carved.confidence = if carved.reused_clusters > 0 || available < count {
+                Confidence::Low
+            } else if count > 1 {
+                Confidence::Medium
+            } else {
+                Confidence::High
+            };
+        }
+        Ok(carved)
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, Metadata};
+use vfat::{CancelToken, Cluster, Dir, Entry, Shared, Status, VFat};
+
+/// A consistency problem found by `check()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The cluster chain of the entry at `path` runs into a free, bad, or
+    /// reserved cluster, leaves the volume, or loops.
+    BrokenChain { path: PathBuf },
+    /// The file at `path` is `size` bytes long but has `clusters` clusters
+    /// allocated to it.
+    SizeMismatch {
+        path: PathBuf,
+        size: u64,
+        clusters: usize,
+    },
+    /// The cluster `cluster` in the chain of the entry at `path` is also part
+    /// of the chain of an entry checked earlier.
+    CrossLinked { path: PathBuf, cluster: Cluster },
+    /// `clusters` clusters are marked as in use in the FAT but belong to no
+    /// entry.
+    LostClusters { clusters: usize },
+}
+
+/// Result of a consistency check of a volume.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Number of files checked.
+    pub files: usize,
+    /// Number of directories checked, including the root directory.
+    pub dirs: usize,
+    /// Problems found, in the order they were found.
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BrokenChain { ref path } => {
+                write!(f, "{}: broken cluster chain", path.display())
+            }
+            Problem::SizeMismatch {
+                ref path,
+                size,
+                clusters,
+            } => write!(
+                f,
+                "{}: size {} does not match {} allocated clusters",
+                path.display(),
+                size,
+                clusters
+            ),
+            Problem::CrossLinked { ref path, cluster } => write!(
+                f,
+                "{}: cluster {} is cross-linked",
+                path.display(),
+                cluster.fat_index()
+            ),
+            Problem::LostClusters { clusters } => {
+                write!(f, "{} lost clusters", clusters)
+            }
+        }
+    }
+}
+
+/// State threaded through a check.
+struct Checker<'a> {
+    cancel: &'a CancelToken,
+    cluster_size: u64,
+    used: Vec<bool>,
+    report: CheckReport,
+}
+
+impl Shared<VFat> {
+    /// Checks the consistency of the directory tree and the FAT.
+    ///
+    /// Every cluster chain reachable from the root directory is followed once.
+    /// Broken and cross-linked chains, files whose size does not match their
+    /// chain, and allocated clusters that no entry refers to are reported.
+    /// Directories with broken or cross-linked chains are not descended into.
+    /// The volume is never modified.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the device fails. Corrupt structures
+    /// are reported as problems, not errors.
+    pub fn check(&self) -> io::Result<CheckReport> {
+        self.check_with_cancel(&CancelToken::new())
+    }
+
+    /// Like `check()`, but checks `cancel` before checking each entry and
+    /// while counting lost clusters, so that checking a huge or badly
+    /// corrupted volume can be abandoned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if `cancel` is cancelled before the check
+    /// completes, and otherwise any error that `check()` returns.
+    pub fn check_with_cancel(&self, cancel: &CancelToken) -> io::Result<CheckReport> {
+        let (cluster_size, end) = {
+            let vfat = self.borrow();
+            (vfat.cluster_size() as u64, vfat.data_clusters() as usize + 2)
+        };
+        let mut checker = Checker {
+            cancel,
+            cluster_size,
+            used: vec![false; end],
+            report: CheckReport::default(),
+        };
+
+        let root = Dir::root(self.clone());
+        checker.report.dirs += 1;
+        if self.check_chain(&mut checker, Path::new("/"), root.cluster())?.is_some() {
+            self.check_dir(&mut checker, &root, Path::new("/"))?;
+        }
+
+        let mut lost = 0;
+        let mut vfat = self.borrow_mut();
+        for index in 2..end {
+            cancel.check()?;
+            match vfat.fat_entry(Cluster::from(index as u32))?.status() {
+                Status::Data(_) | Status::Eoc(_) if !checker.used[index] => lost += 1,
+                _ => {}
+            }
+        }
+        if lost > 0 {
+            checker.report.problems.push(Problem::LostClusters { clusters: lost });
+        }
+
+        Ok(checker.report)
+    }
+
+    fn check_dir(&self, checker: &mut Checker, dir: &Dir, path: &Path) -> io::Result<()> {
+        for entry in dir.entries()? {
+            checker.cancel.check()?;
+            if entry.name() == "." || entry.name() == ".." || entry.metadata().volume_id() {
+                continue;
+            }
+
+            let path = path.join(entry.name());
+            match entry {
+                Entry::File(ref file) => {
+                    checker.report.files += 1;
+                    let clusters = if file.cluster.fat_index() < 2 {
+                        Some(0)
+                    } else {
+                        self.check_chain(checker, &path, file.cluster)?
+                    };
+
+                    let size = file.size as u64;
+                    let expected = (size + checker.cluster_size - 1) / checker.cluster_size;
+                    match clusters {
+                        Some(clusters) if clusters as u64 != expected => {
+                            checker.report.problems.push(Problem::SizeMismatch {
+                                path,
+                                size,
+                                clusters,
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+                Entry::Dir(ref child) => {
+                    checker.report.dirs += 1;
+                    if self.check_chain(checker, &path, child.cluster())?.is_some() {
+                        self.check_dir(checker, child, &path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the chain starting at `start`, marking its clusters as used.
+    /// Returns the length of the chain, or `None` if the chain is broken or
+    /// cross-linked, in which case a problem is recorded.
+    fn check_chain(
+        &self,
+        checker: &mut Checker,
+        path: &Path,
+        start: Cluster,
+    ) -> io::Result<Option<usize>> {
+        let end = checker.used.len() as u32;
+        let broken = Problem::BrokenChain {
+            path: path.to_path_buf(),
+        };
+        if start.fat_index() < 2 || start.fat_index() >= end {
+            checker.report.problems.push(broken);
+            return Ok(None);
+        }
+
+        let chain = match self.borrow_mut().chain(start) {
+            Ok(chain) => chain,
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                checker.report.problems.push(broken);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if chain.iter().any(|c| c.fat_index() < 2 || c.fat_index() >= end) {
+            checker.report.problems.push(broken);
+            return Ok(None);
+        }
+
+        if let Some(&cluster) = chain.iter().find(|c| checker.used[c.fat_index() as usize]) {
+            checker.report.problems.push(Problem::CrossLinked {
+                path: path.to_path_buf(),
+                cluster,
+            });
+            return Ok(None);
+        }
+
+        for cluster in &chain {
+            checker.used[cluster.fat_index() as usize] = true;
+        }
+        Ok(Some(chain.len()))
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+use vfat::Timestamp;
+
+/// The source of the times a volume stamps entries with as they are created
+/// and written.
+///
+/// A volume uses the clock set in `MountOptions::clock` or with
+/// `VFat::set_clock()`, and `SystemClock` if none is set. Systems without a
+/// wall clock that `SystemClock` can read, like a kernel reading a real-time
+/// clock, supply their own.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Timestamp;
+}
+
+/// The clock of the platform: `SystemTime` where the standard library has
+/// one, JavaScript's `Date` on WebAssembly with the `wasm` feature, and
+/// otherwise a clock stopped at the earliest FAT timestamp, 1980-01-01.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock {
+    /// Seconds east of UTC of the local time the clock returns, as FAT
+    /// timestamps are stored in local time. 0 by default, for UTC.
+    pub utc_offset: i32,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::now(self.utc_offset)
+    }
+}
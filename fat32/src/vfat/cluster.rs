@@ -4,6 +4,13 @@ use std::io;
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone, Hash)]
 pub struct Cluster(u32);
 
+/// A run of `len` consecutive clusters starting at `start`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Hash)]
+pub struct Extent {
+    pub start: Cluster,
+    pub len: u32,
+}
+
 impl From<u32> for Cluster {
     fn from(raw_num: u32) -> Cluster {
         Cluster(raw_num & !(0xF << 28))
@@ -25,3 +32,24 @@ impl Cluster {
         }
     }
 }
+
+impl Extent {
+    /// Splits the cluster chain `chain` into its runs of consecutive clusters,
+    /// in chain order.
+    pub fn from_chain(chain: &[Cluster]) -> Vec<Extent> {
+        let mut extents: Vec<Extent> = Vec::new();
+        for &cluster in chain {
+            if let Some(last) = extents.last_mut() {
+                if last.start.0 + last.len == cluster.0 {
+                    last.len += 1;
+                    continue;
+                }
+            }
+            extents.push(Extent {
+                start: cluster,
+                len: 1,
+            });
+        }
+        extents
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata as MetadataTrait};
+use vfat::{Entry, EntryLocation, Metadata, Shared, VFat};
+
+impl Shared<VFat> {
+    /// Copies the file or directory tree at `from` to `to`, which must not
+    /// exist yet. Both paths must be absolute.
+    ///
+    /// Every copied entry is created anew in its destination directory, with
+    /// a short name generated there for names that need a long file name.
+    /// The attributes and the creation, modification, and access times of
+    /// every entry are preserved. The clusters of each copied file are
+    /// allocated up front, so a copy is as contiguous as the free space
+    /// allows. The volume label is not copied.
+    ///
+    /// A copy that fails partway leaves the entries copied so far in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `from`, an error of `InvalidInput`
+    /// if either path is relative or if a directory would be copied into
+    /// itself, and the errors of `create_file()` and `create_dir()` for `to`
+    /// and every entry below it. Returns an error of `Other` if the volume
+    /// runs out of free clusters.
+    pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !from.has_root() || !to.has_root() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path must be absolute",
+            ));
+        }
+
+        let source = self.open(from)?;
+        if let Entry::Dir(ref dir) = source {
+            let mut ancestor = to.parent();
+            while let Some(path) = ancestor {
+                if self.open(path)?.location() == dir.location() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "can't copy a directory into itself",
+                    ));
+                }
+                ancestor = path.parent();
+            }
+        }
+        self.copy_entry(source, to)
+    }
+
+    /// Copies `source` to the new entry at `to`.
+    fn copy_entry(&self, source: Entry, to: &Path) -> io::Result<()> {
+        let metadata = source.metadata().clone();
+        let location = match source {
+            Entry::File(mut source) => {
+                let mut file = self.create_file(to)?;
+                file.preallocate(source.size as u64)?;
+                let mut buf = vec![0u8; self.borrow().cluster_size()];
+                loop {
+                    match source.read(&mut buf)? {
+                        0 => break,
+                        n => file.write_all(&buf[..n])?,
+                    }
+                }
+                file.location
+            }
+            Entry::Dir(source) => {
+                let location = self.create_dir(to, false)?.location();
+                for child in source.entries()? {
+                    let name = child.name().to_string();
+                    if name == "." || name == ".." || child.metadata().volume_id() {
+                        continue;
+                    }
+                    self.copy_entry(child, &to.join(name))?;
+                }
+                location
+            }
+        };
+
+        match location {
+            Some(location) => self.copy_metadata(location, &metadata),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the attributes and times of the entry at `location` to those in
+    /// `metadata`.
+    fn copy_metadata(&self, location: EntryLocation, metadata: &Metadata) -> io::Result<()> {
+        self.borrow_mut().update_dir_entry(location, |entry| {
+            entry.set_times(metadata.created, metadata.modified, metadata.accessed);
+            entry.set_attributes(metadata.attr);
+        })
+    }
+}
@@ -0,0 +1,182 @@
+use std::io;
+use std::path::Path;
+
+use vfat::{Cluster, Dir, Entry, Extent, File, FreeBitmap, Shared, Status, VFat};
+
+/// Summary of a defragmentation pass.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DefragReport {
+    /// Number of files whose clusters are not stored contiguously.
+    pub fragmented_files: usize,
+    /// Number of fragmented files that were (or, in a dry run, would be)
+    /// relocated.
+    pub moved_files: usize,
+    /// Number of clusters that were (or, in a dry run, would be) relocated.
+    pub moved_clusters: usize,
+    /// Number of fragmented files left in place because no free run of
+    /// clusters was large enough to hold them or because they were open.
+    pub skipped_files: usize,
+}
+
+/// Summary of file and free space fragmentation across a volume.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// Number of non-empty files.
+    pub files: usize,
+    /// Number of files stored in more than one extent.
+    pub fragmented_files: usize,
+    /// Total number of extents across all files.
+    pub file_extents: usize,
+    /// Number of clusters used by files.
+    pub file_clusters: usize,
+    /// Number of free clusters.
+    pub free_clusters: usize,
+    /// Number of runs of consecutive free clusters.
+    pub free_extents: usize,
+    /// Length, in clusters, of the longest run of free clusters.
+    pub largest_free_extent: usize,
+}
+
+impl Shared<VFat> {
+    /// Summarizes the fragmentation of every file and of the free space in
+    /// the volume.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the directory tree or the FAT fails.
+    pub fn fragmentation_report(&self) -> io::Result<FragmentationReport> {
+        let mut report = FragmentationReport::default();
+        Dir::root(self.clone()).walk(Path::new("/"), &mut |_, entry| {
+            if let Entry::File(ref file) = *entry {
+                let extents = file.extents()?;
+                if !extents.is_empty() {
+                    report.files += 1;
+                    if extents.len() > 1 {
+                        report.fragmented_files += 1;
+                    }
+                    report.file_extents += extents.len();
+                    report.file_clusters += extents.iter().map(|e| e.len as usize).sum::<usize>();
+                }
+            }
+            Ok(())
+        })?;
+
+        let free = self.borrow_mut().free_map()?;
+        let mut run = 0;
+        for &is_free in free.iter().chain(Some(&false)) {
+            if is_free {
+                report.free_clusters += 1;
+                run += 1;
+            } else if run > 0 {
+                report.free_extents += 1;
+                report.largest_free_extent = ::std::cmp::max(report.largest_free_extent, run);
+                run = 0;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Relocates every fragmented file into a contiguous run of clusters,
+    /// updating the FAT and the file's directory entry.
+    ///
+    /// If `dry_run` is `true`, the volume is not modified and the returned
+    /// report describes the moves that would have been made. Directories are
+    /// never relocated, and files with open handles are skipped.
+    ///
+    /// For each file, the data is copied into its new clusters and the new
+    /// chain is linked before the directory entry is pointed at it; only then
+    /// is the old chain freed. All changes are written back to the device
+    /// before this method returns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the directory tree, reading or writing a
+    /// cluster, or writing back to the device fails.
+    pub fn defragment(&self, dry_run: bool) -> io::Result<DefragReport> {
+        let mut report = DefragReport::default();
+        // A copy of the volume's bitmap, so that a dry run sees the clusters
+        // taken by the moves it plans.
+        let mut free = self.borrow_mut().free_bitmap()?.clone();
+        Dir::root(self.clone()).walk(Path::new("/"), &mut |_, entry| match *entry {
+            Entry::File(ref file) => self.defragment_file(file, dry_run, &mut free, &mut report),
+            Entry::Dir(_) => Ok(()),
+        })?;
+        if !dry_run {
+            self.borrow_mut().flush()?;
+        }
+        Ok(report)
+    }
+
+    fn defragment_file(
+        &self,
+        file: &File,
+        dry_run: bool,
+        free: &mut FreeBitmap,
+        report: &mut DefragReport,
+    ) -> io::Result<()> {
+        let location = match file.location {
+            Some(location) if file.size > 0 && file.cluster.fat_index() >= 2 => location,
+            _ => return Ok(()),
+        };
+
+        let mut vfat = self.borrow_mut();
+        let old_chain = vfat.chain(file.cluster)?;
+        if Extent::from_chain(&old_chain).len() <= 1 {
+            return Ok(());
+        }
+
+        report.fragmented_files += 1;
+
+        // `file` itself is one handle; other handles would be left pointing at
+        // the old chain.
+        let start = if vfat.handles.open_count(location) > 1 {
+            None
+        } else {
+            free.find_free_run(2, old_chain.len() as u32)
+        };
+        let start = match start {
+            Some(start) => start,
+            None => {
+                report.skipped_files += 1;
+                return Ok(());
+            }
+        };
+
+        report.moved_files += 1;
+        report.moved_clusters += old_chain.len();
+
+        let new_chain: Vec<Cluster> = (start..start + old_chain.len() as u32)
+            .map(Cluster::from)
+            .collect();
+        for cluster in &new_chain {
+            free.set(cluster.fat_index(), false);
+        }
+        for cluster in &old_chain {
+            free.set(cluster.fat_index(), true);
+        }
+
+        if dry_run {
+            return Ok(());
+        }
+
+        let mut buf = vec![0u8; vfat.cluster_size()];
+        for (&old, &new) in old_chain.iter().zip(new_chain.iter()) {
+            vfat.read_cluster(old, 0, &mut buf)?;
+            vfat.write_cluster(new, 0, &buf)?;
+        }
+
+        for pair in new_chain.windows(2) {
+            vfat.set_fat_entry(pair[0], Status::Data(pair[1]))?;
+        }
+        vfat.set_fat_entry(new_chain[new_chain.len() - 1], Status::Eoc(0x0FFF_FFFF))?;
+
+        vfat.update_dir_entry(location, |entry| entry.set_cluster(new_chain[0]))?;
+
+        for &cluster in &old_chain {
+            vfat.set_fat_entry(cluster, Status::Free)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use traits::{Entry as EntryTrait, FileSystem, Metadata};
+use vfat::{Dir, EntryInfo, Shared, Timestamp, VFat};
+
+/// A difference between two volumes found by `diff()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The entry at `path` exists only in the other volume. The entries
+    /// below an added directory are not reported separately.
+    Added { path: PathBuf, is_dir: bool },
+    /// The entry at `path` exists only in this volume. The entries below a
+    /// removed directory are not reported separately.
+    Removed { path: PathBuf, is_dir: bool },
+    /// The entry at `path` is a file in one volume and a directory in the
+    /// other.
+    TypeChanged { path: PathBuf },
+    /// The file at `path` exists in both volumes but differs. `size` and
+    /// `modified` hold the value in this volume and in the other one if they
+    /// differ. `contents` is `true` if the files have the same size but
+    /// different contents.
+    Changed {
+        path: PathBuf,
+        size: Option<(u64, u64)>,
+        modified: Option<(Timestamp, Timestamp)>,
+        contents: bool,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Added { ref path, .. } => write!(f, "+ {}", path.display()),
+            Change::Removed { ref path, .. } => write!(f, "- {}", path.display()),
+            Change::TypeChanged { ref path } => {
+                write!(f, "~ {}: file replaced by directory or vice versa", path.display())
+            }
+            Change::Changed {
+                ref path,
+                size,
+                modified,
+                contents,
+            } => {
+                write!(f, "M {}:", path.display())?;
+                if let Some((old, new)) = size {
+                    write!(f, " size {} -> {}", old, new)?;
+                }
+                if let Some((old, new)) = modified {
+                    write!(f, " modified {} -> {}", old, new)?;
+                }
+                if contents {
+                    write!(f, " contents differ")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Compares the directory tree of this volume with that of `other` and
+    /// returns the differences, ordered by path. Files present in both
+    /// volumes are compared by size and modification time, and files of the
+    /// same size are compared byte for byte, so that an exact copy of a
+    /// volume produces no differences. Names are compared exactly, and volume
+    /// labels are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a directory or a file of either volume
+    /// fails.
+    pub fn diff(&self, other: &Shared<VFat>) -> io::Result<Vec<Change>> {
+        let old = self.tree()?;
+        let new = other.tree()?;
+        let paths: BTreeSet<&PathBuf> = old.keys().chain(new.keys()).collect();
+
+        let mut changes = Vec::new();
+        let mut skipped: Option<&Path> = None;
+        for path in paths {
+            if skipped.map_or(false, |dir| path.starts_with(dir)) {
+                continue;
+            }
+
+            let change = match (old.get(path), new.get(path)) {
+                (Some(old), None) => Change::Removed {
+                    path: path.clone(),
+                    is_dir: old.is_dir,
+                },
+                (None, Some(new)) => Change::Added {
+                    path: path.clone(),
+                    is_dir: new.is_dir,
+                },
+                (Some(old), Some(new)) if old.is_dir != new.is_dir => Change::TypeChanged {
+                    path: path.clone(),
+                },
+                (Some(old), Some(new)) => {
+                    if old.is_dir {
+                        continue;
+                    }
+                    match self.compare_files(other, path, old, new)? {
+                        Some(change) => change,
+                        None => continue,
+                    }
+                }
+                (None, None) => unreachable!(),
+            };
+
+            // Only the root of a tree that was added, removed, or replaced is
+            // reported.
+            match change {
+                Change::Changed { .. } => {}
+                _ => skipped = Some(path.as_path()),
+            }
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// Returns a snapshot of every entry in the volume, by path.
+    fn tree(&self) -> io::Result<BTreeMap<PathBuf, EntryInfo>> {
+        let mut entries = BTreeMap::new();
+        Dir::root(self.clone()).walk(Path::new("/"), &mut |path, entry| {
+            if !entry.metadata().volume_id() {
+                entries.insert(path.to_path_buf(), entry.info());
+            }
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Compares the file at `path` in this volume, described by `old`, with
+    /// the one in `other`, described by `new`.
+    fn compare_files(
+        &self,
+        other: &Shared<VFat>,
+        path: &Path,
+        old: &EntryInfo,
+        new: &EntryInfo,
+    ) -> io::Result<Option<Change>> {
+        let size = if old.size != new.size {
+            Some((old.size, new.size))
+        } else {
+            None
+        };
+        let modified = if old.metadata.modified != new.metadata.modified {
+            Some((old.metadata.modified, new.metadata.modified))
+        } else {
+            None
+        };
+        let contents = size.is_none() && !same_contents(
+            &mut self.open_file(path)?,
+            &mut other.open_file(path)?,
+        )?;
+
+        if size.is_none() && modified.is_none() && !contents {
+            return Ok(None);
+        }
+        Ok(Some(Change::Changed {
+            path: path.to_path_buf(),
+            size,
+            modified,
+            contents,
+        }))
+    }
+}
+
+/// Returns `true` if `a` and `b`, which have the same size, hold the same
+/// bytes.
+pub(crate) fn same_contents<A: Read, B: Read>(a: &mut A, b: &mut B) -> io::Result<bool> {
+    let mut a_buf = vec![0u8; 64 * 1024];
+    let mut b_buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = a.read(&mut a_buf)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut b_buf[..read])?;
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
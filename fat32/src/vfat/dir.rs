@@ -1,19 +1,72 @@
 use std::char::{decode_utf16, REPLACEMENT_CHARACTER};
+use std::cmp::Ordering;
 use std::ffi::OsStr;
 // use std::borrow::Cow;
 use std::io;
+use std::mem::size_of;
+use std::path::Path;
 
 use traits;
-use util::VecExt;
-use vfat::{Attributes, Date, Metadata, Time, Timestamp};
-use vfat::{Cluster, Entry, File, Shared, VFat};
+use util::{Le16, Le32, VecExt};
+use vfat::{Attributes, Date, Metadata, Time, Timestamp, WatchEvent};
+use vfat::{CancelToken, Cluster, Entry, EntryInfo, File, Handle, HandleTable, Shared, Status, VFat};
+use vfat::{validate_name, Codepage};
 
-#[derive(Debug)]
+const ATTR_LFN: u8 = 0x0F;
+
+/// Flags set by Windows NT in the reserved byte of a regular entry whose name
+/// fits in 8.3 but isn't all uppercase, marking the base and extension of the
+/// short name as displayed in lowercase.
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+/// The most entries a directory can hold, as 2 MiB of 32-byte entries.
+const MAX_DIR_ENTRIES: usize = 65536;
+
+/// Summary of the compaction of a directory by `Dir::compact()`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Number of deleted entries squeezed out.
+    pub removed_entries: usize,
+    /// Number of clusters left empty at the end of the directory and freed.
+    pub freed_clusters: usize,
+}
+
+/// The first byte stored for a short name starting with 0xE5, which would
+/// otherwise mark the entry as deleted.
+const ESCAPED_E5: u8 = 0x05;
+
+/// The order of the entries returned by `Dir::entries_sorted()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// By name.
+    Name,
+    /// By size, smallest first. Directories have size 0.
+    Size,
+    /// By modification time, oldest first.
+    Modified,
+    /// Directories before files, each by name.
+    DirsFirst,
+}
+
+#[derive(Debug, Clone)]
 pub struct Dir {
     name: String,
     cluster: Cluster,
     vfat: Shared<VFat>,
     metadata: Metadata,
+    location: Option<EntryLocation>,
+    /// Keeps the entry registered as open while `self` is alive.
+    _handle: Option<Handle>,
+}
+
+/// The location of a regular directory entry on disk: the first cluster of the
+/// directory containing it and the index of the 32-byte entry in that
+/// directory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EntryLocation {
+    pub dir: Cluster,
+    pub index: usize,
 }
 
 #[repr(C, packed)]
@@ -27,24 +80,24 @@ pub struct VFatRegularDirEntry {
     ctime: Time,
     cdate: Date,
     adate: Date,
-    cluster_hi: u16,
+    cluster_hi: Le16,
     mtime: Time,
     mdate: Date,
-    cluster_lo: u16,
-    size: u32,
+    cluster_lo: Le16,
+    size: Le32,
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatLfnDirEntry {
     seq_number: u8,
-    name1: [u16; 5],
+    name1: [Le16; 5],
     attr: Attributes,
     lfn_type: u8,
     checksum: u8,
-    name2: [u16; 6],
-    zero_pad: u16,
-    name3: [u16; 2],
+    name2: [Le16; 6],
+    zero_pad: Le16,
+    name3: [Le16; 2],
 }
 
 #[repr(C, packed)]
@@ -56,6 +109,9 @@ pub struct VFatUnknownDirEntry {
     unknown2: [u8; 20],
 }
 
+/// A raw 32-byte directory entry. Every field of every variant is made of
+/// bytes, so any bit pattern is a valid value of each variant, and reading
+/// one through the accessors below is always sound.
 pub union VFatDirEntry {
     unknown: VFatUnknownDirEntry,
     regular: VFatRegularDirEntry,
@@ -65,7 +121,41 @@ pub union VFatDirEntry {
 pub struct EntryIter {
     entries: Vec<VFatDirEntry>,
     index: usize,
+    cluster: Cluster,
     vfat: Shared<VFat>,
+    handles: HandleTable,
+    codepage: Codepage,
+}
+
+impl VFatDirEntry {
+    /// Returns the entry interpreted as an entry of unknown kind, to tell
+    /// which kind it is.
+    pub fn unknown(&self) -> VFatUnknownDirEntry {
+        unsafe { self.unknown }
+    }
+
+    /// Returns the entry interpreted as an LFN entry. Every bit pattern is a
+    /// valid LFN entry.
+    pub fn long_filename(&self) -> &VFatLfnDirEntry {
+        unsafe { &self.long_filename }
+    }
+
+    /// Returns the entry interpreted as a regular directory entry. Every bit
+    /// pattern is a valid regular entry.
+    pub fn regular(&self) -> VFatRegularDirEntry {
+        unsafe { self.regular }
+    }
+
+    /// Returns the entry interpreted as a regular directory entry. Every bit
+    /// pattern is a valid regular entry.
+    pub fn regular_mut(&mut self) -> &mut VFatRegularDirEntry {
+        unsafe { &mut self.regular }
+    }
+
+    /// Marks the entry as deleted.
+    pub fn mark_deleted(&mut self) {
+        self.unknown.id = 0xE5;
+    }
 }
 
 impl VFatUnknownDirEntry {
@@ -83,34 +173,185 @@ impl VFatUnknownDirEntry {
 }
 
 impl VFatRegularDirEntry {
+    /// Returns an entry with a blank name, attributes `attr`, first cluster
+    /// `cluster`, size 0, and every timestamp set to `now`.
+    fn new(attr: u8, cluster: Cluster, now: Timestamp) -> VFatRegularDirEntry {
+        VFatRegularDirEntry {
+            name: [b' '; 8],
+            ext: [b' '; 3],
+            attr: Attributes(attr),
+            reserved: 0,
+            ctime_fine: now.fine,
+            ctime: now.time,
+            cdate: now.date,
+            adate: now.date,
+            cluster_hi: Le16::new((cluster.fat_index() >> 16) as u16),
+            mtime: now.time,
+            mdate: now.date,
+            cluster_lo: Le16::new(cluster.fat_index() as u16),
+            size: Le32::new(0),
+        }
+    }
+
+    /// The space-padded 8.3 name of the entry, without the dot. A name stored
+    /// with its first byte escaped as 0x05 starts with 0xE5.
+    pub fn short_name(&self) -> [u8; 11] {
+        let mut short_name = [0u8; 11];
+        short_name[..8].copy_from_slice(&{ self.name });
+        short_name[8..].copy_from_slice(&{ self.ext });
+        if short_name[0] == ESCAPED_E5 {
+            short_name[0] = 0xE5;
+        }
+        short_name
+    }
+
+    /// Sets the short name of the entry, displayed in uppercase. A first byte
+    /// of 0xE5 is stored as 0x05.
+    pub fn set_short_name(&mut self, short_name: &[u8; 11]) {
+        let mut name = [0u8; 8];
+        let mut ext = [0u8; 3];
+        name.copy_from_slice(&short_name[..8]);
+        ext.copy_from_slice(&short_name[8..]);
+        if name[0] == 0xE5 {
+            name[0] = ESCAPED_E5;
+        }
+        self.name = name;
+        self.ext = ext;
+        self.reserved &= !(NT_LOWERCASE_BASE | NT_LOWERCASE_EXT);
+    }
+
+    /// The short name of the entry as displayed, decoded with `codepage`, with
+    /// a dot before a non-empty extension and with the base and extension in
+    /// lowercase if the entry's NT lowercase flags say so.
+    pub fn display_name(&self, codepage: Codepage) -> String {
+        let short_name = self.short_name();
+        let mut s = oem_to_string(&short_name[..8], codepage).unwrap_or_default();
+        if self.reserved & NT_LOWERCASE_BASE != 0 {
+            s.make_ascii_lowercase();
+        }
+        if let Some(mut ext) = oem_to_string(&short_name[8..], codepage) {
+            if self.reserved & NT_LOWERCASE_EXT != 0 {
+                ext.make_ascii_lowercase();
+            }
+            s.push('.');
+            s.push_str(&ext);
+        }
+        s
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size.get()
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        self.size.set(size);
+    }
+
+    pub fn set_times(&mut self, created: Timestamp, modified: Timestamp, accessed: Timestamp) {
+        self.ctime_fine = created.fine;
+        self.ctime = created.time;
+        self.cdate = created.date;
+        self.mtime = modified.time;
+        self.mdate = modified.date;
+        self.adate = accessed.date;
+    }
+
+    /// Copies everything but the name, including how it is displayed, from
+    /// `other`: the attributes, times, first cluster, and size.
+    pub fn replace_contents(&mut self, other: &VFatRegularDirEntry) {
+        let name = self.short_name();
+        let case = self.reserved & (NT_LOWERCASE_BASE | NT_LOWERCASE_EXT);
+        *self = *other;
+        self.set_short_name(&name);
+        self.reserved |= case;
+    }
+
+    pub fn set_attributes(&mut self, attr: Attributes) {
+        self.attr = attr;
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        let attr = if read_only {
+            self.attr.0 | Attributes::READ_ONLY
+        } else {
+            self.attr.0 & !Attributes::READ_ONLY
+        };
+        self.attr = Attributes(attr);
+    }
+
+    /// Sets the date of the last access to the entry.
+    pub fn set_accessed(&mut self, date: Date) {
+        self.adate = date;
+    }
+
+    /// Records a modification of the entry's contents at `now`: sets the
+    /// modification time and the archive attribute.
+    pub fn mark_modified(&mut self, now: Timestamp) {
+        self.mtime = now.time;
+        self.mdate = now.date;
+        self.attr = Attributes(self.attr.0 | Attributes::ARCHIVE);
+    }
+
     pub fn metadata(&self) -> Metadata {
         Metadata {
             attr: self.attr,
             created: Timestamp {
                 date: self.cdate,
                 time: self.ctime,
+                fine: self.ctime_fine,
             },
             accessed: Timestamp {
                 date: self.adate,
-                time: Time(0),
+                time: Time::default(),
+                fine: 0,
             },
             modified: Timestamp {
                 date: self.mdate,
                 time: self.mtime,
+                fine: 0,
             },
         }
     }
 
     pub fn cluster(&self) -> Cluster {
-        let cluster = ((self.cluster_hi as u32) << 16) | self.cluster_lo as u32;
+        let cluster = (self.cluster_hi.get() as u32) << 16 | self.cluster_lo.get() as u32;
         Cluster::from(cluster)
     }
 
+    pub fn set_cluster(&mut self, cluster: Cluster) {
+        self.cluster_hi.set((cluster.fat_index() >> 16) as u16);
+        self.cluster_lo.set(cluster.fat_index() as u16);
+    }
+
     pub fn is_dir(&self) -> bool {
         self.attr.0 as u8 & 0x10 != 0
     }
 }
 
+impl VFatLfnDirEntry {
+    /// The raw sequence number: the 1-based position of the entry's part in
+    /// the name, with 0x40 set on the entry holding the last part.
+    pub fn sequence(&self) -> u8 {
+        self.seq_number
+    }
+
+    /// The checksum of the short name the entry belongs to.
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
+
+    /// The 13 UTF-16 code units of the name held by the entry, including any
+    /// 0x0000 terminator and 0xFFFF padding.
+    pub fn chars(&self) -> [u16; 13] {
+        let mut chars = [0u16; 13];
+        let parts = self.name1.iter().chain(&self.name2).chain(&self.name3);
+        for (unit, part) in chars.iter_mut().zip(parts) {
+            *unit = part.get();
+        }
+        chars
+    }
+}
+
 pub fn ucs_2_to_string(arr: &[u16]) -> String {
     // File name in LFN entry can be terminated using 0x0000 or 0xFFFF
     decode_utf16(
@@ -122,11 +363,98 @@ pub fn ucs_2_to_string(arr: &[u16]) -> String {
     .collect::<String>()
 }
 
-pub fn ascii_to_string(arr: &[u8]) -> Option<String> {
-    let s = arr
+/// Encodes `name` as UTF-16 for storing in LFN entries: terminated with
+/// 0x0000, unless it fills the last entry, and padded with 0xFFFF to a
+/// multiple of the 13 characters an entry holds.
+///
+/// # Errors
+///
+/// If `name` is longer than 255 UTF-16 code units, an error of
+/// `InvalidInput` is returned.
+pub fn string_to_ucs_2(name: &str) -> io::Result<Vec<u16>> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "name longer than 255 UTF-16 characters",
+        ));
+    }
+
+    if units.len() % 13 != 0 {
+        units.push(0x0000);
+    }
+    while units.len() % 13 != 0 {
+        units.push(0xFFFF);
+    }
+    Ok(units)
+}
+
+/// Copies the part of a long file name held by `lfn` to its place in `name`.
+/// Returns `false` if `lfn` has a malformed sequence number, which can't be
+/// placed in the name.
+fn add_lfn_part(name: &mut [u16; 13 * 20], lfn: &VFatLfnDirEntry) -> bool {
+    let seq = (lfn.seq_number & 0b11111) as usize;
+    if seq == 0 || seq > 20 {
+        return false;
+    }
+
+    name[(seq - 1) * 13..seq * 13].copy_from_slice(&lfn.chars());
+    true
+}
+
+/// Returns `true` if the characters of `name` are those of `needle`, ignoring
+/// ASCII case.
+fn name_eq<I: Iterator<Item = char>>(name: I, needle: &str) -> bool {
+    let mut needle = needle.chars();
+    for c in name {
+        match needle.next() {
+            Some(d) if c.eq_ignore_ascii_case(&d) => {}
+            _ => return false,
+        }
+    }
+    needle.next().is_none()
+}
+
+/// Returns `true` if the name of the entry `regular`, or the long file name
+/// `lfn_name` if one was found, is `needle`, ignoring ASCII case. Names are
+/// decoded as by `ucs_2_to_string()` and `oem_to_string()` but never
+/// collected into a `String`.
+fn entry_name_eq(
+    lfn_name: Option<&[u16]>,
+    regular: &VFatRegularDirEntry,
+    needle: &str,
+    codepage: Codepage,
+) -> bool {
+    if let Some(lfn_name) = lfn_name {
+        let units = lfn_name.iter().take_while(|x| **x != 0x0000 && **x != 0xFFFF).cloned();
+        let chars = decode_utf16(units).map(|r| r.unwrap_or(REPLACEMENT_CHARACTER));
+        return name_eq(chars, needle);
+    }
+
+    let short_name = regular.short_name();
+    let (name, ext) = short_name.split_at(8);
+    let (name, ext) = (trim_padding(name), trim_padding(ext));
+    let dot = if ext.is_empty() { None } else { Some('.') };
+    let decode = |&c: &u8| codepage.decode(c);
+    let chars = name.iter().map(&decode).chain(dot).chain(ext.iter().map(&decode));
+    name_eq(chars, needle)
+}
+
+/// Returns the base or extension of a short name without its padding: up to
+/// the first 0x00 and without trailing spaces. Spaces inside the name, as in
+/// `A B.TXT`, are kept.
+fn trim_padding(arr: &[u8]) -> &[u8] {
+    let end = arr.iter().position(|&c| c == 0x00).unwrap_or(arr.len());
+    let len = arr[..end].iter().rposition(|&c| c != 0x20).map_or(0, |i| i + 1);
+    &arr[..len]
+}
+
+/// Decodes the base or extension of a short name, stored in `codepage`.
+/// Returns `None` if it is empty.
+pub fn oem_to_string(arr: &[u8], codepage: Codepage) -> Option<String> {
+    let s = trim_padding(arr)
         .iter()
-        .take_while(|x| **x != 0x00 && **x != 0x20)
-        .map(|&c| c as char)
+        .map(|&c| codepage.decode(c))
         .collect::<String>();
     if s.is_empty() {
         None
@@ -135,52 +463,223 @@ pub fn ascii_to_string(arr: &[u8]) -> Option<String> {
     }
 }
 
+/// Returns the checksum of a short name stored in the LFN entries preceding
+/// the name's regular entry. The checksum covers the name as stored, with a
+/// first byte of 0xE5 escaped.
+pub(crate) fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    let first = if short_name[0] == 0xE5 { ESCAPED_E5 } else { short_name[0] };
+    ::std::iter::once(&first)
+        .chain(&short_name[1..])
+        .fold(0u8, |sum, &c| (sum >> 1 | sum << 7).wrapping_add(c))
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// Returns `name` as a space-padded 8.3 short name if it can be stored as a
+/// short name as-is, without an LFN: a base of 1 to 8 and an extension of at
+/// most 3 uppercase letters, digits, or permitted symbols.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.find('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+
+    let valid = |part: &str, max: usize| part.len() <= max && part.bytes().all(is_short_name_char);
+    if base.is_empty() || !valid(base, 8) || !valid(ext, 3) || name.ends_with('.') {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short_name)
+}
+
+/// Generates a short name of the form `BASIS~N.EXT` for `name` that is not
+/// in `taken`. Characters are uppercased and encoded in `codepage`;
+/// characters that can't be stored in a short name are replaced with `_`.
+fn generate_short_name(name: &str, taken: &[[u8; 11]], codepage: Codepage) -> io::Result<[u8; 11]> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let mut upper = c.to_uppercase();
+                let c = match (upper.next(), upper.next()) {
+                    (Some(c), None) => c,
+                    _ => c,
+                };
+                match codepage.encode(c) {
+                    Some(b) if b >= 0x80 && c.is_alphabetic() => b,
+                    Some(b) if is_short_name_char(b) => b,
+                    _ => b'_',
+                }
+            })
+            .collect()
+    };
+
+    let name = name.trim_left_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (clean(&name[..i]), clean(&name[i + 1..])),
+        None => (clean(name), Vec::new()),
+    };
+    let ext_len = ::std::cmp::min(ext.len(), 3);
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = ::std::cmp::min(base.len(), 8 - tail.len());
+
+        let mut short_name = [b' '; 11];
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "no unique short name available",
+    ))
+}
+
+/// Returns the LFN entries storing `name`, in on-disk order, for the short
+/// name with checksum `checksum`.
+fn lfn_entries(name: &str, checksum: u8) -> io::Result<Vec<VFatLfnDirEntry>> {
+    let units = string_to_ucs_2(name)?;
+    let count = units.len() / 13;
+    let mut entries = Vec::with_capacity(count);
+    for seq in (1..count + 1).rev() {
+        let chars = &units[(seq - 1) * 13..seq * 13];
+        let mut name1 = [Le16::default(); 5];
+        let mut name2 = [Le16::default(); 6];
+        let mut name3 = [Le16::default(); 2];
+        for (part, &unit) in name1.iter_mut().chain(&mut name2).chain(&mut name3).zip(chars) {
+            part.set(unit);
+        }
+        entries.push(VFatLfnDirEntry {
+            seq_number: seq as u8 | if seq == count { 0x40 } else { 0 },
+            name1,
+            attr: Attributes(ATTR_LFN),
+            lfn_type: 0,
+            checksum,
+            name2,
+            zero_pad: Le16::new(0),
+            name3,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Returns the short names of the live regular entries in `entries`.
+fn short_names(entries: &[VFatDirEntry]) -> Vec<[u8; 11]> {
+    let mut names = Vec::new();
+    for entry in entries {
+        let unknown = entry.unknown();
+        if unknown.is_end() {
+            break;
+        }
+        if !unknown.is_deleted() && !unknown.is_lfn() {
+            names.push(entry.regular().short_name());
+        }
+    }
+    names
+}
+
+/// Returns the index of the first run of `count` consecutive free entries in
+/// `entries`. Every entry at or after the end-of-directory marker is free.
+fn find_free_slots(entries: &[VFatDirEntry], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let unknown = entry.unknown();
+        if unknown.is_end() {
+            let start = index - run;
+            return if entries.len() - start >= count {
+                Some(start)
+            } else {
+                None
+            };
+        }
+
+        if unknown.is_deleted() {
+            run += 1;
+            if run == count {
+                return Some(index + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+/// Returns the index of the first entry of the run of free entries at the end
+/// of `entries`, or `entries.len()` if the last entry is in use.
+fn free_tail(entries: &[VFatDirEntry]) -> usize {
+    let mut start = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let unknown = entry.unknown();
+        if unknown.is_end() {
+            return start;
+        } else if !unknown.is_deleted() {
+            start = index + 1;
+        }
+    }
+    start
+}
+
+impl EntryIter {
+    /// Consumes the iterator and returns the buffer that the directory was
+    /// read into, emptied, for reuse with `Dir::entries_with_buffer()`.
+    pub fn into_buffer(self) -> Vec<u8> {
+        let mut buf: Vec<u8> = unsafe { self.entries.cast() }.expect("entries are bytes");
+        buf.clear();
+        buf
+    }
+}
+
 impl Iterator for EntryIter {
     type Item = Entry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut unknown_entry = unsafe { self.entries[self.index].unknown };
-
         // 13 (5+6+2) characters in LFN entry. Up to 20 LFN entries can be chained.
         let mut lfn_name = [0u16; 13 * 20];
         let mut lfn_found = false;
 
-        while !unknown_entry.is_end() {
+        while self.index < self.entries.len() {
+            let unknown_entry = self.entries[self.index].unknown();
+            if unknown_entry.is_end() {
+                break;
+            }
+
             if unknown_entry.is_deleted() {
                 self.index += 1;
-                unknown_entry = unsafe { self.entries[self.index].unknown };
                 continue;
             }
 
             if unknown_entry.is_lfn() {
-                let lfn = unsafe { self.entries[self.index].long_filename };
-                lfn_found = true;
-                let pos = ((lfn.seq_number & 0b11111) as usize - 1) * 13;
-                lfn_name[pos..pos + 5].copy_from_slice(&lfn.name1);
-                lfn_name[pos + 5..pos + 11].copy_from_slice(&lfn.name2);
-                lfn_name[pos + 11..pos + 13].copy_from_slice(&lfn.name3);
+                let lfn = *self.entries[self.index].long_filename();
                 self.index += 1;
-                unknown_entry = unsafe { self.entries[self.index].unknown };
+                lfn_found |= add_lfn_part(&mut lfn_name, &lfn);
             } else {
                 // regular entry
-                let regular = unsafe { self.entries[self.index].regular };
+                let regular = self.entries[self.index].regular();
 
                 let name = if lfn_found {
                     ucs_2_to_string(&lfn_name)
                 } else {
-                    match ascii_to_string(&regular.ext) {
-                        None => ascii_to_string(&regular.name).unwrap(),
-                        Some(ext) => {
-                            let mut s = ascii_to_string(&regular.name).unwrap();
-                            s.push('.');
-                            s.push_str(&ext);
-                            s
-                        }
-                    }
+                    regular.display_name(self.codepage)
                 };
 
                 let metadata = regular.metadata();
                 let cluster = regular.cluster();
+                let location = Some(EntryLocation {
+                    dir: self.cluster,
+                    index: self.index,
+                });
 
                 self.index += 1;
 
@@ -190,6 +689,8 @@ impl Iterator for EntryIter {
                         cluster,
                         vfat: self.vfat.clone(),
                         metadata,
+                        location,
+                        _handle: self.handles.register(location),
                     }));
                 } else {
                     return Some(Entry::File(File {
@@ -197,8 +698,11 @@ impl Iterator for EntryIter {
                         cluster,
                         vfat: self.vfat.clone(),
                         metadata,
-                        size: regular.size as usize,
+                        size: regular.size.get() as usize,
                         read_ptr: 0,
+                        location,
+                        _handle: self.handles.register(location),
+                        chain_cache: None,
                     }));
                 }
             }
@@ -217,15 +721,107 @@ impl Dir {
         &self.metadata
     }
 
+    /// The first cluster of the directory.
+    pub fn cluster(&self) -> Cluster {
+        self.cluster
+    }
+
+    /// The location of the directory's entry in its parent, if it has one.
+    pub(crate) fn location(&self) -> Option<EntryLocation> {
+        self.location
+    }
+
+    /// The file system the directory belongs to.
+    pub(crate) fn vfat(&self) -> &Shared<VFat> {
+        &self.vfat
+    }
+
     pub fn root(vfat: Shared<VFat>) -> Dir {
         Dir {
             name: String::from("/"),
             cluster: vfat.borrow().root_dir_cluster,
             vfat: vfat.clone(),
             metadata: Metadata::default(),
+            location: None,
+            _handle: None,
+        }
+    }
+
+    /// Returns the directory starting at `cluster`, with no name, default
+    /// metadata, and no entry in a parent.
+    pub(crate) fn at_cluster(vfat: Shared<VFat>, cluster: Cluster) -> Dir {
+        Dir {
+            name: String::new(),
+            cluster,
+            vfat,
+            metadata: Metadata::default(),
+            location: None,
+            _handle: None,
         }
     }
 
+    /// Returns a snapshot of every entry in `self`, including `.` and `..` and
+    /// the volume label, in on-disk order.
+    pub fn list(&self) -> io::Result<Vec<EntryInfo>> {
+        use traits::Dir;
+
+        Ok(self.entries()?.map(|entry| entry.info()).collect())
+    }
+
+    /// Returns a snapshot of every file and directory in `self`, not counting
+    /// `.`, `..`, or the volume label, sorted by `key`. Names are compared
+    /// case-insensitively and break ties between entries that compare equal
+    /// by `key`.
+    pub fn entries_sorted(&self, key: SortKey) -> io::Result<Vec<EntryInfo>> {
+        use traits::Metadata;
+
+        let mut entries: Vec<EntryInfo> = self
+            .list()?
+            .into_iter()
+            .filter(|info| info.name != "." && info.name != ".." && !info.metadata.volume_id())
+            .collect();
+        entries.sort_by(|a, b| {
+            let by_key = match key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.metadata.modified.cmp(&b.metadata.modified),
+                SortKey::DirsFirst => b.is_dir.cmp(&a.is_dir),
+            };
+            by_key.then_with(|| {
+                let a = a.name.bytes().map(|b| b.to_ascii_lowercase());
+                a.cmp(b.name.bytes().map(|b| b.to_ascii_lowercase()))
+            })
+        });
+        Ok(entries)
+    }
+
+    /// Returns the number of files and directories in `self`, not counting
+    /// `.`, `..`, or the volume label. No `Entry` is built for each directory
+    /// entry, and long file names are not decoded.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(self.live_entries()?.count())
+    }
+
+    /// Returns `true` if `self` contains no files or directories other than
+    /// `.` and `..`.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.live_entries()?.next().is_none())
+    }
+
+    /// Returns the regular entries of the files and directories in `self`.
+    fn live_entries(&self) -> io::Result<impl Iterator<Item = VFatRegularDirEntry>> {
+        let entries = self.raw_entries()?;
+        Ok(entries
+            .into_iter()
+            .take_while(|entry| !entry.unknown().is_end())
+            .filter(|entry| {
+                let unknown = entry.unknown();
+                !unknown.is_deleted() && !unknown.is_lfn()
+            })
+            .map(|entry| entry.regular())
+            .filter(|regular| !regular.attr.volume_id() && regular.name[0] != b'.'))
+    }
+
     /// Finds the entry named `name` in `self` and returns it. Comparison is
     /// case-insensitive.
     ///
@@ -242,15 +838,419 @@ impl Dir {
             .to_str()
             .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid UTF-8"))?;
 
-        use traits::{Dir, Entry};
-        for entry in self.entries()? {
-            if entry.name().eq_ignore_ascii_case(name) {
-                return Ok(entry);
+        // Names are compared in place, as the entries are scanned; only the
+        // matching entry is built.
+        let entries = self.raw_entries()?;
+        let codepage = self.vfat.borrow().codepage;
+        let mut lfn_name = [0u16; 13 * 20];
+        let mut lfn_found = false;
+        let mut start = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            let unknown = entry.unknown();
+            if unknown.is_end() {
+                break;
+            } else if unknown.is_deleted() {
+                continue;
+            } else if unknown.is_lfn() {
+                lfn_found |= add_lfn_part(&mut lfn_name, entry.long_filename());
+                continue;
+            }
+
+            let lfn = if lfn_found { Some(&lfn_name[..]) } else { None };
+            if entry_name_eq(lfn, &entry.regular(), name, codepage) {
+                let mut iter = EntryIter {
+                    entries,
+                    index: start,
+                    cluster: self.cluster,
+                    vfat: self.vfat.clone(),
+                    handles: self.vfat.borrow().handles.clone(),
+                    codepage,
+                };
+                return Ok(iter.next().expect("matching entry"));
+            }
+
+            if lfn_found {
+                lfn_name = [0; 13 * 20];
+                lfn_found = false;
             }
+            start = index + 1;
         }
 
         Err(io::Error::new(io::ErrorKind::NotFound, "Entry not found"))
     }
+
+    /// Reads every raw entry of the directory, from its cluster chain or, for
+    /// the root directory, wherever the volume stores it.
+    fn raw_entries(&self) -> io::Result<Vec<VFatDirEntry>> {
+        self.raw_entries_into(Vec::new())
+    }
+
+    /// Like `raw_entries()`, but reads the entries into `buf`, whose contents
+    /// are discarded, reusing its allocation.
+    fn raw_entries_into(&self, mut buf: Vec<u8>) -> io::Result<Vec<VFatDirEntry>> {
+        buf.clear();
+        {
+            let mut vfat = self.vfat.borrow_mut();
+            let source = vfat.dir_source(self.cluster);
+            vfat.read_dir_source(source, &mut buf)?;
+        }
+        // The whole allocation must hold entries, not just the bytes read.
+        if buf.capacity() % size_of::<VFatDirEntry>() != 0 {
+            buf.shrink_to_fit();
+        }
+        unsafe { buf.cast() }
+    }
+
+    /// Like `entries()`, but reads the directory into `buf`, whose contents
+    /// are discarded, instead of a newly allocated buffer. The buffer is
+    /// returned by `EntryIter::into_buffer()` once iteration is done, so that
+    /// one buffer can be reused to scan many directories.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `entries()`.
+    pub fn entries_with_buffer(&self, buf: Vec<u8>) -> io::Result<EntryIter> {
+        let entries = self.raw_entries_into(buf)?;
+        let vfat = self.vfat.borrow();
+        Ok(EntryIter {
+            entries,
+            index: 0,
+            cluster: self.cluster,
+            vfat: self.vfat.clone(),
+            handles: vfat.handles.clone(),
+            codepage: vfat.codepage,
+        })
+    }
+
+    /// Registers a handle to the new entry at `location`.
+    fn register(&self, location: EntryLocation) -> Option<Handle> {
+        self.vfat.borrow().handles.register(Some(location))
+    }
+
+    /// Marks the regular entry at `location` in `self` and the LFN entries
+    /// directly preceding it as deleted.
+    pub(crate) fn remove_entry(&self, location: EntryLocation) -> io::Result<()> {
+        let raw = self.raw_entries()?;
+        let mut vfat = self.vfat.borrow_mut();
+        vfat.accessed.remove(&location);
+        let mut index = location.index;
+        loop {
+            vfat.dir_entry_mut(EntryLocation { index, ..location })?.mark_deleted();
+            if index == 0 {
+                return Ok(());
+            }
+
+            index -= 1;
+            let unknown = raw[index].unknown();
+            if !unknown.is_lfn() || unknown.is_deleted() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Checks that `name` is usable as the name of a new entry in `self`.
+    pub(crate) fn check_new_name(&self, name: &str) -> io::Result<()> {
+        let windows_names = self.vfat.borrow().windows_names;
+        validate_name(name, windows_names)?;
+
+        match self.find(name) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "entry already exists",
+            )),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `entry` to a free slot in `self` under the name `name`, preceded
+    /// by LFN entries if `name` is not a valid short name, and returns the
+    /// location of the regular entry.
+    pub(crate) fn add_entry(&self, name: &str, mut entry: VFatRegularDirEntry) -> io::Result<EntryLocation> {
+        let raw = self.raw_entries()?;
+        let codepage = self.vfat.borrow().codepage;
+        let (short_name, lfn) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => {
+                let short_name = generate_short_name(name, &short_names(&raw), codepage)?;
+                (short_name, lfn_entries(name, short_name_checksum(&short_name))?)
+            }
+        };
+        entry.set_short_name(&short_name);
+
+        let count = lfn.len() + 1;
+        let index = match find_free_slots(&raw, count) {
+            Some(index) => index,
+            None => {
+                let index = free_tail(&raw);
+                self.grow(index + count - raw.len())?;
+                index
+            }
+        };
+
+        let mut vfat = self.vfat.borrow_mut();
+        let location = EntryLocation {
+            dir: self.cluster,
+            index: index + lfn.len(),
+        };
+        for (i, lfn) in lfn.into_iter().enumerate() {
+            let lfn_location = EntryLocation {
+                dir: self.cluster,
+                index: index + i,
+            };
+            *vfat.dir_entry_mut(lfn_location)? = VFatDirEntry { long_filename: lfn };
+        }
+        *vfat.dir_entry_mut(location)? = VFatDirEntry { regular: entry };
+
+        Ok(location)
+    }
+
+    /// Appends enough zeroed clusters to the chain of `self` to hold `entries`
+    /// more entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if the directory would hold more than
+    /// 65536 entries or if the volume runs out of free clusters.
+    fn grow(&self, entries: usize) -> io::Result<()> {
+        let mut vfat = self.vfat.borrow_mut();
+        let per_cluster = vfat.cluster_size() / size_of::<VFatDirEntry>();
+        let clusters = (entries + per_cluster - 1) / per_cluster;
+        let chain = vfat.chain(self.cluster)?;
+        if (chain.len() + clusters) * per_cluster > MAX_DIR_ENTRIES {
+            return Err(io::Error::new(io::ErrorKind::Other, "directory is full"));
+        }
+
+        let mut last = *chain.last().expect("chain is never empty");
+        for _ in 0..clusters {
+            let cluster = vfat.alloc_cluster(Some(last))?;
+            vfat.zero_cluster(cluster)?;
+            last = cluster;
+        }
+        trace!("grew directory {:?} by {} clusters", self.cluster, clusters);
+        Ok(())
+    }
+
+    /// Rewrites the entries of `self` to squeeze out deleted entries, keeping
+    /// the live entries in order, and frees the clusters at the end of the
+    /// directory left empty. The first cluster is always kept.
+    ///
+    /// The moved entries are flushed to the device before the freed clusters
+    /// are removed from the directory's chain, so that a crash can't lose
+    /// entries that were still stored in them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if a file or directory in `self` is open,
+    /// since moving its entry would invalidate the handle, and the errors of
+    /// `entries()` and `VFat::flush()`.
+    pub fn compact(&self) -> io::Result<CompactReport> {
+        let raw = self.raw_entries()?;
+        let mut vfat = self.vfat.borrow_mut();
+        if vfat.handles.any_open_in(self.cluster) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "directory has open entries",
+            ));
+        }
+        // Pending access dates are written before the entries move.
+        vfat.write_accessed()?;
+
+        let end = raw
+            .iter()
+            .position(|entry| entry.unknown().is_end())
+            .unwrap_or(raw.len());
+        let live: Vec<usize> = (0..end)
+            .filter(|&index| !raw[index].unknown().is_deleted())
+            .collect();
+
+        let location = |index| EntryLocation {
+            dir: self.cluster,
+            index,
+        };
+        for (new, &old) in live.iter().enumerate() {
+            if new != old {
+                *vfat.dir_entry_mut(location(new))? = VFatDirEntry {
+                    unknown: raw[old].unknown(),
+                };
+            }
+        }
+        let per_cluster = vfat.cluster_size() / size_of::<VFatDirEntry>();
+        let clusters = ::std::cmp::max(1, (live.len() + per_cluster - 1) / per_cluster);
+        for index in live.len()..::std::cmp::min(end, clusters * per_cluster) {
+            *vfat.dir_entry_mut(location(index))? = VFatDirEntry {
+                unknown: VFatUnknownDirEntry {
+                    id: 0,
+                    unknown1: [0; 10],
+                    attr: 0,
+                    unknown2: [0; 20],
+                },
+            };
+        }
+        vfat.flush()?;
+
+        let chain = vfat.chain(self.cluster)?;
+        let freed_clusters = chain.len().saturating_sub(clusters);
+        if freed_clusters > 0 {
+            vfat.free_chain(chain[clusters])?;
+            vfat.set_fat_entry(chain[clusters - 1], Status::Eoc(0x0FFF_FFFF))?;
+        }
+        Ok(CompactReport {
+            removed_entries: end - live.len(),
+            freed_clusters,
+        })
+    }
+
+    /// Creates an empty file named `name` in `self` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// If an entry named `name` already exists, an error of `AlreadyExists` is
+    /// returned. If `name` is not a valid name, an error of `InvalidInput`
+    /// holding the `NameError` returned by `validate_name()` is returned. If
+    /// `self` has no room for the new entry, an error of `Other` is returned.
+    pub fn create_file(&self, name: &str) -> io::Result<File> {
+        self.check_new_name(name)?;
+
+        let now = self.vfat.borrow().now();
+        let entry = VFatRegularDirEntry::new(Attributes::ARCHIVE, Cluster::from(0), now);
+        let location = self.add_entry(name, entry)?;
+        self.vfat.borrow_mut().notify(self.cluster, WatchEvent::Created(name.into()));
+        Ok(File {
+            name: name.to_string(),
+            cluster: Cluster::from(0),
+            vfat: self.vfat.clone(),
+            metadata: entry.metadata(),
+            size: 0,
+            read_ptr: 0,
+            location: Some(location),
+            _handle: self.register(location),
+            chain_cache: None,
+        })
+    }
+
+    /// Creates an empty directory named `name` in `self` and returns it.
+    ///
+    /// # Errors
+    ///
+    /// The errors of `create_file()`, and an error of `Other` if there is no
+    /// free cluster for the new directory.
+    pub fn create_dir(&self, name: &str) -> io::Result<Dir> {
+        self.check_new_name(name)?;
+
+        let now = self.vfat.borrow().now();
+        let cluster = {
+            let mut vfat = self.vfat.borrow_mut();
+            let cluster = vfat.alloc_cluster(None)?;
+            vfat.zero_cluster(cluster)?;
+
+            // The `..` entry of a directory in the root refers to cluster 0.
+            let parent = if self.cluster == vfat.root_dir_cluster {
+                Cluster::from(0)
+            } else {
+                self.cluster
+            };
+            for (index, &(short_name, target)) in [(b".          ", cluster), (b"..         ", parent)]
+                .iter()
+                .enumerate()
+            {
+                let mut dot = VFatRegularDirEntry::new(Attributes::DIRECTORY, target, now);
+                dot.set_short_name(short_name);
+                *vfat.dir_entry_mut(EntryLocation { dir: cluster, index })? =
+                    VFatDirEntry { regular: dot };
+            }
+            cluster
+        };
+
+        let entry = VFatRegularDirEntry::new(Attributes::DIRECTORY, cluster, now);
+        let location = match self.add_entry(name, entry) {
+            Ok(location) => location,
+            Err(e) => {
+                self.vfat.borrow_mut().set_fat_entry(cluster, Status::Free)?;
+                return Err(e);
+            }
+        };
+        self.vfat.borrow_mut().notify(self.cluster, WatchEvent::Created(name.into()));
+
+        Ok(Dir {
+            name: name.to_string(),
+            cluster,
+            vfat: self.vfat.clone(),
+            metadata: entry.metadata(),
+            location: Some(location),
+            _handle: self.register(location),
+        })
+    }
+
+    /// Calls `f` with the path and entry of every entry in the tree rooted at
+    /// `self`, depth-first, with each directory visited before its children.
+    /// `path` is the path of `self`; the `.` and `..` entries are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error returned by `f` or encountered while reading a
+    /// directory.
+    pub(crate) fn walk<F>(&self, path: &Path, f: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&Path, &Entry) -> io::Result<()>,
+    {
+        self.walk_with_cancel(path, &CancelToken::new(), f)
+    }
+
+    /// Like `walk()`, but checks `cancel` before visiting each entry, so that
+    /// a walk of a huge tree can be abandoned between entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if `cancel` is cancelled before the walk
+    /// completes, and otherwise the first error returned by `f` or
+    /// encountered while reading a directory.
+    pub fn walk_with_cancel<F>(
+        &self,
+        path: &Path,
+        cancel: &CancelToken,
+        f: &mut F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&Path, &Entry) -> io::Result<()>,
+    {
+        self.walk_pooled(path, cancel, f, &mut Vec::new())
+    }
+
+    /// Implements `walk_with_cancel()`, reading each directory into a buffer
+    /// taken from `pool` and returning the buffer to `pool` afterwards, so
+    /// that a walk allocates one buffer per level of the tree rather than
+    /// one per directory.
+    fn walk_pooled<F>(
+        &self,
+        path: &Path,
+        cancel: &CancelToken,
+        f: &mut F,
+        pool: &mut Vec<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&Path, &Entry) -> io::Result<()>,
+    {
+        let buf = pool.pop().unwrap_or_default();
+        let mut entries = self.entries_with_buffer(buf)?;
+        while let Some(entry) = entries.next() {
+            cancel.check()?;
+            let name = match entry {
+                Entry::Dir(ref dir) if dir.name() == "." || dir.name() == ".." => continue,
+                Entry::Dir(ref dir) => dir.name(),
+                Entry::File(ref file) => file.name(),
+            };
+
+            let entry_path = path.join(name);
+            f(&entry_path, &entry)?;
+            if let Entry::Dir(ref dir) = entry {
+                dir.walk_pooled(&entry_path, cancel, f, pool)?;
+            }
+        }
+
+        pool.push(entries.into_buffer());
+        Ok(())
+    }
 }
 
 impl traits::Dir for Dir {
@@ -262,13 +1262,7 @@ impl traits::Dir for Dir {
 
     /// Returns an interator over the entries in this directory.
     fn entries(&self) -> io::Result<Self::Iter> {
-        let mut buf = Vec::new();
-        self.vfat.borrow_mut().read_chain(self.cluster, &mut buf)?;
-        Ok(EntryIter {
-            entries: unsafe { buf.cast() },
-            index: 0,
-            vfat: self.vfat.clone(),
-        })
+        self.entries_with_buffer(Vec::new())
     }
 }
 
@@ -277,12 +1271,87 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_ascii_to_string() {
+    fn test_oem_to_string() {
         let arr: [u8; 8] = [0x48, 0x45, 0x4C, 0x4C, 0x4F, 0x20, 0x4F, 0x00];
-        assert_eq!(ascii_to_string(&arr), Some("HELLO".to_string()));
+        assert_eq!(oem_to_string(&arr, Codepage::Cp437), Some("HELLO O".to_string()));
+        let arr: [u8; 8] = [0x48, 0x45, 0x4C, 0x4C, 0x4F, 0x20, 0x20, 0x20];
+        assert_eq!(oem_to_string(&arr, Codepage::Cp437), Some("HELLO".to_string()));
+        let arr: [u8; 8] = [0x20; 8];
+        assert_eq!(oem_to_string(&arr, Codepage::Cp437), None);
 
         let arr: [u8; 8] = [0x00, 0x45, 0x4C, 0x4C, 0x4F, 0x20, 0x00, 0x00];
-        assert_eq!(ascii_to_string(&arr), None);
+        assert_eq!(oem_to_string(&arr, Codepage::Cp437), None);
+    }
+
+    #[test]
+    fn test_entry_name_eq() {
+        let mut regular = VFatRegularDirEntry::new(0, Cluster::from(0), Timestamp::default());
+        regular.set_short_name(b"HELLO   TXT");
+        assert!(entry_name_eq(None, &regular, "hello.txt", Codepage::Cp437));
+        assert!(!entry_name_eq(None, &regular, "hello.tx", Codepage::Cp437));
+        regular.set_short_name(b"A B     T T");
+        assert_eq!(regular.display_name(Codepage::Cp437), "A B.T T");
+        assert!(entry_name_eq(None, &regular, "a b.t t", Codepage::Cp437));
+        assert!(!entry_name_eq(None, &regular, "a.t", Codepage::Cp437));
+        regular.set_short_name(b"HELLO   TXT");
+        assert!(!entry_name_eq(None, &regular, "hello", Codepage::Cp437));
+        regular.set_short_name(b"README     ");
+        assert!(entry_name_eq(None, &regular, "ReadMe", Codepage::Cp437));
+        assert!(!entry_name_eq(None, &regular, "readme.", Codepage::Cp437));
+
+        let lfn: Vec<u16> = "Long Näme.txt".encode_utf16().chain(Some(0)).collect();
+        assert!(entry_name_eq(Some(&lfn), &regular, "long näme.TXT", Codepage::Cp437));
+        assert!(!entry_name_eq(Some(&lfn), &regular, "long nÄme.txt", Codepage::Cp437));
+        assert!(!entry_name_eq(Some(&lfn), &regular, "README", Codepage::Cp437));
+    }
+
+    #[test]
+    fn test_display_name() {
+        let mut regular = VFatRegularDirEntry::new(0, Cluster::from(0), Timestamp::default());
+        regular.set_short_name(b"README  TXT");
+        assert_eq!(regular.display_name(Codepage::Cp437), "README.TXT");
+        regular.reserved = NT_LOWERCASE_BASE | NT_LOWERCASE_EXT;
+        assert_eq!(regular.display_name(Codepage::Cp437), "readme.txt");
+        regular.reserved = NT_LOWERCASE_EXT;
+        assert_eq!(regular.display_name(Codepage::Cp437), "README.txt");
+        regular.reserved = NT_LOWERCASE_BASE;
+        assert_eq!(regular.display_name(Codepage::Cp437), "readme.TXT");
+        assert!(entry_name_eq(None, &regular, "readme.txt", Codepage::Cp437));
+
+        regular.set_short_name(b"MAKEFILE   ");
+        assert_eq!(regular.display_name(Codepage::Cp437), "MAKEFILE");
+    }
+
+    #[test]
+    fn test_escaped_e5() {
+        let mut short_name = *b"\xE5ABC    TXT";
+        let mut regular = VFatRegularDirEntry::new(0, Cluster::from(0), Timestamp::default());
+        regular.set_short_name(&short_name);
+        assert_eq!({ regular.name }[0], ESCAPED_E5);
+        assert_eq!(regular.short_name(), short_name);
+        assert_eq!(regular.display_name(Codepage::Cp437), "σABC.TXT");
+        assert_eq!(regular.display_name(Codepage::Cp866), "хABC.TXT");
+        assert!(entry_name_eq(None, &regular, "σabc.txt", Codepage::Cp437));
+
+        let entry = VFatDirEntry { regular };
+        assert!(!entry.unknown().is_deleted());
+
+        let checksum = short_name_checksum(&short_name);
+        short_name[0] = ESCAPED_E5;
+        assert_eq!(checksum, short_name_checksum(&short_name));
+    }
+
+    #[test]
+    fn test_generate_short_name_codepage() {
+        let short_name = generate_short_name("привет.txt", &[], Codepage::Cp866).expect("generate");
+        let mut regular = VFatRegularDirEntry::new(0, Cluster::from(0), Timestamp::default());
+        regular.set_short_name(&short_name);
+        assert_eq!(regular.display_name(Codepage::Cp866), "ПРИВЕТ~1.TXT");
+
+        let short_name = generate_short_name("café.txt", &[], Codepage::Cp850).expect("generate");
+        assert_eq!(&short_name, b"CAF\x90~1  TXT");
+        let short_name = generate_short_name("привет.txt", &[], Codepage::Cp437).expect("generate");
+        assert_eq!(&short_name, b"______~1TXT");
     }
 
     #[test]
@@ -292,4 +1361,25 @@ mod tests {
         ];
         assert_eq!(ucs_2_to_string(&arr), "�Привет�".to_string());
     }
+
+    #[test]
+    fn test_string_to_ucs_2() {
+        let units = string_to_ucs_2("Привет 𝄞").expect("encode");
+        assert_eq!(units.len(), 13);
+        let expected = [
+            0x041F, 0x0440, 0x0438, 0x0432, 0x0435, 0x0442, 0x0020, 0xD834, 0xDD1E, 0x0000,
+        ];
+        assert_eq!(&units[..10], &expected);
+        assert_eq!(&units[10..], &[0xFFFF; 3]);
+        assert_eq!(ucs_2_to_string(&units), "Привет 𝄞");
+
+        // A name filling its last entry is not terminated.
+        let units = string_to_ucs_2(&"a".repeat(26)).expect("encode");
+        assert_eq!(units, vec![0x0061; 26]);
+        assert_eq!(string_to_ucs_2("").expect("encode"), Vec::<u16>::new());
+
+        assert_eq!(string_to_ucs_2(&"a".repeat(255)).expect("encode").len(), 260);
+        let e = string_to_ucs_2(&"a".repeat(256)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
 }
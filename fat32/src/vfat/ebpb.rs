@@ -1,6 +1,7 @@
 use std::{fmt, mem};
 
 use traits::BlockDevice;
+use util::{Le16, Le32};
 use vfat::Error;
 
 #[repr(C, packed)]
@@ -8,34 +9,34 @@ pub struct BiosParameterBlock {
     // BPB
     jmp: [u8; 3],
     oem_id: [u8; 8],
-    pub bytes_per_sector: u16,
-    pub sectors_per_cluster: u8,
-    pub sectors_reserved: u16,
-    pub fats_number: u8,
-    max_dir_entries: u16,
-    logical_sectors_small: u16,
+    bytes_per_sector: Le16,
+    sectors_per_cluster: u8,
+    sectors_reserved: Le16,
+    fats_number: u8,
+    max_dir_entries: Le16,
+    logical_sectors_small: Le16,
     fat_id: u8,
-    sectors_per_fat16: u16,
-    sectors_per_track: u16,
-    heads: u16,
-    hidden_sectors: u32,
-    logical_sectors_big: u32,
+    sectors_per_fat16: Le16,
+    sectors_per_track: Le16,
+    heads: Le16,
+    hidden_sectors: Le32,
+    logical_sectors_big: Le32,
     // EBPB
-    pub sectors_per_fat: u32,
-    flags: u16,
-    fat_ver: u16,
-    pub root_dir_cluster: u32,
-    fsinfo_sector: u16,
-    backup_boot_sector: u16,
+    sectors_per_fat: Le32,
+    flags: Le16,
+    fat_ver: Le16,
+    root_dir_cluster: Le32,
+    fsinfo_sector: Le16,
+    backup_boot_sector: Le16,
     reserved: [u8; 12],
     drive_number: u8,
     reserved2: u8,
     signature: u8,
-    volume_serial: u32,
+    volume_serial: Le32,
     volume_label: [u8; 11],
     system_id: [u8; 8],
     boot_code: [u8; 420],
-    bootable_signature: u16,
+    bootable_signature: Le16,
 }
 
 const EBPB_SIZE: usize = mem::size_of::<BiosParameterBlock>();
@@ -52,12 +53,83 @@ impl BiosParameterBlock {
         let _ebpb_size = device.read_sector(sector, &mut buf)?;
         let ebpb: BiosParameterBlock = unsafe { mem::transmute(buf) };
 
-        if ebpb.bootable_signature != 0xAA55 {
+        if ebpb.bootable_signature.get() != 0xAA55 {
             return Err(Error::BadSignature);
         }
 
         Ok(ebpb)
     }
+
+    /// Returns `true` if the volume is laid out as FAT12 or FAT16: with a
+    /// 16-bit FAT size and a fixed-size root directory region between the
+    /// FATs and the data clusters, instead of a root directory stored in a
+    /// cluster chain starting at `root_dir_cluster`.
+    pub fn has_root_dir_region(&self) -> bool {
+        self.max_dir_entries.get() != 0 || self.sectors_per_fat16.get() != 0
+    }
+
+    /// The number of 32-byte entries in the fixed-size root directory region
+    /// of a FAT12 or FAT16 volume; 0 on FAT32.
+    pub fn root_dir_entries(&self) -> u16 {
+        self.max_dir_entries.get()
+    }
+
+    /// The size of one FAT in sectors on a FAT12 or FAT16 volume; 0 on FAT32,
+    /// where `sectors_per_fat()` holds it.
+    pub fn sectors_per_fat16(&self) -> u16 {
+        self.sectors_per_fat16.get()
+    }
+
+    /// Returns the total number of logical sectors in the volume.
+    pub fn total_sectors(&self) -> u64 {
+        match self.logical_sectors_small.get() {
+            0 => self.logical_sectors_big.get() as u64,
+            small => small as u64,
+        }
+    }
+
+    pub fn bytes_per_sector(&self) -> u16 {
+        self.bytes_per_sector.get()
+    }
+
+    pub fn sectors_per_cluster(&self) -> u8 {
+        self.sectors_per_cluster
+    }
+
+    /// The number of sectors before the first FAT, counted from the boot
+    /// sector.
+    pub fn sectors_reserved(&self) -> u16 {
+        self.sectors_reserved.get()
+    }
+
+    /// The media descriptor byte, also stored in the low byte of the FAT
+    /// entry of cluster 0.
+    pub fn media_descriptor(&self) -> u8 {
+        self.fat_id
+    }
+
+    pub fn fats_number(&self) -> u8 {
+        self.fats_number
+    }
+
+    pub fn sectors_per_fat(&self) -> u32 {
+        self.sectors_per_fat.get()
+    }
+
+    pub fn root_dir_cluster(&self) -> u32 {
+        self.root_dir_cluster.get()
+    }
+
+    /// The sector of the FSInfo structure, counted from the boot sector, or 0
+    /// or 0xFFFF if the volume has none.
+    pub fn fsinfo_sector(&self) -> u16 {
+        self.fsinfo_sector.get()
+    }
+
+    /// The serial number the volume was given when it was formatted.
+    pub fn volume_serial(&self) -> u32 {
+        self.volume_serial.get()
+    }
 }
 
 impl fmt::Debug for BiosParameterBlock {
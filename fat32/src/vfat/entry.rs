@@ -1,14 +1,104 @@
+use std::hash::{Hash, Hasher};
+
 use traits;
-use vfat::{Dir, File, Metadata};
+use vfat::{Dir, EntryLocation, File, Metadata};
 
 // TODO: You may need to change this definition.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Entry {
     File(File),
     Dir(Dir),
 }
 
+/// A snapshot of an entry's name, type, size, and metadata, detached from
+/// the file system it was read from.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct EntryInfo {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size in bytes; always 0 for directories.
+    pub size: u64,
+    /// The first cluster of the entry's data, or 0 for an empty file.
+    pub cluster: u32,
+    pub metadata: Metadata,
+}
+
+/// The identity of an entry: the serial number of its volume and the location
+/// of its directory entry. Unlike the entry's name or first cluster, it stays
+/// the same while the entry is written to, and across separate iterations of
+/// its directory, until the entry is removed or moved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntryId {
+    /// The serial number of the volume.
+    pub volume_serial: u32,
+    /// The first cluster of the directory containing the entry, or 0 for the
+    /// root directory, which has no directory entry.
+    pub parent: u32,
+    /// The byte offset of the entry in its directory.
+    pub offset: u64,
+}
+
 // TODO: Implement any useful helper methods on `Entry`.
+impl Entry {
+    /// Returns a snapshot of the entry.
+    pub fn info(&self) -> EntryInfo {
+        use traits::Entry as EntryTrait;
+
+        let (size, cluster) = match *self {
+            Entry::File(ref f) => (f.size as u64, f.cluster),
+            Entry::Dir(ref d) => (0, d.cluster()),
+        };
+        EntryInfo {
+            name: self.name().to_string(),
+            is_dir: self.is_dir(),
+            size,
+            cluster: cluster.fat_index(),
+            metadata: self.metadata().clone(),
+        }
+    }
+
+    /// Returns the identity of the entry. Two entries are equal, and hash
+    /// alike, if and only if their identities are.
+    pub fn id(&self) -> EntryId {
+        let volume_serial = match *self {
+            Entry::Dir(ref d) => d.vfat().borrow().volume_serial(),
+            Entry::File(ref f) => f.vfat.borrow().volume_serial(),
+        };
+        let (parent, offset) = match self.location() {
+            Some(location) => (location.dir.fat_index(), location.index as u64 * 32),
+            None => (0, 0),
+        };
+        EntryId {
+            volume_serial,
+            parent,
+            offset,
+        }
+    }
+
+    /// The location of the entry's directory entry on disk, if it has one. The
+    /// root directory has none.
+    pub(crate) fn location(&self) -> Option<EntryLocation> {
+        match *self {
+            Entry::Dir(ref d) => d.location(),
+            Entry::File(ref f) => f.location,
+        }
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for Entry {}
+
+impl Hash for Entry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
+    }
+}
 
 impl traits::Entry for Entry {
     type File = File;
@@ -1,6 +1,7 @@
 use std::io;
 
 use mbr;
+use vfat::MountProblem;
 
 #[derive(Debug)]
 pub enum Error {
@@ -8,6 +9,12 @@ pub enum Error {
     Io(io::Error),
     BadSignature,
     NotFound,
+    /// The disk is partitioned with a GUID partition table: its MBR is a
+    /// protective MBR holding a single partition of type 0xEE, and no FAT32
+    /// partition. GPT disks are not supported.
+    GptDisk,
+    /// A `MountCheck::Quick` check found the volume to be corrupt.
+    Corrupt(Vec<MountProblem>),
 }
 
 impl From<mbr::Error> for Error {
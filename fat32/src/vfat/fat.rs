@@ -1,9 +1,10 @@
 use std::fmt;
+use util::Le32;
 use vfat::*;
 
 // use self::Status::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Status {
     /// The FAT entry corresponds to an unused (free) cluster.
     Free,
@@ -20,22 +21,36 @@ pub enum Status {
 }
 
 #[repr(C, packed)]
-pub struct FatEntry(pub u32);
+pub struct FatEntry(Le32);
 
 impl FatEntry {
     /// Returns the `Status` of the FAT entry `self`.
     pub fn status(&self) -> Status {
-        let val = self.0 & 0x0FFF_FFFF;
+        let val = self.0.get() & 0x0FFF_FFFF;
         match val {
             0x0000_0000 => Status::Free,
             0x0000_0001 => Status::Reserved,
-            0x0000_0002...0x0FFF_FFEF => Status::Data(Cluster::from(self.0)),
+            0x0000_0002...0x0FFF_FFEF => Status::Data(Cluster::from(val)),
             0x0FFF_FFF0...0x0FFF_FFF6 => Status::Reserved,
             0x0FFF_FFF7 => Status::Bad,
-            0x0FFF_FFF8...0x0FFF_FFFF => Status::Eoc(self.0),
+            0x0FFF_FFF8...0x0FFF_FFFF => Status::Eoc(self.0.get()),
             _ => unreachable!(),
         }
     }
+
+    /// Sets the status of the FAT entry `self` to `status`. The high four
+    /// bits of the entry are reserved and preserved.
+    pub fn set_status(&mut self, status: Status) {
+        let val = match status {
+            Status::Free => 0x0000_0000,
+            Status::Reserved => 0x0FFF_FFF6,
+            Status::Data(cluster) => cluster.fat_index(),
+            Status::Bad => 0x0FFF_FFF7,
+            Status::Eoc(eoc) => eoc,
+        };
+        let reserved = self.0.get() & 0xF000_0000;
+        self.0.set(reserved | (val & 0x0FFF_FFFF));
+    }
 }
 
 impl fmt::Debug for FatEntry {
@@ -1,10 +1,10 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::io::{self, SeekFrom};
 
 use traits;
-use vfat::{Cluster, Metadata, Shared, VFat};
+use vfat::{Cluster, EntryLocation, Extent, Handle, Metadata, Shared, Status, VFat, WatchEvent};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File {
     pub name: String,
     pub cluster: Cluster,
@@ -12,6 +12,20 @@ pub struct File {
     pub metadata: Metadata,
     pub size: usize,
     pub read_ptr: usize,
+    pub location: Option<EntryLocation>,
+    /// Keeps the entry registered as open while `self` is alive.
+    pub(crate) _handle: Option<Handle>,
+    /// The extents of the file's chain, once resolved.
+    pub(crate) chain_cache: Option<ChainCache>,
+}
+
+/// The extents of a file's cluster chain, resolved from the FAT as of the
+/// volume's FAT generation `generation`. Any change to the FAT, such as a
+/// write growing the file or another file being freed, makes it stale.
+#[derive(Debug, Clone)]
+pub(crate) struct ChainCache {
+    generation: u64,
+    extents: Vec<Extent>,
 }
 
 impl File {
@@ -22,13 +36,420 @@ impl File {
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
+
+    /// Returns a new handle to the same file with its own position, initially
+    /// equal to `self`'s. Both handles share the underlying file system and
+    /// can be sent to other threads.
+    ///
+    /// The handles don't share their view of the file's size: a write
+    /// through one handle that grows the file isn't visible through the
+    /// other until the file is opened again.
+    pub fn try_clone(&self) -> io::Result<File> {
+        Ok(self.clone())
+    }
+
+    /// Returns the runs of consecutive clusters allocated to the file, in
+    /// file order, including clusters preallocated past its end. Files
+    /// without clusters have no extents.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file's cluster chain is invalid.
+    pub fn extents(&self) -> io::Result<Vec<Extent>> {
+        if self.cluster.fat_index() < 2 {
+            return Ok(Vec::new());
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        match self.chain_cache {
+            Some(ref cache) if cache.generation == vfat.fat_generation() => {
+                Ok(cache.extents.clone())
+            }
+            _ => Ok(Extent::from_chain(&vfat.chain(self.cluster)?)),
+        }
+    }
+
+    /// Reserves clusters for the first `bytes` bytes of the file, allocating
+    /// the clusters it lacks as one contiguous run that directly follows its
+    /// last cluster where possible. The size of the file doesn't change;
+    /// later writes fill the reserved clusters instead of allocating new
+    /// ones, so a file written sequentially after preallocating stays
+    /// unfragmented.
+    ///
+    /// Until the file is written up to its last reserved cluster, its chain
+    /// is longer than its size calls for, which `check()` reports as a size
+    /// mismatch. `release_preallocated()` frees the clusters that weren't
+    /// used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if the volume has no run of free clusters
+    /// long enough, and an error of `InvalidInput` if `bytes` is beyond the
+    /// 4 GiB FAT32 limit or the file has no directory entry.
+    pub fn preallocate(&mut self, bytes: u64) -> io::Result<()> {
+        let location = self.location.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file has no directory entry",
+        ))?;
+        if bytes > ::std::u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size exceeds FAT32 limit",
+            ));
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let cluster_size = vfat.cluster_size() as u64;
+        let chain = if self.cluster.fat_index() < 2 {
+            Vec::new()
+        } else {
+            vfat.chain(self.cluster)?
+        };
+        let needed = (bytes + cluster_size - 1) / cluster_size;
+        if needed <= chain.len() as u64 {
+            return Ok(());
+        }
+
+        let count = (needed - chain.len() as u64) as u32;
+        let first = vfat.alloc_contiguous(chain.last().cloned(), count)?;
+        if chain.is_empty() {
+            self.cluster = first;
+            vfat.update_dir_entry(location, |entry| entry.set_cluster(first))?;
+        }
+        Ok(())
+    }
+
+    /// Frees the clusters allocated to the file past the cluster holding its
+    /// last byte, such as clusters reserved by `preallocate()` that weren't
+    /// written to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file's cluster chain is invalid, and an error
+    /// of `InvalidInput` if the file has no directory entry.
+    pub fn release_preallocated(&mut self) -> io::Result<()> {
+        let location = self.location.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file has no directory entry",
+        ))?;
+        if self.cluster.fat_index() < 2 {
+            return Ok(());
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let cluster_size = vfat.cluster_size();
+        let chain = vfat.chain(self.cluster)?;
+        let keep = (self.size + cluster_size - 1) / cluster_size;
+        if keep >= chain.len() {
+            return Ok(());
+        }
+
+        vfat.free_chain(chain[keep])?;
+        if keep == 0 {
+            self.cluster = Cluster::from(0);
+            vfat.update_dir_entry(location, |entry| entry.set_cluster(Cluster::from(0)))?;
+        } else {
+            vfat.set_fat_entry(chain[keep - 1], Status::Eoc(0x0FFF_FFFF))?;
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the rest of the file, from the current
+    /// position, in chunks of `chunk_size` bytes rounded up to a whole number
+    /// of clusters, and at least one cluster. The last chunk holds what's
+    /// left. Only one chunk is in memory at a time, so that files of several
+    /// gigabytes can be processed without reading them whole as
+    /// `read_to_end()` does; to fill a buffer of the caller's instead, use
+    /// `read()`.
+    ///
+    /// The iterator follows the cluster chain as it goes rather than from the
+    /// first cluster for each chunk, and advances the file's position past
+    /// every chunk returned. It stops after returning an error, such as one of
+    /// `InvalidData` if the file's cluster chain is shorter than its size.
+    pub fn chunks(&mut self, chunk_size: usize) -> Chunks {
+        let cluster_size = self.vfat.borrow().cluster_size();
+        let clusters = max(1, (chunk_size + cluster_size - 1) / cluster_size);
+        Chunks {
+            file: self,
+            chunk_size: clusters * cluster_size,
+            cluster: None,
+            failed: false,
+        }
+    }
+
+    /// Copies the rest of the file, from the current position, to `writer`
+    /// and returns the number of bytes copied. Clusters are read into a
+    /// single cluster-sized buffer and written to `writer` from there, and
+    /// the cluster chain is followed as the copy goes, so copying costs one
+    /// pass over the chain and one copy of the data, unlike `io::copy()`.
+    /// The volume isn't borrowed while `writer` is written to, so `writer`
+    /// may be a file of the same volume.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `writer`, and an error of `InvalidData` if the
+    /// file's cluster chain is shorter than its size. The file's position is
+    /// then past the bytes read, which may include some that weren't
+    /// written.
+    pub fn copy_to<W: io::Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        let cluster_size = self.vfat.borrow().cluster_size();
+        let mut buf = vec![0u8; cluster_size];
+        let mut cluster = None;
+        let mut copied = 0;
+        while self.read_ptr < self.size {
+            let len = min(
+                cluster_size - self.read_ptr % cluster_size,
+                self.size - self.read_ptr,
+            );
+            self.read_sequential(&mut cluster, &mut buf[..len])?;
+            writer.write_all(&buf[..len])?;
+            copied += len as u64;
+        }
+        Ok(copied)
+    }
+
+    /// Returns an iterator over the bytes of the rest of the file, from the
+    /// current position, which reads a cluster at a time. Unlike
+    /// `io::Read::bytes()` on an unbuffered `File`, which reads one byte per
+    /// call, it needs no `BufReader`. The iterator stops after returning an
+    /// error.
+    pub fn bytes(self) -> Bytes {
+        Bytes {
+            file: self,
+            cluster: None,
+            buf: Vec::new(),
+            pos: 0,
+            failed: false,
+        }
+    }
+
+    /// Reads the next `buf.len()` bytes of the file into `buf`, advancing its
+    /// position. `cluster` is the cluster holding the byte at the position,
+    /// if known, and is updated to the cluster holding the byte at the new
+    /// position, so that sequential reads follow the chain only once.
+    fn read_sequential(&mut self, cluster: &mut Option<Cluster>, buf: &mut [u8]) -> io::Result<()> {
+        let mut vfat = self.vfat.borrow_mut();
+        let cluster_size = vfat.cluster_size();
+        let current = match *cluster {
+            Some(cluster) => cluster,
+            None => cluster_at(&mut self.chain_cache, &mut vfat, self.cluster, self.read_ptr)?,
+        };
+        let mut current = self.read_from(&mut vfat, current, buf)?;
+        record_access(self.location, &mut self.metadata, &mut vfat);
+        self.read_ptr += buf.len();
+        if self.read_ptr % cluster_size == 0 && self.read_ptr < self.size {
+            current = vfat.next_cluster(current)?.ok_or_else(short_chain)?;
+        }
+        *cluster = Some(current);
+
+        #[cfg(feature = "metrics")]
+        vfat.device.record(|m| m.bytes_read(buf.len()));
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes of the file from its position, held by
+    /// `cluster`, into `buf`, and returns the cluster holding the last byte
+    /// read. Everything is read directly into `buf`: each run of consecutive
+    /// whole clusters with a single read, and only the bytes needed of other
+    /// clusters. The position isn't advanced.
+    fn read_from(
+        &self,
+        vfat: &mut VFat,
+        mut cluster: Cluster,
+        buf: &mut [u8],
+    ) -> io::Result<Cluster> {
+        let cluster_size = vfat.cluster_size();
+        let mut read = 0;
+        loop {
+            let offset = (self.read_ptr + read) % cluster_size;
+            let mut n = min(cluster_size - offset, buf.len() - read);
+            if n == cluster_size {
+                let mut last = cluster;
+                let max = match vfat.max_run_clusters {
+                    0 => ::std::usize::MAX,
+                    max => max,
+                };
+                while buf.len() - read - n >= cluster_size && n / cluster_size < max {
+                    match vfat.next_cluster(last)? {
+                        Some(next) if next.fat_index() == last.fat_index() + 1 => last = next,
+                        _ => break,
+                    }
+                    n += cluster_size;
+                }
+                vfat.read_clusters(cluster, n / cluster_size, &mut buf[read..read + n])?;
+                cluster = last;
+            } else {
+                vfat.read_cluster_bytes(cluster, offset, &mut buf[read..read + n])?;
+            }
+
+            read += n;
+            if read == buf.len() {
+                return Ok(cluster);
+            }
+            cluster = vfat.next_cluster(cluster)?.ok_or_else(short_chain)?;
+        }
+    }
+}
+
+/// An iterator over the contents of a file in chunks of whole clusters,
+/// returned by `File::chunks()`.
+#[derive(Debug)]
+pub struct Chunks<'a> {
+    file: &'a mut File,
+    chunk_size: usize,
+    /// The cluster holding the byte at the file's position, once known.
+    cluster: Option<Cluster>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let len = min(self.chunk_size, self.file.size - self.file.read_ptr);
+        if len == 0 || self.failed {
+            return None;
+        }
+
+        let mut chunk = vec![0u8; len];
+        match self.file.read_sequential(&mut self.cluster, &mut chunk) {
+            Ok(()) => Some(Ok(chunk)),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// An iterator over the bytes of a file, returned by `File::bytes()`.
+#[derive(Debug)]
+pub struct Bytes {
+    file: File,
+    /// The cluster holding the byte at the file's position, once known.
+    cluster: Option<Cluster>,
+    /// The bytes read but not yet returned, from `pos` on.
+    buf: Vec<u8>,
+    pos: usize,
+    failed: bool,
+}
+
+impl Iterator for Bytes {
+    type Item = io::Result<u8>;
+
+    fn next(&mut self) -> Option<io::Result<u8>> {
+        if self.pos == self.buf.len() {
+            let file = &mut self.file;
+            let cluster_size = file.vfat.borrow().cluster_size();
+            let len = min(
+                cluster_size - file.read_ptr % cluster_size,
+                file.size - file.read_ptr,
+            );
+            if len == 0 || self.failed {
+                return None;
+            }
+
+            self.buf.resize(len, 0);
+            self.pos = 0;
+            if let Err(e) = file.read_sequential(&mut self.cluster, &mut self.buf) {
+                self.buf.clear();
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+
+        self.pos += 1;
+        Some(Ok(self.buf[self.pos - 1]))
+    }
+}
+
+impl ChainCache {
+    /// Returns the extents of the chain starting at `first` from `cache`,
+    /// resolving them first if `cache` is empty or stale. Returns `None` if
+    /// the chain is empty or invalid.
+    fn extents<'a>(
+        cache: &'a mut Option<ChainCache>,
+        vfat: &mut VFat,
+        first: Cluster,
+    ) -> io::Result<Option<&'a [Extent]>> {
+        let generation = vfat.fat_generation();
+        let fresh = match *cache {
+            Some(ref cache) => cache.generation == generation,
+            None => false,
+        };
+        if !fresh {
+            *cache = None;
+            if first.fat_index() < 2 {
+                return Ok(None);
+            }
+            match vfat.chain(first) {
+                Ok(chain) => {
+                    *cache = Some(ChainCache {
+                        generation,
+                        extents: Extent::from_chain(&chain),
+                    })
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(cache.as_ref().map(|cache| &cache.extents[..]))
+    }
+}
+
+/// Returns the cluster holding byte `pos` of the file whose chain starts at
+/// `first`. The chain is resolved once and kept in `cache` until the FAT
+/// changes, so that seeking and reading again don't follow the chain from the
+/// first cluster each time. A chain that can't be resolved as a whole is
+/// followed only up to `pos`.
+fn cluster_at(
+    cache: &mut Option<ChainCache>,
+    vfat: &mut VFat,
+    first: Cluster,
+    pos: usize,
+) -> io::Result<Cluster> {
+    let index = (pos / vfat.cluster_size()) as u32;
+    if let Some(extents) = ChainCache::extents(cache, vfat, first)? {
+        let mut skipped = 0;
+        for extent in extents {
+            if index < skipped + extent.len {
+                return Ok(Cluster::from(extent.start.fat_index() + index - skipped));
+            }
+            skipped += extent.len;
+        }
+        return Err(short_chain());
+    }
+
+    let mut cluster = first;
+    for _ in 0..index {
+        cluster = vfat.next_cluster(cluster)?.ok_or_else(short_chain)?;
+    }
+    Ok(cluster)
+}
+
+/// Records that the file with the entry at `location` and metadata
+/// `metadata` was read, for `vfat` to update its access date if it does.
+fn record_access(location: Option<EntryLocation>, metadata: &mut Metadata, vfat: &mut VFat) {
+    if let Some(location) = location {
+        if let Some(today) = vfat.record_access(location, metadata.accessed.date) {
+            metadata.accessed.date = today;
+        }
+    }
+}
+
+fn short_chain() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "cluster chain is shorter than file",
+    )
 }
 
 // FIXME: Implement `traits::File` (and its supertraits) for `File`.
 impl traits::File for File {
-    /// Writes any buffered data to disk.
+    /// Writes every pending change to the volume, not only this file's, to
+    /// disk and waits for the device to persist it, as `VFat::sync()` does.
     fn sync(&mut self) -> io::Result<()> {
-        Ok(())
+        self.vfat.borrow_mut().sync()
     }
 
     /// Returns the size of the file in bytes.
@@ -38,29 +459,130 @@ impl traits::File for File {
 }
 
 impl io::Read for File {
+    /// Reads from the current position, following the cluster chain only as
+    /// far as needed. Data is read directly into `buf`, and runs of
+    /// consecutive clusters with a single device read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the file's cluster chain is
+    /// shorter than its size.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.size == 0 {
+        let len = min(buf.len(), self.size - self.read_ptr);
+        if len == 0 {
             return Ok(0);
         }
 
-        let mut buf_vec = Vec::new();
-        self.vfat
-            .borrow_mut()
-            .read_chain(self.cluster, &mut buf_vec)?;
-        let left_to_read = self.size - self.read_ptr;
-        let bytes_to_copy = min(left_to_read, buf.len());
+        let mut vfat = self.vfat.borrow_mut();
+        let cluster = cluster_at(&mut self.chain_cache, &mut vfat, self.cluster, self.read_ptr)?;
+        self.read_from(&mut vfat, cluster, &mut buf[..len])?;
+        record_access(self.location, &mut self.metadata, &mut vfat);
+
+        self.read_ptr += len;
+        #[cfg(feature = "metrics")]
+        vfat.device.record(|m| m.bytes_read(len));
+        Ok(len)
+    }
 
-        buf[..bytes_to_copy]
-            .copy_from_slice(&buf_vec[self.read_ptr..self.read_ptr + bytes_to_copy]);
-        self.read_ptr += bytes_to_copy;
+    /// Reads the rest of the file into `buf`, reserving exactly the space
+    /// needed up front.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        let len = self.size - self.read_ptr;
+        buf.reserve_exact(len);
+        buf.resize(start + len, 0);
 
-        Ok(bytes_to_copy)
+        let result = io::Read::read(self, &mut buf[start..]);
+        let read = *result.as_ref().unwrap_or(&0);
+        buf.truncate(start + read);
+        result
     }
 }
 
 impl io::Write for File {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!()
+    /// Writes `buf` at the current position, allocating clusters as the file
+    /// grows. The file's directory entry is updated with the new size, first
+    /// cluster, and modification time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if the volume runs out of free clusters,
+    /// and an error of `InvalidInput` if the file would grow beyond the 4 GiB
+    /// FAT32 limit or has no directory entry.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let location = self.location.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file has no directory entry",
+        ))?;
+        let end = self.read_ptr + buf.len();
+        if end > ::std::u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size exceeds FAT32 limit",
+            ));
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let cluster_size = vfat.cluster_size();
+
+        let cached: Option<Vec<Cluster>> =
+            ChainCache::extents(&mut self.chain_cache, &mut vfat, self.cluster)?.map(|extents| {
+                extents
+                    .iter()
+                    .flat_map(|e| (0..e.len).map(move |i| Cluster::from(e.start.fat_index() + i)))
+                    .collect()
+            });
+        let mut chain = match cached {
+            Some(chain) => chain,
+            None if self.cluster.fat_index() < 2 => Vec::new(),
+            None => vfat.chain(self.cluster)?,
+        };
+        while chain.len() * cluster_size < end {
+            let prev = chain.last().cloned();
+            chain.push(vfat.alloc_cluster(prev)?);
+        }
+        self.cluster = chain[0];
+        self.chain_cache = Some(ChainCache {
+            generation: vfat.fat_generation(),
+            extents: Extent::from_chain(&chain),
+        });
+
+        let mut written = 0;
+        let mut cluster_buf = vec![0u8; cluster_size];
+        while written < buf.len() {
+            let pos = self.read_ptr + written;
+            let cluster = chain[pos / cluster_size];
+            let offset = pos % cluster_size;
+            let len = min(cluster_size - offset, buf.len() - written);
+            if len < cluster_size {
+                vfat.read_cluster(cluster, 0, &mut cluster_buf)?;
+            }
+            cluster_buf[offset..offset + len].copy_from_slice(&buf[written..written + len]);
+            vfat.write_cluster(cluster, 0, &cluster_buf)?;
+            written += len;
+        }
+
+        self.read_ptr += written;
+        self.size = max(self.size, self.read_ptr);
+
+        let now = vfat.now();
+        let (cluster, size) = (self.cluster, self.size as u32);
+        vfat.update_dir_entry(location, |entry| {
+            entry.set_cluster(cluster);
+            entry.set_size(size);
+            entry.mark_modified(now);
+        })?;
+        self.metadata.modified = now;
+        self.metadata.attr.0 |= 0x20;
+        vfat.notify(location.dir, WatchEvent::Modified(self.name.as_str().into()));
+
+        #[cfg(feature = "metrics")]
+        vfat.device.record(|m| m.bytes_written(written));
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -82,7 +604,24 @@ impl io::Seek for File {
     ///
     /// Seeking before the start of a file or beyond the end of the file results
     /// in an `InvalidInput` error.
-    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
-        unimplemented!("File::seek()")
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset as i64),
+            SeekFrom::End(offset) => (self.size as i64).checked_add(offset),
+            SeekFrom::Current(offset) => (self.read_ptr as i64).checked_add(offset),
+        };
+
+        let new_pos = match new_pos {
+            Some(pos) if pos >= 0 && pos <= self.size as i64 => pos,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek outside of file bounds",
+                ))
+            }
+        };
+
+        self.read_ptr = new_pos as usize;
+        Ok(new_pos as u64)
     }
 }
@@ -0,0 +1,259 @@
+use std::io;
+
+use traits::BlockDevice;
+use util::{Le16, Le32};
+use vfat::CancelToken;
+
+/// Number of reserved sectors at the start of a formatted volume.
+const RESERVED_SECTORS: u64 = 32;
+/// Number of FAT copies written by `format()`.
+const NUMBER_OF_FATS: u64 = 2;
+/// Sector (relative to the volume) holding the FSInfo structure.
+const FSINFO_SECTOR: u64 = 1;
+/// Sector (relative to the volume) holding the backup boot sector.
+const BACKUP_BOOT_SECTOR: u64 = 6;
+/// Cluster number of the root directory.
+const ROOT_DIR_CLUSTER: u32 = 2;
+/// Media descriptor for fixed disks.
+const MEDIA_DESCRIPTOR: u8 = 0xF8;
+
+/// Options controlling the layout written by `format()`.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Number of sectors per cluster. Must be a power of two in [1, 128].
+    pub sectors_per_cluster: u8,
+    /// Volume label of at most 11 ASCII characters. Lowercase characters are
+    /// converted to uppercase. If `None`, the volume is labeled `NO NAME`.
+    pub volume_label: Option<String>,
+    /// Volume serial number stored in the EBPB.
+    pub volume_serial: u32,
+    /// A token checked before writing each sector. If it is cancelled,
+    /// `format()` stops and the device is left partially formatted.
+    pub cancel: Option<CancelToken>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            sectors_per_cluster: 8,
+            volume_label: None,
+            volume_serial: 0,
+            cancel: None,
+        }
+    }
+}
+
+/// The computed on-disk layout of a volume.
+#[derive(Debug)]
+struct Layout {
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    sectors_per_fat: u64,
+    total_sectors: u64,
+    data_clusters: u64,
+}
+
+impl Layout {
+    fn new(bytes_per_sector: u64, sectors_per_cluster: u64, total_sectors: u64) -> io::Result<Layout> {
+        // The boot sector records the total in 32 bits.
+        if total_sectors > ::std::u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "volume size out of range for FAT32",
+            ));
+        }
+
+        let entries_per_sector = bytes_per_sector / 4;
+        let usable = total_sectors.saturating_sub(RESERVED_SECTORS);
+
+        // Initial estimate from the FAT specification, grown until the FAT is
+        // large enough to address every data cluster (plus the two reserved
+        // entries).
+        let divisor = entries_per_sector * sectors_per_cluster + NUMBER_OF_FATS;
+        let mut sectors_per_fat = (usable + divisor - 1) / divisor;
+        loop {
+            let fat_sectors = NUMBER_OF_FATS * sectors_per_fat;
+            let data_clusters = usable.saturating_sub(fat_sectors) / sectors_per_cluster;
+            if data_clusters + 2 <= sectors_per_fat * entries_per_sector {
+                if data_clusters == 0 || data_clusters + 2 > 0x0FFF_FFF7 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "volume size out of range for FAT32",
+                    ));
+                }
+
+                return Ok(Layout {
+                    bytes_per_sector,
+                    sectors_per_cluster,
+                    sectors_per_fat,
+                    total_sectors,
+                    data_clusters,
+                });
+            }
+            sectors_per_fat += 1;
+        }
+    }
+
+    fn fat_start(&self) -> u64 {
+        RESERVED_SECTORS
+    }
+
+    fn data_start(&self) -> u64 {
+        RESERVED_SECTORS + NUMBER_OF_FATS * self.sectors_per_fat
+    }
+}
+
+/// Validates `label` and returns it space-padded to 11 bytes.
+fn volume_label(label: &Option<String>) -> io::Result<[u8; 11]> {
+    let label = match *label {
+        Some(ref label) => label.to_ascii_uppercase(),
+        None => String::from("NO NAME"),
+    };
+
+    let invalid = |c: u8| c < 0x20 || b"\"*+,./:;<=>?[\\]|".contains(&c) || c > 0x7E;
+    if label.is_empty() || label.len() > 11 || label.bytes().any(invalid) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid volume label",
+        ));
+    }
+
+    let mut buf = [b' '; 11];
+    buf[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(buf)
+}
+
+fn boot_sector(layout: &Layout, start: u64, label: &[u8; 11], serial: u32) -> Vec<u8> {
+    let mut buf = vec![0u8; layout.bytes_per_sector as usize];
+
+    // BPB
+    buf[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    buf[3..11].copy_from_slice(b"MSWIN4.1");
+    Le16::write(&mut buf, 11, layout.bytes_per_sector as u16);
+    buf[13] = layout.sectors_per_cluster as u8;
+    Le16::write(&mut buf, 14, RESERVED_SECTORS as u16);
+    buf[16] = NUMBER_OF_FATS as u8;
+    buf[21] = MEDIA_DESCRIPTOR;
+    Le16::write(&mut buf, 24, 63);
+    Le16::write(&mut buf, 26, 255);
+    Le32::write(&mut buf, 28, start as u32);
+    Le32::write(&mut buf, 32, layout.total_sectors as u32);
+
+    // EBPB
+    Le32::write(&mut buf, 36, layout.sectors_per_fat as u32);
+    Le32::write(&mut buf, 44, ROOT_DIR_CLUSTER);
+    Le16::write(&mut buf, 48, FSINFO_SECTOR as u16);
+    Le16::write(&mut buf, 50, BACKUP_BOOT_SECTOR as u16);
+    buf[64] = 0x80;
+    buf[66] = 0x29;
+    Le32::write(&mut buf, 67, serial);
+    buf[71..82].copy_from_slice(label);
+    buf[82..90].copy_from_slice(b"FAT32   ");
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    buf
+}
+
+fn fsinfo_sector(layout: &Layout) -> Vec<u8> {
+    let mut buf = vec![0u8; layout.bytes_per_sector as usize];
+    Le32::write(&mut buf, 0, 0x4161_5252);
+    Le32::write(&mut buf, 484, 0x6141_7272);
+    // The root directory occupies the first data cluster.
+    Le32::write(&mut buf, 488, (layout.data_clusters - 1) as u32);
+    Le32::write(&mut buf, 492, ROOT_DIR_CLUSTER + 1);
+    Le32::write(&mut buf, 508, 0xAA55_0000);
+    buf
+}
+
+/// Writes `buf` to `sector` of `device` unless `cancel` has been cancelled.
+fn write_sector<T: BlockDevice>(
+    device: &mut T,
+    cancel: &CancelToken,
+    sector: u64,
+    buf: &[u8],
+) -> io::Result<()> {
+    cancel.check()?;
+    device.write_sector(sector, buf)?;
+    Ok(())
+}
+
+/// Writes a fresh, empty FAT32 file system onto `device`.
+///
+/// The volume occupies the `sectors` sectors of `device` starting at sector
+/// `start`, typically the bounds of a partition. The written layout consists
+/// of the boot sector and its backup, the FSInfo sector and its backup, two
+/// zeroed FATs, and an empty root directory in the first data cluster. If a
+/// volume label is given, a volume ID entry is added to the root directory.
+///
+/// The sector size of the volume is the sector size of `device`.
+///
+/// # Errors
+///
+/// Returns an error of `InvalidInput` if `options.sectors_per_cluster` is not
+/// a power of two in [1, 128], if the volume label is invalid, or if `sectors`
+/// is too small or too large to hold a FAT32 file system. Returns an error of
+/// `Other` if `options.cancel` is cancelled before formatting completes, and
+/// any I/O error that occurs while writing to `device`.
+pub fn format<T: BlockDevice>(
+    mut device: T,
+    start: u64,
+    sectors: u64,
+    options: &FormatOptions,
+) -> io::Result<()> {
+    let spc = options.sectors_per_cluster;
+    if spc == 0 || spc > 128 || !spc.is_power_of_two() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sectors per cluster must be a power of two in [1, 128]",
+        ));
+    }
+
+    let label = volume_label(&options.volume_label)?;
+    let cancel = options.cancel.clone().unwrap_or_default();
+    let layout = Layout::new(device.sector_size(), spc as u64, sectors)?;
+    let sector_size = layout.bytes_per_sector as usize;
+    let zeroes = vec![0u8; sector_size];
+
+    // Clear the reserved region, then write both copies of the boot sector
+    // and FSInfo sector.
+    for sector in 0..RESERVED_SECTORS {
+        write_sector(&mut device, &cancel, start + sector, &zeroes)?;
+    }
+
+    let boot = boot_sector(&layout, start, &label, options.volume_serial);
+    let fsinfo = fsinfo_sector(&layout);
+    for &base in &[0, BACKUP_BOOT_SECTOR] {
+        write_sector(&mut device, &cancel, start + base, &boot)?;
+        write_sector(&mut device, &cancel, start + base + FSINFO_SECTOR, &fsinfo)?;
+    }
+
+    // Each FAT starts with the media descriptor entry, the reserved entry with
+    // the clean shutdown bits set, and the end-of-chain for the root cluster.
+    let mut first_fat_sector = vec![0u8; sector_size];
+    Le32::write(&mut first_fat_sector, 0, 0x0FFF_FF00 | MEDIA_DESCRIPTOR as u32);
+    Le32::write(&mut first_fat_sector, 4, 0x0FFF_FFFF);
+    Le32::write(&mut first_fat_sector, 8, 0x0FFF_FFFF);
+    for fat in 0..NUMBER_OF_FATS {
+        let fat_start = start + layout.fat_start() + fat * layout.sectors_per_fat;
+        write_sector(&mut device, &cancel, fat_start, &first_fat_sector)?;
+        for sector in 1..layout.sectors_per_fat {
+            write_sector(&mut device, &cancel, fat_start + sector, &zeroes)?;
+        }
+    }
+
+    // The root directory is an empty, zeroed cluster, optionally holding the
+    // volume ID entry.
+    let root_start = start + layout.data_start();
+    for sector in 0..layout.sectors_per_cluster {
+        write_sector(&mut device, &cancel, root_start + sector, &zeroes)?;
+    }
+
+    if options.volume_label.is_some() {
+        let mut root = vec![0u8; sector_size];
+        root[..11].copy_from_slice(&label);
+        root[11] = 0x08;
+        write_sector(&mut device, &cancel, root_start, &root)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fuser::{self, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+            ReplyDirectory, ReplyEntry, ReplyStatfs, Request};
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata as MetadataTrait};
+use vfat::{Entry, Shared, VFat};
+
+/// How long the kernel may cache attributes and lookups. The adapter is the
+/// only writer of the image while it is mounted.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// Exposes a mounted `VFat` through the `fuser` API.
+///
+/// Inode numbers are assigned to paths as they are looked up and stay valid
+/// for the lifetime of the adapter. The file system is currently exposed
+/// read-only.
+pub struct FuseAdapter {
+    vfat: Shared<VFat>,
+    uid: u32,
+    gid: u32,
+    paths: Vec<PathBuf>,
+    inodes: HashMap<PathBuf, u64>,
+}
+
+fn errno(error: &io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        _ => libc::EIO,
+    }
+}
+
+impl FuseAdapter {
+    /// Returns an adapter for `vfat`. Every entry is reported as owned by
+    /// `uid` and `gid`.
+    pub fn new(vfat: Shared<VFat>, uid: u32, gid: u32) -> FuseAdapter {
+        let root = PathBuf::from("/");
+        let mut inodes = HashMap::new();
+        inodes.insert(root.clone(), ROOT_INO);
+        FuseAdapter {
+            vfat,
+            uid,
+            gid,
+            paths: vec![root],
+            inodes,
+        }
+    }
+
+    /// Mounts the adapter at `mountpoint`, blocking until the file system is
+    /// unmounted.
+    pub fn mount<P: AsRef<Path>>(self, mountpoint: P) -> io::Result<()> {
+        let options = [
+            MountOption::RO,
+            MountOption::FSName("fat32".to_string()),
+            MountOption::DefaultPermissions,
+        ];
+        fuser::mount2(self, mountpoint.as_ref(), &options)
+    }
+
+    /// Returns the path of the inode `ino`, if it has been assigned.
+    fn path(&self, ino: u64) -> Option<PathBuf> {
+        ino.checked_sub(1).and_then(|i| self.paths.get(i as usize)).cloned()
+    }
+
+    /// Returns the inode number of `path`, assigning one if necessary.
+    fn inode(&mut self, path: PathBuf) -> u64 {
+        if let Some(&ino) = self.inodes.get(&path) {
+            return ino;
+        }
+        self.paths.push(path.clone());
+        let ino = self.paths.len() as u64;
+        self.inodes.insert(path, ino);
+        ino
+    }
+
+    fn attr(&self, ino: u64, entry: &Entry) -> FileAttr {
+        let metadata = entry.metadata();
+        let (kind, size, perm) = match *entry {
+            Entry::File(ref file) => {
+                let perm = if metadata.read_only() { 0o444 } else { 0o644 };
+                (FileType::RegularFile, file.size as u64, perm)
+            }
+            Entry::Dir(_) => {
+                let perm = if metadata.read_only() { 0o555 } else { 0o755 };
+                (FileType::Directory, 0, perm)
+            }
+        };
+
+        let (blksize, offset) = {
+            let vfat = self.vfat.borrow();
+            (vfat.cluster_size() as u32, vfat.utc_offset())
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: metadata.accessed().to_system_time_at(offset),
+            mtime: metadata.modified().to_system_time_at(offset),
+            ctime: metadata.modified().to_system_time_at(offset),
+            crtime: metadata.created().to_system_time_at(offset),
+            kind,
+            perm,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let mut file = self.vfat.open_file(path)?;
+        let end = ::std::cmp::min(file.size as u64, offset + size as u64);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; (end - offset) as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Filesystem for FuseAdapter {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let path = match self.path(parent) {
+            Some(parent) => parent.join(name),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        match self.vfat.open(&path) {
+            Ok(entry) => {
+                let ino = self.inode(path);
+                reply.entry(&TTL, &self.attr(ino, &entry), 0);
+            }
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(libc::ENOENT),
+        };
+
+        match self.vfat.open(&path) {
+            Ok(entry) => reply.attr(&TTL, &self.attr(ino, &entry)),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(libc::ENOENT),
+        };
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        match self.read_at(&path, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path,
+            None => return reply.error(libc::ENOENT),
+        };
+        let entries = match self.vfat.open_dir(&path).and_then(|dir| dir.entries()) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(errno(&e)),
+        };
+
+        // The root directory has no "." and ".." entries on disk, so they are
+        // synthesized for every directory and skipped when found on disk.
+        let parent = path.parent().map(Path::to_path_buf).unwrap_or(path.clone());
+        let mut listing = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (self.inode(parent), FileType::Directory, "..".to_string()),
+        ];
+        for entry in entries {
+            let name = entry.name().to_string();
+            if name == "." || name == ".." || entry.metadata().volume_id() {
+                continue;
+            }
+            let kind = if entry.is_dir() { FileType::Directory } else { FileType::RegularFile };
+            listing.push((self.inode(path.join(&name)), kind, name));
+        }
+
+        for (i, (ino, kind, name)) in listing.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back to us is that of the next entry.
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let stat = match self.vfat.borrow_mut().statvfs() {
+            Ok(stat) => stat,
+            Err(e) => return reply.error(errno(&e)),
+        };
+        // FAT has no inode table, so there is no limit on files to report.
+        reply.statfs(
+            stat.blocks,
+            stat.free_blocks,
+            stat.available_blocks,
+            0,
+            0,
+            stat.block_size,
+            stat.max_name_len,
+            stat.block_size,
+        );
+    }
+}
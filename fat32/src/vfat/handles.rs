@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+use vfat::{Cluster, EntryLocation, Shared};
+
+/// The table of open files and directories of a volume, keyed by the location
+/// of their directory entries, with the number of open handles to each.
+///
+/// The table has its own lock so that handles can be dropped while the
+/// volume is borrowed.
+#[derive(Debug, Clone)]
+pub(crate) struct HandleTable(Shared<HashMap<EntryLocation, usize>>);
+
+/// The registration of one open handle in a `HandleTable`. Cloning a `Handle`
+/// registers another handle; dropping it releases the registration.
+#[derive(Debug)]
+pub(crate) struct Handle {
+    table: HandleTable,
+    location: EntryLocation,
+}
+
+impl HandleTable {
+    pub fn new() -> HandleTable {
+        HandleTable(Shared::new(HashMap::new()))
+    }
+
+    /// Registers a handle to the entry at `location`. Entries without a
+    /// location, like the root directory, aren't tracked.
+    pub fn register(&self, location: Option<EntryLocation>) -> Option<Handle> {
+        location.map(|location| {
+            *self.0.borrow_mut().entry(location).or_insert(0) += 1;
+            Handle {
+                table: self.clone(),
+                location,
+            }
+        })
+    }
+
+    /// Returns the number of open handles to the entry at `location`.
+    pub fn open_count(&self, location: EntryLocation) -> usize {
+        self.0.borrow().get(&location).cloned().unwrap_or(0)
+    }
+
+    /// Returns `true` if any entry in the directory starting at `dir` is open.
+    pub fn any_open_in(&self, dir: Cluster) -> bool {
+        self.0.borrow().keys().any(|location| location.dir == dir)
+    }
+
+    fn release(&self, location: EntryLocation) {
+        let mut table = self.0.borrow_mut();
+        let remove = match table.get_mut(&location) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            table.remove(&location);
+        }
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        self.table
+            .register(Some(self.location))
+            .expect("registered handle")
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.table.release(self.location);
+    }
+}
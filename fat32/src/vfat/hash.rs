@@ -0,0 +1,43 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use digest::Digest;
+
+use traits::FileSystem;
+use vfat::{Shared, VFat};
+
+impl Shared<VFat> {
+    /// Feeds the contents of the file at `path` to `hasher`, one cluster at a
+    /// time, and returns the number of bytes hashed. The file is never read
+    /// into memory as a whole. `hasher` isn't finalized, so the digest is
+    /// read from it afterwards:
+    ///
+    /// ```rust,ignore
+    /// let mut hasher = Sha256::default();
+    /// vfat.hash_file("/BOOT/KERNEL.IMG", &mut hasher)?;
+    /// let digest = hasher.result();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open_file()` for `path`, and an error if
+    /// reading the file fails.
+    pub fn hash_file<P, D>(&self, path: P, hasher: &mut D) -> io::Result<u64>
+    where
+        P: AsRef<Path>,
+        D: Digest,
+    {
+        let mut file = self.open_file(path)?;
+        let mut buf = vec![0u8; self.borrow().cluster_size()];
+        let mut hashed = 0;
+        loop {
+            match file.read(&mut buf)? {
+                0 => return Ok(hashed),
+                n => {
+                    hasher.input(&buf[..n]);
+                    hashed += n as u64;
+                }
+            }
+        }
+    }
+}
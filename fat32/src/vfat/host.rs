@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use filetime::{self, FileTime};
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata as MetadataTrait};
+use vfat::diff::same_contents;
+use vfat::{Change, Entry, EntryLocation, Metadata, Shared, Timestamp, VFat};
+
+/// Size of the buffer used to copy file contents.
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+fn timestamp(time: io::Result<SystemTime>, utc_offset: i32) -> Option<Timestamp> {
+    time.ok().map(|time| Timestamp::from_system_time_at(time, utc_offset))
+}
+
+fn file_time(time: Timestamp, utc_offset: i32) -> FileTime {
+    FileTime::from_unix_time(time.unix_seconds_at(utc_offset) as i64, 0)
+}
+
+/// Result of a `sync_from_host()`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Number of files that were missing or changed and were copied in,
+    /// excluding those in directories counted in `copied_dirs`.
+    pub copied_files: usize,
+    /// Number of missing directories that were copied in whole.
+    pub copied_dirs: usize,
+    /// Number of files that were left alone because they were unchanged.
+    pub unchanged_files: usize,
+}
+
+fn type_mismatch() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "host file corresponds to a directory or vice versa",
+    )
+}
+
+/// Copies everything from `reader` to `writer` using a `COPY_BUFFER_SIZE`
+/// buffer.
+fn copy<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(()),
+            n => writer.write_all(&buf[..n])?,
+        }
+    }
+}
+
+/// Sets the access and modification times and the read-only flag of the host
+/// file or directory at `path` from `metadata`, whose times are local times
+/// `utc_offset` seconds east of UTC.
+fn set_host_metadata(path: &Path, metadata: &Metadata, utc_offset: i32) -> io::Result<()> {
+    let accessed = file_time(metadata.accessed(), utc_offset);
+    let modified = file_time(metadata.modified(), utc_offset);
+    filetime::set_file_times(path, accessed, modified)?;
+
+    if metadata.read_only() {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+impl Shared<VFat> {
+    /// Copies the regular file at `host_path` on the host file system to a
+    /// new file at `fat_path`.
+    ///
+    /// Names that are not valid 8.3 names are stored as long file names with
+    /// a generated short name. The creation, modification, and access times
+    /// of the host file are preserved, falling back to the modification time
+    /// where the host doesn't record creation time, and a read-only host file
+    /// is marked read-only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `host_path` is not a regular
+    /// file. Returns the errors of `create_file()` for `fat_path`, or any I/O
+    /// error encountered while copying.
+    pub fn copy_from_host<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        host_path: P,
+        fat_path: Q,
+    ) -> io::Result<()> {
+        let mut source = fs::File::open(host_path)?;
+        let meta = source.metadata()?;
+        if !meta.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "host path is not a regular file",
+            ));
+        }
+
+        let mut file = self.create_file(fat_path)?;
+        copy(&mut source, &mut file)?;
+
+        if let Some(location) = file.location {
+            self.copy_host_metadata(location, &meta)?;
+        }
+        Ok(())
+    }
+
+    /// Recursively copies the directory at `host_path` on the host file
+    /// system to `fat_path`, creating `fat_path` and any missing parents.
+    /// Files are copied as with `copy_from_host()`; directory timestamps and
+    /// read-only flags are preserved as well. Entries that are neither regular
+    /// files nor directories, such as symbolic links, are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `host_path` is not a directory.
+    /// Returns an error of `AlreadyExists` if an entry being copied already
+    /// exists in the file system, or any other error encountered while
+    /// copying.
+    pub fn copy_dir_from_host<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        host_path: P,
+        fat_path: Q,
+    ) -> io::Result<()> {
+        let (host_path, fat_path) = (host_path.as_ref(), fat_path.as_ref());
+        let meta = fs::metadata(host_path)?;
+        if !meta.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "host path is not a directory",
+            ));
+        }
+
+        let location = match self.open_dir(fat_path) {
+            Ok(dir) => dir.location(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.create_dir(fat_path, true)?.location()
+            }
+            Err(e) => return Err(e),
+        };
+
+        for entry in fs::read_dir(host_path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let fat_child = fat_path.join(entry.file_name());
+            if file_type.is_file() {
+                self.copy_from_host(entry.path(), fat_child)?;
+            } else if file_type.is_dir() {
+                self.copy_dir_from_host(entry.path(), fat_child)?;
+            }
+        }
+
+        if let Some(location) = location {
+            self.copy_host_metadata(location, &meta)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the directory tree at `fat_path` to match the directory at
+    /// `host_path` on the host file system, copying in only the files that
+    /// are missing or differ, as when repeatedly updating boot files on an SD
+    /// card from a build directory. `fat_path` and any missing parents are
+    /// created.
+    ///
+    /// A file is considered unchanged if its size and modification time, at
+    /// the two-second resolution of FAT, match those of the host file. A
+    /// changed file is copied to a temporary file next to it, which then
+    /// replaces it with `replace()`, so an interrupted sync never leaves a
+    /// partially written file in place. Files and directories copied in are
+    /// copied as with `copy_dir_from_host()`. Entries that exist only in the
+    /// file system are left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `host_path` is not a directory
+    /// or if a host file corresponds to a directory in the file system or
+    /// vice versa. Returns the errors of `copy_from_host()` and `replace()`,
+    /// including an error of `AlreadyExists` if the temporary file of an
+    /// earlier interrupted sync is still present, or any other error
+    /// encountered while copying.
+    pub fn sync_from_host<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        host_path: P,
+        fat_path: Q,
+    ) -> io::Result<SyncReport> {
+        let (host_path, fat_path) = (host_path.as_ref(), fat_path.as_ref());
+        if !fs::metadata(host_path)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "host path is not a directory",
+            ));
+        }
+
+        let mut report = SyncReport::default();
+        self.sync_dir_from_host(host_path, fat_path, &mut report)?;
+        Ok(report)
+    }
+
+    fn sync_dir_from_host(
+        &self,
+        host_path: &Path,
+        fat_path: &Path,
+        report: &mut SyncReport,
+    ) -> io::Result<()> {
+        match self.open(fat_path) {
+            Ok(Entry::Dir(_)) => {}
+            Ok(Entry::File(_)) => return Err(type_mismatch()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.copy_dir_from_host(host_path, fat_path)?;
+                report.copied_dirs += 1;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        for entry in fs::read_dir(host_path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let fat_child = fat_path.join(entry.file_name());
+            if file_type.is_dir() {
+                self.sync_dir_from_host(&entry.path(), &fat_child, report)?;
+            } else if file_type.is_file() {
+                self.sync_file_from_host(&entry.path(), &fat_child, report)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_file_from_host(
+        &self,
+        host_path: &Path,
+        fat_path: &Path,
+        report: &mut SyncReport,
+    ) -> io::Result<()> {
+        let (size, modified) = match self.open(fat_path) {
+            Ok(Entry::File(file)) => (file.size as u64, file.metadata().modified),
+            Ok(Entry::Dir(_)) => return Err(type_mismatch()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.copy_from_host(host_path, fat_path)?;
+                report.copied_files += 1;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let meta = fs::metadata(host_path)?;
+        let offset = self.borrow().utc_offset();
+        let host_modified = timestamp(meta.modified(), offset).map(|t| (t.date, t.time));
+        if meta.len() == size && host_modified == Some((modified.date, modified.time)) {
+            report.unchanged_files += 1;
+            return Ok(());
+        }
+
+        let mut temp_path = fat_path.as_os_str().to_os_string();
+        temp_path.push(".sync-tmp");
+        let temp_path = PathBuf::from(temp_path);
+        self.copy_from_host(host_path, &temp_path)?;
+        self.replace(&temp_path, fat_path)?;
+        report.copied_files += 1;
+        Ok(())
+    }
+
+    /// Sets the timestamps and read-only attribute of the entry at `location`
+    /// from the host metadata `meta`.
+    fn copy_host_metadata(&self, location: EntryLocation, meta: &fs::Metadata) -> io::Result<()> {
+        let offset = self.borrow().utc_offset();
+        let modified = match timestamp(meta.modified(), offset) {
+            Some(modified) => modified,
+            None => self.borrow().now(),
+        };
+        let created = timestamp(meta.created(), offset).unwrap_or(modified);
+        let accessed = timestamp(meta.accessed(), offset).unwrap_or(modified);
+        let read_only = meta.permissions().readonly();
+
+        self.borrow_mut().update_dir_entry(location, |entry| {
+            entry.set_times(created, modified, accessed);
+            entry.set_read_only(read_only);
+        })
+    }
+
+    /// Copies the file or directory tree at `fat_path` to `host_path` on the
+    /// host file system.
+    ///
+    /// A file is copied to a new host file at `host_path`. A directory is
+    /// copied recursively into `host_path`, which is created if it doesn't
+    /// exist; existing host directories are reused. Hidden entries are
+    /// copied as well; the volume label is not. The access and modification
+    /// times of every copied entry are preserved, and read-only entries are
+    /// made read-only on the host.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `fat_path`. Returns an error of
+    /// `AlreadyExists` if a file being copied already exists on the host, an
+    /// error of `InvalidData` if the name of an entry being copied isn't a
+    /// single path component on the host, so that it could be written outside
+    /// of `host_path`, or any other error encountered while copying.
+    pub fn copy_to_host<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        fat_path: P,
+        host_path: Q,
+    ) -> io::Result<()> {
+        let entry = self.open(fat_path.as_ref())?;
+        let offset = self.borrow().utc_offset();
+        copy_entry_to_host(entry, host_path.as_ref(), offset)
+    }
+
+    /// Compares the directory tree at `fat_path` with the directory at
+    /// `host_path` on the host file system and returns the differences,
+    /// ordered by path, as when checking that a flashing tool wrote a build
+    /// directory correctly. No differences means the trees match.
+    ///
+    /// The host tree plays the part of the other volume of `diff()`: an entry
+    /// only on the host is `Added`, and one only in the file system is
+    /// `Removed`. Files of the same size are compared byte for byte;
+    /// modification times aren't compared, so `modified` is always `None`.
+    /// Paths are those in the file system, names are compared exactly, and
+    /// host entries that are neither regular files nor directories, as well
+    /// as the volume label, are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `host_path` is not a directory.
+    /// Returns the errors of `open_dir()` for `fat_path`, and an error if
+    /// reading a directory or a file on either side fails.
+    pub fn verify_against_host<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        fat_path: P,
+        host_path: Q,
+    ) -> io::Result<Vec<Change>> {
+        let (fat_path, host_path) = (fat_path.as_ref(), host_path.as_ref());
+        if !fs::metadata(host_path)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "host path is not a directory",
+            ));
+        }
+
+        let mut fat = BTreeMap::new();
+        self.open_dir(fat_path)?.walk(Path::new(""), &mut |path, entry| {
+            if !entry.metadata().volume_id() {
+                let info = entry.info();
+                fat.insert(path.to_path_buf(), (info.is_dir, info.size));
+            }
+            Ok(())
+        })?;
+        let mut host = BTreeMap::new();
+        host_tree(host_path, Path::new(""), &mut host)?;
+
+        let paths: BTreeSet<&PathBuf> = fat.keys().chain(host.keys()).collect();
+        let mut changes = Vec::new();
+        let mut skipped: Option<&Path> = None;
+        for path in paths {
+            if skipped.map_or(false, |dir| path.starts_with(dir)) {
+                continue;
+            }
+
+            let fat_child = fat_path.join(path);
+            let change = match (fat.get(path), host.get(path)) {
+                (Some(&(is_dir, _)), None) => Change::Removed {
+                    path: fat_child,
+                    is_dir,
+                },
+                (None, Some(&(is_dir, _))) => Change::Added {
+                    path: fat_child,
+                    is_dir,
+                },
+                (Some(old), Some(new)) if old.0 != new.0 => Change::TypeChanged { path: fat_child },
+                (Some(&(true, _)), Some(_)) => continue,
+                (Some(&(_, old)), Some(&(_, new))) => {
+                    let contents = old == new && !same_contents(
+                        &mut self.open_file(&fat_child)?,
+                        &mut fs::File::open(host_path.join(path))?,
+                    )?;
+                    if old == new && !contents {
+                        continue;
+                    }
+                    Change::Changed {
+                        path: fat_child,
+                        size: if old != new { Some((old, new)) } else { None },
+                        modified: None,
+                        contents,
+                    }
+                }
+                (None, None) => unreachable!(),
+            };
+
+            // As in `diff()`, only the root of a tree that is missing on one
+            // side or replaced is reported.
+            match change {
+                Change::Changed { .. } => {}
+                _ => skipped = Some(path.as_path()),
+            }
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+}
+
+/// Adds every regular file and directory in the host directory at
+/// `host_path` to `tree`, by path relative to the root of the walk, with
+/// whether it is a directory and its size. `path` is the relative path of
+/// `host_path`.
+fn host_tree(
+    host_path: &Path,
+    path: &Path,
+    tree: &mut BTreeMap<PathBuf, (bool, u64)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(host_path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let child = path.join(entry.file_name());
+        if file_type.is_dir() {
+            tree.insert(child.clone(), (true, 0));
+            host_tree(&entry.path(), &child, tree)?;
+        } else if file_type.is_file() {
+            tree.insert(child, (false, entry.metadata()?.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `name`, the name of an entry read from the volume, if it is safe to
+/// join to a host path: a single normal path component without separators or
+/// NUL bytes. Names read from a corrupt or crafted volume may be anything.
+fn host_name(name: &str) -> io::Result<&str> {
+    let mut components = Path::new(name).components();
+    let single = match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => true,
+        _ => false,
+    };
+    if !single || name.contains(|c: char| c == '/' || c == '\\' || c == '\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("entry name {:?} is not a valid host file name", name),
+        ));
+    }
+    Ok(name)
+}
+
+fn copy_entry_to_host(entry: Entry, host_path: &Path, utc_offset: i32) -> io::Result<()> {
+    let metadata = entry.metadata().clone();
+    match entry {
+        Entry::File(mut file) => {
+            let mut target = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(host_path)?;
+            copy(&mut file, &mut target)?;
+        }
+        Entry::Dir(dir) => {
+            fs::create_dir_all(host_path)?;
+            for child in dir.entries()? {
+                let name = child.name().to_string();
+                if name == "." || name == ".." || child.metadata().volume_id() {
+                    continue;
+                }
+                let child_path = host_path.join(host_name(&name)?);
+                copy_entry_to_host(child, &child_path, utc_offset)?;
+            }
+        }
+    }
+
+    // Set times last: writing a directory's children updates its mtime.
+    set_host_metadata(host_path, &metadata, utc_offset)
+}
@@ -0,0 +1,398 @@
+use std::cmp::max;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use traits::FileSystem;
+use util::{Le32, Le64};
+use vfat::{Attributes, CachedDevice, Dir, Entry, File, Handle, Shared, VFat};
+
+/// Name of the file in the root directory that holds the journal.
+pub const JOURNAL_NAME: &str = "JOURNAL.SYS";
+
+const MAGIC: &[u8; 8] = b"FATJRNL1";
+const STATE_EMPTY: u32 = 0;
+const STATE_COMMITTED: u32 = 1;
+
+/// A write-ahead journal of the sectors holding the FAT and directory
+/// entries.
+///
+/// The journal is stored in the sectors of a hidden system file. Its first
+/// sector is a header; a transaction follows it as a list of target sector
+/// numbers, as 64-bit little-endian integers padded to a whole sector, and
+/// then the new contents of each target sector. The header is laid out as:
+///
+/// | offset | size | field                                               |
+/// |--------|------|-----------------------------------------------------|
+/// | 0      | 8    | magic, `FATJRNL1`                                   |
+/// | 8      | 4    | state: 0 if empty, 1 if a transaction is committed  |
+/// | 12     | 4    | number of sectors in the transaction                |
+/// | 16     | 8    | sequence number of the transaction                  |
+/// | 24     | 4    | FNV-1a hash of the target list and sector contents  |
+#[derive(Debug)]
+pub(crate) struct Journal {
+    /// The logical sectors of the journal file, in order.
+    sectors: Vec<u64>,
+    sector_size: usize,
+    sequence: u64,
+    /// Keeps the journal file open so that it is never relocated.
+    _handle: Option<Handle>,
+}
+
+/// Returns the 32-bit FNV-1a hash of the concatenation of `chunks`.
+pub(crate) fn fnv1a<'a, I: IntoIterator<Item = &'a [u8]>>(chunks: I) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    for chunk in chunks {
+        for &byte in chunk {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+    }
+    hash
+}
+
+impl Journal {
+    /// Initializes an empty journal in `sectors`, overwriting their contents.
+    pub fn create(
+        device: &mut CachedDevice,
+        sectors: Vec<u64>,
+        sector_size: usize,
+        handle: Option<Handle>,
+    ) -> io::Result<Journal> {
+        let journal = Journal {
+            sectors,
+            sector_size,
+            sequence: 0,
+            _handle: handle,
+        };
+        journal.write_header(device, STATE_EMPTY, 0, 0)?;
+        Ok(journal)
+    }
+
+    /// Opens the journal stored in `sectors`. A committed transaction is
+    /// replayed and a transaction whose contents don't match its hash, or
+    /// that targets a sector outside of `targets`, is discarded; either way,
+    /// the journal is left empty. Returns `None` if `sectors` don't hold a
+    /// journal or are too few to hold a transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading or writing the device fails.
+    pub fn open(
+        device: &mut CachedDevice,
+        sectors: Vec<u64>,
+        sector_size: usize,
+        targets: Range<u64>,
+        handle: Option<Handle>,
+    ) -> io::Result<Option<Journal>> {
+        if sectors.is_empty() {
+            return Ok(None);
+        }
+
+        let header = device.get(sectors[0])?.to_vec();
+        if &header[..8] != MAGIC {
+            return Ok(None);
+        }
+
+        let journal = Journal {
+            sectors,
+            sector_size,
+            sequence: Le64::read(&header, 16),
+            _handle: handle,
+        };
+        if journal.capacity() == 0 {
+            warn!(
+                "ignoring a journal of {} sectors, too small to hold a transaction",
+                journal.sectors.len()
+            );
+            return Ok(None);
+        }
+        if Le32::read(&header, 8) == STATE_COMMITTED {
+            let count = Le32::read(&header, 12) as usize;
+            if count <= journal.capacity() {
+                journal.replay(device, count, Le32::read(&header, 24), targets)?;
+            }
+            journal.write_header(device, STATE_EMPTY, 0, 0)?;
+        }
+        Ok(Some(journal))
+    }
+
+    /// The number of sectors in the target list of a transaction of `count`
+    /// sectors.
+    fn list_sectors(&self, count: usize) -> usize {
+        (count * 8 + self.sector_size - 1) / self.sector_size
+    }
+
+    /// The largest number of sectors a single transaction can hold.
+    fn capacity(&self) -> usize {
+        let available = self.sectors.len().saturating_sub(1);
+        let mut count = available * self.sector_size / (self.sector_size + 8);
+        while count > 0 && 1 + self.list_sectors(count) + count > self.sectors.len() {
+            count -= 1;
+        }
+        count
+    }
+
+    fn write_header(
+        &self,
+        device: &mut CachedDevice,
+        state: u32,
+        count: usize,
+        hash: u32,
+    ) -> io::Result<()> {
+        let mut header = vec![0u8; self.sector_size];
+        header[..8].copy_from_slice(MAGIC);
+        Le32::write(&mut header, 8, state);
+        Le32::write(&mut header, 12, count as u32);
+        Le64::write(&mut header, 16, self.sequence);
+        Le32::write(&mut header, 24, hash);
+        device.write_through(self.sectors[0], &header)
+    }
+
+    /// Writes the committed transaction of `count` sectors to its targets if
+    /// its contents hash to `hash` and every target is in `targets`.
+    fn replay(
+        &self,
+        device: &mut CachedDevice,
+        count: usize,
+        hash: u32,
+        targets: Range<u64>,
+    ) -> io::Result<()> {
+        let list_sectors = self.list_sectors(count);
+        let mut list = Vec::with_capacity(list_sectors * self.sector_size);
+        for &sector in &self.sectors[1..1 + list_sectors] {
+            list.extend_from_slice(device.get(sector)?);
+        }
+        let mut images = Vec::with_capacity(count);
+        for &sector in &self.sectors[1 + list_sectors..1 + list_sectors + count] {
+            images.push(device.get(sector)?.to_vec());
+        }
+
+        let chunks = Some(&list[..]).into_iter().chain(images.iter().map(|i| &i[..]));
+        if fnv1a(chunks) != hash {
+            debug!("discarding torn journal transaction of {} sectors", count);
+            return Ok(());
+        }
+
+        let list: Vec<u64> = (0..count).map(|i| Le64::read(&list, i * 8)).collect();
+        let outside = |&&target: &&u64| target < targets.start || target >= targets.end;
+        if let Some(target) = list.iter().find(outside) {
+            warn!(
+                "discarding journal transaction targeting sector {} outside of the volume",
+                target
+            );
+            return Ok(());
+        }
+
+        debug!("replaying journal transaction of {} sectors", count);
+        for (&target, image) in list.iter().zip(&images) {
+            device.write_through(target, image)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty sectors `data` in place, then commits the dirty
+    /// metadata sectors `metadata` to the journal before writing them in
+    /// place, in order and in as few transactions as the size of the journal
+    /// allows. If `sync` is set, the device is synced between each step so
+    /// that the steps reach the disk in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to or syncing the device fails. Sectors
+    /// that were not written remain dirty.
+    pub fn flush(
+        &mut self,
+        device: &mut CachedDevice,
+        data: &[u64],
+        metadata: &[u64],
+        sync: bool,
+    ) -> io::Result<()> {
+        for &sector in data {
+            device.write_back(sector)?;
+        }
+        if sync && !data.is_empty() {
+            device.sync()?;
+        }
+
+        let capacity = self.capacity();
+        if capacity == 0 && !metadata.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "journal is too small to hold a transaction",
+            ));
+        }
+        for batch in metadata.chunks(max(capacity, 1)) {
+            self.commit(device, batch, sync)?;
+        }
+        Ok(())
+    }
+
+    /// Logs the cached contents of `targets`, commits them, writes them in
+    /// place, and empties the journal, syncing the device after each step if
+    /// `sync` is set.
+    fn commit(
+        &mut self,
+        device: &mut CachedDevice,
+        targets: &[u64],
+        sync: bool,
+    ) -> io::Result<()> {
+        let list_sectors = self.list_sectors(targets.len());
+        let mut list = vec![0u8; list_sectors * self.sector_size];
+        for (i, &target) in targets.iter().enumerate() {
+            Le64::write(&mut list, i * 8, target);
+        }
+        let mut images = Vec::with_capacity(targets.len());
+        for &target in targets {
+            images.push(device.get(target)?.to_vec());
+        }
+
+        for (i, chunk) in list.chunks(self.sector_size).enumerate() {
+            device.write_through(self.sectors[1 + i], chunk)?;
+        }
+        for (i, image) in images.iter().enumerate() {
+            device.write_through(self.sectors[1 + list_sectors + i], image)?;
+        }
+        if sync {
+            device.sync()?;
+        }
+
+        let chunks = Some(&list[..]).into_iter().chain(images.iter().map(|i| &i[..]));
+        let hash = fnv1a(chunks);
+        self.sequence += 1;
+        debug!(
+            "committing journal transaction {} of {} sectors",
+            self.sequence,
+            targets.len()
+        );
+        self.write_header(device, STATE_COMMITTED, targets.len(), hash)?;
+        if sync {
+            device.sync()?;
+        }
+
+        for &target in targets {
+            device.write_back(target)?;
+        }
+        if sync {
+            device.sync()?;
+        }
+        self.write_header(device, STATE_EMPTY, 0, 0)?;
+        if sync {
+            device.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared<VFat> {
+    /// Returns `true` if metadata updates to the volume are journaled.
+    pub fn has_journal(&self) -> bool {
+        self.borrow().journal.is_some()
+    }
+
+    /// Creates a journal of at least `size` bytes in a hidden system file,
+    /// `JOURNAL.SYS`, in the root directory and enables journaling. All
+    /// pending changes are flushed first.
+    ///
+    /// From then on, `flush()` writes updates to the FAT and to directory
+    /// entries to the journal before writing them in place, so that a flush
+    /// interrupted by a crash or power loss is either completed or undone the
+    /// next time the volume is mounted. File contents are written before the
+    /// metadata that refers to them but are not journaled themselves. A flush
+    /// with more metadata changes than fit in the journal is committed in
+    /// several transactions, each of which is atomic on its own.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `AlreadyExists` if the volume already has a
+    /// journal or the root directory already has an entry named
+    /// `JOURNAL.SYS`, and an error of `InvalidInput` if `size` is too small to
+    /// hold a transaction. Returns the errors of `create_file()` and of
+    /// writing the file.
+    pub fn create_journal(&self, size: u64) -> io::Result<()> {
+        if self.has_journal() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "volume already has a journal",
+            ));
+        }
+
+        let (cluster_size, sector_size) = {
+            let vfat = self.borrow();
+            (vfat.cluster_size(), vfat.sector_size())
+        };
+        let size = max(size as usize, 1);
+        let clusters = (size + cluster_size - 1) / cluster_size;
+        // A header, a target list, and one sector.
+        if clusters * cluster_size < 3 * sector_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "journal is too small",
+            ));
+        }
+
+        let mut file = self.create_file(format!("/{}", JOURNAL_NAME))?;
+        let zeros = vec![0u8; cluster_size];
+        for _ in 0..clusters {
+            file.write_all(&zeros)?;
+        }
+
+        let location = file.location.expect("journal file location");
+        let mut vfat = self.borrow_mut();
+        vfat.update_dir_entry(location, |entry| {
+            entry.set_attributes(Attributes(
+                Attributes::READ_ONLY | Attributes::HIDDEN | Attributes::SYSTEM | Attributes::ARCHIVE,
+            ))
+        })?;
+        vfat.flush()?;
+
+        let sectors = vfat.file_sectors(&file)?;
+        let handle = file._handle.take();
+        let journal = Journal::create(&mut vfat.device, sectors, sector_size, handle)?;
+        vfat.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Enables the journal in the root directory, if there is one, replaying
+    /// or discarding a transaction left by an interrupted flush.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if replaying the journal fails. A root directory that
+    /// can't be read is treated as having no journal.
+    pub(crate) fn load_journal(&self) -> io::Result<()> {
+        let mut file = match Dir::root(self.clone()).find(JOURNAL_NAME) {
+            Ok(Entry::File(file)) => file,
+            _ => return Ok(()),
+        };
+        if !file.metadata().attr.system() {
+            return Ok(());
+        }
+
+        let mut vfat = self.borrow_mut();
+        let sectors = match vfat.file_sectors(&file) {
+            Ok(sectors) => sectors,
+            Err(_) => return Ok(()),
+        };
+        let sector_size = vfat.sector_size();
+        let targets = vfat.metadata_sectors();
+        let handle = file._handle.take();
+        let journal = Journal::open(&mut vfat.device, sectors, sector_size, targets, handle)?;
+        vfat.journal = journal;
+        Ok(())
+    }
+}
+
+impl VFat {
+    /// Returns the sectors holding the contents of `file`, in order.
+    fn file_sectors(&mut self, file: &File) -> io::Result<Vec<u64>> {
+        let count = file.size / self.sector_size();
+        let mut sectors = Vec::with_capacity(count);
+        if count > 0 {
+            for cluster in self.chain(file.cluster)? {
+                sectors.extend(self.cluster_sectors(cluster)?);
+            }
+        }
+        sectors.truncate(count);
+        Ok(sectors)
+    }
+}
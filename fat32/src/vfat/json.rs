@@ -0,0 +1,97 @@
+use std::io;
+use std::path::Path;
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, FileSystem, Metadata as MetadataTrait};
+use util::json_string;
+use vfat::{Date, Entry, Shared, Timestamp, VFat};
+
+impl Shared<VFat> {
+    /// Returns the tree rooted at `path` as pretty-printed JSON: an object
+    /// with the entry's `name`, `is_dir`, `size`, first `cluster`,
+    /// `attributes` as decoded flags, and `created`, `modified`, and
+    /// `accessed` times in ISO 8601 format, or `null` if unset. Directories
+    /// also have `children`, sorted by name, without the `.` and `..`
+    /// entries or the volume label. The root directory is named `/`.
+    ///
+    /// The output of two volumes with the same files is identical, whatever
+    /// order the files were written in, so it can be diffed directly.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`, and an error if reading a
+    /// directory fails.
+    pub fn to_json<P: AsRef<Path>>(&self, path: P) -> io::Result<String> {
+        let path = path.as_ref();
+        let entry = self.open(path)?;
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "/".to_string(),
+        };
+
+        Ok(entry_json(&name, &entry, 0)? + "\n")
+    }
+}
+
+/// Returns `entry`, named `name`, as a JSON object whose nested lines are
+/// indented by `depth` levels.
+fn entry_json(name: &str, entry: &Entry, depth: usize) -> io::Result<String> {
+    let pad = "  ".repeat(depth);
+    let info = entry.info();
+    let metadata = &info.metadata;
+    let attr = metadata.attr;
+    let flags: Vec<String> = [
+        ("read_only", attr.read_only()),
+        ("hidden", attr.hidden()),
+        ("system", attr.system()),
+        ("volume_id", attr.volume_id()),
+        ("directory", attr.directory()),
+        ("archive", attr.archive()),
+    ].iter()
+        .map(|&(flag, set)| format!("\"{}\": {}", flag, set))
+        .collect();
+
+    let mut fields = vec![
+        format!("\"name\": {}", json_string(name)),
+        format!("\"is_dir\": {}", info.is_dir),
+        format!("\"size\": {}", info.size),
+        format!("\"cluster\": {}", info.cluster),
+        format!("\"attributes\": {{{}}}", flags.join(", ")),
+        format!("\"created\": {}", json_time(metadata.created)),
+        format!("\"modified\": {}", json_time(metadata.modified)),
+        format!("\"accessed\": {}", json_time(metadata.accessed)),
+    ];
+
+    if let Entry::Dir(ref dir) = *entry {
+        let mut children: Vec<(String, Entry)> = dir
+            .entries()?
+            .filter(|child| {
+                let name = child.name();
+                name != "." && name != ".." && !child.metadata().volume_id()
+            })
+            .map(|child| (child.name().to_string(), child))
+            .collect();
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut items = Vec::with_capacity(children.len());
+        for &(ref name, ref child) in &children {
+            items.push(format!("{}    {}", pad, entry_json(name, child, depth + 2)?));
+        }
+        fields.push(if items.is_empty() {
+            "\"children\": []".to_string()
+        } else {
+            format!("\"children\": [\n{}\n{}  ]", items.join(",\n"), pad)
+        });
+    }
+
+    let fields: Vec<String> = fields.iter().map(|field| format!("{}  {}", pad, field)).collect();
+    Ok(format!("{{\n{}\n{}}}", fields.join(",\n"), pad))
+}
+
+/// Returns `time` as a JSON string, or `null` if it was never set.
+fn json_time(time: Timestamp) -> String {
+    if time.date == Date::default() {
+        "null".to_string()
+    } else {
+        format!("\"{:.2}\"", time)
+    }
+}
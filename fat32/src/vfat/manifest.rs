@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use digest::Digest;
+
+use traits::{Entry as EntryTrait, FileSystem, Metadata};
+use vfat::{Entry, Shared, VFat};
+
+/// The size and hash of one file, as listed by `manifest()`.
+///
+/// A record is displayed as one line of a manifest, the lowercase hex hash,
+/// the size in bytes, and the path, separated by spaces, as in
+/// `2cf24dba5fb0a30e... 5 /BOOT/CONFIG.TXT`. `verify_manifest()` reads these
+/// lines back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestRecord {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: Vec<u8>,
+}
+
+/// A file that doesn't match its record, found by `verify_manifest()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestProblem {
+    /// There is no entry at `path`.
+    Missing { path: PathBuf },
+    /// The entry at `path` is a directory.
+    NotAFile { path: PathBuf },
+    /// The file at `path` is `actual` bytes long rather than `expected`. Its
+    /// contents aren't hashed.
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// The file at `path` has the recorded size but different contents.
+    HashMismatch { path: PathBuf },
+}
+
+impl fmt::Display for ManifestRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, " {} {}", self.size, self.path.display())
+    }
+}
+
+impl fmt::Display for ManifestProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestProblem::Missing { ref path } => write!(f, "{}: missing", path.display()),
+            ManifestProblem::NotAFile { ref path } => {
+                write!(f, "{}: not a file", path.display())
+            }
+            ManifestProblem::SizeMismatch {
+                ref path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: size is {} bytes, expected {}",
+                path.display(),
+                actual,
+                expected
+            ),
+            ManifestProblem::HashMismatch { ref path } => {
+                write!(f, "{}: contents differ", path.display())
+            }
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Returns the size and hash of every file in the tree rooted at `path`,
+    /// or of the file at `path`, ordered by path. Each file is hashed with a
+    /// clone of `algo`, so `algo` is usually a new hasher, as in
+    /// `vfat.manifest("/", Sha256::default())`. The volume label isn't
+    /// listed.
+    ///
+    /// Writing each record on its own line produces a manifest that
+    /// `verify_manifest()` checks the volume, or a copy of it, against.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`, and an error if reading a
+    /// directory or a file fails.
+    pub fn manifest<P, D>(&self, path: P, algo: D) -> io::Result<Vec<ManifestRecord>>
+    where
+        P: AsRef<Path>,
+        D: Digest + Clone,
+    {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        match self.open(path)? {
+            Entry::File(_) => files.push(path.to_path_buf()),
+            Entry::Dir(dir) => dir.walk(path, &mut |path, entry| {
+                if entry.is_file() && !entry.metadata().volume_id() {
+                    files.push(path.to_path_buf());
+                }
+                Ok(())
+            })?,
+        }
+        files.sort();
+
+        let mut records = Vec::with_capacity(files.len());
+        for path in files {
+            let mut hasher = algo.clone();
+            let size = self.hash_file(&path, &mut hasher)?;
+            records.push(ManifestRecord {
+                path,
+                size,
+                hash: hasher.result().to_vec(),
+            });
+        }
+        Ok(records)
+    }
+
+    /// Checks the volume against the manifest read from `reader`, one record
+    /// per line as written by displaying the records of `manifest()`, and
+    /// returns the files that don't match, in manifest order. `algo` must be
+    /// the algorithm the manifest was produced with, or every hash differs.
+    /// Blank lines are ignored. Files on the volume that the manifest doesn't
+    /// list aren't reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if a line isn't a record, and any
+    /// error that occurs while reading `reader` or the volume.
+    pub fn verify_manifest<R, D>(&self, reader: R, algo: D) -> io::Result<Vec<ManifestProblem>>
+    where
+        R: BufRead,
+        D: Digest + Clone,
+    {
+        let mut problems = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = parse_record(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {} of the manifest is not a record", i + 1),
+                )
+            })?;
+            let file = match self.open(&record.path) {
+                Ok(Entry::File(file)) => file,
+                Ok(Entry::Dir(_)) => {
+                    problems.push(ManifestProblem::NotAFile { path: record.path });
+                    continue;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    problems.push(ManifestProblem::Missing { path: record.path });
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if file.size as u64 != record.size {
+                problems.push(ManifestProblem::SizeMismatch {
+                    path: record.path,
+                    expected: record.size,
+                    actual: file.size as u64,
+                });
+                continue;
+            }
+
+            let mut hasher = algo.clone();
+            self.hash_file(&record.path, &mut hasher)?;
+            if hasher.result().as_slice() != &record.hash[..] {
+                problems.push(ManifestProblem::HashMismatch { path: record.path });
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Parses a manifest line written by displaying a `ManifestRecord`.
+fn parse_record(line: &str) -> Option<ManifestRecord> {
+    let mut fields = line.splitn(3, ' ');
+    let hex = fields.next()?;
+    let size = fields.next()?.parse().ok()?;
+    let path = PathBuf::from(fields.next()?);
+    if hex.is_empty() || hex.len() % 2 != 0 || !path.has_root() {
+        return None;
+    }
+
+    let mut hash = Vec::with_capacity(hex.len() / 2);
+    for i in 0..hex.len() / 2 {
+        hash.push(u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?);
+    }
+    Some(ManifestRecord { path, size, hash })
+}
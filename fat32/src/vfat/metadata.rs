@@ -1,71 +1,379 @@
 use std::fmt;
 
 use traits;
+use util::Le16;
 
 /// A date as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Date(u16);
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date(Le16);
 
 /// Time as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Time(pub u16);
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Time(Le16);
 
 /// File attributes as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
 pub struct Attributes(pub u8);
 
-/// A structure containing a date and time.
-#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+/// A structure containing a date and time. Timestamps are ordered
+/// chronologically.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Timestamp {
     pub date: Date,
     pub time: Time,
+    /// Hundredths of a second, in range [0, 200), added to `time`, which has a
+    /// resolution of two seconds. Only creation times are stored with this
+    /// precision; other times are rounded down to an even second on disk.
+    pub fine: u8,
 }
 
 /// Metadata for a directory entry.
 #[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct Metadata {
     pub attr: Attributes,
     pub created: Timestamp,
+    /// FAT stores only the date of the last access, so the time is always
+    /// midnight.
     pub accessed: Timestamp,
     pub modified: Timestamp,
 }
 
+/// Seconds from the Unix epoch to 1980-01-01 00:00:00, the earliest FAT
+/// timestamp.
+const FAT_EPOCH: u64 = 315_532_800;
+
+impl Timestamp {
+    /// Returns the timestamp `secs` seconds after the Unix epoch, treating the
+    /// timestamp as UTC. Times outside of the range representable by FAT,
+    /// 1980-01-01 00:00:00 to 2107-12-31 23:59:58, are clamped to that range.
+    pub(crate) fn from_unix_seconds(secs: u64) -> Timestamp {
+        Timestamp::from_unix_time(secs, 0)
+    }
+
+    /// Returns the timestamp `secs` seconds and `millis` milliseconds after
+    /// the Unix epoch, treating the timestamp as UTC, rounded down to 10 ms.
+    /// Times outside of the range representable by FAT are clamped to that
+    /// range, as with `from_unix_seconds()`.
+    pub(crate) fn from_unix_time(secs: u64, millis: u32) -> Timestamp {
+        if secs < FAT_EPOCH {
+            return Timestamp::from_unix_time(FAT_EPOCH, 0);
+        }
+
+        // Civil date from days since 1970-01-01 (proleptic Gregorian).
+        let days = secs / 86_400 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        if year > 2107 {
+            return Timestamp {
+                date: Date(Le16::new(((2107 - 1980) << 9 | 12 << 5 | 31) as u16)),
+                time: Time(Le16::new((23 << 11 | 59 << 5 | 29) as u16)),
+                fine: 0,
+            };
+        }
+
+        let secs_of_day = secs % 86_400;
+        let time = secs_of_day / 3600 << 11 | secs_of_day / 60 % 60 << 5 | secs_of_day % 60 / 2;
+        Timestamp {
+            date: Date(Le16::new(((year - 1980) << 9 | month << 5 | day) as u16)),
+            time: Time(Le16::new(time as u16)),
+            fine: (secs % 2 * 100 + ::std::cmp::min(millis, 999) as u64 / 10) as u8,
+        }
+    }
+
+    /// Returns the current local time, `utc_offset` seconds east of UTC.
+    #[cfg(not(any(target_os = "ros", target_arch = "wasm32")))]
+    pub(crate) fn now(utc_offset: i32) -> Timestamp {
+        Timestamp::from_system_time_at(::std::time::SystemTime::now(), utc_offset)
+    }
+
+    /// Returns the current local time, `utc_offset` seconds east of UTC, as
+    /// reported by JavaScript's `Date`.
+    #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+    pub(crate) fn now(utc_offset: i32) -> Timestamp {
+        let millis = ::js_sys::Date::now() + utc_offset as f64 * 1000.0;
+        let millis = if millis > 0.0 { millis as u64 } else { 0 };
+        Timestamp::from_unix_time(millis / 1000, (millis % 1000) as u32)
+    }
+
+    /// Returns the current time. Without a wall clock, this is the earliest
+    /// FAT timestamp, whatever `utc_offset` is.
+    #[cfg(any(target_os = "ros", all(target_arch = "wasm32", not(feature = "wasm"))))]
+    pub(crate) fn now(_utc_offset: i32) -> Timestamp {
+        Timestamp::from_unix_seconds(FAT_EPOCH)
+    }
+
+    /// Returns the timestamp for `time`, treating the timestamp as UTC. The
+    /// time is rounded down to 10 ms, and times outside of the range
+    /// representable by FAT, 1980-01-01 00:00:00 to 2107-12-31 23:59:58, are
+    /// clamped to that range.
+    #[cfg(not(target_os = "ros"))]
+    pub fn from_system_time(time: ::std::time::SystemTime) -> Timestamp {
+        Timestamp::from_system_time_at(time, 0)
+    }
+
+    /// Returns the timestamp for `time` in the local time `utc_offset`
+    /// seconds east of UTC, as with `from_system_time()`.
+    #[cfg(not(target_os = "ros"))]
+    pub fn from_system_time_at(time: ::std::time::SystemTime, utc_offset: i32) -> Timestamp {
+        match time.duration_since(::std::time::UNIX_EPOCH) {
+            Ok(d) => match d.as_secs() as i64 + utc_offset as i64 {
+                secs if secs < 0 => Timestamp::from_unix_seconds(0),
+                secs => Timestamp::from_unix_time(secs as u64, d.subsec_nanos() / 1_000_000),
+            },
+            Err(_) => Timestamp::from_unix_seconds(0),
+        }
+    }
+
+    /// Returns `self` as a `SystemTime`, treating `self` as UTC. Zero months
+    /// and days, as found in unset timestamps, are treated as 1.
+    #[cfg(not(target_os = "ros"))]
+    pub fn to_system_time(&self) -> ::std::time::SystemTime {
+        self.to_system_time_at(0)
+    }
+
+    /// Returns `self` as a `SystemTime`, treating `self` as local time
+    /// `utc_offset` seconds east of UTC, as with `to_system_time()`.
+    #[cfg(not(target_os = "ros"))]
+    pub fn to_system_time_at(&self, utc_offset: i32) -> ::std::time::SystemTime {
+        let since_epoch = ::std::time::Duration::from_secs(self.unix_seconds_at(utc_offset))
+            + ::std::time::Duration::from_millis(self.millisecond() as u64);
+        ::std::time::UNIX_EPOCH + since_epoch
+    }
+
+    /// Returns `self` as a date and time with the fixed offset `utc_offset`
+    /// seconds east of UTC, treating `self` as local time at that offset, or
+    /// `None` if `utc_offset` isn't less than a day either way. Zero months
+    /// and days, as found in unset timestamps, are treated as 1.
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime(
+        &self,
+        utc_offset: i32,
+    ) -> Option<::chrono::DateTime<::chrono::FixedOffset>> {
+        use chrono::TimeZone;
+
+        let offset = ::chrono::FixedOffset::east_opt(utc_offset)?;
+        offset.from_local_datetime(&::chrono::NaiveDateTime::from(*self)).single()
+    }
+
+    /// Returns the timestamp for `datetime` in the local time `utc_offset`
+    /// seconds east of UTC, whatever the time zone of `datetime`, rounding
+    /// down to 10 ms and clamping to the range representable by FAT.
+    #[cfg(feature = "chrono")]
+    pub fn from_datetime<Tz: ::chrono::TimeZone>(
+        datetime: &::chrono::DateTime<Tz>,
+        utc_offset: i32,
+    ) -> Timestamp {
+        match datetime.timestamp() + utc_offset as i64 {
+            secs if secs < 0 => Timestamp::from_unix_seconds(0),
+            secs => Timestamp::from_unix_time(secs as u64, datetime.timestamp_subsec_millis()),
+        }
+    }
+
+    /// The millisecond, in range [0, 1000), in steps of 10 ms. Always 0 unless
+    /// `fine` is set.
+    pub fn millisecond(&self) -> u16 {
+        (::std::cmp::min(self.fine, 199) % 100) as u16 * 10
+    }
+
+    /// Returns the number of seconds from the Unix epoch to `self`, treating
+    /// `self` as local time `utc_offset` seconds east of UTC, or 0 if `self`
+    /// is before the epoch.
+    pub(crate) fn unix_seconds_at(&self, utc_offset: i32) -> u64 {
+        ::std::cmp::max(self.unix_seconds() as i64 - utc_offset as i64, 0) as u64
+    }
+
+    /// Returns the number of seconds from the Unix epoch to `self`, treating
+    /// `self` as UTC. Zero months and days, as found in unset timestamps, are
+    /// treated as 1.
+    pub(crate) fn unix_seconds(&self) -> u64 {
+        use traits::Timestamp;
+
+        // Days from 1970-01-01 to the given civil date (proleptic Gregorian).
+        let month = ::std::cmp::max(self.month(), 1) as u64;
+        let day = ::std::cmp::max(self.day(), 1) as u64;
+        let year = self.year() as u64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let month_index = (month + 9) % 12;
+        let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * 86_400
+            + self.hour() as u64 * 3_600
+            + self.minute() as u64 * 60
+            + self.second() as u64
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<Timestamp> for ::chrono::NaiveDateTime {
+    /// Converts `timestamp` to a date and time. Zero months and days, as found
+    /// in unset timestamps, are treated as 1.
+    fn from(timestamp: Timestamp) -> ::chrono::NaiveDateTime {
+        let nanos = timestamp.millisecond() as u32 * 1_000_000;
+        ::chrono::NaiveDateTime::from_timestamp(timestamp.unix_seconds() as i64, nanos)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<::chrono::NaiveDateTime> for Timestamp {
+    /// Converts `datetime` to a timestamp, rounding down to 10 ms. Times
+    /// outside of the range representable by FAT, 1980-01-01 00:00:00 to
+    /// 2107-12-31 23:59:58, are clamped to that range.
+    fn from(datetime: ::chrono::NaiveDateTime) -> Timestamp {
+        match datetime.timestamp() {
+            secs if secs < 0 => Timestamp::from_unix_seconds(0),
+            secs => Timestamp::from_unix_time(secs as u64, datetime.timestamp_subsec_millis()),
+        }
+    }
+}
+
+impl Attributes {
+    pub const READ_ONLY: u8 = 0x01;
+    pub const HIDDEN: u8 = 0x02;
+    pub const SYSTEM: u8 = 0x04;
+    pub const VOLUME_ID: u8 = 0x08;
+    pub const DIRECTORY: u8 = 0x10;
+    pub const ARCHIVE: u8 = 0x20;
+
+    fn has(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.has(Attributes::READ_ONLY)
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.has(Attributes::HIDDEN)
+    }
+
+    pub fn system(&self) -> bool {
+        self.has(Attributes::SYSTEM)
+    }
+
+    pub fn volume_id(&self) -> bool {
+        self.has(Attributes::VOLUME_ID)
+    }
+
+    pub fn directory(&self) -> bool {
+        self.has(Attributes::DIRECTORY)
+    }
+
+    pub fn archive(&self) -> bool {
+        self.has(Attributes::ARCHIVE)
+    }
+}
+
+impl fmt::Display for Attributes {
+    /// Writes the flags as `RHSVDA`, with `-` in place of each flag that is
+    /// not set.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.read_only(), 'R'),
+            (self.hidden(), 'H'),
+            (self.system(), 'S'),
+            (self.volume_id(), 'V'),
+            (self.directory(), 'D'),
+            (self.archive(), 'A'),
+        ];
+        for &(set, c) in flags.iter() {
+            write!(f, "{}", if set { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Attributes({:#04x} {})", { self.0 }, self)
+    }
+}
+
+/// Serializes as the decoded flags, `{"read_only": false, ...}`.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Attributes {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("Attributes", 6)?;
+        s.serialize_field("read_only", &self.read_only())?;
+        s.serialize_field("hidden", &self.hidden())?;
+        s.serialize_field("system", &self.system())?;
+        s.serialize_field("volume_id", &self.volume_id())?;
+        s.serialize_field("directory", &self.directory())?;
+        s.serialize_field("archive", &self.archive())?;
+        s.end()
+    }
+}
+
+/// Serializes as the decoded date and time, `{"year": 2018, ...}`, rather
+/// than the packed on-disk fields.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Timestamp {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        use traits::Timestamp;
+
+        let mut s = serializer.serialize_struct("Timestamp", 7)?;
+        s.serialize_field("year", &self.year())?;
+        s.serialize_field("month", &self.month())?;
+        s.serialize_field("day", &self.day())?;
+        s.serialize_field("hour", &self.hour())?;
+        s.serialize_field("minute", &self.minute())?;
+        s.serialize_field("second", &self.second())?;
+        s.serialize_field("millisecond", &self.millisecond())?;
+        s.end()
+    }
+}
+
 impl traits::Timestamp for Timestamp {
     /// The calendar year.
     ///
     /// The year is not offset. 2009 is 2009.
     fn year(&self) -> usize {
-        ((self.date.0 & 0b1111_1110_0000_0000) >> 9) as usize + 1980
+        ((self.date.0.get() & 0b1111_1110_0000_0000) >> 9) as usize + 1980
     }
 
     /// The calendar month, starting at 1 for January. Always in range [1, 12].
     ///
     /// January is 1, Feburary is 2, ..., December is 12.
     fn month(&self) -> u8 {
-        ((self.date.0 & 0b0000_0001_1110_0000) >> 5) as u8
+        ((self.date.0.get() & 0b0000_0001_1110_0000) >> 5) as u8
     }
 
     /// The calendar day, starting at 1. Always in range [1, 31].
     fn day(&self) -> u8 {
-        (self.date.0 & 0b0000_0000_0001_1111) as u8
+        (self.date.0.get() & 0b0000_0000_0001_1111) as u8
     }
 
     /// The 24-hour hour. Always in range [0, 24).
     fn hour(&self) -> u8 {
-        ((self.time.0 & 0b1111_1000_0000_0000) >> 11) as u8
+        ((self.time.0.get() & 0b1111_1000_0000_0000) >> 11) as u8
     }
 
     /// The minute. Always in range [0, 60).
     fn minute(&self) -> u8 {
-        ((self.time.0 & 0b0000_0111_1110_0000) >> 5) as u8
+        ((self.time.0.get() & 0b0000_0111_1110_0000) >> 5) as u8
     }
 
     /// The second. Always in range [0, 60).
     fn second(&self) -> u8 {
-        (self.time.0 & 0b0000_0000_0001_1111) as u8 * 2
+        let seconds = (self.time.0.get() & 0b0000_0000_0001_1111) as u8 * 2;
+        seconds + ::std::cmp::min(self.fine, 199) / 100
     }
 }
 
@@ -75,17 +383,27 @@ impl traits::Metadata for Metadata {
 
     /// Whether the associated entry is read only.
     fn read_only(&self) -> bool {
-        self.attr.0 & 0x01 == 0x01
+        self.attr.read_only()
     }
 
     /// Whether the entry should be "hidden" from directory traversals.
     fn hidden(&self) -> bool {
-        self.attr.0 & 0x02 == 0x02
+        self.attr.hidden()
+    }
+
+    /// Whether the entry is a system file.
+    fn system(&self) -> bool {
+        self.attr.system()
     }
 
     /// Whether the entry is volume id
     fn volume_id(&self) -> bool {
-        self.attr.0 & 0x08 == 0x08
+        self.attr.volume_id()
+    }
+
+    /// Whether the entry has been modified since it was last backed up.
+    fn archive(&self) -> bool {
+        self.attr.archive()
     }
 
     /// The timestamp when the entry was created.
@@ -104,23 +422,36 @@ impl traits::Metadata for Metadata {
     }
 }
 
-impl fmt::Display for Metadata {
+impl fmt::Display for Timestamp {
+    /// Writes the timestamp in ISO 8601 format, `2019-03-05T07:04:02`. A
+    /// precision, as in `{:.2}`, adds up to three digits of fractional
+    /// seconds: `2019-03-05T07:04:02.25`.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use traits::{Metadata, Timestamp};
+        use traits::Timestamp;
 
-        let r = if self.read_only() { 'R' } else { '-' };
-        let h = if self.hidden() { 'H' } else { '-' };
         write!(
             f,
-            "{}{} {}.{}.{} {}:{}:{}",
-            r,
-            h,
-            self.created().day(),
-            self.created().month(),
-            self.created().year(),
-            self.created().hour(),
-            self.created().minute(),
-            self.created().second()
-        )
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year(),
+            self.month(),
+            self.day(),
+            self.hour(),
+            self.minute(),
+            self.second()
+        )?;
+        match f.precision() {
+            Some(0) | None => Ok(()),
+            Some(digits) => {
+                let digits = ::std::cmp::min(digits, 3);
+                let millis = format!("{:03}", self.millisecond());
+                write!(f, ".{}", &millis[..digits])
+            }
+        }
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.attr, self.created)
     }
 }
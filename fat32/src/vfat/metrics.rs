@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use vfat::Cluster;
+
+/// Hooks called as a volume does I/O, for collecting statistics.
+///
+/// Every method does nothing by default, so an implementation only needs to
+/// override the events it counts. Hooks are called with the volume locked
+/// and should return quickly, and may be called from any thread that uses
+/// the volume. A volume reports to the `Metrics` set with
+/// `VFat::set_metrics()`.
+pub trait Metrics: Send + Sync {
+    /// Called after logical sector `sector`, `bytes` bytes long, is read from
+    /// the device.
+    fn sector_read(&self, _sector: u64, _bytes: usize) {}
+
+    /// Called after logical sector `sector`, `bytes` bytes long, is written
+    /// to the device.
+    fn sector_written(&self, _sector: u64, _bytes: usize) {}
+
+    /// Called when sector `sector` is accessed and is already cached.
+    fn cache_hit(&self, _sector: u64) {}
+
+    /// Called when sector `sector` is accessed and must first be read from
+    /// the device.
+    fn cache_miss(&self, _sector: u64) {}
+
+    /// Called when the FAT entry of `cluster` is looked up.
+    fn fat_lookup(&self, _cluster: Cluster) {}
+
+    /// Called after `bytes` bytes are read from a file.
+    fn bytes_read(&self, _bytes: usize) {}
+
+    /// Called after `bytes` bytes are written to a file.
+    fn bytes_written(&self, _bytes: usize) {}
+}
+
+/// A `Metrics` implementation that counts every event.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub sectors_read: AtomicUsize,
+    pub sectors_written: AtomicUsize,
+    pub cache_hits: AtomicUsize,
+    pub cache_misses: AtomicUsize,
+    pub fat_lookups: AtomicUsize,
+    /// Bytes read from the device.
+    pub device_bytes_read: AtomicUsize,
+    /// Bytes written to the device.
+    pub device_bytes_written: AtomicUsize,
+    /// Bytes read from files.
+    pub file_bytes_read: AtomicUsize,
+    /// Bytes written to files.
+    pub file_bytes_written: AtomicUsize,
+}
+
+impl Metrics for Counters {
+    fn sector_read(&self, _sector: u64, bytes: usize) {
+        self.sectors_read.fetch_add(1, Ordering::Relaxed);
+        self.device_bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sector_written(&self, _sector: u64, bytes: usize) {
+        self.sectors_written.fetch_add(1, Ordering::Relaxed);
+        self.device_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn cache_hit(&self, _sector: u64) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_miss(&self, _sector: u64) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn fat_lookup(&self, _cluster: Cluster) {
+        self.fat_lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_read(&self, bytes: usize) {
+        self.file_bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, bytes: usize) {
+        self.file_bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::min;
+use std::io;
+
+use util::{Le32, SliceExt};
+use vfat::{Cluster, VFat};
+
+/// An entry that differs between the copies of the FAT, found by
+/// `compare_fats()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatMismatch {
+    /// The cluster whose entry differs.
+    pub cluster: Cluster,
+    /// The value of the entry in each copy of the FAT, in copy order, without
+    /// the reserved high four bits.
+    pub values: Vec<u32>,
+}
+
+impl VFat {
+    /// Compares the copies of the FAT entry by entry and returns the entries
+    /// that differ, in cluster order. Changes that haven't been flushed yet
+    /// are compared as they will be written. The reserved high four bits of
+    /// each entry are ignored, as are the entries past the last cluster of
+    /// the volume. A volume with a single FAT has no mismatches.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a FAT sector fails.
+    pub fn compare_fats(&mut self) -> io::Result<Vec<FatMismatch>> {
+        let entries_per_sector = self.sector_size() / 4;
+        let end = self.data_clusters() as usize + 2;
+        let mut mismatches = Vec::new();
+        if self.fats_number() < 2 {
+            return Ok(mismatches);
+        }
+
+        for i in 0..(end + entries_per_sector - 1) / entries_per_sector {
+            let mut copies = Vec::with_capacity(self.fats_number() as usize);
+            for fat in 0..self.fats_number() {
+                let sector = self.fat_sectors(fat).start + i as u64;
+                let entries: &[Le32] = unsafe { self.device.get(sector)?.cast()? };
+                let values = entries.iter().map(|entry| entry.get() & 0x0FFF_FFFF);
+                copies.push(values.collect::<Vec<u32>>());
+            }
+
+            let first = i * entries_per_sector;
+            for j in 0..min(entries_per_sector, end - first) {
+                if copies.iter().any(|copy| copy[j] != copies[0][j]) {
+                    mismatches.push(FatMismatch {
+                        cluster: Cluster::from((first + j) as u32),
+                        values: copies.iter().map(|copy| copy[j]).collect(),
+                    });
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Copies FAT number `source`, numbered from 0, over every other copy of
+    /// the FAT, and returns the number of sectors that changed. The sectors
+    /// are written back by the next flush, like any other change to the FAT.
+    ///
+    /// Usually `compare_fats()` is run first, and the copy whose entries
+    /// match the directory tree is chosen, as checked with `check()` after
+    /// the repair. Only the first copy is ever read, so repairing from
+    /// another copy changes the chains of the volume.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if the volume has no FAT number
+    /// `source`, an error of `PermissionDenied` if the volume is read-only,
+    /// and an error if reading a FAT sector fails.
+    pub fn repair_fats(&mut self, source: u8) -> io::Result<usize> {
+        if source >= self.fats_number() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the volume has no such FAT",
+            ));
+        }
+        self.begin_change()?;
+
+        let mut changed = 0;
+        let sectors = self.fat_sectors(source);
+        for (i, sector) in sectors.clone().enumerate() {
+            let data = self.device.get(sector)?.to_vec();
+            for fat in (0..self.fats_number()).filter(|&fat| fat != source) {
+                let target = self.fat_sectors(fat).start + i as u64;
+                if self.device.get(target)? != &data[..] {
+                    self.device.get_mut_metadata(target)?.copy_from_slice(&data);
+                    changed += 1;
+                }
+            }
+        }
+
+        if source != 0 && changed > 0 {
+            self.invalidate_fat();
+        }
+        Ok(changed)
+    }
+}
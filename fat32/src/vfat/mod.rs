@@ -1,24 +1,101 @@
+pub(crate) mod alloc;
+pub(crate) mod bitmap;
 pub(crate) mod cache;
+pub(crate) mod cancel;
+pub(crate) mod carve;
+pub(crate) mod check;
+pub(crate) mod clock;
+pub(crate) mod codepage;
 pub(crate) mod cluster;
+pub(crate) mod copy;
+pub(crate) mod defrag;
+pub(crate) mod diff;
 pub(crate) mod dir;
 pub(crate) mod ebpb;
 pub(crate) mod entry;
 pub(crate) mod error;
 pub(crate) mod fat;
 pub(crate) mod file;
+pub(crate) mod format;
+pub(crate) mod handles;
+#[cfg(feature = "digest")]
+pub(crate) mod hash;
+pub(crate) mod journal;
+pub(crate) mod json;
+#[cfg(feature = "digest")]
+pub(crate) mod manifest;
+#[cfg(feature = "fuse")]
+pub(crate) mod fuse;
+#[cfg(not(any(target_os = "ros", target_arch = "wasm32")))]
+pub(crate) mod host;
 pub(crate) mod metadata;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+pub(crate) mod mirror;
+pub(crate) mod mount;
+pub(crate) mod name;
+pub(crate) mod owner;
+#[cfg(feature = "rayon")]
+pub(crate) mod parallel;
+pub mod raw;
+pub(crate) mod rename;
+pub(crate) mod root;
+pub(crate) mod scan;
 pub(crate) mod shared;
+pub(crate) mod slack;
+pub(crate) mod tar;
+pub(crate) mod timeline;
+pub(crate) mod transaction;
+pub(crate) mod usage;
+pub(crate) mod usage_map;
 pub(crate) mod vfat;
+pub(crate) mod watch;
 
-pub use self::dir::Dir;
+pub use self::alloc::AllocPolicy;
+pub use self::bitmap::{FreeClusters, FreeRuns};
+pub use self::cancel::CancelToken;
+pub use self::cache::CacheBudget;
+pub use self::carve::{Carved, Confidence};
+pub use self::check::{CheckReport, Problem};
+pub use self::clock::{Clock, SystemClock};
+pub use self::cluster::{Cluster, Extent};
+pub use self::codepage::Codepage;
+pub use self::defrag::{DefragReport, FragmentationReport};
+pub use self::diff::Change;
+pub use self::dir::{CompactReport, Dir, SortKey};
 pub use self::ebpb::BiosParameterBlock;
-pub use self::entry::Entry;
+pub use self::entry::{Entry, EntryId, EntryInfo};
 pub use self::error::Error;
-pub use self::file::File;
+pub use self::file::{Bytes, Chunks, File};
+pub use self::format::{format, FormatOptions};
+#[cfg(not(any(target_os = "ros", target_arch = "wasm32")))]
+pub use self::host::SyncReport;
+#[cfg(feature = "fuse")]
+pub use self::fuse::FuseAdapter;
 pub use self::metadata::{Attributes, Date, Metadata, Time, Timestamp};
+#[cfg(feature = "metrics")]
+pub use self::metrics::{Counters, Metrics};
+#[cfg(feature = "digest")]
+pub use self::manifest::{ManifestProblem, ManifestRecord};
+pub use self::mirror::FatMismatch;
+pub use self::mount::{MountCheck, MountOptions, MountProblem};
+pub use self::name::{validate_name, NameError};
+pub use self::owner::OwnerIndex;
+pub use self::scan::{BadCluster, ScanReport};
 pub use self::shared::Shared;
+pub use self::slack::{Slack, SlackIter};
+pub use self::timeline::{Event, EventKind, TimelineFormat};
+pub use self::transaction::Transaction;
+pub use self::usage::{DiskUsage, StatVfs};
+pub use self::usage_map::{ClusterUsage, UsageMap, UsageRun};
 pub use self::vfat::VFat;
+pub use self::watch::WatchEvent;
 
+pub(crate) use self::bitmap::FreeBitmap;
 pub(crate) use self::cache::{CachedDevice, Partition};
-pub(crate) use self::cluster::Cluster;
+pub(crate) use self::dir::EntryLocation;
 pub(crate) use self::fat::{FatEntry, Status};
+pub(crate) use self::root::RootDirSource;
+pub(crate) use self::handles::{Handle, HandleTable};
+pub(crate) use self::journal::Journal;
+pub(crate) use self::watch::Watcher;
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::sync::Arc;
+
+use util::Le32;
+use vfat::{AllocPolicy, BiosParameterBlock, CacheBudget, CachedDevice, Clock, Codepage};
+
+/// Options controlling how `VFat::from_with_options()` mounts a volume.
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    /// If `true`, nothing is ever written to the device: every operation that
+    /// would modify the volume fails with an error of `PermissionDenied`, and
+    /// a journal left by an interrupted flush is neither replayed nor used.
+    pub read_only: bool,
+    /// The policy used to choose free clusters.
+    pub alloc_policy: AllocPolicy,
+    /// If `true`, new entries can't be given names that Windows refuses, like
+    /// DOS device names, besides the names `validate_name()` always rejects.
+    pub windows_names: bool,
+    /// The codepage short names are stored in.
+    pub codepage: Codepage,
+    /// The consistency check run before the volume is mounted.
+    pub check: MountCheck,
+    /// If `true`, sectors are checksummed when they are read into the cache
+    /// and verified each time they are reused or written back, catching
+    /// memory corruption before corrupted data is returned or written to
+    /// the disk. A sector that fails verification fails the operation with
+    /// an error of `InvalidData`. Costs a hash of the sector on each access.
+    pub verify_cache: bool,
+    /// The clock new and modified entries are stamped with, or `None` for
+    /// `SystemClock` at `utc_offset`.
+    pub clock: Option<Arc<Clock>>,
+    /// Seconds east of UTC of the local time the volume's timestamps are
+    /// stored in, used to convert them to and from the host's times when
+    /// copying files to and from the host, exporting archives, and serving
+    /// them over FUSE. 0 by default, for UTC.
+    pub utc_offset: i32,
+    /// If `true`, reading a file sets its last access date to the current
+    /// date of the volume's clock. The dates are written by the next flush,
+    /// and at most once a day per file, since FAT stores no access time of
+    /// day. `false` by default, like a `noatime` mount, so that reads never
+    /// wear flash media.
+    pub update_accessed: bool,
+    /// If `true`, the disk usage of each directory tree computed by `du()` is
+    /// cached and dropped only when an entry in the tree changes, so that
+    /// `du()` on a volume that changes little doesn't walk the tree again.
+    pub cache_dir_usage: bool,
+    /// The most sectors kept in the sector cache, or 0, the default, for no
+    /// limit. See `VFat::set_cache_capacity()`.
+    pub cache_capacity: usize,
+    /// A budget the sector cache counts against besides `cache_capacity`,
+    /// shared with the other volumes mounted with a clone of it, or `None`,
+    /// the default. See `VFat::set_cache_budget()`.
+    pub cache_budget: Option<CacheBudget>,
+    /// The number of sectors read ahead of a sector that isn't cached, 0 by
+    /// default. See `VFat::set_read_ahead()`.
+    pub read_ahead: usize,
+    /// The most contiguous clusters of a file read by a single device read,
+    /// or 0, the default, for no limit. See `VFat::set_max_run_clusters()`.
+    pub max_run_clusters: usize,
+}
+
+/// The consistency check `VFat::from_with_options()` runs at mount time.
+///
+/// The quick check reads only the first FAT sector, the FAT entry of the root
+/// directory, and the FSInfo sector, so it costs a few sector reads whatever
+/// the size of the volume. It catches a device that doesn't hold a FAT32
+/// volume at all, or whose metadata was overwritten, before reads return
+/// garbage; `check()` is needed to find damaged directories and chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountCheck {
+    /// No check is run.
+    Off,
+    /// Mounting fails with `Error::Corrupt` if any problem is found.
+    Quick,
+    /// Problems found are logged as warnings and returned by
+    /// `mount_problems()`, and the volume is mounted anyway.
+    QuickWarn,
+}
+
+/// A problem found by a `MountCheck::Quick` check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountProblem {
+    /// The FAT entry of cluster 0, holding the value of the entry, doesn't
+    /// hold the media descriptor of the boot sector.
+    MediaEntry(u32),
+    /// The FAT entry of cluster 1, holding the value of the entry, isn't an
+    /// end-of-chain mark.
+    ReservedEntry(u32),
+    /// The first cluster of the root directory, `cluster`, isn't a data
+    /// cluster of the volume, or its FAT entry, `entry`, doesn't mark it as
+    /// allocated.
+    RootDir { cluster: u32, entry: u32 },
+    /// The FSInfo sector, holding the sector number, lies outside the
+    /// reserved sectors.
+    FsInfoSector(u16),
+    /// The FSInfo sector doesn't hold the FSInfo signatures.
+    FsInfoSignature,
+    /// The free cluster count of the FSInfo sector, holding the count, is
+    /// larger than the number of data clusters.
+    FsInfoFreeCount(u32),
+    /// The next-free hint of the FSInfo sector, holding the hint, isn't a
+    /// data cluster of the volume.
+    FsInfoNextFree(u32),
+}
+
+impl Default for MountCheck {
+    fn default() -> MountCheck {
+        MountCheck::Off
+    }
+}
+
+impl fmt::Display for MountProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MountProblem::MediaEntry(entry) => {
+                write!(f, "FAT entry 0 is {:#010x}, not a media descriptor", entry)
+            }
+            MountProblem::ReservedEntry(entry) => {
+                write!(f, "FAT entry 1 is {:#010x}, not an end-of-chain mark", entry)
+            }
+            MountProblem::RootDir { cluster, entry } => write!(
+                f,
+                "root directory cluster {} has FAT entry {:#010x}",
+                cluster, entry
+            ),
+            MountProblem::FsInfoSector(sector) => {
+                write!(f, "FSInfo sector {} is outside the reserved sectors", sector)
+            }
+            MountProblem::FsInfoSignature => write!(f, "FSInfo signatures are missing"),
+            MountProblem::FsInfoFreeCount(count) => {
+                write!(f, "FSInfo free cluster count {} is too large", count)
+            }
+            MountProblem::FsInfoNextFree(hint) => {
+                write!(f, "FSInfo next-free hint {} is out of range", hint)
+            }
+        }
+    }
+}
+
+/// Runs the checks of `MountCheck::Quick` on the volume described by `ebpb`,
+/// whose boot sector is sector `sector` of `device` and which has
+/// `data_clusters` data clusters, and returns the problems found. A sector
+/// that can't be read counts as holding zeros.
+pub(crate) fn quick_check(
+    device: &mut CachedDevice,
+    ebpb: &BiosParameterBlock,
+    sector: u64,
+    data_clusters: u32,
+) -> Vec<MountProblem> {
+    let mut problems = Vec::new();
+    let bytes_per_sector = ebpb.bytes_per_sector() as u64;
+    let fat_start = sector + ebpb.sectors_reserved() as u64;
+    let read_u32 = |device: &mut CachedDevice, offset: u64| {
+        let sector = offset / bytes_per_sector;
+        device
+            .get(sector)
+            .map(|data| Le32::read(data, (offset % bytes_per_sector) as usize))
+            .unwrap_or(0)
+    };
+
+    let media = read_u32(device, fat_start * bytes_per_sector) & 0x0FFF_FFFF;
+    if media != 0x0FFF_FF00 | ebpb.media_descriptor() as u32 {
+        problems.push(MountProblem::MediaEntry(media));
+    }
+
+    // The two high bits of the entry of cluster 1 are volume flags.
+    let reserved = read_u32(device, fat_start * bytes_per_sector + 4) & 0x0FFF_FFFF;
+    if reserved & 0x03FF_FFF8 != 0x03FF_FFF8 {
+        problems.push(MountProblem::ReservedEntry(reserved));
+    }
+
+    let root = ebpb.root_dir_cluster();
+    let entry = if root >= 2 && root < data_clusters + 2 {
+        read_u32(device, fat_start * bytes_per_sector + root as u64 * 4) & 0x0FFF_FFFF
+    } else {
+        0
+    };
+    let allocated = (entry >= 2 && entry < 0x0FFF_FFF0) || entry >= 0x0FFF_FFF8;
+    if !allocated {
+        problems.push(MountProblem::RootDir {
+            cluster: root,
+            entry,
+        });
+    }
+
+    match ebpb.fsinfo_sector() {
+        0 | 0xFFFF => {}
+        fsinfo if fsinfo >= ebpb.sectors_reserved() => {
+            problems.push(MountProblem::FsInfoSector(fsinfo));
+        }
+        fsinfo => {
+            let base = (sector + fsinfo as u64) * bytes_per_sector;
+            let signed = read_u32(device, base) == 0x4161_5252
+                && read_u32(device, base + 484) == 0x6141_7272;
+            if !signed {
+                problems.push(MountProblem::FsInfoSignature);
+            } else {
+                let free = read_u32(device, base + 488);
+                if free != 0xFFFF_FFFF && free > data_clusters {
+                    problems.push(MountProblem::FsInfoFreeCount(free));
+                }
+                let next_free = read_u32(device, base + 492);
+                if next_free != 0xFFFF_FFFF && (next_free < 2 || next_free >= data_clusters + 2) {
+                    problems.push(MountProblem::FsInfoNextFree(next_free));
+                }
+            }
+        }
+    }
+    problems
+}
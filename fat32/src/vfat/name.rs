@@ -0,0 +1,130 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The longest name an LFN can store, in UTF-16 code units.
+pub(crate) const MAX_NAME_LEN: usize = 255;
+
+/// Characters that can't appear in a name, besides control characters.
+const ILLEGAL_CHARS: &str = "\"*/:<>?\\|";
+
+/// Names of DOS devices, which Windows refuses as file names with or without
+/// an extension.
+const DEVICE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The reason a name can't be given to a new entry, returned by
+/// `validate_name()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    /// The name is empty.
+    Empty,
+    /// The name is `.` or `..`.
+    DotName,
+    /// The name is longer than 255 UTF-16 code units.
+    TooLong(usize),
+    /// The name contains a control character or one of `"*/:<>?\|`.
+    IllegalChar(char),
+    /// The name ends with a dot or a space, which Windows strips.
+    TrailingDotOrSpace,
+    /// The name, ignoring its extension, is a DOS device name like `CON` or
+    /// `LPT1`. Only reported in Windows-compatible mode.
+    DeviceName,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameError::Empty => write!(f, "name is empty"),
+            NameError::DotName => write!(f, "name is reserved for dot entries"),
+            NameError::TooLong(len) => {
+                write!(f, "name is {} UTF-16 characters long, more than {}", len, MAX_NAME_LEN)
+            }
+            NameError::IllegalChar(c) => write!(f, "name contains illegal character {:?}", c),
+            NameError::TrailingDotOrSpace => write!(f, "name ends with a dot or a space"),
+            NameError::DeviceName => write!(f, "name is reserved for a device"),
+        }
+    }
+}
+
+impl error::Error for NameError {
+    fn description(&self) -> &str {
+        "invalid name"
+    }
+}
+
+impl From<NameError> for io::Error {
+    fn from(error: NameError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// Checks that `name` can be given to a new entry, without touching any
+/// volume. If `windows_compat` is `true`, names that Windows refuses, like
+/// DOS device names, are rejected too.
+///
+/// # Errors
+///
+/// Returns the first problem found with `name`. Converted to an `io::Error`,
+/// as `create_file()`, `create_dir()`, and `rename()` return it, a
+/// `NameError` is an error of `InvalidInput` that can be recovered with
+/// `get_ref()` and `downcast_ref()`.
+pub fn validate_name(name: &str, windows_compat: bool) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name == "." || name == ".." {
+        return Err(NameError::DotName);
+    }
+    if let Some(c) = name.chars().find(|&c| c.is_control() || ILLEGAL_CHARS.contains(c)) {
+        return Err(NameError::IllegalChar(c));
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(NameError::TrailingDotOrSpace);
+    }
+    let len = name.encode_utf16().count();
+    if len > MAX_NAME_LEN {
+        return Err(NameError::TooLong(len));
+    }
+
+    if windows_compat {
+        let base = match name.find('.') {
+            Some(i) => &name[..i],
+            None => name,
+        };
+        if DEVICE_NAMES.iter().any(|device| device.eq_ignore_ascii_case(base.trim_right())) {
+            return Err(NameError::DeviceName);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        for &name in &["A.TXT", "Info File.txt", "..hidden", "CONSOLE", "ä € 😀"] {
+            assert_eq!(validate_name(name, true), Ok(()), "{}", name);
+        }
+
+        assert_eq!(validate_name("", false), Err(NameError::Empty));
+        assert_eq!(validate_name("..", false), Err(NameError::DotName));
+        assert_eq!(validate_name("a:b", false), Err(NameError::IllegalChar(':')));
+        assert_eq!(validate_name("a/b", false), Err(NameError::IllegalChar('/')));
+        assert_eq!(validate_name("a\tb", false), Err(NameError::IllegalChar('\t')));
+        assert_eq!(validate_name("name.", false), Err(NameError::TrailingDotOrSpace));
+        assert_eq!(validate_name("name ", false), Err(NameError::TrailingDotOrSpace));
+        assert_eq!(validate_name(&"a".repeat(255), false), Ok(()));
+        assert_eq!(validate_name(&"a".repeat(256), false), Err(NameError::TooLong(256)));
+        assert_eq!(validate_name(&"😀".repeat(128), false), Err(NameError::TooLong(256)));
+
+        for &name in &["CON", "nul.txt", "Lpt1", "com9.tar.gz", "AUX .c"] {
+            assert_eq!(validate_name(name, false), Ok(()), "{}", name);
+            assert_eq!(validate_name(name, true), Err(NameError::DeviceName), "{}", name);
+        }
+    }
+}
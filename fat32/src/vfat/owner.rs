@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, Metadata};
+use vfat::{Cluster, Dir, Entry, Shared, VFat};
+
+/// The files and directories using each cluster of a volume, returned by
+/// `owner_index()`.
+#[derive(Debug, Clone, Default)]
+pub struct OwnerIndex {
+    /// The path of every entry with clusters, indexed by `owners`.
+    paths: Vec<PathBuf>,
+    /// The indices in `paths` of the owners of each cluster, by FAT index.
+    owners: HashMap<u32, Vec<usize>>,
+}
+
+impl OwnerIndex {
+    /// Returns the paths of the files and directories whose chains contain
+    /// `cluster`, in directory order. Cross-linked clusters have several
+    /// owners; free clusters and clusters no entry refers to have none.
+    pub fn owners(&self, cluster: Cluster) -> Vec<&Path> {
+        match self.owners.get(&cluster.fat_index()) {
+            Some(owners) => owners.iter().map(|&i| self.paths[i].as_path()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the clusters used by more than one entry, in ascending order.
+    pub fn cross_linked(&self) -> Vec<Cluster> {
+        let mut clusters: Vec<u32> = self
+            .owners
+            .iter()
+            .filter(|&(_, owners)| owners.len() > 1)
+            .map(|(&index, _)| index)
+            .collect();
+        clusters.sort();
+        clusters.into_iter().map(Cluster::from).collect()
+    }
+
+    /// Records `cluster` as used by the entry at `paths[owner]`.
+    fn add(&mut self, cluster: Cluster, owner: usize) {
+        let owners = self.owners.entry(cluster.fat_index()).or_insert_with(Vec::new);
+        if owners.last() != Some(&owner) {
+            owners.push(owner);
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Returns the paths of the files and directories whose chains contain
+    /// `cluster`, as `owner_index()` does. To look up many clusters, build
+    /// the index once with `owner_index()` instead.
+    ///
+    /// A cluster holding an unreadable sector is found with
+    /// `VFat::sector_cluster()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `owner_index()`.
+    pub fn owner_of(&self, cluster: Cluster) -> io::Result<Vec<PathBuf>> {
+        let index = self.owner_index()?;
+        Ok(index.owners(cluster).into_iter().map(Path::to_path_buf).collect())
+    }
+
+    /// Follows the chain of every file and directory in the volume once and
+    /// returns which of them use each cluster. Unlike `check()`, chains that
+    /// cross-link with others are followed too, so that every owner of a
+    /// cross-linked cluster is found. Chains are followed up to the first
+    /// invalid link, and directories are descended into only once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a directory or the FAT fails.
+    pub fn owner_index(&self) -> io::Result<OwnerIndex> {
+        let mut index = OwnerIndex::default();
+        let root = Dir::root(self.clone());
+        self.index_chain(&mut index, Path::new("/"), root.cluster())?;
+        self.index_dir(&mut index, &root, Path::new("/"))?;
+        Ok(index)
+    }
+
+    fn index_dir(&self, index: &mut OwnerIndex, dir: &Dir, path: &Path) -> io::Result<()> {
+        for entry in dir.entries()? {
+            if entry.name() == "." || entry.name() == ".." || entry.metadata().volume_id() {
+                continue;
+            }
+
+            let path = path.join(entry.name());
+            match entry {
+                Entry::File(ref file) => {
+                    self.index_chain(index, &path, file.cluster)?;
+                }
+                Entry::Dir(ref child) => {
+                    if self.index_chain(index, &path, child.cluster())? {
+                        self.index_dir(index, child, &path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the clusters of the chain starting at `start` as used by the
+    /// entry at `path`. Returns `false` if the chain has no clusters or its
+    /// first cluster was already recorded for a directory, which would
+    /// otherwise be listed again.
+    fn index_chain(&self, index: &mut OwnerIndex, path: &Path, start: Cluster) -> io::Result<bool> {
+        let mut vfat = self.borrow_mut();
+        let end = vfat.data_clusters() + 2;
+        if start.fat_index() < 2 || start.fat_index() >= end {
+            return Ok(false);
+        }
+        let seen = index.owners.contains_key(&start.fat_index());
+
+        let owner = index.paths.len();
+        index.paths.push(path.to_path_buf());
+        let mut cluster = start;
+        // A chain can't be longer than the volume, so a loop ends there.
+        for _ in 0..end - 2 {
+            index.add(cluster, owner);
+            cluster = match vfat.next_cluster(cluster) {
+                Ok(Some(next)) if next.fat_index() >= 2 && next.fat_index() < end => next,
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => break,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(!seen)
+    }
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use traits::{Dir as DirTrait, FileSystem};
+use vfat::{Dir, Entry, Shared, VFat};
+
+impl Shared<VFat> {
+    /// Calls `f` with the path and entry of every entry in the tree rooted at
+    /// the directory `path`, like `walk()`, but visits the entries of each
+    /// directory, and the subtrees below them, in parallel on the threads of
+    /// rayon's global pool. Each directory is visited before its children;
+    /// the order is otherwise unspecified. The `.` and `..` entries are
+    /// skipped.
+    ///
+    /// Reads of the volume are still serialized, so the walk is faster than
+    /// `walk()` when `f` does work of its own, like hashing the files it is
+    /// called with, rather than when it only reads the tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open_dir()` for `path`, and otherwise one of
+    /// the errors returned by `f` or encountered while reading a directory.
+    /// Entries may still be visited after the first error, until the walk
+    /// notices it.
+    pub fn par_walk<P, F>(&self, path: P, f: F) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path, &Entry) -> io::Result<()> + Sync,
+    {
+        let path = path.as_ref();
+        par_walk_dir(&self.open_dir(path)?, path, &f)
+    }
+}
+
+/// Implements `par_walk()` for the tree rooted at `dir`, whose path is
+/// `path`.
+fn par_walk_dir<F>(dir: &Dir, path: &Path, f: &F) -> io::Result<()>
+where
+    F: Fn(&Path, &Entry) -> io::Result<()> + Sync,
+{
+    let entries: Vec<Entry> = dir
+        .entries()?
+        .filter(|entry| match *entry {
+            Entry::Dir(ref dir) => dir.name() != "." && dir.name() != "..",
+            Entry::File(_) => true,
+        })
+        .collect();
+
+    let visit = |entry: &Entry| {
+        let entry_path = match *entry {
+            Entry::Dir(ref dir) => path.join(dir.name()),
+            Entry::File(ref file) => path.join(file.name()),
+        };
+        f(&entry_path, entry)?;
+        match *entry {
+            Entry::Dir(ref dir) => par_walk_dir(dir, &entry_path, f),
+            Entry::File(_) => Ok(()),
+        }
+    };
+    match entries.par_iter().map(visit).find_any(|result| result.is_err()) {
+        Some(result) => result,
+        None => Ok(()),
+    }
+}
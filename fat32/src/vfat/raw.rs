@@ -0,0 +1,180 @@
+//! Parsing of raw directory entries, without a mounted volume.
+//!
+//! The functions here read the 32-byte entries of a buffer holding directory
+//! clusters, like one carved from a damaged disk image, and return each entry
+//! as it is stored: a short-name entry, a part of a long file name, a deleted
+//! entry, or an end-of-directory marker. Nothing is combined or skipped, so
+//! that recovery and checking tools see every entry.
+
+use std::fmt;
+use std::io;
+
+use util::SliceExt;
+use vfat::dir::{short_name_checksum, ucs_2_to_string, VFatDirEntry};
+use vfat::dir::{VFatLfnDirEntry, VFatRegularDirEntry};
+use vfat::{Cluster, Codepage, Metadata};
+
+/// The size of a directory entry in bytes.
+pub const ENTRY_SIZE: usize = 32;
+
+/// A directory entry parsed by `parse_entry()` or `entries()`.
+#[derive(Debug, Clone)]
+pub enum RawEntry {
+    /// A short-name entry of a file, a directory, or the volume label.
+    Regular(RegularEntry),
+    /// An entry holding 13 characters of the long file name of the short-name
+    /// entry that follows it.
+    Lfn(LfnEntry),
+    /// An entry whose first byte is 0xE5, marking it as deleted. Only the first
+    /// byte was overwritten, so the rest of the entry is parsed as the kind of
+    /// entry it was.
+    Deleted(DeletedEntry),
+    /// An entry whose first byte is 0x00, marking the end of the directory.
+    /// The entries after it are unused.
+    End,
+}
+
+/// A deleted directory entry, as it was before deletion but for its first
+/// byte: the first character of a short name, or the sequence number of an
+/// LFN entry.
+#[derive(Debug, Clone)]
+pub enum DeletedEntry {
+    Regular(RegularEntry),
+    Lfn(LfnEntry),
+}
+
+/// A short-name directory entry.
+#[derive(Clone)]
+pub struct RegularEntry(VFatRegularDirEntry);
+
+/// A directory entry holding part of a long file name.
+#[derive(Clone)]
+pub struct LfnEntry(VFatLfnDirEntry);
+
+/// Parses the directory entry stored in `bytes`.
+///
+/// # Errors
+///
+/// Returns an error of `InvalidInput` if `bytes` isn't `ENTRY_SIZE` bytes long.
+pub fn parse_entry(bytes: &[u8]) -> io::Result<RawEntry> {
+    if bytes.len() != ENTRY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "directory entries are 32 bytes long",
+        ));
+    }
+
+    let raw: &[VFatDirEntry] = unsafe { bytes.cast()? };
+    let entry = &raw[0];
+    let unknown = entry.unknown();
+    Ok(if unknown.is_end() {
+        RawEntry::End
+    } else if unknown.is_deleted() && unknown.is_lfn() {
+        RawEntry::Deleted(DeletedEntry::Lfn(LfnEntry(*entry.long_filename())))
+    } else if unknown.is_deleted() {
+        RawEntry::Deleted(DeletedEntry::Regular(RegularEntry(entry.regular())))
+    } else if unknown.is_lfn() {
+        RawEntry::Lfn(LfnEntry(*entry.long_filename()))
+    } else {
+        RawEntry::Regular(RegularEntry(entry.regular()))
+    })
+}
+
+/// Returns the directory entries stored in `buf`, one for every 32 bytes, in
+/// order. Entries past an end-of-directory marker are returned as well, and a
+/// partial entry at the end of `buf` is ignored.
+pub fn entries<'a>(buf: &'a [u8]) -> impl Iterator<Item = RawEntry> + 'a {
+    buf.chunks(ENTRY_SIZE)
+        .filter(|chunk| chunk.len() == ENTRY_SIZE)
+        .map(|chunk| parse_entry(chunk).expect("whole entry"))
+}
+
+impl RegularEntry {
+    /// The space-padded 8.3 name of the entry, without the dot, as stored. The
+    /// first byte of a deleted entry's name is 0xE5.
+    pub fn short_name(&self) -> [u8; 11] {
+        self.0.short_name()
+    }
+
+    /// The short name of the entry as it is displayed, decoded with
+    /// `codepage`.
+    pub fn name(&self, codepage: Codepage) -> String {
+        self.0.display_name(codepage)
+    }
+
+    /// The checksum of the short name, which the LFN entries of the entry's
+    /// long file name store.
+    pub fn checksum(&self) -> u8 {
+        short_name_checksum(&self.0.short_name())
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.0.metadata()
+    }
+
+    /// The first cluster of the entry's contents, or cluster 0 if it has none.
+    pub fn cluster(&self) -> Cluster {
+        self.0.cluster()
+    }
+
+    /// The size of the file in bytes. Always 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.0.size()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.0.is_dir()
+    }
+}
+
+impl fmt::Debug for RegularEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegularEntry")
+            .field("short_name", &String::from_utf8_lossy(&self.short_name()))
+            .field("metadata", &self.metadata())
+            .field("cluster", &self.cluster())
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+impl LfnEntry {
+    /// The 1-based position of the entry's 13 characters in the long file
+    /// name. Only meaningful for entries that aren't deleted.
+    pub fn sequence(&self) -> u8 {
+        self.0.sequence() & 0x1F
+    }
+
+    /// Returns `true` if the entry holds the last part of the long file name,
+    /// which is stored first.
+    pub fn is_last(&self) -> bool {
+        self.0.sequence() & 0x40 != 0
+    }
+
+    /// The checksum of the short name of the entry the name belongs to.
+    pub fn checksum(&self) -> u8 {
+        self.0.checksum()
+    }
+
+    /// The 13 UTF-16 code units held by the entry, including any 0x0000
+    /// terminator and 0xFFFF padding.
+    pub fn chars(&self) -> [u16; 13] {
+        self.0.chars()
+    }
+
+    /// The characters held by the entry, up to the terminator.
+    pub fn text(&self) -> String {
+        ucs_2_to_string(&self.chars())
+    }
+}
+
+impl fmt::Debug for LfnEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LfnEntry")
+            .field("sequence", &self.sequence())
+            .field("is_last", &self.is_last())
+            .field("checksum", &self.checksum())
+            .field("text", &self.text())
+            .finish()
+    }
+}
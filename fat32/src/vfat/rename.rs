@@ -0,0 +1,189 @@
+use std::io;
+use std::path::Path;
+
+use traits::{Entry as EntryTrait, FileSystem};
+use vfat::{Cluster, Dir, Entry, EntryLocation, Shared, VFat, WatchEvent};
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Shared<VFat> {
+    /// Renames the entry at `from` to `to`, replacing the file or empty
+    /// directory at `to` if there is one. Both paths must be absolute.
+    ///
+    /// This is the commit step of a safe save: write the new contents to a
+    /// temporary file, then replace the original with it. Every pending
+    /// change is flushed before the rename, and the rename is written in two
+    /// ordered steps:
+    ///
+    ///   1. The entry for `to` is written. A replaced entry keeps its name and
+    ///      has its first cluster, size, times, and attributes overwritten in
+    ///      place, within a single sector. A new entry is written whole, and
+    ///      its name's short entry, written last, commits it.
+    ///   2. The entry for `from` is deleted and the clusters of a replaced
+    ///      entry are freed.
+    ///
+    /// If the volume has a journal, each step is atomic. Otherwise, a crash
+    /// after the first step leaves both `from` and `to` referring to the new
+    /// contents, which a consistency check reports as cross-linked. In
+    /// neither case is `to` ever missing or partially written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `NotFound` if there is no entry at `from`, and an
+    /// error of `InvalidInput` if either path is relative or the root, if the
+    /// parent of `to` is not a directory, if a directory would be moved into
+    /// itself, or if a file would replace a directory or vice versa. Returns
+    /// an error of `Other` if `to` is a directory that isn't empty, if `from`
+    /// or `to` has open handles, or if the directory of `to` is full.
+    pub fn replace<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        self.move_entry(from.as_ref(), to.as_ref(), true)
+    }
+
+    /// Implements `rename()` and `replace()`: `rename()` fails if `to`
+    /// exists, while `replace()` replaces it.
+    pub(crate) fn move_entry(&self, from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        if !from.has_root() || !to.has_root() {
+            return Err(invalid("path must be absolute"));
+        }
+
+        let source = self.open(from)?;
+        let source_location = source.location().ok_or(invalid("can't rename the root directory"))?;
+        let is_dir = match source {
+            Entry::Dir(_) => true,
+            Entry::File(_) => false,
+        };
+        let from_name = source.name().to_string();
+        drop(source);
+
+        let (from_dir, _) = self.parent_dir(from)?;
+        let (to_dir, to_name) = self.parent_dir(to)?;
+        if is_dir {
+            let mut ancestor = to.parent();
+            while let Some(path) = ancestor {
+                if self.open(path)?.location() == Some(source_location) {
+                    return Err(invalid("can't move a directory into itself"));
+                }
+                ancestor = path.parent();
+            }
+        }
+
+        let found = match to_dir.find(to_name) {
+            Ok(target) => Some(target),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let target = match found {
+            // Renaming an entry to itself only changes the spelling of its name.
+            Some(ref target) if target.location() == Some(source_location) => {
+                if target.name() == to_name {
+                    return Ok(());
+                }
+                None
+            }
+            Some(_) if !replace => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "entry already exists",
+                ))
+            }
+            Some(Entry::Dir(_)) if !is_dir => return Err(invalid("target is a directory")),
+            Some(Entry::File(_)) if is_dir => return Err(invalid("target is not a directory")),
+            Some(Entry::Dir(ref dir)) if !dir.is_empty()? => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "target directory is not empty",
+                ))
+            }
+            ref target => target.as_ref().map(|t| t.location().expect("target has a location")),
+        };
+        drop(found);
+
+        self.check_closed(source_location)?;
+        if let Some(target) = target {
+            self.check_closed(target)?;
+        }
+        to_dir.check_new_name_syntax(to_name)?;
+
+        let mut vfat = self.borrow_mut();
+        vfat.flush()?;
+        let entry = vfat.dir_entry(source_location)?;
+        drop(vfat);
+
+        // Step 1: write the entry for `to`.
+        let (location, old_cluster) = match target {
+            Some(target) => {
+                let mut vfat = self.borrow_mut();
+                let old_cluster = vfat.dir_entry(target)?.cluster();
+                vfat.update_dir_entry(target, |target| target.replace_contents(&entry))?;
+                (target, Some(old_cluster))
+            }
+            None => (to_dir.add_entry(to_name, entry)?, None),
+        };
+        let mut vfat = self.borrow_mut();
+        if is_dir && from_dir.cluster() != to_dir.cluster() {
+            // Point the `..` entry at the new parent; it refers to cluster 0
+            // when the parent is the root directory.
+            let parent = if to_dir.cluster() == vfat.root_dir_cluster {
+                Cluster::from(0)
+            } else {
+                to_dir.cluster()
+            };
+            let dotdot = EntryLocation {
+                dir: entry.cluster(),
+                index: 1,
+            };
+            vfat.update_dir_entry(dotdot, |dotdot| dotdot.set_cluster(parent))?;
+        }
+        vfat.flush()?;
+        drop(vfat);
+
+        // Step 2: delete the entry for `from` and free what `to` replaced.
+        if location != source_location {
+            from_dir.remove_entry(source_location)?;
+        }
+        let mut vfat = self.borrow_mut();
+        match old_cluster {
+            Some(cluster) if cluster.fat_index() >= 2 => vfat.free_chain(cluster)?,
+            _ => {}
+        }
+        if from_dir.cluster() == to_dir.cluster() {
+            let event = WatchEvent::Renamed {
+                from: from_name.into(),
+                to: to_name.into(),
+            };
+            vfat.notify(from_dir.cluster(), event);
+        } else {
+            vfat.notify(from_dir.cluster(), WatchEvent::Removed(from_name.into()));
+            let event = match old_cluster {
+                Some(_) => WatchEvent::Modified(to_name.into()),
+                None => WatchEvent::Created(to_name.into()),
+            };
+            vfat.notify(to_dir.cluster(), event);
+        }
+        vfat.flush()
+    }
+
+    /// Returns an error if any handle to the entry at `location` is open.
+    fn check_closed(&self, location: EntryLocation) -> io::Result<()> {
+        if self.borrow().handles.open_count(location) > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "entry has open handles",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Dir {
+    /// Checks that `name` is usable as the name of an entry, whether or not an
+    /// entry with that name exists.
+    fn check_new_name_syntax(&self, name: &str) -> io::Result<()> {
+        match self.check_new_name(name) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    }
+}
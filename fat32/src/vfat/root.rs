@@ -0,0 +1,71 @@
+use std::io;
+
+use vfat::{BiosParameterBlock, Cluster, VFat};
+
+/// Where the entries of a volume's root directory are stored. Directories are
+/// read through it, so that one implementation of directory iteration serves
+/// every layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum RootDirSource {
+    /// A cluster chain starting at the cluster, like any other directory, as
+    /// on FAT32.
+    Chain(Cluster),
+    /// A fixed-size region of `sectors` logical sectors starting at `start`,
+    /// between the FATs and the data clusters, as on FAT12 and FAT16.
+    Region { start: u64, sectors: u64 },
+}
+
+impl RootDirSource {
+    /// Returns the root directory source described by `ebpb`, for the volume
+    /// whose boot sector is logical sector `sector`.
+    pub fn from_bpb(ebpb: &BiosParameterBlock, sector: u64) -> RootDirSource {
+        if !ebpb.has_root_dir_region() {
+            return RootDirSource::Chain(Cluster::from(ebpb.root_dir_cluster()));
+        }
+
+        let bytes_per_sector = ebpb.bytes_per_sector() as u64;
+        let fats = ebpb.fats_number() as u64 * ebpb.sectors_per_fat16() as u64;
+        let bytes = ebpb.root_dir_entries() as u64 * 32;
+        RootDirSource::Region {
+            start: sector + ebpb.sectors_reserved() as u64 + fats,
+            sectors: (bytes + bytes_per_sector - 1) / bytes_per_sector,
+        }
+    }
+}
+
+impl VFat {
+    /// Returns where the entries of the directory starting at `cluster` are
+    /// stored: the root directory's source for the root directory, and the
+    /// cluster chain starting at `cluster` for any other.
+    pub(crate) fn dir_source(&self, cluster: Cluster) -> RootDirSource {
+        if cluster == self.root_dir_cluster {
+            self.root_dir
+        } else {
+            RootDirSource::Chain(cluster)
+        }
+    }
+
+    /// Appends the raw entries stored in `source` to `buf`, returning the
+    /// number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `read_chain()`, and an error if reading a sector
+    /// of a region fails.
+    pub(crate) fn read_dir_source(
+        &mut self,
+        source: RootDirSource,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        match source {
+            RootDirSource::Chain(start) => self.read_chain(start, buf),
+            RootDirSource::Region { start, sectors } => {
+                let before = buf.len();
+                for sector in start..start + sectors {
+                    buf.extend_from_slice(self.device.get(sector)?);
+                }
+                Ok(buf.len() - before)
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use traits::{Dir as DirTrait, Entry as EntryTrait, Metadata};
+use vfat::{Cluster, Dir, Entry, EntryLocation, Shared, Status, VFat};
+
+/// An unreadable cluster found by `scan_surface()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadCluster {
+    pub cluster: Cluster,
+    /// The path of the file or directory whose chain contains the cluster,
+    /// or `None` if the cluster belongs to no entry.
+    pub owner: Option<PathBuf>,
+    /// The cluster that the readable sectors of the cluster were moved to, if
+    /// the cluster was relocated.
+    pub relocated_to: Option<Cluster>,
+}
+
+/// Result of a surface scan of a volume.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ScanReport {
+    /// Number of clusters read. Clusters already marked bad aren't read.
+    pub clusters: usize,
+    /// Clusters with at least one unreadable sector, in ascending order.
+    pub bad: Vec<BadCluster>,
+}
+
+/// The place of a cluster in the chain of an entry.
+#[derive(Clone)]
+struct Link {
+    path: PathBuf,
+    /// The directory entry of the chain, or `None` for the root directory.
+    location: Option<EntryLocation>,
+    /// The preceding cluster in the chain, or `None` for the first one.
+    prev: Option<Cluster>,
+    is_dir: bool,
+}
+
+impl Shared<VFat> {
+    /// Reads every data cluster of the volume from the device, bypassing the
+    /// cache, and reports the clusters that can't be read. Clusters already
+    /// marked bad in the FAT are skipped.
+    ///
+    /// If `repair` is set, pending changes are flushed first and every
+    /// unreadable cluster is marked bad in the FAT so that it is never
+    /// allocated again. A bad cluster in the chain of a file or in a
+    /// directory past its first cluster is first replaced by a newly
+    /// allocated cluster holding the readable sectors of the bad one, with
+    /// unreadable sectors zeroed. The first clusters of directories and of
+    /// open files, and clusters that belong to no entry, are reported but
+    /// left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the directory tree or the FAT fails, or if
+    /// repairing fails; an unreadable data cluster is reported, not an error.
+    pub fn scan_surface(&self, repair: bool) -> io::Result<ScanReport> {
+        if repair {
+            self.borrow_mut().flush()?;
+        }
+
+        let mut owners = HashMap::new();
+        let root = Dir::root(self.clone());
+        self.map_chain(&mut owners, Path::new("/"), None, root.cluster(), true);
+        self.map_dir(&mut owners, &root, Path::new("/"))?;
+
+        let mut report = ScanReport::default();
+        let end = self.borrow().data_clusters() + 2;
+        for index in 2..end {
+            let cluster = Cluster::from(index);
+            let mut vfat = self.borrow_mut();
+            if vfat.fat_entry(cluster)?.status() == Status::Bad {
+                continue;
+            }
+
+            report.clusters += 1;
+            let (data, readable) = vfat.read_cluster_uncached(cluster)?;
+            if readable {
+                continue;
+            }
+
+            let link = owners.get(&index).cloned();
+            let mut bad = BadCluster {
+                cluster,
+                owner: link.as_ref().map(|link| link.path.clone()),
+                relocated_to: None,
+            };
+            if repair {
+                match link {
+                    Some(link) => {
+                        bad.relocated_to = vfat.relocate(&mut owners, cluster, link, &data)?;
+                    }
+                    None => {
+                        if vfat.fat_entry(cluster)?.status() == Status::Free {
+                            vfat.set_fat_entry(cluster, Status::Bad)?;
+                        }
+                    }
+                }
+            }
+            report.bad.push(bad);
+        }
+
+        if repair {
+            self.borrow_mut().flush()?;
+        }
+        Ok(report)
+    }
+
+    /// Records the clusters of every entry below `dir`, at `path`, in
+    /// `owners`.
+    fn map_dir(&self, owners: &mut HashMap<u32, Link>, dir: &Dir, path: &Path) -> io::Result<()> {
+        for entry in dir.entries()? {
+            if entry.name() == "." || entry.name() == ".." || entry.metadata().volume_id() {
+                continue;
+            }
+
+            let path = path.join(entry.name());
+            let location = entry.location();
+            match entry {
+                Entry::File(ref file) => {
+                    self.map_chain(owners, &path, location, file.cluster, false);
+                }
+                Entry::Dir(ref child) => {
+                    if self.map_chain(owners, &path, location, child.cluster(), true) {
+                        self.map_dir(owners, child, &path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the clusters of the chain starting at `start` in `owners`.
+    /// Returns `false` if the chain is broken or any of its clusters is
+    /// already recorded.
+    fn map_chain(
+        &self,
+        owners: &mut HashMap<u32, Link>,
+        path: &Path,
+        location: Option<EntryLocation>,
+        start: Cluster,
+        is_dir: bool,
+    ) -> bool {
+        if start.fat_index() < 2 {
+            return false;
+        }
+        let chain = match self.borrow_mut().chain(start) {
+            Ok(chain) => chain,
+            Err(_) => return false,
+        };
+        if chain.iter().any(|cluster| owners.contains_key(&cluster.fat_index())) {
+            return false;
+        }
+
+        let mut prev = None;
+        for cluster in chain {
+            let link = Link {
+                path: path.to_path_buf(),
+                location,
+                prev,
+                is_dir,
+            };
+            owners.insert(cluster.fat_index(), link);
+            prev = Some(cluster);
+        }
+        true
+    }
+}
+
+impl VFat {
+    /// Reads `cluster` from the device, bypassing the cache. Returns its
+    /// contents, with unreadable sectors zeroed, and whether every sector
+    /// could be read.
+    fn read_cluster_uncached(&mut self, cluster: Cluster) -> io::Result<(Vec<u8>, bool)> {
+        let sector_size = self.sector_size();
+        let mut data = Vec::with_capacity(self.cluster_size());
+        let mut readable = true;
+        for sector in self.cluster_sectors(cluster)? {
+            match self.device.read_uncached(sector) {
+                Ok(ref buf) if buf.len() == sector_size => data.extend_from_slice(buf),
+                _ => {
+                    data.extend(::std::iter::repeat(0).take(sector_size));
+                    readable = false;
+                }
+            }
+        }
+        Ok((data, readable))
+    }
+
+    /// Replaces the bad cluster `cluster`, linked into its chain by `link`,
+    /// with a new cluster holding `data` and marks it bad. Returns the new
+    /// cluster, or `None` if the cluster can't be relocated.
+    fn relocate(
+        &mut self,
+        owners: &mut HashMap<u32, Link>,
+        cluster: Cluster,
+        link: Link,
+        data: &[u8],
+    ) -> io::Result<Option<Cluster>> {
+        // The first cluster of a directory is also referred to by its `.`
+        // entry and by the `..` entries of its subdirectories, and that of an
+        // open file by its handles.
+        if link.prev.is_none() {
+            let open = link.location.map_or(false, |l| self.handles.open_count(l) > 0);
+            if link.is_dir || open {
+                return Ok(None);
+            }
+        }
+
+        let status = self.fat_entry(cluster)?.status();
+        let new = self.alloc_cluster(None)?;
+        self.write_cluster(new, 0, data)?;
+        self.set_fat_entry(new, status)?;
+        match link.prev {
+            Some(prev) => self.set_fat_entry(prev, Status::Data(new))?,
+            None => {
+                let location = link.location.expect("file has a directory entry");
+                self.update_dir_entry(location, |entry| entry.set_cluster(new))?;
+            }
+        }
+        self.set_fat_entry(cluster, Status::Bad)?;
+
+        // Later clusters of the chain now follow the new cluster, which is
+        // scanned like any other if it comes later in the volume.
+        if let Status::Data(next) = status {
+            if let Some(next) = owners.get_mut(&next.fat_index()) {
+                next.prev = Some(new);
+            }
+        }
+        owners.insert(new.fat_index(), link);
+        Ok(Some(new))
+    }
+}
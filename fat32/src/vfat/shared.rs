@@ -20,6 +20,10 @@ mod imp {
         Rc::new(Mutex::new(val))
     }
 
+    pub fn try_unwrap<T>(inner: Inner<T>) -> Result<Mutex<T>, Inner<T>> {
+        Rc::try_unwrap(inner)
+    }
+
     // Without an enabled MMU/cache, the processor faults on atomic accesses.
     // As such, use an `Rc` instead of an `Arc` when running on ROS until
     // multithreading, the MMU, and caches are enabled.
@@ -36,6 +40,10 @@ mod imp {
     pub fn new<T>(val: T) -> Inner<T> {
         Arc::new(Mutex::new(val))
     }
+
+    pub fn try_unwrap<T>(inner: Inner<T>) -> Result<Mutex<T>, Inner<T>> {
+        Arc::try_unwrap(inner)
+    }
 }
 
 impl<T> Shared<T> {
@@ -59,6 +67,22 @@ impl<T> Shared<T> {
     pub fn borrow_mut<'a>(&'a self) -> impl DerefMut<Target = T> + 'a {
         self.0.lock().expect("all okay")
     }
+
+    /// Like `borrow_mut()`, but returns `None` instead of panicking if a
+    /// thread panicked while it held a borrow, as is safe to call while
+    /// unwinding.
+    pub(crate) fn try_borrow_mut<'a>(&'a self) -> Option<impl DerefMut<Target = T> + 'a> {
+        self.0.lock().ok()
+    }
+
+    /// Returns the inner value if this is the only pointer to it, or `self`
+    /// otherwise.
+    pub fn try_unwrap(self) -> Result<T, Shared<T>> {
+        match imp::try_unwrap(self.0) {
+            Ok(mutex) => Ok(mutex.into_inner().expect("all okay")),
+            Err(inner) => Err(Shared(inner)),
+        }
+    }
 }
 
 impl<T> Clone for Shared<T> {
@@ -0,0 +1,132 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use traits::{Entry as EntryTrait, Metadata};
+use vfat::{Cluster, Dir, File, Shared, VFat};
+
+/// The slack space of a file, returned by `slack()`: the bytes between the end
+/// of the file and the end of the cluster holding its last byte, which still
+/// hold whatever was written there before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slack {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The cluster holding the last byte of the file, and the slack.
+    pub cluster: Cluster,
+    /// The offset of the slack in `cluster`.
+    pub offset: usize,
+    /// The contents of the slack.
+    pub data: Vec<u8>,
+}
+
+/// An iterator over the slack space of every file in a volume, returned by
+/// `slack()`.
+pub struct SlackIter {
+    vfat: Shared<VFat>,
+    files: ::std::vec::IntoIter<(PathBuf, Cluster, usize)>,
+}
+
+impl VFat {
+    /// Appends the slack of the file of `size` bytes starting at `first` to
+    /// `buf`. Returns the cluster holding the slack, or `None` if the file
+    /// has no slack because it is empty or ends on a cluster boundary.
+    pub(crate) fn read_slack(
+        &mut self,
+        first: Cluster,
+        size: usize,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<Option<Cluster>> {
+        let cluster_size = self.cluster_size();
+        let used = size % cluster_size;
+        if first.fat_index() < 2 || used == 0 {
+            return Ok(None);
+        }
+
+        let mut cluster = first;
+        for _ in 0..size / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cluster chain is shorter than file",
+            ))?;
+        }
+
+        // Clusters are read by whole sectors, so the used part is read too
+        // and dropped.
+        let start = buf.len();
+        buf.resize(start + cluster_size, 0);
+        if let Err(e) = self.read_cluster(cluster, 0, &mut buf[start..]) {
+            buf.truncate(start);
+            return Err(e);
+        }
+        buf.drain(start..start + used);
+        Ok(Some(cluster))
+    }
+}
+
+impl File {
+    /// Appends the file's slack space to `buf`: the bytes between the end of
+    /// the file and the end of the cluster holding its last byte, which
+    /// normal reads never return. Returns the number of bytes appended, 0 if
+    /// the file is empty or ends on a cluster boundary. Clusters allocated
+    /// past the last byte, like those reserved by `preallocate()`, aren't
+    /// part of the slack.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the file's cluster chain is
+    /// shorter than its size.
+    pub fn slack(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        self.vfat.borrow_mut().read_slack(self.cluster, self.size, buf)?;
+        Ok(buf.len() - start)
+    }
+}
+
+impl Shared<VFat> {
+    /// Returns the slack space of every file in the volume that has any, in
+    /// depth-first directory order. The directory tree is read up front; the
+    /// slack of each file is read as the iterator reaches it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a directory fails. The iterator returns
+    /// an error for a file whose cluster chain is shorter than its size.
+    pub fn slack(&self) -> io::Result<SlackIter> {
+        let mut files = Vec::new();
+        Dir::root(self.clone()).walk(Path::new("/"), &mut |path, entry| {
+            if let Some(file) = entry.as_file() {
+                if !file.metadata().volume_id() {
+                    files.push((path.to_path_buf(), file.cluster, file.size));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(SlackIter {
+            vfat: self.clone(),
+            files: files.into_iter(),
+        })
+    }
+}
+
+impl Iterator for SlackIter {
+    type Item = io::Result<Slack>;
+
+    fn next(&mut self) -> Option<io::Result<Slack>> {
+        for (path, first, size) in &mut self.files {
+            let mut data = Vec::new();
+            let cluster = match self.vfat.borrow_mut().read_slack(first, size, &mut data) {
+                Ok(Some(cluster)) => cluster,
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            let offset = size % self.vfat.borrow().cluster_size();
+            return Some(Ok(Slack {
+                path,
+                cluster,
+                offset,
+                data,
+            }));
+        }
+        None
+    }
+}
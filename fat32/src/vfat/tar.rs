@@ -0,0 +1,171 @@
+use std::cmp::min;
+use std::io::{self, Write};
+use std::path::Path;
+
+use traits::{FileSystem, Metadata};
+use vfat::{Entry, File, Shared, VFat};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Writes `val` as a NUL-terminated, zero-padded octal number filling `field`.
+fn put_octal(field: &mut [u8], val: u64) {
+    let digits = format!("{:o}", val);
+    let width = field.len() - 1;
+    let padded = format!("{:0>width$}", digits, width = width);
+    field[..width].copy_from_slice(&padded.as_bytes()[padded.len() - width..]);
+    field[width] = 0;
+}
+
+/// Splits `path` into the ustar `prefix` and `name` fields, if possible.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+
+    // The prefix and name are joined with a '/' that is not stored.
+    path.char_indices()
+        .filter(|&(i, c)| c == '/' && i <= 155 && path.len() - i - 1 <= 100)
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|&(_, name)| !name.is_empty())
+}
+
+/// Returns a ustar header block for an entry of type `kind` at `path`.
+///
+/// Paths that do not fit in the ustar header are truncated in the header and
+/// must be preceded by a PAX extended header carrying the full path.
+fn header(path: &str, kind: u8, size: u64, mode: u32, mtime: u64) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+
+    let (prefix, name) = split_path(path).unwrap_or(("", path));
+    let name_len = min(name.len(), 100);
+    block[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+    block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    put_octal(&mut block[100..108], mode as u64);
+    put_octal(&mut block[108..116], 0);
+    put_octal(&mut block[116..124], 0);
+    put_octal(&mut block[124..136], size);
+    put_octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field set to spaces.
+    for byte in &mut block[148..156] {
+        *byte = b' ';
+    }
+    let checksum: u64 = block.iter().map(|&b| b as u64).sum();
+    put_octal(&mut block[148..155], checksum);
+    block[155] = b' ';
+
+    block
+}
+
+/// Writes zero bytes to `writer` to pad `len` bytes to a block boundary.
+fn pad<W: Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    let rem = (len % BLOCK_SIZE as u64) as usize;
+    if rem != 0 {
+        writer.write_all(&[0u8; BLOCK_SIZE][rem..])?;
+    }
+    Ok(())
+}
+
+/// Writes a PAX extended header recording the full `path`.
+fn write_pax_path<W: Write>(writer: &mut W, path: &str) -> io::Result<()> {
+    // Each record is "<len> path=<path>\n", where <len> counts itself.
+    let body_len = " path=\n".len() + path.len();
+    let mut len = body_len + 1;
+    while format!("{}", len).len() + body_len != len {
+        len += 1;
+    }
+    let record = format!("{} path={}\n", len, path);
+
+    writer.write_all(&header("././@PaxHeader", b'x', len as u64, 0o644, 0))?;
+    writer.write_all(record.as_bytes())?;
+    pad(writer, len as u64)
+}
+
+fn write_header<W: Write>(
+    writer: &mut W,
+    path: &str,
+    kind: u8,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+) -> io::Result<()> {
+    if split_path(path).is_none() {
+        write_pax_path(writer, path)?;
+    }
+    writer.write_all(&header(path, kind, size, mode, mtime))
+}
+
+impl Shared<VFat> {
+    /// Writes the file or directory tree at `path` to `writer` as a tar
+    /// archive.
+    ///
+    /// Entries are named relative to `path`: exporting a directory `/a`
+    /// containing `b.txt` yields the single entry `b.txt`; exporting a file
+    /// yields one entry named after the file. Modification times are stored
+    /// as the entry's mtime, and read-only entries lose their write
+    /// permission bits. Paths too long for a ustar header are recorded in PAX
+    /// extended headers.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`, or any error encountered
+    /// while reading the tree or writing to `writer`.
+    pub fn export_tar<P: AsRef<Path>, W: Write>(&self, path: P, mut writer: W) -> io::Result<()> {
+        let offset = self.borrow().utc_offset();
+        match self.open(path.as_ref())? {
+            Entry::File(ref file) => self.write_tar_file(&mut writer, Path::new(file.name()), file)?,
+            Entry::Dir(ref dir) => dir.walk(Path::new(""), &mut |path, entry| match *entry {
+                Entry::File(ref file) => self.write_tar_file(&mut writer, path, file),
+                Entry::Dir(ref dir) => {
+                    let name = format!("{}/", path.to_string_lossy());
+                    let mode = if dir.metadata().read_only() { 0o555 } else { 0o755 };
+                    let mtime = dir.metadata().modified().unix_seconds_at(offset);
+                    write_header(&mut writer, &name, b'5', 0, mode, mtime)
+                }
+            })?,
+        }
+
+        writer.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+        writer.flush()
+    }
+
+    /// Writes the header and contents of `file` to `writer`, reading the
+    /// file one cluster at a time.
+    fn write_tar_file<W: Write>(&self, writer: &mut W, path: &Path, file: &File) -> io::Result<()> {
+        let size = file.size as u64;
+        let mode = if file.metadata().read_only() { 0o444 } else { 0o644 };
+        let offset = self.borrow().utc_offset();
+        let mtime = file.metadata().modified().unix_seconds_at(offset);
+        write_header(writer, &path.to_string_lossy(), b'0', size, mode, mtime)?;
+
+        if size > 0 {
+            // The volume is only borrowed while reading, so that `writer`
+            // may write to a file on the same volume.
+            let mut buf = vec![0u8; self.borrow().cluster_size()];
+            let chain = self.borrow_mut().chain(file.cluster)?;
+            let mut remaining = size;
+            for cluster in chain {
+                if remaining == 0 {
+                    break;
+                }
+                self.borrow_mut().read_cluster(cluster, 0, &mut buf)?;
+                let len = min(remaining, buf.len() as u64) as usize;
+                writer.write_all(&buf[..len])?;
+                remaining -= len as u64;
+            }
+
+            if remaining != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "cluster chain shorter than file size",
+                ));
+            }
+        }
+
+        pad(writer, size)
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use traits::{Entry as EntryTrait, Metadata};
+use util::json_string;
+use vfat::{Date, Dir, Shared, Timestamp, VFat};
+
+/// What happened to an entry at the time of an `Event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum EventKind {
+    Created,
+    Modified,
+    /// FAT stores only the date of the last access, so access events are at
+    /// midnight.
+    Accessed,
+}
+
+/// One timestamp of one entry, as listed by `timeline()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Event {
+    pub time: Timestamp,
+    pub kind: EventKind,
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// Size in bytes; always 0 for directories.
+    pub size: u64,
+}
+
+/// The format `write_timeline()` writes events in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelineFormat {
+    /// Comma-separated values with a header row: `time,event,path,type,size`.
+    Csv,
+    /// A JSON array of objects with the fields of `Event`.
+    Json,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EventKind::Created => write!(f, "created"),
+            EventKind::Modified => write!(f, "modified"),
+            EventKind::Accessed => write!(f, "accessed"),
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Returns the creation, modification, and access times of every file
+    /// and directory in the volume as events, ordered chronologically and
+    /// then by path. Times that were never set, stored as zero, are left
+    /// out, as are the volume label and the root directory, which has no
+    /// timestamps.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a directory fails.
+    pub fn timeline(&self) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        Dir::root(self.clone()).walk(Path::new("/"), &mut |path, entry| {
+            let metadata = entry.metadata();
+            if metadata.volume_id() {
+                return Ok(());
+            }
+
+            let info = entry.info();
+            let times = [
+                (EventKind::Created, metadata.created),
+                (EventKind::Modified, metadata.modified),
+                (EventKind::Accessed, metadata.accessed),
+            ];
+            for &(kind, time) in times.iter().filter(|&&(_, t)| t.date != Date::default()) {
+                events.push(Event {
+                    time,
+                    kind,
+                    path: path.to_path_buf(),
+                    is_dir: info.is_dir,
+                    size: info.size,
+                });
+            }
+            Ok(())
+        })?;
+
+        events.sort_by(|a, b| (a.time, &a.path, a.kind).cmp(&(b.time, &b.path, b.kind)));
+        Ok(events)
+    }
+
+    /// Writes the events of `timeline()` to `writer` in `format`. Times are
+    /// written in ISO 8601 format with hundredths of a second, as in
+    /// `2019-03-05T07:04:02.25`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `timeline()` and any error that occurs while
+    /// writing to `writer`.
+    pub fn write_timeline<W: Write>(
+        &self,
+        format: TimelineFormat,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let events = self.timeline()?;
+        match format {
+            TimelineFormat::Csv => {
+                writeln!(writer, "time,event,path,type,size")?;
+                for event in &events {
+                    writeln!(
+                        writer,
+                        "{:.2},{},{},{},{}",
+                        event.time,
+                        event.kind,
+                        csv_field(&event.path.to_string_lossy()),
+                        if event.is_dir { "dir" } else { "file" },
+                        event.size
+                    )?;
+                }
+            }
+            TimelineFormat::Json => {
+                write!(writer, "[")?;
+                for (i, event) in events.iter().enumerate() {
+                    write!(
+                        writer,
+                        "{}\n  {{\"time\": \"{:.2}\", \"event\": \"{}\", \"path\": {}, \
+                         \"is_dir\": {}, \"size\": {}}}",
+                        if i == 0 { "" } else { "," },
+                        event.time,
+                        event.kind,
+                        json_string(&event.path.to_string_lossy()),
+                        event.is_dir,
+                        event.size
+                    )?;
+                }
+                writeln!(writer, "\n]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns `field` quoted for CSV if it contains a comma, a quote, or a line
+/// break.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
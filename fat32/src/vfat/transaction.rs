@@ -0,0 +1,144 @@
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::thread;
+
+use traits::FileSystem;
+use vfat::{Dir, File, Shared, VFat};
+
+/// The operations of a transaction started by `transaction()`. Changes made
+/// through it stay in memory until the transaction is committed.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    vfat: &'a Shared<VFat>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Returns the volume, for operations the transaction has no method for.
+    /// Their changes are part of the transaction as well.
+    pub fn volume(&self) -> &'a Shared<VFat> {
+        self.vfat
+    }
+
+    /// Creates an empty file at `path`, like `create_file()`.
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> io::Result<File> {
+        self.vfat.create_file(path)
+    }
+
+    /// Creates a directory at `path`, like `create_dir()`.
+    pub fn create_dir<P: AsRef<Path>>(&self, path: P, parents: bool) -> io::Result<Dir> {
+        self.vfat.create_dir(path, parents)
+    }
+
+    /// Writes `data` at the start of the file at `path`, creating the file if
+    /// it doesn't exist. Files can't shrink, so if the file is longer than
+    /// `data`, the rest of it is kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open_file()` other than `NotFound`, of
+    /// `create_file()`, and of writing the file.
+    pub fn write<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut file = match self.vfat.open_file(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => self.vfat.create_file(path)?,
+            Err(e) => return Err(e),
+        };
+        file.write_all(data)
+    }
+
+    /// Renames the entry at `from` to `to`, like `rename()`.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        self.vfat.rename(from, to)
+    }
+
+    /// Renames the entry at `from` to `to`, replacing an entry at `to`, like
+    /// `replace()`.
+    pub fn replace<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        self.vfat.replace(from, to)
+    }
+}
+
+/// Ends the transaction of a volume if it is dropped while armed, as when the
+/// function run as the transaction panics: the changes made are discarded, so
+/// that they aren't written later, and the volume can be used again.
+struct Abort<'a> {
+    vfat: &'a Shared<VFat>,
+    next_free: u32,
+}
+
+impl<'a> Drop for Abort<'a> {
+    fn drop(&mut self) {
+        // A volume borrowed by the panicking thread is poisoned, and its
+        // transaction never ends, so nothing in it is ever written back.
+        if let Some(mut vfat) = self.vfat.try_borrow_mut() {
+            vfat.transaction = None;
+            vfat.discard_changes(self.next_free);
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Runs `f` as a transaction: the changes it makes to the FAT, directory
+    /// entries, and file contents are kept in memory, and written together
+    /// by a `sync()` if `f` returns `Ok`, so that a sector changed by several
+    /// steps is written once and the writes follow the order of `flush()`.
+    /// With a journal, the directory entry and FAT changes of the whole
+    /// transaction are committed atomically if they fit in the journal. If
+    /// `f` returns an error, its changes are discarded and the error is
+    /// returned.
+    ///
+    /// Pending changes are flushed before `f` is called. Files and
+    /// directories opened before a discarded transaction may still reflect
+    /// its changes, and events sent to watchers aren't taken back. If `f`
+    /// panics, its changes are discarded as well.
+    ///
+    /// The transaction belongs to the calling thread. Until it ends, other
+    /// threads can read from the volume, but changing or flushing it fails
+    /// with an error of `WouldBlock`, so that their changes are neither
+    /// committed nor discarded with the transaction's.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if a transaction is already in progress,
+    /// the errors of `flush()` before `f` is called, the error returned by
+    /// `f`, and the errors of `sync()` when committing, after which the
+    /// changes that weren't written remain pending.
+    pub fn transaction<F, T>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&Transaction) -> io::Result<T>,
+    {
+        let next_free = {
+            let mut vfat = self.borrow_mut();
+            if vfat.transaction.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "a transaction is already in progress",
+                ));
+            }
+            vfat.flush()?;
+            vfat.transaction = Some(thread::current().id());
+            vfat.next_free()
+        };
+
+        let abort = Abort {
+            vfat: self,
+            next_free,
+        };
+        let result = f(&Transaction { vfat: self });
+        mem::forget(abort);
+        let mut vfat = self.borrow_mut();
+        vfat.transaction = None;
+        match result {
+            Ok(value) => {
+                vfat.sync()?;
+                Ok(value)
+            }
+            Err(e) => {
+                vfat.discard_changes(next_free);
+                Err(e)
+            }
+        }
+    }
+}
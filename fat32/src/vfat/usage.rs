@@ -0,0 +1,174 @@
+use std::io;
+use std::path::Path;
+
+use traits::{Dir as DirTrait, FileSystem};
+use vfat::name::MAX_NAME_LEN;
+use vfat::{Dir, Entry, Shared, VFat};
+
+/// Disk usage of a file or directory tree.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Number of files in the tree.
+    pub files: usize,
+    /// Number of directories in the tree, including its root.
+    pub dirs: usize,
+    /// Sum of the sizes of every file in the tree, in bytes.
+    pub bytes: u64,
+    /// Space allocated on disk to every file and directory in the tree, in
+    /// bytes. Always a multiple of the cluster size.
+    pub allocated: u64,
+}
+
+/// The capacity and state of a volume, returned by `statvfs()`, with the
+/// fields of POSIX `statvfs` that apply to FAT. Blocks are clusters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatVfs {
+    /// The size of a block, a cluster, in bytes.
+    pub block_size: u32,
+    /// The number of data clusters in the volume.
+    pub blocks: u64,
+    /// The number of free clusters.
+    pub free_blocks: u64,
+    /// The number of free clusters that can be allocated: 0 if the volume is
+    /// read-only, `free_blocks` otherwise.
+    pub available_blocks: u64,
+    /// The longest name an entry can have, in UTF-16 code units.
+    pub max_name_len: u32,
+    /// Whether the volume was mounted read-only.
+    pub read_only: bool,
+    /// Whether the volume was marked dirty when it was mounted, as reported
+    /// by `was_dirty()`.
+    pub dirty: bool,
+}
+
+impl DiskUsage {
+    fn add(&mut self, other: &DiskUsage) {
+        self.files += other.files;
+        self.dirs += other.dirs;
+        self.bytes += other.bytes;
+        self.allocated += other.allocated;
+    }
+}
+
+impl VFat {
+    /// Returns the capacity and state of the volume in one call. The number
+    /// of free clusters includes the changes that haven't been flushed yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the FAT to count the free clusters fails.
+    pub fn statvfs(&mut self) -> io::Result<StatVfs> {
+        let free = self.free_cluster_count()? as u64;
+        let read_only = self.is_read_only();
+        Ok(StatVfs {
+            block_size: self.cluster_size() as u32,
+            blocks: self.data_clusters() as u64,
+            free_blocks: free,
+            available_blocks: if read_only { 0 } else { free },
+            max_name_len: MAX_NAME_LEN as u32,
+            read_only,
+            dirty: self.was_dirty(),
+        })
+    }
+}
+
+impl Shared<VFat> {
+    /// Computes the disk usage of the file or directory tree at `path` in a
+    /// single pass over the directory entries. File sizes are taken from the
+    /// directory entries; files are never opened.
+    ///
+    /// If the volume caches disk usage, see `MountOptions::cache_dir_usage`,
+    /// the usage of every directory in the tree is cached, and only the
+    /// directories changed since are read again by the next call.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`, or an error if reading a
+    /// directory or a directory's cluster chain fails.
+    pub fn du<P: AsRef<Path>>(&self, path: P) -> io::Result<DiskUsage> {
+        let path = path.as_ref();
+        let cluster_size = self.borrow().cluster_size() as u64;
+        let entry = self.open(path)?;
+        if let Entry::Dir(ref dir) = entry {
+            if self.borrow().dir_usage.is_some() {
+                return self.cached_du(dir);
+            }
+        }
+
+        let mut usage = DiskUsage::default();
+
+        {
+            let mut add = |entry: &Entry| -> io::Result<()> {
+                match *entry {
+                    Entry::File(ref file) => {
+                        let clusters = (file.size as u64 + cluster_size - 1) / cluster_size;
+                        usage.files += 1;
+                        usage.bytes += file.size as u64;
+                        usage.allocated += clusters * cluster_size;
+                    }
+                    Entry::Dir(ref dir) => {
+                        usage.dirs += 1;
+                        usage.allocated += self.dir_allocated(dir)? * cluster_size;
+                    }
+                }
+                Ok(())
+            };
+
+            add(&entry)?;
+            if let Entry::Dir(ref dir) = entry {
+                dir.walk(path, &mut |_, entry| add(entry))?;
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Returns the disk usage of the tree rooted at `dir`, from the cache if
+    /// it is there, and caches the usage of `dir` and of every directory
+    /// below it that had to be read.
+    fn cached_du(&self, dir: &Dir) -> io::Result<DiskUsage> {
+        let generation = {
+            let vfat = self.borrow();
+            let cached = vfat.dir_usage.as_ref().and_then(|cache| cache.get(&dir.cluster()));
+            if let Some(usage) = cached {
+                return Ok(usage.clone());
+            }
+            vfat.dir_usage_generation
+        };
+
+        let cluster_size = self.borrow().cluster_size() as u64;
+        let mut usage = DiskUsage {
+            dirs: 1,
+            allocated: self.dir_allocated(dir)? * cluster_size,
+            ..DiskUsage::default()
+        };
+        for entry in dir.entries()? {
+            match entry {
+                Entry::Dir(ref child) if child.name() == "." || child.name() == ".." => {}
+                Entry::Dir(ref child) => usage.add(&self.cached_du(child)?),
+                Entry::File(ref file) => {
+                    let clusters = (file.size as u64 + cluster_size - 1) / cluster_size;
+                    usage.files += 1;
+                    usage.bytes += file.size as u64;
+                    usage.allocated += clusters * cluster_size;
+                }
+            }
+        }
+
+        let mut vfat = self.borrow_mut();
+        if vfat.dir_usage_generation == generation {
+            if let Some(ref mut cache) = vfat.dir_usage {
+                cache.insert(dir.cluster(), usage.clone());
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Returns the number of clusters allocated to the directory `dir`.
+    fn dir_allocated(&self, dir: &Dir) -> io::Result<u64> {
+        if dir.cluster().fat_index() < 2 {
+            return Ok(0);
+        }
+        Ok(self.borrow_mut().chain(dir.cluster())?.len() as u64)
+    }
+}
@@ -0,0 +1,133 @@
+use std::cmp::max;
+use std::io;
+
+use vfat::{Cluster, Status, VFat};
+
+/// The state of a data cluster in a `UsageMap`. States are ordered by the
+/// precedence they take when several clusters are summarized as one block:
+/// a block with any bad cluster is bad, and otherwise one with any used
+/// cluster is used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum ClusterUsage {
+    Free,
+    /// The cluster holds data, or is otherwise reserved in the FAT.
+    Used,
+    Bad,
+}
+
+/// A run of `len` consecutive clusters in the same state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct UsageRun {
+    pub usage: ClusterUsage,
+    pub len: u32,
+}
+
+/// A run-length encoded map of the state of every data cluster of a volume,
+/// starting at cluster 2, returned by `VFat::usage_map()`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct UsageMap {
+    runs: Vec<UsageRun>,
+}
+
+impl UsageMap {
+    /// The runs of the map in cluster order. Consecutive runs are always in
+    /// different states.
+    pub fn runs(&self) -> &[UsageRun] {
+        &self.runs
+    }
+
+    /// The number of clusters in the map.
+    pub fn len(&self) -> u32 {
+        self.runs.iter().map(|run| run.len).sum()
+    }
+
+    /// Returns `true` if the map has no clusters.
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Returns the state of `cluster`, or `None` if it isn't a data cluster of
+    /// the volume.
+    pub fn get(&self, cluster: Cluster) -> Option<ClusterUsage> {
+        let mut index = cluster.data_index().ok()?;
+        for run in &self.runs {
+            if index < run.len {
+                return Some(run.usage);
+            }
+            index -= run.len;
+        }
+        None
+    }
+
+    /// Returns the number of clusters in state `usage`.
+    pub fn count(&self, usage: ClusterUsage) -> u32 {
+        self.runs.iter().filter(|run| run.usage == usage).map(|run| run.len).sum()
+    }
+
+    /// Summarizes the map as `width` blocks of about equal numbers of
+    /// clusters, as in the block view of a defragmenter. Each block takes the
+    /// highest-precedence state of its clusters. If there are fewer clusters
+    /// than blocks, clusters are repeated across blocks.
+    pub fn blocks(&self, width: usize) -> Vec<ClusterUsage> {
+        let len = self.len() as u64;
+        if len == 0 {
+            return Vec::new();
+        }
+
+        let mut blocks = Vec::with_capacity(width);
+        let (mut index, mut index_start) = (0, 0u64);
+        for block in 0..width as u64 {
+            let start = block * len / width as u64;
+            let end = max((block + 1) * len / width as u64, start + 1);
+            while index_start + self.runs[index].len as u64 <= start {
+                index_start += self.runs[index].len as u64;
+                index += 1;
+            }
+
+            let mut usage = ClusterUsage::Free;
+            let (mut run, mut run_start) = (index, index_start);
+            while run < self.runs.len() && run_start < end {
+                usage = max(usage, self.runs[run].usage);
+                run_start += self.runs[run].len as u64;
+                run += 1;
+            }
+            blocks.push(usage);
+        }
+        blocks
+    }
+
+    /// Appends `len` clusters in state `usage`.
+    fn push(&mut self, usage: ClusterUsage, len: u32) {
+        if let Some(last) = self.runs.last_mut() {
+            if last.usage == usage {
+                last.len += len;
+                return;
+            }
+        }
+        self.runs.push(UsageRun { usage, len });
+    }
+}
+
+impl VFat {
+    /// Returns a map of which data clusters are free, used, or marked bad,
+    /// read from the FAT.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the FAT fails.
+    pub fn usage_map(&mut self) -> io::Result<UsageMap> {
+        let mut map = UsageMap::default();
+        for index in 2..self.data_clusters() + 2 {
+            let usage = match self.fat_entry(Cluster::from(index))?.status() {
+                Status::Free => ClusterUsage::Free,
+                Status::Bad => ClusterUsage::Bad,
+                Status::Data(_) | Status::Eoc(_) | Status::Reserved => ClusterUsage::Used,
+            };
+            map.push(usage, 1);
+        }
+        Ok(map)
+    }
+}
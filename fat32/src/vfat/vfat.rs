@@ -1,53 +1,290 @@
 use std::cmp::min;
+use std::collections::HashMap;
 use std::io;
 use std::mem::size_of;
 use std::path::{Component, Path};
+use std::sync::Arc;
+use std::thread::{self, ThreadId};
 
 use mbr::MasterBootRecord;
-use traits::{BlockDevice, FileSystem};
-use util::SliceExt;
-use vfat::{BiosParameterBlock, CachedDevice, Partition};
-use vfat::{Cluster, Dir, Entry, Error, FatEntry, File, Shared, Status};
+use traits::{BlockDevice, Entry as EntryTrait, FileSystem};
+use util::{Le32, SliceExt};
+use vfat::dir::{VFatDirEntry, VFatRegularDirEntry};
+use vfat::{BiosParameterBlock, CachedDevice, EntryLocation, FreeBitmap, FreeClusters};
+use vfat::{CacheBudget, HandleTable, Journal};
+use vfat::Partition;
+use vfat::{AllocPolicy, CancelToken, Cluster, Dir, Entry, Error, FatEntry, File, MountOptions, Shared};
+use vfat::mount::quick_check;
+use vfat::{Clock, Codepage, Date, DiskUsage, MountCheck, MountProblem, Status, SystemClock};
+use vfat::{RootDirSource, Timestamp, WatchEvent, Watcher};
+#[cfg(feature = "metrics")]
+use vfat::Metrics;
 
 #[derive(Debug)]
 pub struct VFat {
-    device: CachedDevice,
+    pub(crate) device: CachedDevice,
     bytes_per_sector: u16,
     sectors_per_cluster: u8,
     sectors_per_fat: u32,
+    fats_number: u8,
     fat_start_sector: u64,
     data_start_sector: u64,
+    data_clusters: u32,
+    volume_serial: u32,
+    next_free: u32,
+    alloc_policy: AllocPolicy,
+    /// The most contiguous clusters of a file read at once, or 0 for any
+    /// number.
+    pub(crate) max_run_clusters: usize,
+    /// Whether new names are validated in Windows-compatible mode.
+    pub(crate) windows_names: bool,
+    /// The codepage short names are decoded and encoded with.
+    pub(crate) codepage: Codepage,
+    /// Whether the volume was marked dirty when it was mounted.
+    was_dirty: bool,
+    /// Whether the volume was marked dirty on the disk since it was mounted,
+    /// by the first change to it, and not yet marked clean again.
+    marked_dirty: bool,
+    hard_error: bool,
+    /// The free clusters of the volume, built from the FAT when a cluster is
+    /// first allocated and kept up to date by `set_fat_entry()`.
+    free_bitmap: Option<FreeBitmap>,
     pub root_dir_cluster: Cluster,
+    /// Where the entries of the root directory are stored.
+    pub(crate) root_dir: RootDirSource,
+    pub(crate) handles: HandleTable,
+    pub(crate) journal: Option<Journal>,
+    /// Clusters freed since the last flush, to be discarded once the FAT
+    /// entries freeing them are written.
+    freed: Vec<Cluster>,
+    /// The problems found by a `MountCheck::QuickWarn` check at mount time.
+    mount_problems: Vec<MountProblem>,
+    /// Incremented by `set_fat_entry()`, so that chains resolved from the
+    /// FAT can be cached until the FAT next changes.
+    fat_generation: u64,
+    /// The sector of the FSInfo structure, if the volume has one.
+    fsinfo_sector: Option<u64>,
+    clock: Arc<Clock>,
+    utc_offset: i32,
+    update_accessed: bool,
+    /// The access dates of entries read since the last flush, written by the
+    /// next flush.
+    pub(crate) accessed: HashMap<EntryLocation, Date>,
+    /// The disk usage of directory trees, by the first cluster of their root
+    /// directory, if it is cached.
+    pub(crate) dir_usage: Option<HashMap<Cluster, DiskUsage>>,
+    /// Incremented whenever cached disk usage is dropped, so that usage
+    /// computed while the tree changed isn't cached.
+    pub(crate) dir_usage_generation: u64,
+    /// The receivers of changes registered by `watch()`.
+    pub(crate) watchers: Vec<Watcher>,
+    /// The thread running the transaction in progress, if any. Its flushes
+    /// are put off until the transaction is committed, and other threads
+    /// can't change the volume or flush it until then.
+    pub(crate) transaction: Option<ThreadId>,
 }
 
 impl VFat {
-    pub fn from<T>(mut device: T) -> Result<Shared<VFat>, Error>
+    /// Mounts the first FAT32 partition of `device` with the default
+    /// `MountOptions`.
+    pub fn from<T>(device: T) -> Result<Shared<VFat>, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        VFat::from_with_options(device, &MountOptions::default())
+    }
+
+    /// Mounts the first FAT32 partition of `device` as configured by
+    /// `options`.
+    pub fn from_with_options<T>(
+        mut device: T,
+        options: &MountOptions,
+    ) -> Result<Shared<VFat>, Error>
     where
         T: BlockDevice + 'static,
     {
         let mbr = MasterBootRecord::from(&mut device)?;
-        let sector = mbr.first_fat32()?.sector();
+        let sector = match mbr.first_fat32() {
+            Ok(partition) => partition.sector(),
+            Err(_) if mbr.is_protective() => return Err(Error::GptDisk),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        VFat::mount(device, sector, options)
+    }
+
+    /// Mounts the volume filling `device`, whose first sector is the volume's
+    /// boot sector rather than an MBR, with the default `MountOptions`.
+    pub fn from_volume<T>(device: T) -> Result<Shared<VFat>, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        VFat::from_volume_with_options(device, &MountOptions::default())
+    }
+
+    /// Mounts the volume filling `device`, as `from_volume()` does, as
+    /// configured by `options`. Together with `PartitionDevice`, this mounts
+    /// any partition of a disk, and several partitions of the same disk at
+    /// once.
+    pub fn from_volume_with_options<T>(
+        device: T,
+        options: &MountOptions,
+    ) -> Result<Shared<VFat>, Error>
+    where
+        T: BlockDevice + 'static,
+    {
+        VFat::mount(device, 0, options)
+    }
+
+    /// Mounts the volume whose boot sector is sector `sector` of `device`.
+    fn mount<T>(mut device: T, sector: u64, options: &MountOptions) -> Result<Shared<VFat>, Error>
+    where
+        T: BlockDevice + 'static,
+    {
         let ebpb = BiosParameterBlock::from(&mut device, sector)?;
-        let fat_start_sector = sector + ebpb.sectors_reserved as u64;
+        if ebpb.bytes_per_sector() < 512
+            || ebpb.bytes_per_sector() % 512 != 0
+            || ebpb.sectors_per_cluster() == 0
+            || ebpb.fats_number() == 0
+            || ebpb.sectors_per_fat() == 0
+        {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid FAT32 geometry",
+            )));
+        }
+        // The root directory region is read through `RootDirSource`, but the
+        // FAT is only read as 32-bit entries.
+        if ebpb.has_root_dir_region() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "FAT12 and FAT16 volumes are not supported",
+            )));
+        }
+
+        let fat_start_sector = sector + ebpb.sectors_reserved() as u64;
         let data_start_sector =
-            fat_start_sector + ebpb.fats_number as u64 * ebpb.sectors_per_fat as u64;
+            fat_start_sector + ebpb.fats_number() as u64 * ebpb.sectors_per_fat() as u64;
+
+        // The number of data clusters is bounded by both the size of the
+        // volume and the number of entries in the FAT.
+        let data_sectors = (sector + ebpb.total_sectors()).saturating_sub(data_start_sector);
+        let fat_entries = ebpb.sectors_per_fat() as u64 * ebpb.bytes_per_sector() as u64 / 4;
+        let data_clusters = min(
+            data_sectors / ebpb.sectors_per_cluster() as u64,
+            fat_entries.saturating_sub(2),
+        ) as u32;
 
         let partition = Partition {
             start: sector,
-            sector_size: ebpb.bytes_per_sector as u64,
+            sector_size: ebpb.bytes_per_sector() as u64,
         };
 
-        let cache_device = CachedDevice::new(device, partition);
+        debug!(
+            "mounting volume at sector {}: {} bytes per sector, {} sectors per cluster, \
+             {} FATs of {} sectors, {} data clusters, root directory at cluster {}",
+            sector,
+            ebpb.bytes_per_sector(),
+            ebpb.sectors_per_cluster(),
+            ebpb.fats_number(),
+            ebpb.sectors_per_fat(),
+            data_clusters,
+            ebpb.root_dir_cluster()
+        );
+        let mut cache_device = CachedDevice::new(device, partition);
+        cache_device.set_read_only(options.read_only);
+        cache_device.set_verify(options.verify_cache);
+        cache_device.set_capacity(options.cache_capacity);
+        cache_device.set_budget(options.cache_budget.clone());
+        let end = data_start_sector + data_clusters as u64 * ebpb.sectors_per_cluster() as u64;
+        cache_device.set_read_ahead(options.read_ahead, end);
+        let mount_problems = match options.check {
+            MountCheck::Off => Vec::new(),
+            MountCheck::Quick | MountCheck::QuickWarn => {
+                quick_check(&mut cache_device, &ebpb, sector, data_clusters)
+            }
+        };
+        if options.check == MountCheck::Quick && !mount_problems.is_empty() {
+            return Err(Error::Corrupt(mount_problems));
+        }
+        for problem in &mount_problems {
+            warn!("volume at sector {} may be corrupt: {}", sector, problem);
+        }
+        let fsinfo_sector = match ebpb.fsinfo_sector() {
+            0 | 0xFFFF => None,
+            fsinfo if fsinfo >= ebpb.sectors_reserved() => None,
+            fsinfo => Some(sector + fsinfo as u64),
+        };
+        let next_free = match fsinfo_sector {
+            Some(fsinfo) => read_next_free_hint(&mut cache_device, fsinfo),
+            None => None,
+        };
+        let next_free = match next_free {
+            Some(hint) if hint >= 2 && hint < data_clusters + 2 => hint,
+            _ => 2,
+        };
 
-        Ok(Shared::new(VFat {
+        let vfat = Shared::new(VFat {
             device: cache_device,
-            bytes_per_sector: ebpb.bytes_per_sector,
-            sectors_per_cluster: ebpb.sectors_per_cluster,
-            sectors_per_fat: ebpb.sectors_per_fat,
-            fat_start_sector: sector + ebpb.sectors_reserved as u64,
+            bytes_per_sector: ebpb.bytes_per_sector(),
+            sectors_per_cluster: ebpb.sectors_per_cluster(),
+            sectors_per_fat: ebpb.sectors_per_fat(),
+            fats_number: ebpb.fats_number(),
+            fat_start_sector: sector + ebpb.sectors_reserved() as u64,
             data_start_sector,
-            root_dir_cluster: Cluster::from(ebpb.root_dir_cluster),
-        }))
+            data_clusters,
+            volume_serial: ebpb.volume_serial(),
+            next_free,
+            alloc_policy: options.alloc_policy,
+            max_run_clusters: options.max_run_clusters,
+            windows_names: options.windows_names,
+            codepage: options.codepage,
+            was_dirty: false,
+            marked_dirty: false,
+            hard_error: false,
+            free_bitmap: None,
+            root_dir_cluster: Cluster::from(ebpb.root_dir_cluster()),
+            root_dir: RootDirSource::from_bpb(&ebpb, sector),
+            handles: HandleTable::new(),
+            journal: None,
+            freed: Vec::new(),
+            mount_problems,
+            fat_generation: 0,
+            fsinfo_sector,
+            clock: options.clock.clone().unwrap_or_else(|| {
+                Arc::new(SystemClock {
+                    utc_offset: options.utc_offset,
+                })
+            }),
+            utc_offset: options.utc_offset,
+            update_accessed: options.update_accessed,
+            accessed: HashMap::new(),
+            dir_usage: if options.cache_dir_usage { Some(HashMap::new()) } else { None },
+            dir_usage_generation: 0,
+            watchers: Vec::new(),
+            transaction: None,
+        });
+        {
+            let mut vfat = vfat.borrow_mut();
+            let flags = vfat.read_volume_flags();
+            vfat.was_dirty = flags & CLEAN_SHUTDOWN == 0;
+            vfat.hard_error = flags & NO_HARD_ERROR == 0;
+            debug!(
+                "volume flags: dirty: {}, hard error: {}, next free cluster: {}, read-only: {}",
+                vfat.was_dirty,
+                vfat.hard_error,
+                next_free,
+                options.read_only
+            );
+        }
+        if !options.read_only {
+            vfat.load_journal()?;
+        }
+        Ok(vfat)
+    }
+
+    /// Returns `true` if the volume was mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
     }
 
     /// A method to read from an offset of a cluster into a buffer.
@@ -57,75 +294,202 @@ impl VFat {
         offset: usize,
         buf: &mut [u8],
     ) -> io::Result<usize> {
-        let first_sector_of_cluster =
-            self.data_start_sector + cluster.data_index()? as u64 * self.sectors_per_cluster as u64;
-        let last_sector_of_cluster = first_sector_of_cluster + self.sectors_per_cluster as u64;
+        let sectors = self.cluster_sectors(cluster)?;
+        let start_sector = min(sectors.start + offset as u64, sectors.end);
+        let len = min(
+            buf.len() as u64 / self.bytes_per_sector as u64,
+            sectors.end - start_sector,
+        ) as usize;
 
-        let start_sector = first_sector_of_cluster + offset as u64;
-
-        let buf_size_in_sectors = buf.len() as u64 / self.bytes_per_sector as u64;
-        let last_sector_to_read = min(last_sector_of_cluster, start_sector + buf_size_in_sectors);
+        self.device.get_run(start_sector, &mut buf[..len * self.bytes_per_sector as usize])
+    }
 
-        let mut read = 0;
-        for sec in start_sector..last_sector_to_read {
-            read += self.device.read_sector(sec, &mut buf[read..])?;
-        }
+    /// Reads the bytes of `cluster` from byte `offset` on into `buf`, as many
+    /// as `buf` holds or the cluster has past `offset`, and returns the number
+    /// of bytes read. Unlike with `read_cluster()`, whose offset is in sectors
+    /// and which reads whole sectors only, `offset` and the length of `buf`
+    /// needn't be multiples of the sector size: the sectors at either end
+    /// that are only partly read are read through the cache and copied from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `cluster` isn't a data cluster or reading from the
+    /// device fails.
+    pub fn read_cluster_bytes(
+        &mut self,
+        cluster: Cluster,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let first_sector = self.cluster_sectors(cluster)?.start;
+        let len = min(buf.len(), self.cluster_size().saturating_sub(offset));
+        self.device.read_range(first_sector, offset, &mut buf[..len])
+    }
 
-        Ok(read)
+    /// Reads the `count` consecutive clusters starting at `start` into the
+    /// start of `buf` with as few device reads as possible, and returns the
+    /// number of bytes read. `buf` must hold at least `count` clusters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `start` isn't a data cluster or reading from the
+    /// device fails.
+    pub(crate) fn read_clusters(
+        &mut self,
+        start: Cluster,
+        count: usize,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let first_sector = self.cluster_sectors(start)?.start;
+        let len = count * self.cluster_size();
+        self.device.get_run(first_sector, &mut buf[..len])
     }
 
     /// A method to read all of the clusters chained from a starting cluster
     /// into a vector.
     pub fn read_chain(&mut self, start: Cluster, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.read_chain_with_cancel(start, buf, &CancelToken::new())
+    }
+
+    /// Like `read_chain()`, but checks `cancel` before reading each cluster,
+    /// so that reading a huge or looping chain can be abandoned.
+    ///
+    /// Each run of consecutive clusters in the chain is read from the device
+    /// at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if `cancel` is cancelled before the whole
+    /// chain is read; `buf` then holds the clusters read so far.
+    pub fn read_chain_with_cancel(
+        &mut self,
+        start: Cluster,
+        buf: &mut Vec<u8>,
+        cancel: &CancelToken,
+    ) -> io::Result<usize> {
+        self.read_chain_limited(start, usize::max_value(), buf, cancel)
+    }
+
+    /// Like `read_chain()`, but appends only the first `len` bytes of the
+    /// chain to `buf`, as when reading a file of `len` bytes: the slack after
+    /// the last byte, which holds whatever was stored there before, isn't
+    /// returned, and the chain is followed only as far as needed. Returns the
+    /// number of bytes appended, less than `len` if the chain is shorter.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `read_chain()`.
+    pub fn read_chain_up_to(
+        &mut self,
+        start: Cluster,
+        len: usize,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        // A corrupt size mustn't reserve more than the volume can hold.
+        let volume_size = self.data_clusters as usize * self.cluster_size();
+        buf.reserve_exact(min(len, volume_size));
+        self.read_chain_limited(start, len, buf, &CancelToken::new())
+    }
+
+    /// Appends the first `limit` bytes of the chain starting at `start` to
+    /// `buf`, checking `cancel` before following each link.
+    fn read_chain_limited(
+        &mut self,
+        start: Cluster,
+        limit: usize,
+        buf: &mut Vec<u8>,
+        cancel: &CancelToken,
+    ) -> io::Result<usize> {
+        if limit == 0 {
+            return Ok(0);
+        }
+
+        let cluster_size = self.cluster_size();
         let mut cluster = start;
+        // The run of consecutive clusters not read yet: its first cluster and
+        // length.
+        let mut run = (start, 0);
         let mut read = 0;
 
-        while let Status::Data(next_cluster) = self.fat_entry(cluster)?.status() {
-            let buf_len = buf.len();
-            buf.resize(
-                buf_len + self.bytes_per_sector as usize * self.sectors_per_cluster as usize,
-                0,
-            );
-            read += self.read_cluster(cluster, 0, &mut buf[read..])?;
-            cluster = next_cluster;
-        }
+        loop {
+            let next = match self.fat_entry(cluster)?.status() {
+                Status::Data(next_cluster) => Some(next_cluster),
+                Status::Eoc(_eoc) => None,
+                Status::Free => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "can't read from free sector",
+                    ))
+                }
+                Status::Bad => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "can't read from bad sector",
+                    ))
+                }
+                Status::Reserved => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "can't read from reserved sector",
+                    ))
+                }
+            };
 
-        match self.fat_entry(cluster)?.status() {
-            Status::Eoc(_eoc) => {
-                let buf_len = buf.len();
-                buf.resize(
-                    buf_len + self.bytes_per_sector as usize * self.sectors_per_cluster as usize,
-                    0,
-                );
-                read += self.read_cluster(cluster, 0, &mut buf[read..])?;
-            }
-            Status::Free => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "can't read from free sector",
-                ))
+            if run.1 > 0 && cluster.fat_index() != run.0.fat_index() + run.1 as u32 {
+                read += self.read_run(run, limit - read, buf)?;
+                run = (cluster, 0);
             }
-            Status::Bad => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "can't read from bad sector",
-                ))
+            run.1 += 1;
+            if run.1 * cluster_size >= limit - read {
+                break;
             }
-            Status::Reserved => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "can't read from reserved sector",
-                ))
+
+            match next {
+                Some(next_cluster) => {
+                    if let Err(e) = cancel.check() {
+                        self.read_run(run, limit - read, buf)?;
+                        return Err(e);
+                    }
+                    cluster = next_cluster;
+                }
+                None => break,
             }
-            Status::Data(_next_cluster) => unreachable!(),
         }
+        read += self.read_run(run, limit - read, buf)?;
+
+        trace!("read chain of cluster {}: {} bytes", start.fat_index(), read);
+        Ok(read)
+    }
 
+    /// Appends the `run.1` consecutive clusters starting at `run.0` to `buf`,
+    /// but no more than `limit` bytes of them.
+    fn read_run(
+        &mut self,
+        run: (Cluster, usize),
+        limit: usize,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        let cluster_size = self.cluster_size();
+        let whole = min(run.1, limit / cluster_size);
+        let start = buf.len();
+        buf.resize(start + whole * cluster_size, 0);
+        let mut read = self.read_clusters(run.0, whole, &mut buf[start..])?;
+
+        let rest = min(limit - whole * cluster_size, cluster_size);
+        if whole < run.1 && rest > 0 {
+            let last = Cluster::from(run.0.fat_index() + whole as u32);
+            let start = buf.len();
+            buf.resize(start + rest, 0);
+            read += self.read_cluster_bytes(last, 0, &mut buf[start..])?;
+        }
         Ok(read)
     }
 
     /// A method to return a reference to a `FatEntry` for a cluster where the
     /// reference points directly into a cached sector.
     pub fn fat_entry(&mut self, cluster: Cluster) -> io::Result<&FatEntry> {
+        #[cfg(feature = "metrics")]
+        self.device.record(|m| m.fat_lookup(cluster));
         let cluster_index = cluster.fat_index() as usize;
         let fat_entries_per_sector = self.bytes_per_sector as usize / size_of::<FatEntry>();
 
@@ -134,11 +498,969 @@ impl VFat {
         let sector = self
             .device
             .get(self.fat_start_sector + sector_of_fat_entry as u64)?;
-        let fat_entries: &[FatEntry] = unsafe { sector.cast() };
+        let fat_entries: &[FatEntry] = unsafe { sector.cast()? };
 
         let fat_entry_index_in_sector = cluster_index % fat_entries_per_sector;
         Ok(&fat_entries[fat_entry_index_in_sector])
     }
+
+    /// The size of a cluster in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector as usize * self.sectors_per_cluster as usize
+    }
+
+    /// The number of data clusters in the volume. Valid data clusters are
+    /// numbered from 2 to `data_clusters() + 1`.
+    pub fn data_clusters(&self) -> u32 {
+        self.data_clusters
+    }
+
+    /// The serial number of the volume, from its boot sector.
+    pub fn volume_serial(&self) -> u32 {
+        self.volume_serial
+    }
+
+    /// Returns an error of `InvalidInput` if `cluster` isn't a data cluster.
+    fn check_data_cluster(&self, cluster: Cluster) -> io::Result<()> {
+        if cluster.fat_index() < 2 || cluster.fat_index() - 2 >= self.data_clusters {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cluster is not a data cluster",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the first sector of the data cluster `cluster`.
+    fn cluster_start_sector(&self, cluster: Cluster) -> io::Result<u64> {
+        Ok(self.data_start_sector + cluster.data_index()? as u64 * self.sectors_per_cluster as u64)
+    }
+
+    /// The size of a logical sector in bytes.
+    pub(crate) fn sector_size(&self) -> usize {
+        self.bytes_per_sector as usize
+    }
+
+    /// Returns the logical sectors of `cluster`.
+    pub(crate) fn cluster_sectors(&self, cluster: Cluster) -> io::Result<::std::ops::Range<u64>> {
+        let start = self.cluster_start_sector(cluster)?;
+        Ok(start..start + self.sectors_per_cluster as u64)
+    }
+
+    /// Returns the data cluster holding the logical sector `sector`, counted
+    /// in sectors of the volume's sector size from the start of the device,
+    /// or `None` if `sector` lies outside the data region.
+    pub fn sector_cluster(&self, sector: u64) -> Option<Cluster> {
+        let index = sector.checked_sub(self.data_start_sector)? / self.sectors_per_cluster as u64;
+        if index < self.data_clusters as u64 {
+            Some(Cluster::from(index as u32 + 2))
+        } else {
+            None
+        }
+    }
+
+    /// A method to write a buffer into a cluster at an offset (in sectors).
+    /// Only whole sectors from `buf` are written.
+    pub(crate) fn write_cluster(
+        &mut self,
+        cluster: Cluster,
+        offset: usize,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        self.begin_change()?;
+        let first_sector_of_cluster = self.cluster_start_sector(cluster)?;
+        let last_sector_of_cluster = first_sector_of_cluster + self.sectors_per_cluster as u64;
+
+        let start_sector = first_sector_of_cluster + offset as u64;
+
+        let buf_size_in_sectors = buf.len() as u64 / self.bytes_per_sector as u64;
+        let last_sector_to_write = min(last_sector_of_cluster, start_sector + buf_size_in_sectors);
+
+        let mut written = 0;
+        for sec in start_sector..last_sector_to_write {
+            written += self.device.write_sector(sec, &buf[written..])?;
+        }
+
+        Ok(written)
+    }
+
+    /// Sets the FAT entry for `cluster` to `status` in every copy of the FAT.
+    pub(crate) fn set_fat_entry(&mut self, cluster: Cluster, status: Status) -> io::Result<()> {
+        self.begin_change()?;
+        // Bumped first, as a failed update may have changed some copies.
+        self.fat_generation += 1;
+        let cluster_index = cluster.fat_index() as usize;
+        let fat_entries_per_sector = self.bytes_per_sector as usize / size_of::<FatEntry>();
+        let sector_of_fat_entry = (cluster_index / fat_entries_per_sector) as u64;
+        let fat_entry_index_in_sector = cluster_index % fat_entries_per_sector;
+
+        for fat in 0..self.fats_number as u64 {
+            let fat_start = self.fat_start_sector + fat * self.sectors_per_fat as u64;
+            let sector = self.device.get_mut_metadata(fat_start + sector_of_fat_entry)?;
+            let fat_entries: &mut [FatEntry] = unsafe { sector.cast_mut()? };
+            fat_entries[fat_entry_index_in_sector].set_status(status);
+        }
+
+        if let Some(ref mut bitmap) = self.free_bitmap {
+            bitmap.set(cluster_index as u32, status == Status::Free);
+        }
+        if status == Status::Free {
+            self.freed.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Returns a number that changes whenever an entry of the FAT is set.
+    pub(crate) fn fat_generation(&self) -> u64 {
+        self.fat_generation
+    }
+
+    /// Undoes every change made since the last flush by dropping the dirty
+    /// cached sectors and what was derived from them.
+    pub(crate) fn discard_changes(&mut self, next_free: u32) {
+        let dropped = self.device.drop_dirty();
+        debug!("discarding {} changed sectors", dropped);
+        self.next_free = next_free;
+        self.freed.clear();
+        self.accessed.clear();
+        self.invalidate_fat();
+    }
+
+    /// Returns an error of `WouldBlock` if a transaction started by another
+    /// thread is in progress, as a change or flush made now would be
+    /// committed or discarded along with it.
+    pub(crate) fn check_transaction(&self) -> io::Result<()> {
+        match self.transaction {
+            Some(owner) if owner != thread::current().id() => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "a transaction is in progress on another thread",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the volume can be changed by this thread, as
+    /// `check_transaction()` does, and marks the volume dirty on the disk if
+    /// this is the first change since it was mounted. Called before each
+    /// change to a sector of the volume.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `check_transaction()`, and an error if writing
+    /// the volume flags fails.
+    pub(crate) fn begin_change(&mut self) -> io::Result<()> {
+        self.check_transaction()?;
+        if self.marked_dirty || self.is_read_only() {
+            return Ok(());
+        }
+        self.set_volume_flags(CLEAN_SHUTDOWN, false)?;
+        self.marked_dirty = true;
+        Ok(())
+    }
+
+    /// Marks the volume clean on the disk if it was marked dirty, once its
+    /// changes were written back at the end of the session.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing the volume flags fails.
+    fn mark_clean(&mut self) -> io::Result<()> {
+        if self.marked_dirty {
+            self.set_volume_flags(CLEAN_SHUTDOWN, true)?;
+            self.marked_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Returns the hint of the next free cluster.
+    pub(crate) fn next_free(&self) -> u32 {
+        self.next_free
+    }
+
+    /// Forgets what was derived from the FAT, after the FAT was changed
+    /// other than by `set_fat_entry()`.
+    pub(crate) fn invalidate_fat(&mut self) {
+        self.fat_generation += 1;
+        self.free_bitmap = None;
+        if let Some(ref mut cache) = self.dir_usage {
+            cache.clear();
+            self.dir_usage_generation += 1;
+        }
+    }
+
+    /// The number of copies of the FAT.
+    pub(crate) fn fats_number(&self) -> u8 {
+        self.fats_number
+    }
+
+    /// Returns the logical sectors that can hold metadata: those of the FATs
+    /// and of the data region, where directories are stored.
+    pub(crate) fn metadata_sectors(&self) -> ::std::ops::Range<u64> {
+        let data_sectors = self.data_clusters as u64 * self.sectors_per_cluster as u64;
+        self.fat_start_sector..self.data_start_sector + data_sectors
+    }
+
+    /// Returns the logical sectors of copy `fat` of the FAT, numbered from 0.
+    pub(crate) fn fat_sectors(&self, fat: u8) -> ::std::ops::Range<u64> {
+        let start = self.fat_start_sector + fat as u64 * self.sectors_per_fat as u64;
+        start..start + self.sectors_per_fat as u64
+    }
+
+    /// Returns the cluster following `cluster` in its chain, or `None` if
+    /// `cluster` is the last cluster of the chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `cluster` is free, bad, or reserved.
+    pub fn next_cluster(&mut self, cluster: Cluster) -> io::Result<Option<Cluster>> {
+        match self.fat_entry(cluster)?.status() {
+            Status::Data(next) => Ok(Some(next)),
+            Status::Eoc(_) => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cluster is not part of a chain",
+            )),
+        }
+    }
+
+    /// Returns the clusters in the chain starting at `start`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the chain contains a free, bad, or reserved cluster,
+    /// or if the chain is longer than the number of clusters in the volume.
+    pub fn chain(&mut self, start: Cluster) -> io::Result<Vec<Cluster>> {
+        let mut chain = vec![start];
+        let mut cluster = start;
+        while let Some(next) = self.next_cluster(cluster)? {
+            if chain.len() > self.data_clusters as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cluster chain contains a cycle",
+                ));
+            }
+            chain.push(next);
+            cluster = next;
+        }
+        trace!("chain of cluster {}: {} clusters", start.fat_index(), chain.len());
+        Ok(chain)
+    }
+
+    /// The policy used to choose free clusters.
+    pub fn alloc_policy(&self) -> AllocPolicy {
+        self.alloc_policy
+    }
+
+    /// Sets the policy used to choose free clusters from now on.
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.alloc_policy = policy;
+    }
+
+    /// Keeps at most `sectors` sectors in the sector cache, or any number if
+    /// `sectors` is 0. The clean sectors read longest ago are dropped first;
+    /// sectors with changes that weren't flushed are always kept. A smaller
+    /// cache saves memory at the cost of reading metadata from the device
+    /// again, which matters most for slow devices.
+    pub fn set_cache_capacity(&mut self, sectors: usize) {
+        self.device.set_capacity(sectors);
+    }
+
+    /// Counts the sectors in the sector cache against `budget` as well as
+    /// against the cache's capacity, or stops counting them against a budget
+    /// if `budget` is `None`. Volumes mounted from `PartitionDevice`s of the
+    /// same disk share a clone of one budget to bound the memory the disk's
+    /// caches take together.
+    pub fn set_cache_budget(&mut self, budget: Option<CacheBudget>) {
+        self.device.set_budget(budget);
+    }
+
+    /// Reads the `sectors` sectors that follow a sector that isn't cached
+    /// along with it, so that reads smaller than a sector, like those of
+    /// directory entries and FAT entries, and small sequential file reads
+    /// take one device read per run rather than per sector. Pays off on
+    /// devices with a high cost per read; 0 disables reading ahead.
+    pub fn set_read_ahead(&mut self, sectors: usize) {
+        let clusters = self.data_clusters as u64;
+        let end = self.data_start_sector + clusters * self.sectors_per_cluster as u64;
+        self.device.set_read_ahead(sectors, end);
+    }
+
+    /// Reads at most `clusters` contiguous clusters of a file with a single
+    /// device read, or any number if `clusters` is 0. By default, a read into
+    /// a large buffer coalesces every run of contiguous clusters it covers;
+    /// limiting runs bounds the size of each device read, for devices that
+    /// handle large transfers poorly.
+    pub fn set_max_run_clusters(&mut self, clusters: usize) {
+        self.max_run_clusters = clusters;
+    }
+
+    /// Turns caching of the disk usage computed by `du()` on or off, as with
+    /// `MountOptions::cache_dir_usage`. Turning it off drops the cache.
+    pub fn set_dir_usage_cache(&mut self, enabled: bool) {
+        if enabled != self.dir_usage.is_some() {
+            self.dir_usage = if enabled { Some(HashMap::new()) } else { None };
+            self.dir_usage_generation += 1;
+        }
+    }
+
+    /// Drops the cached disk usage of the directory starting at `dir` and of
+    /// every directory above it, found by following `..` entries up to the
+    /// root. If the ancestors can't be found, the whole cache is dropped.
+    fn invalidate_dir_usage(&mut self, dir: Cluster) {
+        match self.dir_usage {
+            Some(ref cache) if !cache.is_empty() => {}
+            _ => return,
+        }
+        self.dir_usage_generation += 1;
+
+        let mut dir = dir;
+        for _ in 0..self.data_clusters + 1 {
+            self.dir_usage.as_mut().expect("usage cache").remove(&dir);
+            if dir == self.root_dir_cluster || dir.fat_index() < 2 {
+                return;
+            }
+            match self.dir_entry(EntryLocation { dir, index: 1 }) {
+                Ok(ref dotdot) if dotdot.short_name() == *b"..         " => {
+                    dir = match dotdot.cluster() {
+                        parent if parent.fat_index() == 0 => self.root_dir_cluster,
+                        parent => parent,
+                    };
+                }
+                _ => break,
+            }
+        }
+        self.dir_usage = Some(HashMap::new());
+    }
+
+    /// Returns the current time of the volume's clock.
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// Stamps new and modified entries with the times of `clock` from now on.
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+    }
+
+    /// Seconds east of UTC of the local time the volume's timestamps are
+    /// stored in, as set in `MountOptions::utc_offset`.
+    pub fn utc_offset(&self) -> i32 {
+        self.utc_offset
+    }
+
+    /// Records that the file with the entry at `location`, last accessed on
+    /// `accessed`, was just read, and returns the new access date, if the
+    /// volume updates access dates and `accessed` isn't already today.
+    pub(crate) fn record_access(
+        &mut self,
+        location: EntryLocation,
+        accessed: Date,
+    ) -> Option<Date> {
+        if !self.update_accessed || self.is_read_only() {
+            return None;
+        }
+        let today = self.now().date;
+        if today == accessed {
+            return None;
+        }
+        self.accessed.insert(location, today);
+        Some(today)
+    }
+
+    /// Writes the access dates recorded by `record_access()` to the cached
+    /// directory entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a directory entry fails. The dates that
+    /// weren't written are dropped.
+    pub(crate) fn write_accessed(&mut self) -> io::Result<()> {
+        let accessed = ::std::mem::replace(&mut self.accessed, HashMap::new());
+        for (location, date) in accessed {
+            self.update_dir_entry(location, |entry| entry.set_accessed(date))?;
+        }
+        Ok(())
+    }
+
+    /// Reports the volume's I/O to `metrics` from now on, or to nothing if
+    /// `metrics` is `None`.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.device.set_metrics(metrics);
+    }
+
+    /// Allocates a free cluster chosen by the volume's `AllocPolicy`, marks it
+    /// as the end of its chain, and links it after `prev`, if given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if the volume has no free clusters.
+    pub(crate) fn alloc_cluster(&mut self, prev: Option<Cluster>) -> io::Result<Cluster> {
+        let (policy, next_free) = (self.alloc_policy, self.next_free);
+        let index = {
+            let bitmap = self.free_bitmap()?;
+            match policy {
+                AllocPolicy::FirstFit => bitmap.find_free(2),
+                AllocPolicy::NextFit => bitmap.find_free(next_free),
+                AllocPolicy::Contiguous => match prev {
+                    Some(prev) if bitmap.is_free(prev.fat_index() + 1) => Some(prev.fat_index() + 1),
+                    _ => bitmap.longest_free_run().map(|(start, _)| start),
+                },
+            }
+        };
+        let index = index.ok_or(io::Error::new(io::ErrorKind::Other, "no free clusters"))?;
+        let cluster = Cluster::from(index);
+
+        self.set_fat_entry(cluster, Status::Eoc(0x0FFF_FFFF))?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, Status::Data(cluster))?;
+        }
+        self.next_free = 2 + (index - 1) % self.data_clusters;
+        Ok(cluster)
+    }
+
+    /// Allocates a run of `count` consecutive free clusters, chains them
+    /// together, and links the first after `prev`, if given. The run follows
+    /// `prev` directly if the clusters there are free and is otherwise the
+    /// lowest-numbered run that is long enough. Returns the first cluster of
+    /// the run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if the volume has no run of `count` free
+    /// clusters.
+    pub(crate) fn alloc_contiguous(
+        &mut self,
+        prev: Option<Cluster>,
+        count: u32,
+    ) -> io::Result<Cluster> {
+        let start = {
+            let bitmap = self.free_bitmap()?;
+            let after_prev = prev.map(|prev| prev.fat_index() + 1);
+            match after_prev {
+                Some(next) if bitmap.find_free_run(next, count) == Some(next) => Some(next),
+                _ => bitmap.find_free_run(2, count),
+            }
+        };
+        let start = start.ok_or(io::Error::new(
+            io::ErrorKind::Other,
+            "no run of free clusters is long enough",
+        ))?;
+
+        for index in start..start + count - 1 {
+            self.set_fat_entry(Cluster::from(index), Status::Data(Cluster::from(index + 1)))?;
+        }
+        self.set_fat_entry(Cluster::from(start + count - 1), Status::Eoc(0x0FFF_FFFF))?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, Status::Data(Cluster::from(start)))?;
+        }
+        Ok(Cluster::from(start))
+    }
+
+    /// Returns the bitmap of free clusters, reading the whole FAT to build it
+    /// if it hasn't been built yet.
+    pub(crate) fn free_bitmap(&mut self) -> io::Result<&mut FreeBitmap> {
+        if self.free_bitmap.is_none() {
+            let end = self.data_clusters as usize + 2;
+            let mut free = vec![false; end];
+            for index in 2..end {
+                let cluster = Cluster::from(index as u32);
+                free[index] = self.fat_entry(cluster)?.status() == Status::Free;
+            }
+            self.free_bitmap = Some(FreeBitmap::new(&free));
+        }
+        Ok(self.free_bitmap.as_mut().expect("free bitmap"))
+    }
+
+    /// Fills `cluster` with zeroes.
+    pub(crate) fn zero_cluster(&mut self, cluster: Cluster) -> io::Result<()> {
+        let zeroes = vec![0u8; self.cluster_size()];
+        self.write_cluster(cluster, 0, &zeroes)?;
+        Ok(())
+    }
+
+    /// Returns the number of free data clusters in the volume, reading the
+    /// whole FAT the first time it is needed.
+    pub(crate) fn free_cluster_count(&mut self) -> io::Result<u32> {
+        Ok(self.free_bitmap()?.count())
+    }
+
+    /// Returns an iterator over the free data clusters of the volume in
+    /// cluster order, reading the whole FAT the first time it is needed. Use
+    /// `runs()` on it to iterate over runs of contiguous free clusters
+    /// instead.
+    ///
+    /// The iterator works on a snapshot of the free clusters, so it doesn't
+    /// borrow the volume and isn't affected by later allocations.
+    pub fn free_clusters(&mut self) -> io::Result<FreeClusters> {
+        Ok(self.free_bitmap()?.iter())
+    }
+
+    /// Returns a vector indexed by cluster number that is `true` for every
+    /// free data cluster in the volume.
+    pub(crate) fn free_map(&mut self) -> io::Result<Vec<bool>> {
+        let end = self.data_clusters + 2;
+        let bitmap = self.free_bitmap()?;
+        Ok((0..end).map(|index| bitmap.is_free(index)).collect())
+    }
+
+    /// Returns the sector and the byte offset within that sector of the
+    /// directory entry at `location`.
+    fn dir_entry_position(&mut self, location: EntryLocation) -> io::Result<(u64, usize)> {
+        let cluster_size = self.cluster_size();
+        let byte_offset = location.index * size_of::<VFatDirEntry>();
+
+        let mut cluster = location.dir;
+        for _ in 0..byte_offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "directory entry beyond end of directory",
+            ))?;
+        }
+
+        let offset_in_cluster = byte_offset % cluster_size;
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let sector = self.cluster_start_sector(cluster)? + (offset_in_cluster / bytes_per_sector) as u64;
+        Ok((sector, offset_in_cluster % bytes_per_sector))
+    }
+
+    /// Returns a copy of the regular directory entry at `location`.
+    pub(crate) fn dir_entry(&mut self, location: EntryLocation) -> io::Result<VFatRegularDirEntry> {
+        let (sector, offset) = self.dir_entry_position(location)?;
+        let mut data = [0u8; 32];
+        self.device.read_range(sector, offset, &mut data)?;
+        let entries: &[VFatDirEntry] = unsafe { data.cast()? };
+        Ok(entries[0].regular())
+    }
+
+    /// Returns a mutable reference to the directory entry at `location` in its
+    /// cached sector. The sector is marked dirty.
+    pub(crate) fn dir_entry_mut(&mut self, location: EntryLocation) -> io::Result<&mut VFatDirEntry> {
+        self.begin_change()?;
+        self.invalidate_dir_usage(location.dir);
+        let (sector, offset) = self.dir_entry_position(location)?;
+        let data = self.device.get_mut_metadata(sector)?;
+        let entries: &mut [VFatDirEntry] =
+            unsafe { data[offset..offset + size_of::<VFatDirEntry>()].cast_mut()? };
+        Ok(&mut entries[0])
+    }
+
+    /// Applies `f` to the regular directory entry at `location`, writing the
+    /// modified entry back to the cached sector.
+    pub(crate) fn update_dir_entry<F>(&mut self, location: EntryLocation, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut VFatRegularDirEntry),
+    {
+        f(self.dir_entry_mut(location)?.regular_mut());
+        Ok(())
+    }
+
+    /// Marks every cluster in the chain starting at `start` as free.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `chain()`.
+    pub(crate) fn free_chain(&mut self, start: Cluster) -> io::Result<()> {
+        for cluster in self.chain(start)? {
+            self.set_fat_entry(cluster, Status::Free)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all dirty cached sectors back to the underlying device, through
+    /// the journal if the volume has one. The access dates of files read
+    /// since the last flush, if the volume updates them, are written first.
+    /// Flushes are put off while a transaction, see `transaction()`, is in
+    /// progress.
+    ///
+    /// Sectors are written in three groups: file contents and other sectors
+    /// that aren't metadata, then the FAT, then directory entries. A flush
+    /// that a crash interrupts can thus leave newly allocated clusters that
+    /// no entry refers to, which a check reports as lost, but never an entry
+    /// that refers to clusters whose contents or FAT entries weren't written.
+    /// Without a journal, each group is written in ascending sector order.
+    /// A flush doesn't mark the volume clean on the disk: it stays marked
+    /// dirty from its first change until it is unmounted or dropped, so that
+    /// `was_dirty()` reports a volume that was changed and then removed
+    /// without being unmounted the next time it is mounted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the device fails. Sectors that were not
+    /// written remain dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_back(false)
+    }
+
+    /// Like `flush()`, but also waits for the device to persist each group of
+    /// sectors before writing the next, and the last group before returning,
+    /// so that the order of `flush()` holds on devices that cache or reorder
+    /// writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `flush()` and of the device's `sync()`.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.write_back(true)
+    }
+
+    fn write_back(&mut self, sync: bool) -> io::Result<()> {
+        self.check_transaction()?;
+        if self.transaction.is_some() {
+            // The transaction's own changes are written when it is committed.
+            return Ok(());
+        }
+        self.write_accessed()?;
+        let (data, fat, dirs) = self.dirty_sectors();
+        if data.is_empty() && fat.is_empty() && dirs.is_empty() {
+            return self.discard_freed();
+        }
+        debug!(
+            "writing back {} data, {} FAT, and {} directory sectors (sync: {}, journal: {})",
+            data.len(),
+            fat.len(),
+            dirs.len(),
+            sync,
+            self.journal.is_some()
+        );
+
+        // Changes are only made after marking the volume dirty, but a flush
+        // never writes without it.
+        self.begin_change()?;
+        if sync {
+            self.device.sync()?;
+        }
+        match self.journal {
+            Some(ref mut journal) => {
+                let metadata: Vec<u64> = fat.into_iter().chain(dirs).collect();
+                journal.flush(&mut self.device, &data, &metadata, sync)?;
+            }
+            None => {
+                for group in &[data, fat, dirs] {
+                    for &sector in group {
+                        self.device.write_back(sector)?;
+                    }
+                    if sync && !group.is_empty() {
+                        self.device.sync()?;
+                    }
+                }
+            }
+        }
+        self.device.shrink();
+        self.discard_freed()
+    }
+
+    /// Writes the number of free clusters and the next-free cluster hint to
+    /// the FSInfo structure, if the volume has a valid one. Nothing is written
+    /// if the FAT hasn't changed since the volume was mounted or the structure
+    /// already holds both values.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the FAT or writing to the device fails.
+    fn write_fsinfo(&mut self) -> io::Result<()> {
+        let sector = match self.fsinfo_sector {
+            Some(sector) if self.fat_generation > 0 && !self.is_read_only() => sector,
+            _ => return Ok(()),
+        };
+        let free = self.free_cluster_count()?;
+        let next_free = self.next_free;
+        {
+            let data = self.device.get(sector)?;
+            if data.len() < 512
+                || Le32::read(data, 0) != 0x4161_5252
+                || Le32::read(data, 484) != 0x6141_7272
+                || (Le32::read(data, 488) == free && Le32::read(data, 492) == next_free)
+            {
+                return Ok(());
+            }
+        }
+
+        debug!("writing FSInfo: {} free clusters, next free cluster: {}", free, next_free);
+        self.device.update_on_disk(sector, |data| {
+            Le32::write(data, 488, free);
+            Le32::write(data, 492, next_free);
+        })
+    }
+
+    /// Returns `true` if the volume was marked dirty when it was mounted:
+    /// it wasn't unmounted cleanly, or a flush was interrupted, and may need
+    /// to be checked.
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty
+    }
+
+    /// Returns the problems found by a `MountCheck::QuickWarn` check when the
+    /// volume was mounted, empty if none were found or no check was run.
+    pub fn mount_problems(&self) -> &[MountProblem] {
+        &self.mount_problems
+    }
+
+    /// Returns `true` if the volume is marked as having had a disk I/O error.
+    pub fn has_hard_error(&self) -> bool {
+        self.hard_error
+    }
+
+    /// Sets or clears the mark that the volume had a disk I/O error, writing
+    /// it to the disk immediately.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if writing to the device fails.
+    pub fn set_hard_error(&mut self, hard_error: bool) -> io::Result<()> {
+        self.set_volume_flags(NO_HARD_ERROR, !hard_error)?;
+        self.hard_error = hard_error;
+        Ok(())
+    }
+
+    /// Returns the high bits of the FAT entry of cluster 1, which hold the
+    /// volume flags, or `0` if the FAT can't be read.
+    fn read_volume_flags(&mut self) -> u32 {
+        let sector = self.fat_start_sector;
+        match self.device.get(sector) {
+            Ok(data) => Le32::read(data, 4) & (CLEAN_SHUTDOWN | NO_HARD_ERROR),
+            Err(_) => 0,
+        }
+    }
+
+    /// Sets or clears the volume flags in `mask` in the FAT entry of cluster
+    /// 1 of every FAT, writing them to the disk immediately and leaving any
+    /// pending changes to the FAT pending.
+    fn set_volume_flags(&mut self, mask: u32, set: bool) -> io::Result<()> {
+        for fat in 0..self.fats_number as u64 {
+            let sector = self.fat_start_sector + fat * self.sectors_per_fat as u64;
+            self.device.update_on_disk(sector, |data| {
+                let entry = Le32::read(data, 4);
+                Le32::write(data, 4, if set { entry | mask } else { entry & !mask });
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Discards the sectors of the clusters freed since the last flush that
+    /// are still free, in runs of contiguous clusters. Clusters that were
+    /// allocated again in the meantime are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the FAT or discarding fails. Discarding is
+    /// only a hint, so the clusters that weren't discarded are forgotten.
+    fn discard_freed(&mut self) -> io::Result<()> {
+        let mut freed = ::std::mem::replace(&mut self.freed, Vec::new());
+        freed.sort();
+        freed.dedup();
+
+        let mut runs: Vec<(Cluster, u64)> = Vec::new();
+        for cluster in freed {
+            if self.fat_entry(cluster)?.status() != Status::Free {
+                continue;
+            }
+            if let Some(run) = runs.last_mut() {
+                if run.0.fat_index() as u64 + run.1 == cluster.fat_index() as u64 {
+                    run.1 += 1;
+                    continue;
+                }
+            }
+            runs.push((cluster, 1));
+        }
+
+        let sectors_per_cluster = self.sectors_per_cluster as u64;
+        for (start, len) in runs {
+            let first_sector = self.cluster_sectors(start)?.start;
+            self.device.discard(first_sector, len * sectors_per_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the dirty cached sectors split into sectors that aren't
+    /// metadata, FAT sectors, and directory sectors, each in ascending order.
+    fn dirty_sectors(&self) -> (Vec<u64>, Vec<u64>, Vec<u64>) {
+        let (mut data, mut fat, mut dirs) = (Vec::new(), Vec::new(), Vec::new());
+        for (sector, is_metadata) in self.device.dirty_sectors() {
+            if !is_metadata {
+                data.push(sector);
+            } else if sector >= self.fat_start_sector && sector < self.data_start_sector {
+                fat.push(sector);
+            } else {
+                dirs.push(sector);
+            }
+        }
+        (data, fat, dirs)
+    }
+}
+
+/// Bit of the FAT entry of cluster 1 that is set while the volume is cleanly
+/// unmounted.
+const CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+/// Bit of the FAT entry of cluster 1 that is cleared after a disk I/O error.
+const NO_HARD_ERROR: u32 = 0x0400_0000;
+
+/// Reads the next-free cluster hint from the FSInfo structure in sector
+/// `sector`, if the sector holds one and the hint is set.
+fn read_next_free_hint(device: &mut CachedDevice, sector: u64) -> Option<u32> {
+    let data = device.get(sector).ok()?;
+    if data.len() < 512 {
+        return None;
+    }
+
+    let valid = Le32::read(data, 0) == 0x4161_5252 && Le32::read(data, 484) == 0x6141_7272;
+    match Le32::read(data, 492) {
+        0xFFFF_FFFF => None,
+        hint if valid => Some(hint),
+        _ => None,
+    }
+}
+
+impl Drop for VFat {
+    /// Writes back the volume's dirty sectors and FSInfo and marks the volume
+    /// clean, as `unmount()` does. Errors can't be returned from a drop, so
+    /// they are logged; call `unmount()` to handle them.
+    fn drop(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+        let result = self.flush().and_then(|_| self.write_fsinfo());
+        if let Err(e) = result.and_then(|_| self.mark_clean()) {
+            warn!("failed to write back volume while dropping it: {}", e);
+        }
+    }
+}
+
+impl Shared<VFat> {
+    /// Ends the session with the volume: writes back its dirty sectors, then
+    /// the number of free clusters and the next-free cluster hint to the
+    /// FSInfo structure, waits for the device to persist them, and marks the
+    /// volume clean. A volume that is dropped without being unmounted is
+    /// written back the same way, but errors are only logged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if other handles to the volume, such as
+    /// open files and directories, are still alive; the volume is then
+    /// written back once the last of them is dropped. Returns the errors of
+    /// `VFat::sync()` and any error that occurs while writing the FSInfo
+    /// structure or the volume flags.
+    pub fn unmount(self) -> io::Result<()> {
+        let mut vfat = self.try_unwrap().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "volume is still in use")
+        })?;
+        if vfat.is_read_only() {
+            return Ok(());
+        }
+        vfat.sync()?;
+        vfat.write_fsinfo()?;
+        vfat.device.sync()?;
+        vfat.mark_clean()?;
+        vfat.device.sync()
+    }
+
+    /// Splits the absolute path `path` into its parent directory, which is
+    /// opened, and its last component.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `path` is not absolute, has no
+    /// last component, is not valid UTF-8, or if its parent is not a
+    /// directory, and an error of `NotFound` if its parent doesn't exist.
+    pub(crate) fn parent_dir<'p>(&self, path: &'p Path) -> io::Result<(Dir, &'p str)> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if !path.has_root() {
+            return Err(invalid("path must be absolute"));
+        }
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(invalid("path has no valid last component"))?;
+        let parent = path.parent().ok_or(invalid("path has no parent"))?;
+        let dir = self.open_dir(parent).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                io::Error::new(io::ErrorKind::NotFound, "parent directory doesn't exist")
+            }
+            io::ErrorKind::Other => invalid("parent is not a directory"),
+            _ => e,
+        })?;
+        Ok((dir, name))
+    }
+
+    /// Sets the creation, modification, and last access times of the file or
+    /// directory at `path`. FAT stores creation times with 10 ms resolution,
+    /// modification times with two-second resolution, and only the date of
+    /// the last access, so `modified` is rounded down to an even second and
+    /// the time of day of `accessed` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`. Returns an error of
+    /// `InvalidInput` if `path` is the root directory, which has no times.
+    pub fn set_times<P: AsRef<Path>>(
+        &self,
+        path: P,
+        created: Timestamp,
+        modified: Timestamp,
+        accessed: Timestamp,
+    ) -> io::Result<()> {
+        let entry = self.open(path)?;
+        let location = entry.location().ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root directory has no times",
+        ))?;
+        let mut vfat = self.borrow_mut();
+        vfat.update_dir_entry(location, |entry| entry.set_times(created, modified, accessed))?;
+        vfat.notify(location.dir, WatchEvent::Modified(entry.name().into()));
+        Ok(())
+    }
+
+    /// Returns the directory whose first cluster is `cluster`, bypassing the
+    /// directory tree. Meant for recovery tools that find a directory's
+    /// clusters, e.g. by scanning for `.` and `..` entries, after the entry
+    /// pointing to it was damaged or deleted. The directory has no name and
+    /// default metadata; it can be read and written like any other, but the
+    /// clusters aren't checked to hold directory entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `cluster` isn't a data cluster
+    /// of the volume.
+    pub fn open_cluster_as_dir(&self, cluster: Cluster) -> io::Result<Dir> {
+        self.borrow().check_data_cluster(cluster)?;
+        Ok(Dir::at_cluster(self.clone(), cluster))
+    }
+
+    /// Returns the file of `size` bytes whose cluster chain starts at
+    /// `cluster`, bypassing the directory tree, so that the contents of a
+    /// file can be read when its directory entry is damaged or missing. The
+    /// file has no name and default metadata. Having no directory entry to
+    /// update, it can't be written to.
+    ///
+    /// Reading past the end of the chain returns an error of `InvalidData`,
+    /// so a `size` found elsewhere, or guessed, can be checked by reading.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `cluster` isn't a data cluster
+    /// of the volume or `size` is beyond the 4 GiB FAT32 limit.
+    pub fn open_cluster_chain_as_file(&self, cluster: Cluster, size: u64) -> io::Result<File> {
+        self.borrow().check_data_cluster(cluster)?;
+        if size > ::std::u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size exceeds FAT32 limit",
+            ));
+        }
+
+        Ok(File {
+            name: String::new(),
+            cluster,
+            vfat: self.clone(),
+            metadata: Default::default(),
+            size: size as usize,
+            read_ptr: 0,
+            location: None,
+            _handle: None,
+            chain_cache: None,
+        })
+    }
 }
 
 impl<'a> FileSystem for &'a Shared<VFat> {
@@ -150,42 +1472,72 @@ impl<'a> FileSystem for &'a Shared<VFat> {
         use traits::Entry;
         use vfat::Entry as VFatEntry;
 
-        let mut cur_dir = VFatEntry::Dir(Dir::root(self.clone()));
+        // The entries along the path so far, so that `..` can return to the
+        // previous one. As on Unix, the parent of the root is the root.
+        let mut entries = vec![VFatEntry::Dir(Dir::root(self.clone()))];
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "File not found");
 
         for comp in path.as_ref().components() {
             match comp {
                 Component::Normal(name) => {
-                    cur_dir = cur_dir
-                        .as_dir()
-                        .ok_or(io::Error::new(io::ErrorKind::NotFound, "File not found"))?
-                        .find(name)?
+                    let entry = entries
+                        .last()
+                        .and_then(|e| e.as_dir())
+                        .ok_or(not_found())?
+                        .find(name)?;
+                    entries.push(entry);
+                }
+                Component::RootDir => entries.truncate(1),
+                Component::CurDir | Component::ParentDir => {
+                    if entries.last().and_then(|e| e.as_dir()).is_none() {
+                        return Err(not_found());
+                    }
+                    if comp == Component::ParentDir && entries.len() > 1 {
+                        entries.pop();
+                    }
+                }
+                Component::Prefix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "path prefixes are not supported",
+                    ))
                 }
-                Component::RootDir => {}
-                Component::CurDir => unimplemented!("CurDir"),
-                Component::ParentDir => unimplemented!("ParentDir"),
-                Component::Prefix(_) => unimplemented!("Prefix"),
             }
         }
-        Ok(cur_dir)
+        Ok(entries.pop().expect("root entry"))
     }
 
-    fn create_file<P: AsRef<Path>>(self, _path: P) -> io::Result<Self::File> {
-        unimplemented!("read only file system")
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let (parent, name) = self.parent_dir(path.as_ref())?;
+        parent.create_file(name)
     }
 
-    fn create_dir<P>(self, _path: P, _parents: bool) -> io::Result<Self::Dir>
+    fn create_dir<P>(self, path: P, parents: bool) -> io::Result<Self::Dir>
     where
         P: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        let path = path.as_ref();
+        if parents {
+            if let Some(parent) = path.parent() {
+                match self.open(parent) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        self.create_dir(parent, true)?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let (parent, name) = self.parent_dir(path)?;
+        parent.create_dir(name)
     }
 
-    fn rename<P, Q>(self, _from: P, _to: Q) -> io::Result<()>
+    fn rename<P, Q>(self, from: P, to: Q) -> io::Result<()>
     where
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        self.move_entry(from.as_ref(), to.as_ref(), false)
     }
 
     fn remove<P: AsRef<Path>>(self, _path: P, _children: bool) -> io::Result<()> {
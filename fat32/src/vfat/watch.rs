@@ -0,0 +1,114 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use traits::FileSystem;
+use vfat::{Cluster, Entry, Shared, VFat};
+
+/// A change to an entry, sent to the receivers returned by `watch()`.
+///
+/// Paths start with the path that was passed to `watch()`. Only changes made
+/// through this crate are reported, as they are made, whether or not they
+/// were flushed yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The file or directory at the path was created.
+    Created(PathBuf),
+    /// The entry at the path was removed. An entry moved to another directory
+    /// is reported as removed from the directory it was in.
+    Removed(PathBuf),
+    /// The contents, size, or times of the file at the path changed, or the
+    /// entry at the path was replaced by an entry moved from another
+    /// directory.
+    Modified(PathBuf),
+    /// The entry at `from` was renamed to `to`, in the same directory. An
+    /// entry moved to another directory is reported as `Created` there.
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+impl WatchEvent {
+    /// Returns `true` if the event changes the entry named `name`.
+    fn concerns(&self, name: &str) -> bool {
+        let eq = |path: &PathBuf| path.to_str().map_or(false, |p| p.to_lowercase() == name);
+        match *self {
+            WatchEvent::Created(ref path)
+            | WatchEvent::Removed(ref path)
+            | WatchEvent::Modified(ref path) => eq(path),
+            WatchEvent::Renamed { ref from, ref to } => eq(from) || eq(to),
+        }
+    }
+
+    /// Returns the event with `base` prepended to its paths.
+    fn under(&self, base: &Path) -> WatchEvent {
+        match *self {
+            WatchEvent::Created(ref path) => WatchEvent::Created(base.join(path)),
+            WatchEvent::Removed(ref path) => WatchEvent::Removed(base.join(path)),
+            WatchEvent::Modified(ref path) => WatchEvent::Modified(base.join(path)),
+            WatchEvent::Renamed { ref from, ref to } => WatchEvent::Renamed {
+                from: base.join(from),
+                to: base.join(to),
+            },
+        }
+    }
+}
+
+/// A receiver of the changes to the entries of a directory.
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    /// The first cluster of the directory.
+    dir: Cluster,
+    /// The path of the directory, as passed to `watch()`.
+    path: PathBuf,
+    /// The lowercase name of the only entry to report changes of, if the
+    /// watched path is a file.
+    name: Option<String>,
+    sender: Sender<WatchEvent>,
+}
+
+impl VFat {
+    /// Sends `event`, whose paths are names of entries in the directory that
+    /// starts at `dir`, to the watchers of that directory. A watcher whose
+    /// receiver was dropped is removed when an event would be sent to it.
+    pub(crate) fn notify(&mut self, dir: Cluster, event: WatchEvent) {
+        self.watchers.retain(|watcher| {
+            match watcher.name {
+                _ if watcher.dir != dir => return true,
+                Some(ref name) if !event.concerns(name) => return true,
+                _ => {}
+            }
+            watcher.sender.send(event.under(&watcher.path)).is_ok()
+        });
+    }
+}
+
+impl Shared<VFat> {
+    /// Watches the entry at `path` and returns a receiver of the changes made
+    /// to it through this crate: if `path` is a directory, the creation,
+    /// removal, modification, and renaming of the entries in it, not in its
+    /// subdirectories; if `path` is a file, the changes to the entry with its
+    /// name in its directory. Dropping the receiver stops the watch.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `open()` for `path`.
+    pub fn watch<P: AsRef<Path>>(&self, path: P) -> io::Result<Receiver<WatchEvent>> {
+        let path = path.as_ref();
+        let (dir, base, name) = match self.open(path)? {
+            Entry::Dir(ref dir) => (dir.cluster(), path, None),
+            Entry::File(ref file) => {
+                let location = file.location.expect("file has a location");
+                let parent = path.parent().unwrap_or(path);
+                (location.dir, parent, Some(file.name().to_lowercase()))
+            }
+        };
+
+        let (sender, receiver) = channel();
+        self.borrow_mut().watchers.push(Watcher {
+            dir,
+            path: base.to_path_buf(),
+            name,
+            sender,
+        });
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,75 @@
+//! Support for running in the browser on `wasm32-unknown-unknown`.
+//!
+//! The driver itself only needs a `BlockDevice`; `ArrayBufferDevice` provides
+//! one backed by a JavaScript `ArrayBuffer`, such as the contents of an image
+//! file selected by the user. Host file system support (`copy_from_host()` and
+//! friends) is not available on this target. Build the library alone with
+//! `cargo build --lib --target wasm32-unknown-unknown --features wasm`.
+
+use std::io;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+
+use traits::BlockDevice;
+
+/// A `BlockDevice` backed by a JavaScript `ArrayBuffer`.
+///
+/// Writes go directly to the buffer, so the modified image can be handed back
+/// to JavaScript with `array()`.
+pub struct ArrayBufferDevice {
+    data: Uint8Array,
+}
+
+// JavaScript values cannot be shared between threads, but
+// `wasm32-unknown-unknown` only ever runs a single thread.
+unsafe impl Send for ArrayBufferDevice {}
+
+impl ArrayBufferDevice {
+    /// Returns a device reading from and writing to `buffer`.
+    pub fn new(buffer: &ArrayBuffer) -> ArrayBufferDevice {
+        ArrayBufferDevice::from_array(Uint8Array::new(buffer))
+    }
+
+    /// Returns a device reading from and writing to the bytes viewed by
+    /// `data`.
+    pub fn from_array(data: Uint8Array) -> ArrayBufferDevice {
+        ArrayBufferDevice { data }
+    }
+
+    /// Returns the bytes backing the device.
+    pub fn array(&self) -> &Uint8Array {
+        &self.data
+    }
+
+    /// Returns a view of the `len` bytes of sector `n`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `UnexpectedEof` if the sector extends past the end
+    /// of the buffer.
+    fn sector(&self, n: u64, len: usize) -> io::Result<Uint8Array> {
+        let start = n * self.sector_size();
+        let end = start + len as u64;
+        if end > self.data.length() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sector beyond end of buffer",
+            ));
+        }
+        Ok(self.data.subarray(start as u32, end as u32))
+    }
+}
+
+impl BlockDevice for ArrayBufferDevice {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = ::std::cmp::min(self.sector_size() as usize, buf.len());
+        self.sector(n, to_read)?.copy_to(&mut buf[..to_read]);
+        Ok(to_read)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        let to_write = ::std::cmp::min(self.sector_size() as usize, buf.len());
+        self.sector(n, to_write)?.copy_from(&buf[..to_write]);
+        Ok(to_write)
+    }
+}
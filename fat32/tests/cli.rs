@@ -0,0 +1,114 @@
+//! Integration tests of the `fat32` command-line tool against a freshly
+//! formatted image.
+
+extern crate fat32;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use fat32::traits::FileSystem;
+use fat32::vfat::{format, FormatOptions, VFat};
+
+/// The size of the image, in sectors.
+const SECTORS: u64 = 8192;
+/// The sector the image's only partition starts at.
+const PARTITION_START: u64 = 63;
+
+/// Returns the path of the `fat32` binary, which Cargo builds into the parent
+/// of the test executable's directory.
+fn binary() -> PathBuf {
+    let mut path = env::current_exe().expect("test executable");
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("fat32{}", env::consts::EXE_SUFFIX))
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(binary()).args(args).output().expect("run fat32")
+}
+
+fn read_host(path: &Path) -> Vec<u8> {
+    let mut data = Vec::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .expect("read host file");
+    data
+}
+
+/// Writes an image with an MBR and one formatted FAT32 partition, holding
+/// `/DIR/HELLO.TXT`, to `path`.
+fn make_image(path: &Path) {
+    let mut mbr = [0u8; 512];
+    mbr[446 + 4] = 0x0C;
+    for i in 0..4 {
+        mbr[446 + 8 + i] = (PARTITION_START >> (i * 8)) as u8;
+        mbr[446 + 12 + i] = ((SECTORS - PARTITION_START) >> (i * 8)) as u8;
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .expect("create image");
+    file.set_len(SECTORS * 512).expect("size image");
+    file.write_all(&mbr).expect("write MBR");
+    let options = FormatOptions::default();
+    format(&mut file, PARTITION_START, SECTORS - PARTITION_START, &options)
+        .expect("format image");
+
+    let vfat = VFat::from(file).expect("mount image");
+    vfat.create_dir("/DIR", false).expect("create directory");
+    vfat.create_file("/DIR/HELLO.TXT")
+        .and_then(|mut f| f.write_all(b"hello, world\n"))
+        .expect("write file");
+    vfat.unmount().expect("unmount image");
+}
+
+#[test]
+fn cli_ls_cat_cp() {
+    let dir = env::temp_dir().join(format!("fat32-cli-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create scratch directory");
+    let image = dir.join("card.img");
+    make_image(&image);
+    let image = image.to_str().expect("UTF-8 path");
+
+    let output = run(&["ls", image, "/DIR"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("HELLO.TXT"));
+    let output = run(&["cat", image, "/DIR/HELLO.TXT"]);
+    assert_eq!(output.stdout, b"hello, world\n");
+
+    // `.` and `..` are resolved, and a bad path is an error, not a crash.
+    let output = run(&["ls", image, "/DIR/.."]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("DIR"));
+    let output = run(&["cat", image, "/DIR/./../DIR/HELLO.TXT"]);
+    assert_eq!(output.stdout, b"hello, world\n");
+    let output = run(&["ls", image, "/MISSING/.."]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+
+    let host = dir.join("hello.txt");
+    let output = run(&["cp", image, "::/DIR/HELLO.TXT", host.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(read_host(&host), b"hello, world\n");
+
+    let input = dir.join("input.txt");
+    fs::File::create(&input)
+        .and_then(|mut f| f.write_all(b"copied in"))
+        .expect("write host file");
+    let output = run(&["cp", image, input.to_str().unwrap(), "::/DIR/IN.TXT"]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&["cat", image, "/DIR/IN.TXT"]);
+    assert_eq!(output.stdout, b"copied in");
+
+    fs::remove_dir_all(&dir).expect("clean up");
+}
@@ -0,0 +1,171 @@
+//! Integration tests against images written by other FAT32 implementations.
+//! See `images/README.md` for the fixture layout.
+//!
+//! The images aren't part of the repository, so the tests are ignored by
+//! default. Build or fetch the fixtures as described in `images/README.md`,
+//! then run them with `cargo test --test golden -- --ignored`.
+
+extern crate fat32;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use fat32::traits::{Dir, Entry, FileSystem, Metadata, Timestamp};
+use fat32::vfat::{Shared, VFat};
+
+/// A golden image and the tree it is expected to contain.
+struct Fixture {
+    name: String,
+    vfat: Shared<VFat>,
+    root: PathBuf,
+}
+
+/// Mounts every fixture under `images/` that has an image. Panics if there is
+/// none, so that the tests don't pass without checking anything.
+fn fixtures() -> Vec<Fixture> {
+    let images = Path::new(env!("CARGO_MANIFEST_DIR")).join("images");
+    let mut fixtures = Vec::new();
+    for entry in fs::read_dir(&images).expect("read images directory") {
+        let dir = entry.expect("read images directory").path();
+        let image = dir.join("image.img");
+        if !image.is_file() {
+            continue;
+        }
+
+        let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+        let device = fs::File::open(&image).expect("open golden image");
+        let vfat = VFat::from(device)
+            .unwrap_or_else(|e| panic!("{}: failed to mount image: {:?}", name, e));
+        fixtures.push(Fixture {
+            name,
+            vfat,
+            root: dir.join("tree"),
+        });
+    }
+
+    assert!(
+        !fixtures.is_empty(),
+        "no golden images found in {}; see images/README.md",
+        images.display()
+    );
+    fixtures
+}
+
+/// Returns the sorted names of the entries of the directory `path` in the
+/// image, excluding `.`, `..`, and the volume label.
+fn image_listing(vfat: &Shared<VFat>, path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = vfat
+        .open_dir(path)
+        .expect("open directory in image")
+        .entries()
+        .expect("list directory in image")
+        .filter(|e| e.name() != "." && e.name() != ".." && !e.metadata().volume_id())
+        .map(|e| e.name().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Returns the sorted names of the entries of the host directory `path`.
+fn host_listing(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .expect("list expected tree")
+        .map(|e| e.expect("list expected tree").file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Calls `f` with the image path and host path of every entry in the
+/// expected tree below `host`, parents first.
+fn visit<F: FnMut(&Path, &Path)>(host: &Path, image: &Path, f: &mut F) {
+    for name in host_listing(host) {
+        let (host, image) = (host.join(&name), image.join(&name));
+        f(&image, &host);
+        if host.is_dir() {
+            visit(&host, &image, f);
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn golden_listings() {
+    for fixture in fixtures() {
+        let vfat = &fixture.vfat;
+        let root = Path::new("/");
+        assert_eq!(image_listing(vfat, root), host_listing(&fixture.root), "{}: /", fixture.name);
+
+        visit(&fixture.root, root, &mut |image, host| {
+            if host.is_dir() {
+                assert_eq!(
+                    image_listing(vfat, image),
+                    host_listing(host),
+                    "{}: {}",
+                    fixture.name,
+                    image.display()
+                );
+            }
+        });
+    }
+}
+
+#[test]
+#[ignore]
+fn golden_contents() {
+    for fixture in fixtures() {
+        visit(&fixture.root, Path::new("/"), &mut |image, host| {
+            if !host.is_file() {
+                return;
+            }
+
+            let mut expected = Vec::new();
+            fs::File::open(host)
+                .and_then(|mut f| f.read_to_end(&mut expected))
+                .expect("read expected file");
+
+            let mut file = fixture.vfat.open_file(image).expect("open file in image");
+            let mut actual = Vec::new();
+            file.read_to_end(&mut actual).expect("read file in image");
+            assert!(actual == expected, "{}: contents of {}", fixture.name, image.display());
+        });
+    }
+}
+
+#[test]
+#[ignore]
+fn golden_timestamps() {
+    for fixture in fixtures() {
+        let path = fixture.root.with_file_name("timestamps");
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line.expect("read timestamps");
+            let mut fields = line.splitn(2, '\t');
+            let (path, expected) = match (fields.next(), fields.next()) {
+                (Some(path), Some(time)) => (path, time),
+                _ => continue,
+            };
+
+            let entry = fixture
+                .vfat
+                .open(Path::new("/").join(path))
+                .unwrap_or_else(|e| panic!("{}: open {}: {}", fixture.name, path, e));
+            let time = entry.metadata().modified();
+            let actual = format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                time.year(),
+                time.month(),
+                time.day(),
+                time.hour(),
+                time.minute(),
+                time.second()
+            );
+            assert_eq!(actual, expected, "{}: modification time of {}", fixture.name, path);
+        }
+    }
+}